@@ -4,7 +4,12 @@ use std::{cell::Cell, fs::File, thread::JoinHandle, time::Instant};
 use camino::Utf8PathBuf;
 use egui::{Color32, ColorImage, Layout, RichText, TextureHandle, Vec2};
 use egui_dock::Tree;
-use rawproc::{colorspace::Srgb, image::Image};
+use imgout::{Format, JpegOptions, SaveOptions, WebpOptions};
+use rawproc::{
+	algorithms,
+	colorspace::LinSrgb,
+	image::{Image, Interpolation, WhiteBalance},
+};
 use rgb::FromSlice;
 
 use crate::TrichromedImage;
@@ -87,9 +92,10 @@ struct SelectedChannel {
 	channel: Channel,
 	filename: Option<String>,
 	file_is_error: bool,
-	working_thread: Option<JoinHandle<Result<Image<u8, Srgb>, rawproc::Error>>>,
+	working_thread: Option<JoinHandle<Result<Image<f32, LinSrgb>, rawproc::Error>>>,
 
-	data: Option<Vec<u8>>,
+	/// Linear, so the composite can be balanced before it's drawn
+	data: Option<Vec<f32>>,
 	width: usize,
 	height: usize,
 }
@@ -153,7 +159,7 @@ impl SelectedChannel {
 		}
 	}
 
-	fn extract_channel(img: Image<u8, Srgb>, channel: Channel) -> Vec<u8> {
+	fn extract_channel(img: Image<f32, LinSrgb>, channel: Channel) -> Vec<f32> {
 		let chidx = channel.index();
 
 		let Image {
@@ -167,24 +173,29 @@ impl SelectedChannel {
 			data[idx] = data[idx * 3 + chidx];
 		}
 
-		data.resize(width * height, 0);
+		data.resize(width * height, 0.0);
 		data
 	}
 
-	fn spawn_work_thread(fname: String) -> JoinHandle<Result<Image<u8, Srgb>, rawproc::Error>> {
-		std::thread::spawn(|| {
+	fn spawn_work_thread(fname: String) -> JoinHandle<Result<Image<f32, LinSrgb>, rawproc::Error>> {
+		std::thread::spawn(move || {
 			let mut file = File::open(fname).unwrap();
-			rawproc::decode(&mut file).map(|mut img| {
-				img.crop();
-				img.whitebalance();
-				img.debayer().to_xyz().to_linsrgb().gamma().floats().bytes()
+			rawproc::decode(&mut file).and_then(|mut img| {
+				img.crop()?;
+				img.blacklevels(None);
+				img.whitebalance(WhiteBalance::AsShot);
+				Ok(img
+					.debayer(Interpolation::MalvarHeCutler)
+					.to_xyz()
+					.to_linsrgb()
+					.floats())
 			})
 		})
 	}
 }
 
 struct BorrowImage<'a> {
-	data: &'a [u8],
+	data: &'a [f32],
 	width: usize,
 	height: usize,
 }
@@ -269,6 +280,10 @@ struct DslrTrichrome {
 	red_offset: (isize, isize),
 	green_offset: (isize, isize),
 	blue_offset: (isize, isize),
+	/// Red, green, and blue multipliers for the composite. Each exposure
+	/// only gives us one channel, so a grey patch can only be balanced once
+	/// they're put together.
+	balance: [f32; 3],
 
 	texture: Option<TextureHandle>,
 	// This is an option so we can take() it, use DslrTrichrome as the TabViewer, and then put it back.
@@ -332,6 +347,7 @@ impl eframe::App for DslrTrichrome {
 				self.make_texture();
 			}
 
+			let mut spot = None;
 			ui.vertical(|ui| {
 				let avsize = ui.available_size();
 				ui.allocate_ui(Vec2::new(avsize.x, avsize.y), |ui| {
@@ -358,13 +374,35 @@ impl eframe::App for DslrTrichrome {
 							(true, true, _) | (false, _, false) => Vec2::new(hm_x, hm_y),
 						};
 
-						ui.with_layout(
-							egui::Layout::centered_and_justified(egui::Direction::LeftToRight),
-							|ui| ui.image(texture, tsize),
-						);
+						let response = ui
+							.with_layout(
+								egui::Layout::centered_and_justified(egui::Direction::LeftToRight),
+								|ui| {
+									ui.add(
+										egui::Image::new(texture, tsize)
+											.sense(egui::Sense::click()),
+									)
+								},
+							)
+							.inner;
+
+						// Clicking the preview picks a grey patch to whitebalance to
+						if response.clicked() {
+							if let Some(pos) = response.interact_pointer_pos() {
+								let rect = response.rect;
+								spot = Some((
+									(pos.x - rect.min.x) / rect.width(),
+									(pos.y - rect.min.y) / rect.height(),
+								));
+							}
+						}
 					});
 				});
 			});
+
+			if let Some((x, y)) = spot {
+				self.spot_whitebalance(x, y);
+			}
 		});
 	}
 }
@@ -406,6 +444,7 @@ impl DslrTrichrome {
 			red_offset: (0, 0),
 			green_offset: (0, 0),
 			blue_offset: (0, 0),
+			balance: [1.0; 3],
 
 			texture: None,
 			tabs: Some(tree),
@@ -476,6 +515,59 @@ impl DslrTrichrome {
 		}
 	}
 
+	/// How many pixels across the patch picked for a spot whitebalance is.
+	const SPOT_SIZE: isize = 16;
+
+	/// Balance the composite so the patch under the click is grey. `x` and `y`
+	/// go from 0 to 1 across the preview.
+	fn spot_whitebalance(&mut self, x: f32, y: f32) {
+		let (width, height) = match self.image.as_ref() {
+			None => return,
+			Some(img) => (img.width(), img.height()),
+		};
+
+		let x = (x.clamp(0.0, 1.0) * width as f32) as isize - Self::SPOT_SIZE / 2;
+		let y = (y.clamp(0.0, 1.0) * height as f32) as isize - Self::SPOT_SIZE / 2;
+
+		let mean = |selected: &SelectedChannel, offset: (isize, isize)| {
+			let data = selected.data.as_ref()?;
+
+			// The channels are drawn offset, so undo that to get to their
+			// pixels. Whatever of the patch falls off the channel is skipped.
+			let (mut sum, mut count) = (0.0, 0);
+			for chy in y - offset.1..y - offset.1 + Self::SPOT_SIZE {
+				for chx in x - offset.0..x - offset.0 + Self::SPOT_SIZE {
+					if chx >= 0
+						&& chy >= 0 && (chx as usize) < selected.width
+						&& (chy as usize) < selected.height
+					{
+						sum += data[chy as usize * selected.width + chx as usize];
+						count += 1;
+					}
+				}
+			}
+
+			Some(sum / count as f32).filter(|mean| count > 0 && *mean > 0.0)
+		};
+
+		let means = match (
+			mean(&self.red, self.red_offset),
+			mean(&self.green, self.green_offset),
+			mean(&self.blue, self.blue_offset),
+		) {
+			(Some(red), Some(green), Some(blue)) => [red, green, blue],
+			_ => return,
+		};
+
+		// Keep green where it is, like the camera's multipliers do
+		self.balance = [means[1] / means[0], 1.0, means[1] / means[2]];
+
+		self.redraw_channel(Channel::Red);
+		self.redraw_channel(Channel::Green);
+		self.redraw_channel(Channel::Blue);
+		self.make_texture();
+	}
+
 	fn redraw_selected_channel(&mut self) {
 		self.redraw_channel(self.selected)
 	}
@@ -521,7 +613,9 @@ impl DslrTrichrome {
 
 				let channel_idx = y as usize * width + x as usize;
 				let idx = yoff as usize * img.width() + xoff as usize;
-				img.as_raw_mut()[idx * 3 + channel.index()] = channel_img[channel_idx];
+				let light = channel_img[channel_idx] * self.balance[channel.index()];
+				img.as_raw_mut()[idx * 3 + channel.index()] =
+					(algorithms::srgb_gamma(light) * 255.0).round() as u8;
 			}
 		}
 	}
@@ -620,24 +714,27 @@ impl DslrTrichrome {
 	fn output<P: Into<Utf8PathBuf>>(&self, path: P, img: &ColorImage, fmt: OutputFormat) {
 		let mut path: Utf8PathBuf = path.into();
 
-		if !path.ends_with(fmt.ext()) {
-			path.set_file_name(format!("{}.{}", path.file_name().unwrap(), fmt.ext()));
+		let options = fmt.options();
+		let ext = options.format().extension();
+		if !path.ends_with(ext) {
+			path.set_file_name(format!("{}.{}", path.file_name().unwrap(), ext));
 		}
 
-		let mut data = img.as_raw().to_vec();
-		data.resize(img.width() * img.height() * 3, 0);
+		// ColorImage is RGBA and we want RGB
+		let data = img
+			.pixels
+			.iter()
+			.flat_map(|px| [px.r(), px.g(), px.b()])
+			.collect();
 
 		let trimg = TrichromedImage {
 			width: img.width(),
 			height: img.height(),
 			data,
+			metadata: None,
 		};
 
-		match fmt {
-			OutputFormat::Png => trimg.png(path),
-			OutputFormat::Jpeg => trimg.jpeg(path, 50.0),
-			OutputFormat::Webp => trimg.webp(path, 50.0),
-		}
+		trimg.save(path, options);
 	}
 }
 
@@ -649,11 +746,19 @@ enum OutputFormat {
 }
 
 impl OutputFormat {
-	pub fn ext(&self) -> &'static str {
+	pub fn options(&self) -> SaveOptions {
 		match self {
-			OutputFormat::Png => "png",
-			OutputFormat::Jpeg => "jpg",
-			OutputFormat::Webp => "webp",
+			OutputFormat::Png => Format::Png.into(),
+			OutputFormat::Jpeg => JpegOptions {
+				quality: 50.0,
+				..Default::default()
+			}
+			.into(),
+			OutputFormat::Webp => WebpOptions {
+				quality: 50.0,
+				..Default::default()
+			}
+			.into(),
 		}
 	}
 }
@@ -1,18 +1,16 @@
 mod gui;
 
-use std::{
-	fs::File,
-	io::{BufReader, Write},
-};
+use std::{fs::File, io::BufReader};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use exif::{Field, In, Tag};
-use rand::{thread_rng, Rng};
+use imgout::{Format, JpegOptions, Metadata, OutImage, SaveOptions, WebpOptions};
 use rawproc::{
 	colorspace::{BayerRgb, LinRgb, LinSrgb, Srgb},
 	decode,
-	image::Image,
+	image::{Image, WhiteBalance},
+	RollingRandom,
 };
 
 #[derive(Parser, Debug)]
@@ -36,6 +34,11 @@ struct Trichrome {
 	output_set: bool,
 	#[arg(long)]
 	gui: bool,
+	/// Seed for the random neighbour picking in the debayer. One is chosen and
+	/// printed if you don't give one, and it's written in the Software tag of
+	/// the output, so you can reproduce an output later.
+	#[arg(long)]
+	seed: Option<u64>,
 }
 
 impl Trichrome {
@@ -145,12 +148,18 @@ fn main() {
 		exposures
 	};
 
+	let mut rr = match args.seed {
+		Some(seed) => RollingRandom::new(seed),
+		None => RollingRandom::from_entropy(),
+	};
+	println!("Seed {}", rr.seed());
+
 	if args.bracketed {
 		println!("Bracketed");
-		bracketed(explicit, args.output_prefix, args.output_set)
+		bracketed(explicit, args.output_prefix, args.output_set, &mut rr)
 	} else {
 		println!("Trichrome (not bracketed)");
-		trichrome(explicit, args.output_prefix, args.output_set)
+		trichrome(explicit, args.output_prefix, args.output_set, &mut rr)
 	}
 }
 
@@ -222,10 +231,10 @@ fn print_file_exif<P: AsRef<Utf8Path>>(path: P) -> Result<(), exif::Error> {
 	Ok(())
 }
 
-fn bracketed(exposures: Exposures, prefix: Utf8PathBuf, set: bool) {
+fn bracketed(exposures: Exposures, prefix: Utf8PathBuf, set: bool, rr: &mut RollingRandom) {
 	let get_raw = |path: &Utf8Path| -> Image<u16, BayerRgb> {
 		let mut file = File::open(path).unwrap();
-		decode(&mut file).unwrap()
+		decode(&mut file).unwrap().into_integer()
 	};
 
 	let (red, green, blue) = match exposures {
@@ -237,36 +246,40 @@ fn bracketed(exposures: Exposures, prefix: Utf8PathBuf, set: bool) {
 	let mut green = get_raw(&green);
 	let mut blue = get_raw(&blue);
 
-	red.crop();
-	green.crop();
-	blue.crop();
+	red.crop().unwrap();
+	green.crop().unwrap();
+	blue.crop().unwrap();
+
+	red.blacklevels(None);
+	green.blacklevels(None);
+	blue.blacklevels(None);
 
-	let mut rgb = trichrome_debayer(red, green, blue);
+	let mut rgb = trichrome_debayer(red, green, blue, rr);
 
 	// Incrasing exposure (this also seems to clip the white square on the color
 	// chart exposures, making it look whiter than it is, oops)
-	let lv = rgb.metadata.whitelevels[0];
-	for light in rgb.data.iter_mut() {
-		*light =
-			(((*light as f32 / lv as f32) * 2f32.powf(2.0)).clamp(0.0, 1.0) * lv as f32) as u16;
+	for (idx, light) in rgb.data.iter_mut().enumerate() {
+		let lv = rgb.metadata.whitelevels[idx % 3] as f32;
+		*light = (((*light as f32 / lv) * 2f32.powf(2.0)).clamp(0.0, 1.0) * lv) as u16;
 	}
 
 	// I'm just transforing the colorspace here so I can get access to the gamma
 	let lsrgb = rgb.to_xyz().to_linsrgb();
 	let srgb = lsrgb.gamma();
 
-	let tri: TrichromedImage = srgb.into();
+	let mut tri: TrichromedImage = srgb.into();
+	tri.record_seed(rr.seed());
 	if set {
 		tri.output_set("bracketed", prefix);
 	} else {
-		tri.png(format!("{prefix}_bracketed.png"));
+		tri.save(format!("{prefix}_bracketed.png"), Format::Png);
 	}
 }
 
-fn trichrome(exposures: Exposures, prefix: Utf8PathBuf, set: bool) {
+fn trichrome(exposures: Exposures, prefix: Utf8PathBuf, set: bool, rr: &mut RollingRandom) {
 	let get_raw = |path: &Utf8Path| -> Image<u16, BayerRgb> {
 		let mut file = File::open(path).unwrap();
-		decode(&mut file).unwrap()
+		decode(&mut file).unwrap().into_integer()
 	};
 
 	let (red, green, blue) = match exposures {
@@ -275,39 +288,42 @@ fn trichrome(exposures: Exposures, prefix: Utf8PathBuf, set: bool) {
 	};
 
 	let mut red = get_raw(&red);
-	red.crop();
-	red.whitebalance();
+	red.crop().unwrap();
+	red.blacklevels(None);
+	red.whitebalance(WhiteBalance::AsShot);
 
 	let mut green = get_raw(&green);
-	green.crop();
-	green.whitebalance();
+	green.crop().unwrap();
+	green.blacklevels(None);
+	green.whitebalance(WhiteBalance::AsShot);
 
 	let mut blue = get_raw(&blue);
-	blue.crop();
-	blue.whitebalance();
+	blue.crop().unwrap();
+	blue.blacklevels(None);
+	blue.whitebalance(WhiteBalance::AsShot);
 
-	let mut rgb = trichrome_debayer(red, green, blue);
+	let mut rgb = trichrome_debayer(red, green, blue, rr);
 
 	// Incrasing exposure
-	let lv = rgb.metadata.whitelevels[0];
-	for light in rgb.data.iter_mut() {
-		*light =
-			(((*light as f32 / lv as f32) * 2f32.powf(2.0)).clamp(0.0, 1.0) * lv as f32) as u16;
+	for (idx, light) in rgb.data.iter_mut().enumerate() {
+		let lv = rgb.metadata.whitelevels[idx % 3] as f32;
+		*light = (((*light as f32 / lv) * 2f32.powf(2.0)).clamp(0.0, 1.0) * lv) as u16;
 	}
 
 	let linsrgb = rgb.to_xyz().to_linsrgb();
 	let srgb = linsrgb.gamma();
 
-	let tri: TrichromedImage = srgb.into();
+	let mut tri: TrichromedImage = srgb.into();
+	tri.record_seed(rr.seed());
 	if set {
 		tri.output_set("trichrome", prefix);
 	} else {
-		tri.png(format!("{prefix}_trichrome.png"));
+		tri.save(format!("{prefix}_trichrome.png"), Format::Png);
 	}
 }
 
 type Raw = Image<u16, BayerRgb>;
-fn trichrome_debayer(r: Raw, g: Raw, b: Raw) -> Image<u16, LinRgb> {
+fn trichrome_debayer(r: Raw, g: Raw, b: Raw, rr: &mut RollingRandom) -> Image<u16, LinRgb> {
 	let mut rgb = vec![0; g.width * g.height * 3];
 
 	// We use g as the reference throughout. It doesn't *really* matter which
@@ -315,7 +331,6 @@ fn trichrome_debayer(r: Raw, g: Raw, b: Raw) -> Image<u16, LinRgb> {
 	// of the time it's the "standard" in the white balance. I mean, the other
 	// values are normalized according to green
 	let cfa = g.metadata.cfa.clone();
-	let mut rr = RollingRandom::new();
 
 	#[rustfmt::skip]
 	let options = [
@@ -334,33 +349,33 @@ fn trichrome_debayer(r: Raw, g: Raw, b: Raw) -> Image<u16, LinRgb> {
 			let options = options.clone().into_iter().map(|(x_off, y_off)| {
 				let x = (x as isize + x_off) as usize;
 				let y = (y as isize + y_off) as usize;
-				(cfa.color_at(x, y), x, y)
+				(cfa.color_at(y, x), x, y)
 			});
 
-			match cfa.color_at(x, y) {
+			match cfa.color_at(y, x) {
 				0 => {
 					// Red
 					set(x, y, 0, get(&r, (x, y)));
 					// Green
-					set(x, y, 1, get(&g, pick_color(&mut rr, options.clone(), 1)));
+					set(x, y, 1, get(&g, pick_color(rr, options.clone(), 1)));
 					// Blue
-					set(x, y, 2, get(&b, pick_color(&mut rr, options, 2)));
+					set(x, y, 2, get(&b, pick_color(rr, options, 2)));
 				}
 				1 => {
 					// Green
 					set(x, y, 1, get(&g, (x, y)));
 					// Red
-					set(x, y, 0, get(&r, pick_color(&mut rr, options.clone(), 0)));
+					set(x, y, 0, get(&r, pick_color(rr, options.clone(), 0)));
 					// Blue
-					set(x, y, 2, get(&b, pick_color(&mut rr, options, 2)));
+					set(x, y, 2, get(&b, pick_color(rr, options, 2)));
 				}
 				2 => {
 					// Blue
 					set(x, y, 2, get(&b, (x, y)));
 					// Red
-					set(x, y, 0, get(&r, pick_color(&mut rr, options.clone(), 0)));
+					set(x, y, 0, get(&r, pick_color(rr, options.clone(), 0)));
 					// Green
-					set(x, y, 1, get(&g, pick_color(&mut rr, options, 1)));
+					set(x, y, 1, get(&g, pick_color(rr, options, 1)));
 				}
 				_ => unreachable!(),
 			}
@@ -382,53 +397,25 @@ where
 	(picked.1, picked.2)
 }
 
-// I carry this struct around with me a lot, lol. I should put it in a crate, but it
-// almost doesn't feel worth it. It's a fast random thing because generating random
-// values is slow. This just allocates a big ol' array and fills it all at once so
-// we can use them at will. It wraps around, but it should be enough random to not
-// generate a pattern? maybe???
-struct RollingRandom {
-	values: [u8; Self::BUCKET_SIZE],
-	index: u16,
-}
-
-impl RollingRandom {
-	// 8.3K
-	// .3 because 4K is a common image length, and I'd rather if there is a pattern
-	// that it fall on a diagonal I guess?
-	const BUCKET_SIZE: usize = 8533;
-
-	pub fn new() -> Self {
-		let mut values = [0u8; Self::BUCKET_SIZE];
-		thread_rng().fill(&mut values[..]);
-
-		Self { values, index: 0 }
-	}
-
-	pub fn random_bool(&mut self) -> bool {
-		self.random_u8() % 2 == 0
-	}
-
-	pub fn random_u8(&mut self) -> u8 {
-		let value = self.values[self.index as usize];
-
-		self.index += 1;
-		if self.index as usize >= Self::BUCKET_SIZE {
-			self.index = 0;
-		}
-
-		value
-	}
-}
-
 // Oh yeah, this is a great name.
 struct TrichromedImage {
 	width: usize,
 	height: usize,
 	data: Vec<u8>,
+	/// The camera and exposure, from the green frame
+	metadata: Option<Metadata>,
 }
 
 impl TrichromedImage {
+	// Write the debayer's seed down with the image so it can be made again
+	pub fn record_seed(&mut self, seed: u64) {
+		let metadata = self.metadata.get_or_insert_with(Default::default);
+		metadata.software = Some(format!(
+			"dslr-trichrome {}, seed {seed}",
+			env!("CARGO_PKG_VERSION")
+		));
+	}
+
 	// Make a half-sized image using neam to downscale
 	pub fn half(&self) -> TrichromedImage {
 		// The div by four then scale by 2 is a cheap way to get even dimesnions,
@@ -448,41 +435,17 @@ impl TrichromedImage {
 			width,
 			height,
 			data,
+			metadata: self.metadata.clone(),
 		}
 	}
 
-	// Save as a PNG
-	pub fn png<P: AsRef<Utf8Path>>(&self, path: P) {
-		let file = std::fs::File::create(path.as_ref()).unwrap();
-		let mut enc = png::Encoder::new(file, self.width as u32, self.height as u32);
-		enc.set_color(png::ColorType::Rgb);
-		enc.set_depth(png::BitDepth::Eight);
-
-		let mut writer = enc.write_header().unwrap();
-		writer.write_image_data(&self.data).unwrap();
-	}
-
-	pub fn jpeg<P: AsRef<Utf8Path>>(&self, path: P, quality: f32) {
-		let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
-
-		comp.set_size(self.width, self.height);
-		comp.set_quality(quality);
-		comp.set_mem_dest();
-		comp.start_compress();
-		assert!(comp.write_scanlines(&self.data[..]));
-
-		comp.finish_compress();
-
-		let mut file = File::create(path.as_ref()).unwrap();
-		file.write_all(&comp.data_to_vec().unwrap()).unwrap();
-	}
-
-	pub fn webp<P: AsRef<Utf8Path>>(&self, path: P, quality: f32) {
-		let enc = webp::Encoder::from_rgb(&self.data, self.width as u32, self.height as u32);
-		let img = enc.encode(quality);
-
-		let mut file = File::create(path.as_ref()).unwrap();
-		file.write_all(&img).unwrap();
+	// Save as whatever format the options are for
+	pub fn save<P: AsRef<Utf8Path>, O: Into<SaveOptions>>(&self, path: P, options: O) {
+		let mut img = OutImage::new(self.width, self.height, self.data.clone()).unwrap();
+		if let Some(metadata) = &self.metadata {
+			img = img.with_metadata(metadata.clone());
+		}
+		img.save_with(path.as_ref(), options).unwrap();
 	}
 
 	pub fn output_set<P: Into<Utf8PathBuf>, S: AsRef<str>>(&self, dir_name: P, prefix: S) {
@@ -496,31 +459,35 @@ impl TrichromedImage {
 		let make_path =
 			|size: &'static str, ext: &'static str| dir_name.join(format!("{prefix}_{size}.{ext}"));
 
-		self.png(make_path("full", "png"));
+		let jpeg = |quality| JpegOptions {
+			quality,
+			..Default::default()
+		};
+		let webp = |quality| WebpOptions {
+			quality,
+			..Default::default()
+		};
+
+		self.save(make_path("full", "png"), Format::Png);
 		let half = self.half();
-		half.jpeg(make_path("half", "jpg"), 50.0);
-		half.webp(make_path("half", "webp"), 45.0);
+		half.save(make_path("half", "jpg"), jpeg(50.0));
+		half.save(make_path("half", "webp"), webp(45.0));
 		let quart = half.half();
-		quart.jpeg(make_path("quarter", "jpg"), 80.0);
-		quart.webp(make_path("quarter", "webp"), 75.0);
+		quart.save(make_path("quarter", "jpg"), jpeg(80.0));
+		quart.save(make_path("quarter", "webp"), webp(75.0));
 	}
 }
 
 impl From<Image<u16, Srgb>> for TrichromedImage {
 	fn from(srgb: Image<u16, Srgb>) -> Self {
-		// yes I should use the proper whitelevel per channel. but! i do not care!
-		// they're all the same for my camera, and you're too i'd imagine
-		let lvl = srgb.metadata.whitelevels[0];
-		let eight: Vec<u8> = srgb
-			.data
-			.into_iter()
-			.map(|pix| ((pix as f32 / lvl as f32) * 255.0) as u8)
-			.collect();
+		// floats() normalizes each channel against its own whitelevel
+		let eight = srgb.floats().bytes();
 
 		Self {
-			width: srgb.width,
-			height: srgb.height,
-			data: eight,
+			width: eight.width,
+			height: eight.height,
+			metadata: Some((&eight.metadata).into()),
+			data: eight.data,
 		}
 	}
 }
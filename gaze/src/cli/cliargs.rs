@@ -1,6 +1,6 @@
-use super::{OneOrThree, ParseError};
+use super::{OneOrThree, ParseError, Region};
 use getopts::Options;
-use image::ImageFormat;
+use imgout::Format;
 use std::error::Error;
 use std::fmt;
 use std::io::Error as IoError;
@@ -12,11 +12,14 @@ pub struct CliArgs {
     pub in_is_dir: bool,
 
     pub out_path: PathBuf,
-    pub out_type: ImageFormat,
+    pub out_type: Format,
 
     pub thumb: bool,
+    pub keep_orientation: bool,
+    pub previews: bool,
     pub black: Option<OneOrThree<u16>>,
     pub white: Option<OneOrThree<f32>>,
+    pub white_region: Option<Region>,
     pub auto_level: bool,
     pub exposure: Option<f32>,
     pub contrast: Option<f32>,
@@ -66,10 +69,21 @@ impl CliArgs {
         opts.optopt(
             "",
             "type",
-            "Set the output image type\nAvailable types are: png, jpeg",
+            "Set the output image type\nAvailable types are: png, jpeg, webp, tiff",
             "TYPE",
         );
         opts.optflag("t", "thumb", "Scale the image down to 1/4 size");
+        opts.optflag(
+            "p",
+            "previews",
+            "Write out the JPEG preview embedded in the raw instead of processing it\n\
+            Much faster, but the output can only be JPEG",
+        );
+        opts.optflag(
+            "",
+            "keep-orientation",
+            "Don't turn the image the way the camera was held",
+        );
         opts.optopt(
             "l",
             "black",
@@ -82,6 +96,15 @@ impl CliArgs {
             "White balance adjustment values\nDefaults to camera's values\nEx: 1.0 or 2.1,1.0,1.3",
             "FLOATS",
         );
+        opts.optopt(
+            "",
+            "white-region",
+            "White balance so this region of the image is neutral, like a grey card\n\
+            Overrides --white. In pixels of the full size image, turned the\n\
+            way the camera was held unless --keep-orientation is given\n\
+            Ex: 100,200,50,50 for x, y, width, height",
+            "INTS",
+        );
         opts.optflag(
             "a",
             "auto-level",
@@ -113,13 +136,13 @@ impl CliArgs {
         };
 
         let mut out_type = if let Some(s) = matches.opt_str("type") {
-            match ImageFormat::from_extension(&s) {
+            match Format::from_extension(&s) {
                 Some(format) => format,
                 None => return Err(ParseError::imageformat(s).into()),
             }
         } else {
             // Defaults to jpeg
-            ImageFormat::Jpeg
+            Format::Jpeg
         };
 
         let out_path = match matches.opt_str("opath").map(|s| PathBuf::from(s)) {
@@ -136,11 +159,11 @@ impl CliArgs {
                     match path.extension() {
                         None => {
                             // No extension, add one from out_type
-                            path.set_extension(out_type.extensions_str()[0]);
+                            path.set_extension(out_type.extension());
                         }
                         Some(ext) => {
                             // Out path has extension, does it match a format?
-                            match ImageFormat::from_extension(ext) {
+                            match Format::from_extension(ext) {
                                 Some(fmt) => {
                                     // Yes! Set the format.
                                     out_type = fmt;
@@ -163,16 +186,24 @@ impl CliArgs {
                     return Err(CliError::OutPathError);
                 } else {
                     let mut out = in_path.clone();
-                    out.set_extension(out_type.extensions_str()[0]);
+                    out.set_extension(out_type.extension());
                     out
                 }
             }
         };
 
         let thumb = matches.opt_present("thumb");
+        let keep_orientation = matches.opt_present("keep-orientation");
+        let previews = matches.opt_present("previews");
+        if previews && out_type != Format::Jpeg {
+            return Err(CliError::PreviewType);
+        }
 
         let black = matches.opt_get("black").map_err(|e| ParseError::from(e))?;
         let white = matches.opt_get("white").map_err(|e| ParseError::from(e))?;
+        let white_region = matches
+            .opt_get("white-region")
+            .map_err(|e| ParseError::from(e))?;
         let auto_level = matches.opt_present("auto-level");
         let exposure = matches
             .opt_get("exposure")
@@ -202,9 +233,12 @@ impl CliArgs {
             out_path,
             out_type,
             thumb,
+            keep_orientation,
+            previews,
 
             black,
             white,
+            white_region,
             auto_level,
             exposure,
             contrast,
@@ -223,6 +257,7 @@ impl CliArgs {
 pub enum CliError {
     InPathError(IoError),
     OutPathError,
+    PreviewType,
     MatchError(String),
     ParseError(ParseError),
 }
@@ -239,6 +274,10 @@ impl fmt::Display for CliError {
                 "An output path is requried if the input path is a directory\n\
                 If you want to output in the current directory, use '.' as the out path"
             ),
+            CliError::PreviewType => write!(
+                f,
+                "Previews are already JPEGs, so they can only be written as JPEG"
+            ),
             CliError::MatchError(usage) => write!(f, "{}", usage),
             CliError::ParseError(err) => err.fmt(f),
         }
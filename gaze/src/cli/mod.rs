@@ -4,4 +4,4 @@ mod types;
 
 pub use cliargs::CliArgs;
 pub use parseerror::ParseError;
-pub use types::{OneOrThree, ParseOneOrThreeError};
+pub use types::{OneOrThree, ParseOneOrThreeError, ParseRegionError, Region};
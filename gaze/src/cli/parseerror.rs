@@ -1,4 +1,4 @@
-use super::ParseOneOrThreeError;
+use super::{ParseOneOrThreeError, ParseRegionError};
 use std::error::Error;
 use std::fmt;
 use std::num::{ParseFloatError, ParseIntError};
@@ -46,6 +46,16 @@ impl fmt::Display for ParseError {
                     err
                 )
             }
+            ParseErrorKind::Region(err) => {
+                write!(
+                    f,
+                    "Failed to parse region: {}\n\n\
+                        A region is four whole numbers seperated by a comma: x, y, width, height.\n\
+                        Ex:\n\t\
+                            100,200,50,50 or \"100, 200, 50, 50\"",
+                    err
+                )
+            }
             ParseErrorKind::ImageFormat(format) => {
                 write!(f, "'{}' is not a recognized image format", format)
             }
@@ -58,6 +68,7 @@ pub enum ParseErrorKind {
     Int(ParseIntError),
     Float(ParseFloatError),
     OneOrThree(ParseOneOrThreeError),
+    Region(ParseRegionError),
     ImageFormat(String),
 }
 
@@ -85,3 +96,11 @@ impl From<ParseOneOrThreeError> for ParseError {
         }
     }
 }
+
+impl From<ParseRegionError> for ParseError {
+    fn from(frm: ParseRegionError) -> Self {
+        ParseError {
+            kind: ParseErrorKind::Region(frm),
+        }
+    }
+}
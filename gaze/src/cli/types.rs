@@ -135,3 +135,58 @@ enum OneOrThreeErrorKind {
     TooManyValues(usize),
     ValueParseError(String),
 }
+
+/// A rectangle on the image, given as `x,y,width,height`.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FromStr for Region {
+    type Err = ParseRegionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<&str> = s.split(',').map(|s| s.trim()).collect();
+
+        if values.len() != 4 {
+            return Err(ParseRegionError::ValueCount(values.len()));
+        }
+
+        Ok(Region {
+            x: values[0].parse()?,
+            y: values[1].parse()?,
+            width: values[2].parse()?,
+            height: values[3].parse()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseRegionError {
+    ValueCount(usize),
+    ValueParseError(ParseIntError),
+}
+
+impl Error for ParseRegionError {}
+
+impl fmt::Display for ParseRegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseRegionError::ValueCount(num) => {
+                write!(f, "Expected four values, but saw {}", num)
+            }
+            ParseRegionError::ValueParseError(err) => {
+                write!(f, "Failed to parse a value: {}", err)
+            }
+        }
+    }
+}
+
+impl From<ParseIntError> for ParseRegionError {
+    fn from(frm: ParseIntError) -> Self {
+        ParseRegionError::ValueParseError(frm)
+    }
+}
@@ -4,14 +4,9 @@ mod subsample;
 mod tui;
 
 use cli::CliArgs;
-use image::imageops::rotate270;
-use image::imageops::rotate90;
-use image::ImageBuffer;
-use image::Rgb as ImageRgb;
-use rawproc::debayer::{Debayer, Interpolation};
-use rawproc::image::HsvImage;
-use rawproc::image::RgbImage;
-use rawproc::image::SensorImage;
+use imgout::{Metadata, OutImage};
+use rawproc::colorspace::{BayerRgb, Hsv, Srgb};
+use rawproc::image::{Image, Interpolation, Orientation, WhiteBalance};
 use std::fs::File;
 use std::path::PathBuf;
 
@@ -37,20 +32,65 @@ fn main() {
 }
 
 fn file(cli: CliArgs, in_file: &PathBuf, out_file: &PathBuf) {
-    let mut rimg = rawproc::read_file(in_file.to_str().unwrap());
+    if cli.previews {
+        return preview(&cli, in_file, out_file);
+    }
+
+    let mut file = File::open(in_file).unwrap();
+    let mut rimg = rawproc::decode(&mut file).unwrap().into_floats();
+    rimg.crop().unwrap();
 
     if cli.thumb {
         rimg = subsample::subsample(rimg);
     }
 
-    let bytes = process(cli.clone(), rimg);
+    let mut bytes = match process(cli.clone(), rimg) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", in_file.to_string_lossy(), e);
+            return;
+        }
+    };
+
+    if !cli.keep_orientation {
+        bytes.orient();
+    }
+
+    let mut metadata: Metadata = (&bytes.metadata).into();
+    if cli.keep_orientation {
+        // Left how the sensor saw it, so don't have viewers turn it either
+        metadata.orientation = None;
+    }
+
+    OutImage::new(bytes.width, bytes.height, bytes.data)
+        .unwrap()
+        .with_metadata(metadata)
+        .save_with(out_file, cli.out_type)
+        .unwrap()
+}
 
-    let imgbuf: ImageBuffer<ImageRgb<u8>, Vec<u8>> =
-        ImageBuffer::from_raw(bytes.meta.width, bytes.meta.height, bytes.data).unwrap();
+// Write out the largest JPEG the camera embedded without touching the sensor
+// data. It's tagged with the capture and orientation rather than turned, which
+// would mean decoding it
+fn preview(cli: &CliArgs, in_file: &PathBuf, out_file: &PathBuf) {
+    let mut file = File::open(in_file).unwrap();
+    let previews = rawproc::read_previews(&mut file).unwrap();
+
+    let preview = match previews.first() {
+        Some(preview) => preview,
+        None => {
+            eprintln!("{} has no embedded preview", in_file.to_string_lossy());
+            return;
+        }
+    };
 
-    let imgbuf = rotate270(&imgbuf);
+    let mut metadata: Metadata = (&preview.capture).into();
+    if !cli.keep_orientation {
+        metadata.orientation = Some(preview.orientation.exif());
+    }
+    let jpeg = imgout::tag_jpeg(&preview.jpeg, &metadata).unwrap();
 
-    imgbuf.save_with_format(out_file, cli.out_type).unwrap()
+    std::fs::write(out_file, jpeg).unwrap();
 }
 
 fn directory(cli: CliArgs) {
@@ -61,7 +101,7 @@ fn directory(cli: CliArgs) {
     for entry in contents {
         let entry = entry.expect("Failed reading a file");
         let mut filename = PathBuf::from(&entry.file_name());
-        filename.set_extension(cli.out_type.extensions_str()[0]);
+        filename.set_extension(cli.out_type.extension());
 
         let cliclone = cli.clone();
 
@@ -83,10 +123,15 @@ fn directory(cli: CliArgs) {
     threadpool.join();
 }
 
-fn process(cli: CliArgs, mut sensor_ints: SensorImage<u16>) -> RgbImage<u8> {
-    sensor_ints.black_levels(cli.black.map(|or3| or3.as_triple_tuple()));
-
-    let mut sensor_floats = sensor_ints.into_floats();
+fn process(
+    cli: CliArgs,
+    mut sensor_floats: Image<f32, BayerRgb>,
+) -> Result<Image<u8, Srgb>, rawproc::Error> {
+    // The fourth level is for emerald, which we treat like green
+    sensor_floats.blacklevels(cli.black.map(|or3| {
+        let (r, g, b) = or3.as_triple_tuple();
+        [r, g, b, g]
+    }));
 
     if cli.auto_level {
         let mut lowest = 1.0;
@@ -108,10 +153,66 @@ fn process(cli: CliArgs, mut sensor_ints: SensorImage<u16>) -> RgbImage<u8> {
         }
     }
 
-    sensor_floats.white_balance(cli.white.map(|or3| or3.as_triple_tuple()));
+    match cli.white_region {
+        Some(mut region) => {
+            if cli.thumb {
+                // subsample took us down to a quarter
+                region.x /= 4;
+                region.y /= 4;
+                region.width = (region.width / 4).max(1);
+                region.height = (region.height / 4).max(1);
+            }
+
+            // The region is given on the image the right way up, but we're
+            // still in the sensor's orientation
+            let orientation = if cli.keep_orientation {
+                Orientation::Normal
+            } else {
+                sensor_floats.metadata.orientation
+            };
+            let (width, height) = (sensor_floats.width, sensor_floats.height);
+            let (x, y, region_width, region_height) = orientation
+                .source_rect(
+                    region.x,
+                    region.y,
+                    region.width,
+                    region.height,
+                    width,
+                    height,
+                )
+                .ok_or_else(|| {
+                    let (width, height) = if orientation.swaps_dimensions() {
+                        (height, width)
+                    } else {
+                        (width, height)
+                    };
+
+                    rawproc::Error::RectOutOfBounds {
+                        x: region.x,
+                        y: region.y,
+                        rect_width: region.width,
+                        rect_height: region.height,
+                        width,
+                        height,
+                    }
+                })?;
+
+            sensor_floats.spot_whitebalance(x, y, region_width, region_height)?;
+        }
+        None => {
+            if let Some(or3) = cli.white {
+                let (r, g, b) = or3.as_triple_tuple();
+                sensor_floats.metadata.whitebalance = [r, g, b, g];
+            }
+        }
+    }
+    sensor_floats.whitebalance(WhiteBalance::AsShot);
 
     if let Some(ev) = cli.exposure {
-        sensor_floats.exposure(ev);
+        let scale = 2f32.powf(ev);
+        for light in sensor_floats.data.iter_mut() {
+            *light *= scale;
+        }
     }
 
     if let Some(curve_file) = cli.tone_curve_path {
@@ -131,74 +232,38 @@ fn process(cli: CliArgs, mut sensor_ints: SensorImage<u16>) -> RgbImage<u8> {
         }
     }
 
-    let debayer = Debayer::new(sensor_floats);
-    let mut rgb_floats = debayer.interpolate(Interpolation::Bilinear);
-
-    rgb_floats.to_srgb();
+    // The colour conversions are on integers
+    let srgb = sensor_floats
+        .debayer(Interpolation::Bilinear)
+        .sxiteen()
+        .to_xyz()
+        .to_linsrgb()
+        .gamma()
+        .floats();
+
+    let mut hsv_floats: Image<f32, Hsv> = srgb.into();
+    for hsv in hsv_floats.data.chunks_exact_mut(3) {
+        if let Some(shift) = cli.hue_shift {
+            hsv[0] = (hsv[0] + shift).rem_euclid(360.0);
+        }
 
-    let mut hsv_floats = rgb_floats.into_hsv();
-    if let Some(bright) = cli.brightness {
-        hsv_floats.brightness(bright);
+        if let Some(bright) = cli.brightness {
+            hsv[2] = (hsv[2] + bright).clamp(0.0, 1.0);
+        }
     }
 
     if let Some(sat) = cli.saturation {
         hsv_floats.saturation(sat);
     }
 
-    if let Some(shift) = cli.hue_shift {
-        hsv_floats.hue_shift(shift);
-    }
-
-    let mut rgb_floats = hsv_floats.into_rgb();
+    let mut rgb_floats: Image<f32, Srgb> = hsv_floats.into();
     if let Some(con) = cli.contrast {
         rgb_floats.contrast(con);
     }
 
-    rgb_floats.into_u8s()
+    Ok(rgb_floats.bytes())
 }
 
 fn lerp(start: f32, end: f32, percent: f32) -> f32 {
     start + (end - start) * percent
 }
-
-fn temperature_to_rgb(kelvin: f32) -> (f32, f32, f32) {
-    let temperature = kelvin / 100.0;
-
-    let red = if temperature <= 66.0 {
-        255.0
-    } else {
-        let mut red = temperature - 60.0;
-        red = 329.698727446 * red.powf(-0.1332047592);
-
-        red.clamp(0.0, 255.0)
-    };
-
-    let green = if temperature <= 66.0 {
-        let mut green = temperature;
-        green = 99.4708025861 * green.ln() - 161.1195681661;
-
-        green.clamp(0.0, 255.0)
-    } else {
-        let mut green = temperature - 60.0;
-        green = 288.1221695283 * green.powf(-0.0755148492);
-
-        green.clamp(0.0, 255.0)
-    };
-
-    let blue = if temperature >= 66.0 {
-        255.0
-    } else {
-        if temperature <= 19.0 {
-            0.0
-        } else {
-            let mut blue = temperature - 10.0;
-            blue = 138.5177312231 * blue.ln() - 305.0447927307;
-
-            blue.clamp(0.0, 255.0)
-        }
-    };
-
-    let max = red.min(green).min(blue);
-
-    (1.0 / (red / max), 1.0 / (green / max), 1.0 / (blue / max))
-}
@@ -1,24 +1,26 @@
-use rawproc::image::{Component, Metadata, SensorImage};
+use rawproc::colorspace::BayerRgb;
+use rawproc::image::Image;
 
-pub fn subsample<T: Component>(rimg: SensorImage<T>) -> SensorImage<T> {
-    // Assuming RGGB and a 1/4 scale
+pub fn subsample<T: Copy>(rimg: Image<T, BayerRgb>) -> Image<T, BayerRgb> {
+    // A 2x2 from every 8x8 for a 1/4 scale. Eight is a multiple of the 2x2
+    // CFA, so every 2x2 starts on the same colour and the pattern is the same
     let mut raw = vec![];
 
     // These are dimensions in CFA groups
-    let width = rimg.meta.width / 8;
-    let height = rimg.meta.height / 8;
+    let width = rimg.width / 8;
+    let height = rimg.height / 8;
 
     for y in 0..height {
-        let j = y as usize * 8 * rimg.meta.width as usize;
+        let j = y * 8 * rimg.width;
         for x in 0..width {
-            let i = j + (x as usize * 8);
+            let i = j + (x * 8);
 
             raw.push(rimg.data[i]);
             raw.push(rimg.data[i + 1]);
         }
 
         for x in 0..width {
-            let i = j + (x as usize * 8) + rimg.meta.width as usize;
+            let i = j + (x * 8) + rimg.width;
 
             raw.push(rimg.data[i]);
             raw.push(rimg.data[i + 1]);
@@ -28,10 +30,7 @@ pub fn subsample<T: Component>(rimg: SensorImage<T>) -> SensorImage<T> {
     let width = width * 2;
     let height = height * 2;
 
-    assert_eq!(width as usize * height as usize, raw.len());
+    assert_eq!(width * height, raw.len());
 
-    SensorImage {
-        data: raw,
-        meta: Metadata::new(width, height, rimg.meta.cfa, rimg.meta.colordata),
-    }
+    Image::from_raw_parts(width, height, rimg.metadata, raw)
 }
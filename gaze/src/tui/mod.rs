@@ -7,8 +7,8 @@ use std::{
 
 use give::Give;
 use rawproc::{
-    debayer::{Debayer, Interpolation},
-    image::{Image, RgbImage, SensorImage},
+    colorspace::{BayerRgb, Hsv, Srgb},
+    image::{Image, Interpolation, WhiteBalance},
 };
 
 use crate::{cli::CliArgs, subsample};
@@ -26,7 +26,9 @@ impl Tui {
         }
 
         let file_path = cliargs.in_path;
-        let sensor = rawproc::read_file(file_path.to_str().unwrap());
+        let mut file = std::fs::File::open(&file_path).unwrap();
+        let mut sensor = rawproc::decode(&mut file).unwrap().into_floats();
+        sensor.crop().unwrap();
         // This only works when we subsample, so uh, UH
         let sensor = subsample::subsample(sensor);
 
@@ -124,24 +126,21 @@ struct Preview {
 }
 
 impl Preview {
-    pub fn new(image: &RgbImage<u8>) -> Self {
+    pub fn new(image: &Image<u8, Srgb>) -> Self {
         let mut this = Self { give: Give::new() };
         this.update(image);
         this.give.make_window(640, 480);
         this
     }
 
-    pub fn update(&mut self, image: &RgbImage<u8>) {
-        self.give.display_buffered_rgb8(
-            image.meta.width as usize,
-            image.meta.height as usize,
-            image.data.clone(),
-        );
+    pub fn update(&mut self, image: &Image<u8, Srgb>) {
+        self.give
+            .display_buffered_rgb8(image.width, image.height, image.data.clone());
     }
 }
 
 struct EditingImage {
-    raw: SensorImage<u16>,
+    raw: Image<f32, BayerRgb>,
 
     black_levels: Option<Color<u16>>,
     white_balance: Option<Color<f32>>,
@@ -151,31 +150,31 @@ struct EditingImage {
     /// - black level correction
     /// - white balancing
     /// - exposure
-    adjusted: SensorImage<f32>,
+    adjusted: Image<f32, BayerRgb>,
 
     /// The adjusted_sensor image with these, too:
     /// - Debayering
     /// - sRGB conversion
-    srgb: RgbImage<f32>,
+    srgb: Image<f32, Srgb>,
 
     brightness: Option<f32>,
     saturation: Option<f32>,
     contrast: Option<f32>,
 
     /// The final image after all adjustments are applied
-    done: RgbImage<u8>,
+    done: Image<u8, Srgb>,
 }
 
 impl EditingImage {
-    pub fn builder(image: SensorImage<u16>) -> EditingImageBuilder {
+    pub fn builder(image: Image<f32, BayerRgb>) -> EditingImageBuilder {
         EditingImageBuilder::new(image)
     }
 
     pub fn major_size(&self) -> usize {
-        let raw = self.raw.data().len() * size_of::<u16>();
-        let adjusted = self.adjusted.data().len() * size_of::<f32>();
-        let srgb = self.srgb.data().len() * size_of::<f32>();
-        let done = self.done.data().len() * size_of::<u8>();
+        let raw = self.raw.data.len() * size_of::<f32>();
+        let adjusted = self.adjusted.data.len() * size_of::<f32>();
+        let srgb = self.srgb.data.len() * size_of::<f32>();
+        let done = self.done.data.len() * size_of::<u8>();
 
         raw + adjusted + srgb + done
     }
@@ -216,7 +215,7 @@ impl EditingImage {
 }
 
 struct EditingImageBuilder {
-    image: SensorImage<u16>,
+    image: Image<f32, BayerRgb>,
     black_levels: Option<Color<u16>>,
     white_balance: Option<Color<f32>>,
     exposure: Option<f32>,
@@ -226,7 +225,7 @@ struct EditingImageBuilder {
 }
 
 impl EditingImageBuilder {
-    pub fn new(image: SensorImage<u16>) -> Self {
+    pub fn new(image: Image<f32, BayerRgb>) -> Self {
         Self {
             image,
             black_levels: None,
@@ -275,54 +274,62 @@ struct Color<T: Copy> {
     b: T,
 }
 
-impl<T: Copy> Into<(T, T, T)> for Color<T> {
-    fn into(self) -> (T, T, T) {
-        (self.r, self.g, self.b)
-    }
-}
-
 fn step1(
-    mut sensor: SensorImage<u16>,
+    mut sensor: Image<f32, BayerRgb>,
     black_levels: Option<Color<u16>>,
     white_balance: Option<Color<f32>>,
     exposure: Option<f32>,
-) -> SensorImage<f32> {
-    sensor.black_levels(black_levels.map(|bl| bl.into()));
-    let mut floats = sensor.into_floats();
-    floats.white_balance(white_balance.map(|wb| wb.into()));
+) -> Image<f32, BayerRgb> {
+    // The fourth colour is emerald, which we treat like green
+    sensor.blacklevels(black_levels.map(|bl| [bl.r, bl.g, bl.b, bl.g]));
+    if let Some(wb) = white_balance {
+        sensor.metadata.whitebalance = [wb.r, wb.g, wb.b, wb.g];
+    }
+    sensor.whitebalance(WhiteBalance::AsShot);
+
     if let Some(ev) = exposure {
-        floats.exposure(ev);
+        let scale = 2f32.powf(ev);
+        for light in sensor.data.iter_mut() {
+            *light *= scale;
+        }
     }
-    floats
+    sensor
 }
 
-fn step2(adjusted: SensorImage<f32>) -> RgbImage<f32> {
-    let mut debayered = Debayer::new(adjusted).interpolate(Interpolation::Bilinear);
-    debayered.to_srgb();
-    debayered
+fn step2(adjusted: Image<f32, BayerRgb>) -> Image<f32, Srgb> {
+    // The colour conversions are on integers
+    adjusted
+        .debayer(Interpolation::Bilinear)
+        .sxiteen()
+        .to_xyz()
+        .to_linsrgb()
+        .gamma()
+        .floats()
 }
 
 fn step3(
-    srgb: RgbImage<f32>,
+    srgb: Image<f32, Srgb>,
     brightness: Option<f32>,
     saturation: Option<f32>,
     contrast: Option<f32>,
-) -> RgbImage<u8> {
-    let mut hsv = srgb.into_hsv();
+) -> Image<u8, Srgb> {
+    let mut hsv: Image<f32, Hsv> = srgb.into();
 
     if let Some(v) = brightness {
-        hsv.brightness(v);
+        for px in hsv.data.chunks_exact_mut(3) {
+            px[2] = (px[2] + v).clamp(0.0, 1.0);
+        }
     }
 
     if let Some(s) = saturation {
         hsv.saturation(s);
     }
 
-    let mut floats = hsv.into_rgb();
+    let mut floats: Image<f32, Srgb> = hsv.into();
 
     if let Some(c) = contrast {
         floats.contrast(c);
     }
 
-    floats.into_u8s()
+    floats.bytes()
 }
@@ -0,0 +1,356 @@
+//! Wrapping an ICC profile, EXIF, and XMP up the way each format wants them.
+
+/// The data of a PNG iCCP chunk. The profile has to be zlib compressed, but
+/// nothing says it has to be any smaller for it, so it goes in stored blocks.
+pub(crate) fn png_iccp(name: &str, profile: &[u8]) -> Vec<u8> {
+	// Names are 1 to 79 bytes of Latin-1
+	let name: String = name.chars().filter(char::is_ascii).take(79).collect();
+
+	let mut chunk = name.into_bytes();
+	chunk.push(0);
+	// Compression method 0, zlib
+	chunk.push(0);
+	chunk.extend(zlib_stored(profile));
+	chunk
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+	// Deflate, 32K window, no dictionary, and a check value that makes this a
+	// multiple of 31
+	let mut out = vec![0x78, 0x01];
+
+	let mut blocks = data.chunks(u16::MAX as usize).peekable();
+	if blocks.peek().is_none() {
+		out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+	}
+
+	while let Some(block) = blocks.next() {
+		let last = blocks.peek().is_none();
+		let len = block.len() as u16;
+
+		out.push(last as u8);
+		out.extend_from_slice(&len.to_le_bytes());
+		out.extend_from_slice(&(!len).to_le_bytes());
+		out.extend_from_slice(block);
+	}
+
+	out.extend_from_slice(&adler32(data).to_be_bytes());
+	out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+	let (mut a, mut b) = (1u32, 0u32);
+	for byte in data {
+		a = (a + *byte as u32) % 65521;
+		b = (b + a) % 65521;
+	}
+	(b << 16) | a
+}
+
+const ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+/// A JPEG segment's length is a u16 that counts itself, and then the ICC
+/// marker and the two sequence bytes go in front of the profile.
+const ICC_SEGMENT_DATA: usize = u16::MAX as usize - 2 - ICC_MARKER.len() - 2;
+
+/// The data of the APP2 segments that carry the profile in a JPEG. Big
+/// profiles get split across as many as they need. The segments are
+/// numbered with a byte, so `None` if it would take more than 255.
+pub(crate) fn jpeg_app2(profile: &[u8]) -> Option<Vec<Vec<u8>>> {
+	let count = u8::try_from(profile.chunks(ICC_SEGMENT_DATA).count()).ok()?;
+
+	Some(
+		profile
+			.chunks(ICC_SEGMENT_DATA)
+			.enumerate()
+			.map(|(idx, chunk)| {
+				let mut segment = ICC_MARKER.to_vec();
+				// Sequence numbers start at one
+				segment.push(idx as u8 + 1);
+				segment.push(count);
+				segment.extend_from_slice(chunk);
+				segment
+			})
+			.collect(),
+	)
+}
+
+/// The data of the APP1 segment that carries EXIF in a JPEG.
+pub(crate) fn jpeg_exif(exif: &[u8]) -> Vec<u8> {
+	let mut segment = b"Exif\0\0".to_vec();
+	segment.extend_from_slice(exif);
+	segment
+}
+
+/// The data of the APP1 segment that carries XMP in a JPEG.
+pub(crate) fn jpeg_xmp(xmp: &str) -> Vec<u8> {
+	let mut segment = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+	segment.extend_from_slice(xmp.as_bytes());
+	segment
+}
+
+/// A JPEG segment's marker and its data, without the length.
+pub(crate) type Segment<'a> = (u8, &'a [u8]);
+
+/// The segments of an encoded JPEG that come before its first scan, and
+/// where that scan starts. `None` if it doesn't look like a JPEG or there's
+/// no scan. rawproc walks JPEGs the same way.
+pub(crate) fn jpeg_segments(jpeg: &[u8]) -> Option<(Vec<Segment<'_>>, usize)> {
+	if jpeg.get(0..2)? != [0xFF, 0xD8] {
+		return None;
+	}
+
+	let mut segments = vec![];
+	let mut at = 2;
+	loop {
+		if *jpeg.get(at)? != 0xFF {
+			return None;
+		}
+		// Markers can have any number of 0xFF before them as padding
+		while *jpeg.get(at + 1)? == 0xFF {
+			at += 1;
+		}
+
+		match jpeg[at + 1] {
+			0xDA => return Some((segments, at)),
+			// The end of the image
+			0xD9 => return None,
+			// TEM, the restarts, and SOI stand alone without a length
+			0x01 | 0xD0..=0xD8 => at += 2,
+			marker => {
+				let length = u16::from_be_bytes([*jpeg.get(at + 2)?, *jpeg.get(at + 3)?]) as usize;
+				segments.push((marker, jpeg.get(at + 4..at + 2 + length)?));
+				at += 2 + length;
+			}
+		}
+	}
+}
+
+/// The width and height from the data of a frame header segment
+pub(crate) fn jpeg_frame_size(marker: u8, data: &[u8]) -> Option<(usize, usize)> {
+	// Every SOF but DHT, JPG, and DAC, which share the range
+	let frame = matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC);
+	if !frame || data.len() < 5 {
+		return None;
+	}
+
+	let height = u16::from_be_bytes([data[1], data[2]]) as usize;
+	let width = u16::from_be_bytes([data[3], data[4]]) as usize;
+	Some((width, height))
+}
+
+/// If the data of an APP1 segment is EXIF or XMP
+pub(crate) fn is_jpeg_metadata(data: &[u8]) -> bool {
+	data.starts_with(b"Exif\0\0") || data.starts_with(b"http://ns.adobe.com/xap/1.0/\0")
+}
+
+/// The data of a PNG iTXt chunk with XMP in it, uncompressed.
+pub(crate) fn png_xmp(xmp: &str) -> Vec<u8> {
+	let mut chunk = b"XML:com.adobe.xmp\0".to_vec();
+	// Not compressed, and no language or translated keyword
+	chunk.extend_from_slice(&[0, 0, 0, 0]);
+	chunk.extend_from_slice(xmp.as_bytes());
+	chunk
+}
+
+/// What goes in a WebP besides the image.
+pub(crate) struct WebpExtras<'a> {
+	pub alpha: bool,
+	pub profile: &'a [u8],
+	pub exif: Option<&'a [u8]>,
+	pub xmp: Option<&'a str>,
+}
+
+/// Give a WebP an ICCP chunk, and EXIF and XMP if there are any. A simple
+/// WebP, a RIFF with one VP8 or VP8L chunk, is rebuilt as an extended one.
+/// The encoder gives an extended one when there's lossy alpha and then the
+/// profile goes right after its VP8X. Anything we don't understand is given
+/// back untouched.
+pub(crate) fn webp_extended(
+	webp: &[u8],
+	width: usize,
+	height: usize,
+	extras: WebpExtras,
+) -> Vec<u8> {
+	if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+		return webp.to_vec();
+	}
+
+	let image = &webp[12..];
+	let (mut vp8x, image) = if image.get(0..4) == Some(b"VP8X") {
+		// VP8X is always ten bytes
+		match image.get(8..18) {
+			Some(vp8x) if image[4..8] == [10, 0, 0, 0] => (vp8x.to_vec(), &image[18..]),
+			_ => return webp.to_vec(),
+		}
+	} else {
+		// The canvas is stored one less than it is, in 24 bits
+		let less_one = |v: usize| {
+			u32::try_from(v)
+				.ok()?
+				.checked_sub(1)
+				.filter(|v| *v < 1 << 24)
+		};
+		let (width, height) = match (less_one(width), less_one(height)) {
+			(Some(width), Some(height)) => (width, height),
+			_ => return webp.to_vec(),
+		};
+
+		let mut vp8x = vec![0; 10];
+		vp8x[4..7].copy_from_slice(&width.to_le_bytes()[..3]);
+		vp8x[7..10].copy_from_slice(&height.to_le_bytes()[..3]);
+		(vp8x, image)
+	};
+
+	// The ICC profile flag
+	vp8x[0] |= 0x20;
+	if extras.alpha {
+		vp8x[0] |= 0x10;
+	}
+	if extras.exif.is_some() {
+		vp8x[0] |= 0x08;
+	}
+	if extras.xmp.is_some() {
+		vp8x[0] |= 0x04;
+	}
+
+	// The ICC profile has to come right after VP8X, and the metadata after
+	// the image
+	let mut body = b"WEBP".to_vec();
+	push_riff_chunk(&mut body, b"VP8X", &vp8x);
+	push_riff_chunk(&mut body, b"ICCP", extras.profile);
+	body.extend_from_slice(image);
+	if let Some(exif) = extras.exif {
+		push_riff_chunk(&mut body, b"EXIF", exif);
+	}
+	if let Some(xmp) = extras.xmp {
+		push_riff_chunk(&mut body, b"XMP ", xmp.as_bytes());
+	}
+
+	let mut out = b"RIFF".to_vec();
+	out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+	out.extend(body);
+	out
+}
+
+fn push_riff_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+	out.extend_from_slice(fourcc);
+	out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	out.extend_from_slice(data);
+	// Chunks are padded out to an even length
+	if data.len() % 2 == 1 {
+		out.push(0);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn adler32_known_values() {
+		assert_eq!(adler32(b""), 1);
+		assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+	}
+
+	#[test]
+	fn stored_zlib_inflates() {
+		// png doesn't give back the whole of an iCCP in this version, so the
+		// stream goes in as the image data instead. One block, and a few with
+		// a short one at the end
+		for (width, height) in [(1, 1), (300, 200)] {
+			let rgb: Vec<u8> = (0..width * height * 3).map(|v| (v % 251) as u8).collect();
+			// Each row starts with its filter type, none
+			let scanlines: Vec<u8> = rgb
+				.chunks(width * 3)
+				.flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+				.collect();
+
+			let mut png = vec![];
+			let mut enc = png::Encoder::new(&mut png, width as u32, height as u32);
+			enc.set_color(png::ColorType::Rgb);
+			enc.set_depth(png::BitDepth::Eight);
+			let mut writer = enc.write_header().unwrap();
+			writer
+				.write_chunk(png::chunk::IDAT, &zlib_stored(&scanlines))
+				.unwrap();
+			drop(writer);
+
+			let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+			let mut decoded = vec![0; reader.output_buffer_size()];
+			reader.next_frame(&mut decoded).unwrap();
+			assert_eq!(decoded, rgb, "{width}x{height}");
+		}
+	}
+
+	#[test]
+	fn empty_zlib_is_one_empty_block() {
+		assert_eq!(
+			zlib_stored(&[]),
+			[0x78, 0x01, 1, 0, 0, 0xFF, 0xFF, 0, 0, 0, 1]
+		);
+	}
+
+	#[test]
+	fn jpeg_app2_numbers_segments() {
+		let segments = jpeg_app2(&[7; 100]).unwrap();
+		assert_eq!(segments.len(), 1);
+		assert_eq!(&segments[0][..ICC_MARKER.len()], ICC_MARKER);
+		assert_eq!(
+			&segments[0][ICC_MARKER.len()..ICC_MARKER.len() + 2],
+			&[1, 1]
+		);
+		assert_eq!(segments[0].len(), ICC_MARKER.len() + 2 + 100);
+
+		let most = vec![0; ICC_SEGMENT_DATA * 255];
+		let segments = jpeg_app2(&most).unwrap();
+		assert_eq!(segments.len(), 255);
+		assert!(segments.iter().all(|s| s.len() + 2 == u16::MAX as usize));
+		assert_eq!(
+			&segments[254][ICC_MARKER.len()..ICC_MARKER.len() + 2],
+			&[255, 255]
+		);
+
+		let too_many = vec![0; ICC_SEGMENT_DATA * 255 + 1];
+		assert!(jpeg_app2(&too_many).is_none());
+	}
+
+	#[test]
+	fn jpeg_standalone_markers_are_skipped() {
+		let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 4, b'a', b'b'];
+		// A restart, TEM, and padding
+		jpeg.extend_from_slice(&[0xFF, 0xD3, 0xFF, 0x01, 0xFF, 0xFF]);
+		jpeg.extend_from_slice(&[0xFF, 0xC0, 0, 7, 8, 0, 3, 0, 4]);
+		let scan = jpeg.len();
+		jpeg.extend_from_slice(&[0xFF, 0xDA, 0, 2, 0xFF, 0xD9]);
+
+		let (segments, at) = jpeg_segments(&jpeg).unwrap();
+		assert_eq!(at, scan);
+		assert_eq!(segments, [(0xE0, &b"ab"[..]), (0xC0, &[8, 0, 3, 0, 4][..])]);
+		assert_eq!(jpeg_frame_size(0xC0, segments[1].1), Some((4, 3)));
+
+		// It ends without a scan, or part way through a segment
+		assert_eq!(jpeg_segments(&[0xFF, 0xD8, 0xFF, 0xD9]), None);
+		assert_eq!(jpeg_segments(&jpeg[..10]), None);
+	}
+
+	#[test]
+	fn webp_without_a_canvas_is_untouched() {
+		// An empty VP8 chunk is enough, it's never looked in
+		let webp = b"RIFF\x0c\0\0\0WEBPVP8 \0\0\0\0".to_vec();
+		let extras = || WebpExtras {
+			alpha: false,
+			profile: &[1, 2, 3, 4],
+			exif: None,
+			xmp: None,
+		};
+
+		assert_eq!(webp_extended(&webp, 0, 10, extras()), webp);
+		assert_eq!(webp_extended(&webp, 10, 0, extras()), webp);
+		assert_eq!(webp_extended(&webp, 1 << 24 | 1, 10, extras()), webp);
+
+		let extended = webp_extended(&webp, 10, 20, extras());
+		assert_eq!(&extended[12..16], b"VP8X");
+		// Canvas size, less one, in 24 bits
+		assert_eq!(&extended[24..30], &[9, 0, 0, 19, 0, 0]);
+	}
+}
@@ -0,0 +1,87 @@
+use rawproc::{
+	capture::{self, Capture},
+	colorspace::{AdobeRgb, DisplayP3, LinSrgb, ProPhoto, Rec2020, Srgb},
+	image::{Image, RawMetadata},
+};
+
+use crate::{Colorspace, Metadata, OutImage, Rational, SRational};
+
+impl From<&Capture> for Metadata {
+	fn from(capture: &Capture) -> Self {
+		let rational = |r: capture::Rational| Rational(r.0, r.1);
+
+		Metadata {
+			make: capture.make.clone(),
+			model: capture.model.clone(),
+			serial_number: capture.serial_number.clone(),
+			lens_make: capture.lens_make.clone(),
+			lens_model: capture.lens_model.clone(),
+			artist: capture.artist.clone(),
+			copyright: capture.copyright.clone(),
+			software: None,
+			date_time: capture.date_time.clone(),
+			utc_offset: capture.utc_offset.clone(),
+			exposure_time: capture.exposure_time.map(rational),
+			f_number: capture.f_number.map(rational),
+			iso: capture.iso,
+			focal_length: capture.focal_length.map(rational),
+			focal_length_35mm: capture.focal_length_35mm,
+			exposure_bias: capture.exposure_bias.map(|b| SRational(b.0, b.1)),
+			flash: capture.flash,
+			orientation: None,
+		}
+	}
+}
+
+impl From<&RawMetadata> for Metadata {
+	/// The capture, and the orientation if the image hasn't been
+	/// [oriented](Image::orient) yet.
+	fn from(metadata: &RawMetadata) -> Self {
+		Metadata {
+			orientation: Some(metadata.orientation.exif()),
+			..(&metadata.capture).into()
+		}
+	}
+}
+
+macro_rules! impl_from_image {
+	($colorspace:ty, $tag:expr) => {
+		impl From<Image<u16, $colorspace>> for OutImage {
+			fn from(img: Image<u16, $colorspace>) -> Self {
+				// rawproc's integers top out at the whitelevel, not u16::MAX
+				let levels = img.metadata.whitelevels;
+				let data = img
+					.data
+					.iter()
+					.enumerate()
+					.map(|(idx, v)| {
+						let level = levels[idx % 3].max(1) as u32;
+						let full = (*v as u32 * u16::MAX as u32 + level / 2) / level;
+						full.min(u16::MAX as u32) as u16
+					})
+					.collect();
+
+				OutImage::new_u16(img.width, img.height, data)
+					.expect("rawproc images are always the right size")
+					.with_colorspace($tag)
+					.with_metadata((&img.metadata).into())
+			}
+		}
+
+		impl From<Image<f32, $colorspace>> for OutImage {
+			fn from(img: Image<f32, $colorspace>) -> Self {
+				OutImage::new_f32(img.width, img.height, img.data)
+					.expect("rawproc images are always the right size")
+					.with_colorspace($tag)
+					.with_metadata((&img.metadata).into())
+			}
+		}
+	};
+}
+
+impl_from_image!(Srgb, Colorspace::Srgb);
+impl_from_image!(LinSrgb, Colorspace::LinearSrgb);
+impl_from_image!(DisplayP3, Colorspace::DisplayP3);
+impl_from_image!(Rec2020, Colorspace::Rec2020);
+impl_from_image!(AdobeRgb, Colorspace::AdobeRgb);
+impl_from_image!(ProPhoto, Colorspace::ProPhoto);
@@ -0,0 +1,262 @@
+//! Just enough ICC to describe an RGB colourspace: a matrix/TRC display
+//! profile, version 2.4 so that old readers understand it too.
+//!
+//! <https://www.color.org/ICC_Minor_Revision_for_Web.pdf>
+
+type Matrix = [[f64; 3]; 3];
+
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+#[rustfmt::skip]
+const BRADFORD: Matrix = [
+	[ 0.8951,  0.2664, -0.1614],
+	[-0.7502,  1.7135,  0.0367],
+	[ 0.0389, -0.0685,  1.0296],
+];
+
+/// How many samples the tone curves get. Enough that nobody will see the steps.
+const CURVE_SAMPLES: usize = 1024;
+
+/// Build a display profile for the RGB space with these red, green, and blue
+/// primaries, white, and transfer function. `decode` takes an encoded value
+/// to linear.
+pub(crate) fn matrix_trc(
+	description: &str,
+	primaries: [(f64, f64); 3],
+	white: (f64, f64),
+	decode: impl Fn(f64) -> f64,
+) -> Vec<u8> {
+	let rgb_to_xyz = rgb_to_xyz(primaries, white);
+	let white_xyz = xy_to_xyz(white);
+
+	// Everything in the profile connection space is relative to D50
+	let adapt = bradford(white_xyz, D50);
+	let colorants = multiply(&adapt, &rgb_to_xyz);
+
+	let curve: Vec<u16> = (0..CURVE_SAMPLES)
+		.map(|idx| {
+			let linear = decode(idx as f64 / (CURVE_SAMPLES - 1) as f64);
+			(linear.clamp(0.0, 1.0) * u16::MAX as f64).round() as u16
+		})
+		.collect();
+
+	let column = |c: usize| xyz_tag([colorants[0][c], colorants[1][c], colorants[2][c]]);
+	let trc = curve_tag(&curve);
+
+	let tags: Vec<([u8; 4], Vec<u8>)> = vec![
+		(*b"desc", desc_tag(description)),
+		(*b"cprt", text_tag("No copyright, use freely")),
+		(*b"wtpt", xyz_tag(D50)),
+		(*b"chad", sf32_tag(&adapt)),
+		(*b"rXYZ", column(0)),
+		(*b"gXYZ", column(1)),
+		(*b"bXYZ", column(2)),
+		(*b"rTRC", trc.clone()),
+		(*b"gTRC", trc.clone()),
+		(*b"bTRC", trc),
+	];
+
+	assemble(&tags)
+}
+
+fn assemble(tags: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+	let table_length = 4 + tags.len() * 12;
+
+	let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+	let mut body = vec![];
+	for (signature, data) in tags {
+		let offset = 128 + table_length + body.len();
+		table.extend_from_slice(signature);
+		table.extend_from_slice(&(offset as u32).to_be_bytes());
+		table.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+		body.extend_from_slice(data);
+		// Tags start on four byte boundaries
+		while body.len() % 4 != 0 {
+			body.push(0);
+		}
+	}
+
+	let size = 128 + table.len() + body.len();
+
+	let mut header = vec![0; 128];
+	header[0..4].copy_from_slice(&(size as u32).to_be_bytes());
+	// Version 2.4
+	header[8..12].copy_from_slice(&[2, 0x40, 0, 0]);
+	header[12..16].copy_from_slice(b"mntr");
+	header[16..20].copy_from_slice(b"RGB ");
+	header[20..24].copy_from_slice(b"XYZ ");
+	header[36..40].copy_from_slice(b"acsp");
+	header[68..80].copy_from_slice(&xyz_numbers(D50));
+
+	let mut profile = header;
+	profile.extend(table);
+	profile.extend(body);
+	profile
+}
+
+fn s15fixed16(value: f64) -> [u8; 4] {
+	((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_numbers(xyz: [f64; 3]) -> Vec<u8> {
+	xyz.iter().flat_map(|v| s15fixed16(*v)).collect()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+	let mut tag = b"XYZ \0\0\0\0".to_vec();
+	tag.extend(xyz_numbers(xyz));
+	tag
+}
+
+fn sf32_tag(matrix: &Matrix) -> Vec<u8> {
+	let mut tag = b"sf32\0\0\0\0".to_vec();
+	tag.extend(matrix.iter().flatten().flat_map(|v| s15fixed16(*v)));
+	tag
+}
+
+fn curve_tag(curve: &[u16]) -> Vec<u8> {
+	let mut tag = b"curv\0\0\0\0".to_vec();
+	tag.extend_from_slice(&(curve.len() as u32).to_be_bytes());
+	tag.extend(curve.iter().flat_map(|v| v.to_be_bytes()));
+	tag
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+	let mut tag = b"text\0\0\0\0".to_vec();
+	tag.extend_from_slice(text.as_bytes());
+	tag.push(0);
+	tag
+}
+
+/// The version 2 textDescriptionType. The ASCII part is the one anyone reads,
+/// the Unicode and ScriptCode parts are left empty.
+fn desc_tag(text: &str) -> Vec<u8> {
+	let mut tag = b"desc\0\0\0\0".to_vec();
+	tag.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+	tag.extend_from_slice(text.as_bytes());
+	tag.push(0);
+	// Unicode language and count
+	tag.extend_from_slice(&[0; 8]);
+	// ScriptCode code, count, and its 67 bytes
+	tag.extend_from_slice(&[0; 2 + 1 + 67]);
+	tag
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+	[x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// <http://www.brucelindbloom.com/index.html?Eqn_RGB_XYZ_Matrix.html>
+fn rgb_to_xyz(primaries: [(f64, f64); 3], white: (f64, f64)) -> Matrix {
+	let [r, g, b] = primaries.map(xy_to_xyz);
+
+	#[rustfmt::skip]
+	let columns = [
+		[r[0], g[0], b[0]],
+		[r[1], g[1], b[1]],
+		[r[2], g[2], b[2]],
+	];
+
+	let scale = apply(&invert(&columns), xy_to_xyz(white));
+
+	let mut matrix = columns;
+	for row in matrix.iter_mut() {
+		for (value, s) in row.iter_mut().zip(scale) {
+			*value *= s;
+		}
+	}
+	matrix
+}
+
+fn bradford(from: [f64; 3], to: [f64; 3]) -> Matrix {
+	let from_cone = apply(&BRADFORD, from);
+	let to_cone = apply(&BRADFORD, to);
+
+	let mut scale = [[0.0; 3]; 3];
+	for (c, row) in scale.iter_mut().enumerate() {
+		row[c] = to_cone[c] / from_cone[c];
+	}
+
+	multiply(&invert(&BRADFORD), &multiply(&scale, &BRADFORD))
+}
+
+fn apply(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+	m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+	let mut out = [[0.0; 3]; 3];
+	for (row, out_row) in out.iter_mut().enumerate() {
+		for (col, value) in out_row.iter_mut().enumerate() {
+			*value = (0..3).map(|k| a[row][k] * b[k][col]).sum();
+		}
+	}
+	out
+}
+
+fn invert(m: &Matrix) -> Matrix {
+	let cofactor =
+		|r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+
+	#[rustfmt::skip]
+	let adjugate = [
+		[ cofactor(1, 2, 1, 2), -cofactor(0, 2, 1, 2),  cofactor(0, 1, 1, 2)],
+		[-cofactor(1, 2, 0, 2),  cofactor(0, 2, 0, 2), -cofactor(0, 1, 0, 2)],
+		[ cofactor(1, 2, 0, 1), -cofactor(0, 2, 0, 1),  cofactor(0, 1, 0, 1)],
+	];
+
+	let determinant =
+		m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+
+	adjugate.map(|row| row.map(|v| v / determinant))
+}
+
+#[cfg(test)]
+mod test {
+	use crate::Colorspace;
+
+	/// The data of the tag with this signature
+	fn tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> &'a [u8] {
+		let be = |at: usize| u32::from_be_bytes(profile[at..at + 4].try_into().unwrap()) as usize;
+
+		let count = be(128);
+		(0..count)
+			.map(|idx| 132 + idx * 12)
+			.find(|at| &profile[*at..*at + 4] == signature)
+			.map(|at| &profile[be(at + 4)..be(at + 4) + be(at + 8)])
+			.unwrap()
+	}
+
+	fn xyz(tag: &[u8]) -> [f64; 3] {
+		assert_eq!(&tag[0..4], b"XYZ ");
+		[0, 1, 2].map(|idx| {
+			let at = 8 + idx * 4;
+			i32::from_be_bytes(tag[at..at + 4].try_into().unwrap()) as f64 / 65536.0
+		})
+	}
+
+	#[test]
+	fn srgb_colorants() {
+		// The colorants and white in the ICC's own sRGB profiles, D50 adapted
+		let published: [(&[u8; 4], [u32; 3]); 4] = [
+			(b"rXYZ", [0x6FA0, 0x38F5, 0x0390]),
+			(b"gXYZ", [0x6297, 0xB787, 0x18D9]),
+			(b"bXYZ", [0x249F, 0x0F84, 0xB6C4]),
+			(b"wtpt", [0xF6D6, 0x10000, 0xD32D]),
+		];
+
+		let profile = Colorspace::Srgb.icc_profile();
+		for (signature, expected) in published {
+			let actual = xyz(tag(&profile, signature));
+			for (actual, expected) in actual.iter().zip(expected) {
+				let expected = expected as f64 / 65536.0;
+				assert!(
+					(actual - expected).abs() <= 1.0 / 65536.0,
+					"{}: {actual} isn't {expected}",
+					String::from_utf8_lossy(signature)
+				);
+			}
+		}
+	}
+}
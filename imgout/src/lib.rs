@@ -1,70 +1,781 @@
-use std::{fs::File, io::Write, path::Path};
+mod embed;
+#[cfg(feature = "rawproc")]
+mod from_rawproc;
+mod icc;
+mod metadata;
+mod options;
+mod tiff;
+
+use std::{
+	borrow::Cow,
+	fs::File,
+	io::{self, BufWriter, Write},
+	panic::{self, AssertUnwindSafe},
+	path::{Path, PathBuf},
+};
+
+pub use metadata::{Metadata, Rational, SRational};
+pub use options::{
+	ChromaSubsampling, Format, JpegOptions, PngCompression, PngFilter, PngOptions, SaveOptions,
+	WebpOptions,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("{source}")]
+	Io {
+		#[from]
+		source: io::Error,
+	},
+	#[error("{source}")]
+	Png {
+		#[from]
+		source: png::EncodingError,
+	},
+	#[error("The JPEG encoder failed: {message}")]
+	Jpeg { message: String },
+	#[error("The WebP encoder failed: {message}")]
+	Webp { message: String },
+	#[error("Can't tell what format to save {} as from its extension", .path.display())]
+	UnknownFormat { path: PathBuf },
+	#[error("Not a JPEG, or one we can't make sense of")]
+	NotJpeg,
+	#[error("The metadata doesn't fit in a JPEG segment")]
+	MetadataTooLarge,
+	#[error("The ICC profile doesn't fit in the 255 segments a JPEG can split it across")]
+	ProfileTooLarge,
+	#[error("The image is too large for a TIFF, which can't be more than 4GB")]
+	TooLargeForTiff,
+	// Spitting out the square root seems useful 'cause it's a rough
+	// estimation of the dimensions
+	#[error("Image dimension are {width}x{height} but data len was {actual}, ({:.02} sqrted)", (*.actual as f32).sqrt())]
+	DataLength {
+		width: usize,
+		height: usize,
+		actual: usize,
+	},
+}
 
 // What a great name
 pub struct OutImage {
 	width: usize,
 	height: usize,
-	data: Vec<u8>,
+	samples: Samples,
+	alpha: Option<Vec<u8>>,
+	colorspace: Colorspace,
+	metadata: Option<Metadata>,
+}
+
+/// The pixels, RGB, at whatever depth they came in at. Integers use their
+/// full range and floats are 0.0 to 1.0, but can go past that.
+#[derive(Clone, Debug)]
+pub enum Samples {
+	Eight(Vec<u8>),
+	Sixteen(Vec<u16>),
+	Float(Vec<f32>),
+}
+
+impl Samples {
+	fn len(&self) -> usize {
+		match self {
+			Samples::Eight(data) => data.len(),
+			Samples::Sixteen(data) => data.len(),
+			Samples::Float(data) => data.len(),
+		}
+	}
+}
+
+/// The colourspace the pixels are in, so that whatever opens the file shows
+/// them correctly. It's written as an ICC profile, or as the sRGB chunk for
+/// sRGB PNGs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Colorspace {
+	Srgb,
+	/// sRGB primaries without the transfer function. Good for floats.
+	LinearSrgb,
+	DisplayP3,
+	Rec2020,
+	AdobeRgb,
+	ProPhoto,
+	/// Some other space, described by this ICC profile.
+	Icc(Vec<u8>),
+}
+
+const D65: (f64, f64) = (0.3127, 0.3290);
+const D50: (f64, f64) = (0.3457, 0.3585);
+
+impl Colorspace {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Colorspace::Srgb => "sRGB",
+			Colorspace::LinearSrgb => "Linear sRGB",
+			Colorspace::DisplayP3 => "Display P3",
+			Colorspace::Rec2020 => "Rec. 2020",
+			Colorspace::AdobeRgb => "Adobe RGB (1998) compatible",
+			Colorspace::ProPhoto => "ProPhoto RGB",
+			Colorspace::Icc(_) => "ICC profile",
+		}
+	}
+
+	/// An ICC profile describing the colourspace.
+	pub fn icc_profile(&self) -> Vec<u8> {
+		let srgb = |v: f64| {
+			if v <= 0.04045 {
+				v / 12.92
+			} else {
+				((v + 0.055) / 1.055).powf(2.4)
+			}
+		};
+
+		let name = self.name();
+		match self {
+			Colorspace::Srgb => {
+				icc::matrix_trc(name, [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)], D65, srgb)
+			}
+			Colorspace::LinearSrgb => {
+				icc::matrix_trc(name, [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)], D65, |v| v)
+			}
+			Colorspace::DisplayP3 => icc::matrix_trc(
+				name,
+				[(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)],
+				D65,
+				srgb,
+			),
+			Colorspace::Rec2020 => icc::matrix_trc(
+				name,
+				[(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)],
+				D65,
+				|v| {
+					if v < 0.081 {
+						v / 4.5
+					} else {
+						((v + 0.0993) / 1.0993).powf(1.0 / 0.45)
+					}
+				},
+			),
+			Colorspace::AdobeRgb => {
+				icc::matrix_trc(name, [(0.64, 0.33), (0.21, 0.71), (0.15, 0.06)], D65, |v| {
+					v.powf(563.0 / 256.0)
+				})
+			}
+			Colorspace::ProPhoto => icc::matrix_trc(
+				name,
+				[(0.7347, 0.2653), (0.1596, 0.8404), (0.0366, 0.0001)],
+				D50,
+				|v| {
+					if v < 1.0 / 32.0 {
+						v / 16.0
+					} else {
+						v.powf(1.8)
+					}
+				},
+			),
+			Colorspace::Icc(profile) => profile.clone(),
+		}
+	}
 }
 
 impl OutImage {
-	/// Make a new Image for Output. If your passed in data is not
-	/// `width * height * 3` bytes long, this function will panic.
-	pub fn new(width: usize, height: usize, data: Vec<u8>) -> Self {
-		if data.len() != width * height * 3 {
-			// Spitting out the square root seems useful 'cause it's a rough
-			// estimation of the dimensions
-			panic!(
-				"Image dimension are {width}x{height} but data len was {}, ({:02} sqrted)",
-				data.len(),
-				(data.len() as f32).sqrt()
-			)
+	/// Make a new Image for Output. The data has to be `width * height * 3`
+	/// bytes long.
+	pub fn new(width: usize, height: usize, data: Vec<u8>) -> Result<Self, Error> {
+		Self::from_samples(width, height, Samples::Eight(data))
+	}
+
+	/// An image with 16 bits per sample.
+	pub fn new_u16(width: usize, height: usize, data: Vec<u16>) -> Result<Self, Error> {
+		Self::from_samples(width, height, Samples::Sixteen(data))
+	}
+
+	/// An image with a float per sample.
+	pub fn new_f32(width: usize, height: usize, data: Vec<f32>) -> Result<Self, Error> {
+		Self::from_samples(width, height, Samples::Float(data))
+	}
+
+	pub fn from_samples(width: usize, height: usize, samples: Samples) -> Result<Self, Error> {
+		let expected = width.checked_mul(height).and_then(|px| px.checked_mul(3));
+
+		if expected != Some(samples.len()) {
+			Err(Error::DataLength {
+				width,
+				height,
+				actual: samples.len(),
+			})
 		} else {
-			Self {
+			Ok(Self {
 				width,
 				height,
+				samples,
+				alpha: None,
+				colorspace: Colorspace::Srgb,
+				metadata: None,
+			})
+		}
+	}
+
+	/// Give the image an 8-bit alpha channel, `width * height` long. Only PNG
+	/// and WebP keep it, JPEG and TIFF drop it.
+	pub fn with_alpha(mut self, alpha: Vec<u8>) -> Result<Self, Error> {
+		if alpha.len() != self.width * self.height {
+			return Err(Error::DataLength {
+				width: self.width,
+				height: self.height,
+				actual: alpha.len(),
+			});
+		}
+
+		self.alpha = Some(alpha);
+		Ok(self)
+	}
+
+	/// The samples as bytes, for the formats that only do 8 bits.
+	fn eight_bit(&self) -> Cow<'_, [u8]> {
+		match &self.samples {
+			Samples::Eight(data) => Cow::Borrowed(data),
+			Samples::Sixteen(data) => Cow::Owned(data.iter().map(|v| (v >> 8) as u8).collect()),
+			Samples::Float(data) => Cow::Owned(
+				data.iter()
+					.map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+					.collect(),
+			),
+		}
+	}
+
+	/// Tag the image with the colourspace its pixels are in. It's sRGB if
+	/// you don't.
+	pub fn with_colorspace(mut self, colorspace: Colorspace) -> Self {
+		self.colorspace = colorspace;
+		self
+	}
+
+	/// Save the image in whatever format the path's extension says, with that
+	/// format's default options.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let path = path.as_ref();
+		let format = Format::from_path(path).ok_or_else(|| Error::UnknownFormat {
+			path: path.to_owned(),
+		})?;
+
+		self.save_with(path, format)
+	}
+
+	/// Save the image with these options. They can be a [Format], for its
+	/// defaults, or any of the format's options. The path's extension isn't
+	/// looked at.
+	pub fn save_with<P: AsRef<Path>, O: Into<SaveOptions>>(
+		&self,
+		path: P,
+		options: O,
+	) -> Result<(), Error> {
+		self.write(BufWriter::new(File::create(path)?), options)
+	}
+
+	/// Write the image with these options, see [OutImage::save_with].
+	pub fn write<W: Write, O: Into<SaveOptions>>(
+		&self,
+		writer: W,
+		options: O,
+	) -> Result<(), Error> {
+		match options.into() {
+			SaveOptions::Png(options) => self.encode_png(writer, &options),
+			SaveOptions::Jpeg(options) => self.encode_jpeg(writer, &options),
+			SaveOptions::Webp(options) => self.encode_webp(writer, &options),
+			SaveOptions::Tiff => self.encode_tiff(writer),
+		}
+	}
+
+	/// Write this metadata along with the image as EXIF and XMP. JPEG, PNG,
+	/// and WebP carry it, TIFF doesn't.
+	pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+		self.metadata = Some(metadata);
+		self
+	}
+
+	fn exif(&self) -> Option<Vec<u8>> {
+		self.metadata
+			.as_ref()
+			.map(|meta| meta.exif(self.width, self.height, &self.colorspace))
+	}
+
+	fn xmp(&self) -> Option<String> {
+		self.metadata.as_ref().map(Metadata::xmp)
+	}
+
+	/// Output the image as a PNG. RGB, 8 bits deep for 8-bit images and 16 for
+	/// the rest, RGBA if it has alpha. sRGB is marked with the sRGB chunk,
+	/// anything else gets an iCCP. Metadata goes in eXIf and iTXt.
+	pub fn png<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		self.save_with(path, Format::Png)
+	}
+
+	/// Write the image as a PNG, see [OutImage::png].
+	pub fn write_png<W: Write>(&self, writer: W) -> Result<(), Error> {
+		self.write(writer, Format::Png)
+	}
+
+	fn encode_png<W: Write>(&self, mut out: W, options: &PngOptions) -> Result<(), Error> {
+		let mut enc = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+		let color = match self.alpha {
+			None => png::ColorType::Rgb,
+			Some(_) => png::ColorType::Rgba,
+		};
+		enc.set_color(color);
+		let depth = match self.samples {
+			Samples::Eight(_) => png::BitDepth::Eight,
+			_ => png::BitDepth::Sixteen,
+		};
+		enc.set_depth(depth);
+		enc.set_compression(match options.compression {
+			PngCompression::Fast => png::Compression::Fast,
+			PngCompression::Default => png::Compression::Default,
+			PngCompression::Best => png::Compression::Best,
+		});
+		match options.filter {
+			PngFilter::None => enc.set_filter(png::FilterType::NoFilter),
+			PngFilter::Sub => enc.set_filter(png::FilterType::Sub),
+			PngFilter::Up => enc.set_filter(png::FilterType::Up),
+			PngFilter::Average => enc.set_filter(png::FilterType::Avg),
+			PngFilter::Paeth => enc.set_filter(png::FilterType::Paeth),
+			PngFilter::Adaptive => enc.set_adaptive_filter(png::AdaptiveFilterType::Adaptive),
+		}
+		if self.colorspace == Colorspace::Srgb {
+			// The sRGB chunk, with a gAMA and cHRM for older readers
+			enc.set_srgb(png::SrgbRenderingIntent::Perceptual);
+		}
+
+		let mut writer = enc.write_header()?;
+		if self.colorspace != Colorspace::Srgb {
+			let iccp = embed::png_iccp(self.colorspace.name(), &self.colorspace.icc_profile());
+			writer.write_chunk(png::chunk::ChunkType(*b"iCCP"), &iccp)?;
+		}
+		if let Some(exif) = self.exif() {
+			writer.write_chunk(png::chunk::ChunkType(*b"eXIf"), &exif)?;
+		}
+		if let Some(xmp) = self.xmp() {
+			writer.write_chunk(png::chunk::ChunkType(*b"iTXt"), &embed::png_xmp(&xmp))?;
+		}
+
+		// PNG is big endian
+		let sixteen = |v: u16| v.to_be_bytes();
+		let data: Vec<u8> = match &self.samples {
+			Samples::Eight(data) => self.interleave_alpha(data, |v| [*v], |a| [a]),
+			Samples::Sixteen(data) => {
+				self.interleave_alpha(data, |v| sixteen(*v), |a| sixteen(a as u16 * 257))
+			}
+			Samples::Float(data) => self.interleave_alpha(
 				data,
+				|v| sixteen((v.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16),
+				|a| sixteen(a as u16 * 257),
+			),
+		};
+		writer.write_image_data(&data)?;
+		// Dropping the writer would finish it too, but any error would be lost
+		writer.finish()?;
+		out.flush()?;
+
+		Ok(())
+	}
+
+	/// Turn the samples into bytes, putting the alpha after every pixel if
+	/// there is one.
+	fn interleave_alpha<T, const N: usize>(
+		&self,
+		samples: &[T],
+		sample: impl Fn(&T) -> [u8; N],
+		alpha: impl Fn(u8) -> [u8; N],
+	) -> Vec<u8> {
+		match &self.alpha {
+			None => samples.iter().flat_map(sample).collect(),
+			Some(alphas) => samples
+				.chunks(3)
+				.zip(alphas)
+				.flat_map(|(pixel, a)| {
+					let mut bytes = Vec::with_capacity(N * 4);
+					pixel.iter().for_each(|v| bytes.extend(sample(v)));
+					bytes.extend(alpha(*a));
+					bytes
+				})
+				.collect(),
+		}
+	}
+
+	/// Output the image as a JPEG with the provided quality and mozjpeg's
+	/// defaults otherwise. RGB 8bit depth, deeper images are brought down to
+	/// it. The ICC profile goes in APP2, EXIF and XMP in APP1.
+	pub fn jpeg<P: AsRef<Path>>(&self, path: P, quality: f32) -> Result<(), Error> {
+		self.save_with(
+			path,
+			JpegOptions {
+				quality,
+				..Default::default()
+			},
+		)
+	}
+
+	/// Write the image as a JPEG, see [OutImage::jpeg].
+	pub fn write_jpeg<W: Write>(&self, writer: W, quality: f32) -> Result<(), Error> {
+		self.write(
+			writer,
+			JpegOptions {
+				quality,
+				..Default::default()
+			},
+		)
+	}
+
+	fn encode_jpeg<W: Write>(&self, mut writer: W, options: &JpegOptions) -> Result<(), Error> {
+		let profile =
+			embed::jpeg_app2(&self.colorspace.icc_profile()).ok_or(Error::ProfileTooLarge)?;
+
+		// mozjpeg's error handler panics when libjpeg is unhappy. Nothing
+		// here is touched again if it does, so it's fine to catch
+		let encoded = panic::catch_unwind(AssertUnwindSafe(|| -> io::Result<Vec<u8>> {
+			let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+			if !options.progressive {
+				// mozjpeg's own defaults have a progressive scan script, and
+				// only libjpeg's are baseline. They reset everything, so
+				// they come before the rest
+				comp.set_fastest_defaults();
+			}
+
+			comp.set_size(self.width, self.height);
+			comp.set_quality(options.quality);
+			let chroma = options.chroma_subsampling.pixel_size();
+			comp.set_chroma_sampling_pixel_sizes(chroma, chroma);
+			if options.progressive {
+				comp.set_progressive_mode();
+			}
+			comp.set_optimize_coding(options.optimize_huffman);
+
+			let mut comp = comp.start_compress(vec![])?;
+			if let Some(exif) = self.exif() {
+				comp.write_marker(mozjpeg::Marker::APP(1), &embed::jpeg_exif(&exif));
+			}
+			if let Some(xmp) = self.xmp() {
+				comp.write_marker(mozjpeg::Marker::APP(1), &embed::jpeg_xmp(&xmp));
+			}
+			for segment in &profile {
+				comp.write_marker(mozjpeg::Marker::APP(2), segment);
+			}
+			comp.write_scanlines(&self.eight_bit())?;
+			comp.finish()
+		}));
+
+		// It's all going to a Vec, so an io::Error is the encoder's
+		let message = match encoded {
+			Ok(Ok(data)) => {
+				writer.write_all(&data)?;
+				writer.flush()?;
+				return Ok(());
+			}
+			Ok(Err(e)) => e.to_string(),
+			Err(panic) => panic
+				.downcast_ref::<&str>()
+				.map(|s| s.to_string())
+				.or_else(|| panic.downcast_ref::<String>().cloned())
+				.unwrap_or_else(|| "mozjpeg panicked".to_owned()),
+		};
+
+		Err(Error::Jpeg { message })
+	}
+
+	/// Output the image as a lossy WebP with the provided quality, an ICCP
+	/// chunk, and the metadata if there is any.
+	pub fn webp<P: AsRef<Path>>(&self, path: P, quality: f32) -> Result<(), Error> {
+		self.save_with(
+			path,
+			WebpOptions {
+				quality,
+				..Default::default()
+			},
+		)
+	}
+
+	/// Write the image as a WebP, see [OutImage::webp].
+	pub fn write_webp<W: Write>(&self, writer: W, quality: f32) -> Result<(), Error> {
+		self.write(
+			writer,
+			WebpOptions {
+				quality,
+				..Default::default()
+			},
+		)
+	}
+
+	fn encode_webp<W: Write>(&self, mut writer: W, options: &WebpOptions) -> Result<(), Error> {
+		let data = self.eight_bit();
+		let (width, height) = (self.width as u32, self.height as u32);
+		let rgba;
+		let enc = match &self.alpha {
+			None => webp::Encoder::from_rgb(&data, width, height),
+			Some(_) => {
+				rgba = self.interleave_alpha(&data, |v| [*v], |a| [a]);
+				webp::Encoder::from_rgba(&rgba, width, height)
+			}
+		};
+
+		let mut config = webp::WebPConfig::new().map_err(|_| Error::Webp {
+			message: "libwebp is a version we don't understand".to_owned(),
+		})?;
+		config.quality = options.quality.clamp(0.0, 100.0);
+		config.lossless = options.lossless as i32;
+		config.method = options.method.min(6) as i32;
+		config.alpha_quality = options.alpha_quality.min(100) as i32;
+		// Keep the colour under fully transparent pixels when lossless
+		config.exact = options.lossless as i32;
+
+		let img = enc.encode_advanced(&config).map_err(|e| Error::Webp {
+			message: format!("{e:?}"),
+		})?;
+		let (exif, xmp) = (self.exif(), self.xmp());
+		let img = embed::webp_extended(
+			&img,
+			self.width,
+			self.height,
+			embed::WebpExtras {
+				alpha: self.alpha.is_some(),
+				profile: &self.colorspace.icc_profile(),
+				exif: exif.as_deref(),
+				xmp: xmp.as_deref(),
+			},
+		);
+
+		writer.write_all(&img)?;
+		writer.flush()?;
+		Ok(())
+	}
+
+	/// Output the image as an uncompressed TIFF with the ICC profile. The
+	/// samples are written as they are, so 8 and 16 bit images stay that way
+	/// and float images are 32-bit float TIFFs.
+	pub fn tiff<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		self.save_with(path, Format::Tiff)
+	}
+
+	/// Write the image as a TIFF, see [OutImage::tiff].
+	pub fn write_tiff<W: Write>(&self, writer: W) -> Result<(), Error> {
+		self.write(writer, Format::Tiff)
+	}
+
+	fn encode_tiff<W: Write>(&self, writer: W) -> Result<(), Error> {
+		let (bits, format, data): (u16, _, Cow<[u8]>) = match &self.samples {
+			Samples::Eight(data) => (8, tiff::SampleFormat::Unsigned, Cow::Borrowed(data)),
+			Samples::Sixteen(data) => (
+				16,
+				tiff::SampleFormat::Unsigned,
+				Cow::Owned(data.iter().flat_map(|v| v.to_le_bytes()).collect()),
+			),
+			Samples::Float(data) => (
+				32,
+				tiff::SampleFormat::Float,
+				Cow::Owned(data.iter().flat_map(|v| v.to_le_bytes()).collect()),
+			),
+		};
+
+		tiff::write(
+			writer,
+			self.width,
+			self.height,
+			bits,
+			format,
+			&data,
+			Some(&self.colorspace.icc_profile()),
+		)?;
+
+		Ok(())
+	}
+}
+
+/// Give a JPEG that's already encoded, like the preview from a raw, EXIF and
+/// XMP without decoding it. Whatever EXIF and XMP it had are replaced. The
+/// EXIF says the image is sRGB, but an ICC profile it came with is kept.
+pub fn tag_jpeg(jpeg: &[u8], metadata: &Metadata) -> Result<Vec<u8>, Error> {
+	let (segments, scan) = embed::jpeg_segments(jpeg).ok_or(Error::NotJpeg)?;
+	let (width, height) = segments
+		.iter()
+		.find_map(|(marker, data)| embed::jpeg_frame_size(*marker, data))
+		.ok_or(Error::NotJpeg)?;
+
+	let exif = embed::jpeg_exif(&metadata.exif(width, height, &Colorspace::Srgb));
+	let xmp = embed::jpeg_xmp(&metadata.xmp());
+
+	// JFIF's APP0 has to come first, so ours go after it
+	let (jfif, rest): (Vec<_>, Vec<_>) = segments
+		.into_iter()
+		.filter(|(marker, data)| !(*marker == 0xE1 && embed::is_jpeg_metadata(data)))
+		.partition(|(marker, _)| *marker == 0xE0);
+
+	let mut out = vec![0xFF, 0xD8];
+	let ours = [(0xE1, exif.as_slice()), (0xE1, xmp.as_slice())];
+	for (marker, data) in jfif.into_iter().chain(ours).chain(rest) {
+		// The length counts itself
+		let length = u16::try_from(data.len() + 2).map_err(|_| Error::MetadataTooLarge)?;
+		out.extend_from_slice(&[0xFF, marker]);
+		out.extend_from_slice(&length.to_be_bytes());
+		out.extend_from_slice(data);
+	}
+	out.extend_from_slice(&jpeg[scan..]);
+
+	Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn jpeg_encoder_errors_are_returned() {
+		// libjpeg won't make an empty JPEG, or one wider than 65500
+		for (width, height) in [(0, 0), (70000, 1)] {
+			let img = OutImage::new(width, height, vec![128; width * height * 3]).unwrap();
+
+			let mut out = vec![];
+			match img.write(&mut out, Format::Jpeg) {
+				Err(Error::Jpeg { .. }) => (),
+				other => panic!("{width}x{height} gave {other:?}"),
 			}
 		}
 	}
 
-	/// Output the image as a PNG. RGB 8bit depth.
-	// TODO: gen- no more unwrap!
-	pub fn png<P: AsRef<Path>>(&self, path: P) {
-		let file = File::create(path.as_ref()).unwrap();
-		let mut enc = png::Encoder::new(file, self.width as u32, self.height as u32);
-		enc.set_color(png::ColorType::Rgb);
-		enc.set_depth(png::BitDepth::Eight);
+	#[test]
+	fn data_must_be_the_right_length() {
+		assert!(OutImage::new(2, 2, vec![0; 12]).is_ok());
+		assert!(OutImage::new_u16(2, 2, vec![0; 12]).is_ok());
+		assert!(OutImage::new_f32(2, 2, vec![0.0; 12]).is_ok());
 
-		let mut writer = enc.write_header().unwrap();
-		writer.write_image_data(&self.data).unwrap()
+		for (width, height, len) in [
+			(2, 2, 11),
+			(2, 2, 13),
+			// Wraps around to zero if the multiply isn't checked
+			(1 << (usize::BITS - 2), 4, 0),
+			(usize::MAX, usize::MAX, 3),
+		] {
+			let result = OutImage::new(width, height, vec![0; len]);
+			assert!(
+				matches!(result, Err(Error::DataLength { actual, .. }) if actual == len),
+				"{width}x{height} with {len} was accepted"
+			);
+		}
+
+		let img = OutImage::new(2, 2, vec![0; 12]).unwrap();
+		assert!(img.with_alpha(vec![0; 3]).is_err());
 	}
 
-	/// Output the image as a JPEG with the provided quality. RGB 8bit depth.
-	// TODO: gen- Fix panic. mozjpeg will panic if it's unhappy and we should
-	// catch_unwind and return a result
-	pub fn jpeg<P: AsRef<Path>>(&self, path: P, quality: f32) {
-		let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+	fn gradient_data() -> Vec<u8> {
+		(0..37 * 21 * 3).map(|v| (v * 7 % 256) as u8).collect()
+	}
 
-		comp.set_size(self.width, self.height);
-		comp.set_quality(quality);
-		comp.set_mem_dest();
-		comp.start_compress();
-		assert!(comp.write_scanlines(&self.data[..]));
+	fn gradient() -> OutImage {
+		OutImage::new(37, 21, gradient_data()).unwrap()
+	}
 
-		comp.finish_compress();
+	#[test]
+	fn jpeg_baseline_or_progressive() {
+		for progressive in [false, true] {
+			let mut out = vec![];
+			gradient()
+				.write(
+					&mut out,
+					JpegOptions {
+						progressive,
+						..Default::default()
+					},
+				)
+				.unwrap();
 
-		let mut file = File::create(path.as_ref()).unwrap();
-		file.write_all(&comp.data_as_mut_slice().unwrap()).unwrap();
+			let (segments, _) = embed::jpeg_segments(&out).unwrap();
+			let frames: Vec<u8> = segments
+				.iter()
+				.map(|(marker, _)| *marker)
+				.filter(|marker| matches!(marker, 0xC0..=0xC2))
+				.collect();
+			// SOF2 is progressive, SOF0 baseline
+			assert_eq!(frames, [if progressive { 0xC2 } else { 0xC0 }]);
+		}
 	}
 
-	/// Output the image as a lossy WebP with the provided quality.
-	// TODO: gen- no more unwrap :)
-	pub fn webp<P: AsRef<Path>>(&self, path: P, quality: f32) {
-		let enc = webp::Encoder::from_rgb(&self.data, self.width as u32, self.height as u32);
-		let img = enc.encode(quality);
+	#[test]
+	fn png_round_trip() {
+		let expected = gradient_data();
+		for filter in [PngFilter::None, PngFilter::Paeth, PngFilter::Adaptive] {
+			for compression in [PngCompression::Fast, PngCompression::Best] {
+				let mut out = vec![];
+				gradient()
+					.write(
+						&mut out,
+						PngOptions {
+							compression,
+							filter,
+						},
+					)
+					.unwrap();
+
+				let mut reader = png::Decoder::new(&out[..]).read_info().unwrap();
+				let mut buffer = vec![0; reader.output_buffer_size()];
+				let info = reader.next_frame(&mut buffer).unwrap();
+				assert_eq!((info.width, info.height), (37, 21));
+				assert_eq!(info.color_type, png::ColorType::Rgb);
+				assert_eq!(buffer[..info.buffer_size()], expected[..]);
+			}
+		}
+	}
 
-		let mut file = File::create(path.as_ref()).unwrap();
-		file.write_all(&img).unwrap();
+	#[test]
+	fn png_sixteen_bit_with_alpha() {
+		let data = (0..4 * 2 * 3).map(|v| v * 1000).collect();
+		let alpha = (0..8).map(|v| v * 30).collect();
+		let img = OutImage::new_u16(4, 2, data)
+			.unwrap()
+			.with_alpha(alpha)
+			.unwrap()
+			.with_colorspace(Colorspace::DisplayP3);
+
+		let mut out = vec![];
+		img.write(&mut out, Format::Png).unwrap();
+
+		let mut reader = png::Decoder::new(&out[..]).read_info().unwrap();
+		assert!(reader.info().icc_profile.is_some());
+		let mut buffer = vec![0; reader.output_buffer_size()];
+		let info = reader.next_frame(&mut buffer).unwrap();
+		assert_eq!(info.color_type, png::ColorType::Rgba);
+		assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+		// Big endian, and the 8-bit alpha is widened
+		assert_eq!(buffer[0..8], [0, 0, 0x03, 0xE8, 0x07, 0xD0, 0, 0]);
+		assert_eq!(buffer[8..16], [0x0B, 0xB8, 0x0F, 0xA0, 0x13, 0x88, 30, 30]);
+	}
+
+	#[test]
+	fn webp_round_trip() {
+		for lossless in [false, true] {
+			for alpha in [false, true] {
+				let mut img = gradient().with_colorspace(Colorspace::Rec2020);
+				if alpha {
+					img = img.with_alpha(vec![128; 37 * 21]).unwrap();
+				}
+
+				let mut out = vec![];
+				img.write(
+					&mut out,
+					WebpOptions {
+						quality: 80.0,
+						lossless,
+						method: 6,
+						alpha_quality: 50,
+					},
+				)
+				.unwrap();
+
+				let decoded = webp::Decoder::new(&out).decode().unwrap();
+				assert_eq!(
+					(decoded.width(), decoded.height(), decoded.is_alpha()),
+					(37, 21, alpha)
+				);
+				if lossless {
+					let pixel = if alpha { 4 } else { 3 };
+					assert_eq!(decoded[0..3], [0, 7, 14]);
+					assert_eq!(decoded[pixel..pixel + 3], [21, 28, 35]);
+				}
+			}
+		}
 	}
 }
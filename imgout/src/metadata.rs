@@ -0,0 +1,401 @@
+//! How the photo was taken, written out as EXIF and XMP.
+//!
+//! EXIF: <https://www.cipa.jp/std/documents/download_e.html?DC-008-Translation-2023-E>
+//! XMP: <https://developer.adobe.com/xmp/docs/XMPSpecifications/>
+
+use crate::{
+	tiff::{self, Entry},
+	Colorspace,
+};
+
+/// An unsigned fraction, the way EXIF keeps its numbers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rational(pub u32, pub u32);
+
+/// A signed fraction, the way EXIF keeps its numbers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SRational(pub i32, pub i32);
+
+/// The camera, lens, exposure, and whatever else should go along with the
+/// image. Only what's set is written.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+	pub make: Option<String>,
+	pub model: Option<String>,
+	pub serial_number: Option<String>,
+	pub lens_make: Option<String>,
+	pub lens_model: Option<String>,
+	pub artist: Option<String>,
+	pub copyright: Option<String>,
+	/// What made the image. Your program, probably.
+	pub software: Option<String>,
+	/// When the photo was taken, in local time, as EXIF writes it:
+	/// `YYYY:MM:DD HH:MM:SS`
+	pub date_time: Option<String>,
+	/// The offset of that local time from UTC, `+HH:MM`.
+	pub utc_offset: Option<String>,
+	/// In seconds
+	pub exposure_time: Option<Rational>,
+	pub f_number: Option<Rational>,
+	pub iso: Option<u32>,
+	/// In millimetres
+	pub focal_length: Option<Rational>,
+	pub focal_length_35mm: Option<u16>,
+	/// In stops
+	pub exposure_bias: Option<SRational>,
+	/// The EXIF Flash value
+	pub flash: Option<u16>,
+	/// The EXIF orientation, 1 through 8, if the pixels aren't already the
+	/// way up they should be shown. Top left, 1, if it's `None`.
+	pub orientation: Option<u16>,
+}
+
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const ORIENTATION: u16 = 0x0112;
+const SOFTWARE: u16 = 0x0131;
+const DATE_TIME: u16 = 0x0132;
+const ARTIST: u16 = 0x013B;
+const COPYRIGHT: u16 = 0x8298;
+const EXPOSURE_TIME: u16 = 0x829A;
+const F_NUMBER: u16 = 0x829D;
+const EXIF_IFD: u16 = 0x8769;
+const ISO: u16 = 0x8827;
+const EXIF_VERSION: u16 = 0x9000;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const EXPOSURE_BIAS: u16 = 0x9204;
+const FLASH: u16 = 0x9209;
+const FOCAL_LENGTH: u16 = 0x920A;
+const COLOR_SPACE: u16 = 0xA001;
+const PIXEL_X_DIMENSION: u16 = 0xA002;
+const PIXEL_Y_DIMENSION: u16 = 0xA003;
+const FOCAL_LENGTH_35MM: u16 = 0xA405;
+const BODY_SERIAL_NUMBER: u16 = 0xA431;
+const LENS_MAKE: u16 = 0xA433;
+const LENS_MODEL: u16 = 0xA434;
+
+impl Metadata {
+	/// The EXIF as a little endian TIFF, which is what every format wants
+	/// inside their EXIF chunk or segment.
+	pub(crate) fn exif(&self, width: usize, height: usize, colorspace: &Colorspace) -> Vec<u8> {
+		let string = |tag, value: &Option<String>| value.as_deref().map(|v| Entry::ascii(tag, v));
+		// sRGB, or uncalibrated and you go look at the ICC profile
+		let color_space = match colorspace {
+			Colorspace::Srgb => 1,
+			_ => 0xFFFF,
+		};
+
+		let mut primary: Vec<Entry> = [
+			string(MAKE, &self.make),
+			string(MODEL, &self.model),
+			Some(Entry::shorts(ORIENTATION, &[self.orientation()])),
+			string(SOFTWARE, &self.software),
+			string(DATE_TIME, &self.date_time),
+			string(ARTIST, &self.artist),
+			string(COPYRIGHT, &self.copyright),
+		]
+		.into_iter()
+		.flatten()
+		.collect();
+
+		let exif: Vec<Entry> = [
+			self.exposure_time
+				.map(|Rational(n, d)| Entry::rational(EXPOSURE_TIME, n, d)),
+			self.f_number
+				.map(|Rational(n, d)| Entry::rational(F_NUMBER, n, d)),
+			// It's a short. Past that you're meant to say it's 65535 and use
+			// another tag, but nobody shoots at ISO 65536
+			self.iso
+				.map(|iso| Entry::shorts(ISO, &[iso.min(u16::MAX as u32) as u16])),
+			Some(Entry::undefined(EXIF_VERSION, b"0232")),
+			string(DATE_TIME_ORIGINAL, &self.date_time),
+			string(OFFSET_TIME_ORIGINAL, &self.utc_offset),
+			self.exposure_bias
+				.map(|SRational(n, d)| Entry::srational(EXPOSURE_BIAS, n, d)),
+			self.flash.map(|flash| Entry::shorts(FLASH, &[flash])),
+			self.focal_length
+				.map(|Rational(n, d)| Entry::rational(FOCAL_LENGTH, n, d)),
+			Some(Entry::shorts(COLOR_SPACE, &[color_space])),
+			Some(Entry::long(PIXEL_X_DIMENSION, width as u32)),
+			Some(Entry::long(PIXEL_Y_DIMENSION, height as u32)),
+			self.focal_length_35mm
+				.map(|length| Entry::shorts(FOCAL_LENGTH_35MM, &[length])),
+			string(BODY_SERIAL_NUMBER, &self.serial_number),
+			string(LENS_MAKE, &self.lens_make),
+			string(LENS_MODEL, &self.lens_model),
+		]
+		.into_iter()
+		.flatten()
+		.collect();
+
+		// The pointer to the EXIF IFD doesn't change how long IFD0 is, so we
+		// can find out where the EXIF IFD goes with a placeholder
+		let primary_offset = tiff::HEADER.len() + 4;
+		primary.push(Entry::long(EXIF_IFD, 0));
+		let exif_offset = primary_offset + tiff::ifd(&primary, primary_offset).len();
+		primary.pop();
+		primary.push(Entry::long(EXIF_IFD, exif_offset as u32));
+
+		let mut out = tiff::HEADER.to_vec();
+		out.extend_from_slice(&(primary_offset as u32).to_le_bytes());
+		out.extend(tiff::ifd(&primary, primary_offset));
+		out.extend(tiff::ifd(&exif, exif_offset));
+		out
+	}
+
+	/// An XMP packet saying the same as the EXIF.
+	pub(crate) fn xmp(&self) -> String {
+		let mut properties = vec![("tiff:Orientation", self.orientation().to_string())];
+		let mut text = |name, value: &Option<String>| {
+			if let Some(value) = value {
+				properties.push((name, value.clone()));
+			}
+		};
+		text("tiff:Make", &self.make);
+		text("tiff:Model", &self.model);
+		text("exifEX:BodySerialNumber", &self.serial_number);
+		text("exifEX:LensMake", &self.lens_make);
+		text("exifEX:LensModel", &self.lens_model);
+		text("xmp:CreatorTool", &self.software);
+
+		let date = self
+			.date_time
+			.as_deref()
+			.and_then(|date| xmp_date(date, self.utc_offset.as_deref()));
+		if let Some(date) = date {
+			properties.push(("exif:DateTimeOriginal", date.clone()));
+			properties.push(("xmp:CreateDate", date));
+		}
+
+		let rational = |Rational(n, d)| format!("{n}/{d}");
+		let mut number = |name, value: Option<String>| {
+			if let Some(value) = value {
+				properties.push((name, value));
+			}
+		};
+		number("exif:ExposureTime", self.exposure_time.map(rational));
+		number("exif:FNumber", self.f_number.map(rational));
+		number("exif:FocalLength", self.focal_length.map(rational));
+		number(
+			"exif:FocalLengthIn35mmFilm",
+			self.focal_length_35mm.map(|v| v.to_string()),
+		);
+		number(
+			"exif:ExposureBiasValue",
+			self.exposure_bias.map(|SRational(n, d)| format!("{n}/{d}")),
+		);
+		number(
+			"exifEX:PhotographicSensitivity",
+			self.iso.map(|v| v.to_string()),
+		);
+
+		let mut description = String::from("  <rdf:Description rdf:about=\"\"");
+		for (namespace, uri) in NAMESPACES {
+			description.push_str(&format!("\n    xmlns:{namespace}=\"{uri}\""));
+		}
+		for (name, value) in properties {
+			description.push_str(&format!("\n    {name}=\"{}\"", escape(&value)));
+		}
+		description.push('>');
+
+		// The properties that are arrays can't be attributes
+		if let Some(iso) = self.iso {
+			description.push_str(&format!(
+				"\n   <exif:ISOSpeedRatings><rdf:Seq><rdf:li>{iso}</rdf:li></rdf:Seq></exif:ISOSpeedRatings>"
+			));
+		}
+		if let Some(artist) = &self.artist {
+			description.push_str(&format!(
+				"\n   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+				escape(artist)
+			));
+		}
+		if let Some(copyright) = &self.copyright {
+			description.push_str(&format!(
+				"\n   <dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:rights>",
+				escape(copyright)
+			));
+		}
+
+		format!(
+			"<?xpacket begin=\"\u{FEFF}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+			<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+			<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+			{description}\n  \
+			</rdf:Description>\n \
+			</rdf:RDF>\n\
+			</x:xmpmeta>\n\
+			<?xpacket end=\"w\"?>"
+		)
+	}
+
+	/// Anything that isn't one of the eight orientations is top left
+	fn orientation(&self) -> u16 {
+		self.orientation
+			.filter(|o| (1..=8).contains(o))
+			.unwrap_or(1)
+	}
+}
+
+const NAMESPACES: [(&str, &str); 5] = [
+	("dc", "http://purl.org/dc/elements/1.1/"),
+	("xmp", "http://ns.adobe.com/xap/1.0/"),
+	("tiff", "http://ns.adobe.com/tiff/1.0/"),
+	("exif", "http://ns.adobe.com/exif/1.0/"),
+	("exifEX", "http://cipa.jp/exif/1.0/"),
+];
+
+/// EXIF's `YYYY:MM:DD HH:MM:SS` to XMP's `YYYY-MM-DDTHH:MM:SS`, with the
+/// offset from UTC if we have it. `None` if the date doesn't look like that.
+fn xmp_date(date: &str, offset: Option<&str>) -> Option<String> {
+	let bytes = date.as_bytes();
+	let shaped = bytes.len() == 19
+		&& bytes.iter().enumerate().all(|(idx, b)| match idx {
+			4 | 7 | 13 | 16 => *b == b':',
+			10 => *b == b' ',
+			_ => b.is_ascii_digit(),
+		});
+	if !shaped {
+		return None;
+	}
+
+	let mut xmp = format!(
+		"{}-{}-{}T{}",
+		&date[0..4],
+		&date[5..7],
+		&date[8..10],
+		&date[11..]
+	);
+	if let Some(offset) = offset.filter(|o| o.len() == 6 && o.is_ascii()) {
+		xmp.push_str(offset);
+	}
+	Some(xmp)
+}
+
+fn escape(text: &str) -> String {
+	text.chars()
+		.map(|c| match c {
+			'&' => "&amp;".to_owned(),
+			'<' => "&lt;".to_owned(),
+			'>' => "&gt;".to_owned(),
+			'"' => "&quot;".to_owned(),
+			c => c.to_string(),
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::Cursor;
+
+	use exif::{In, Reader, Tag, Value};
+
+	use super::*;
+	use crate::{embed, Format, OutImage};
+
+	fn metadata() -> Metadata {
+		Metadata {
+			make: Some("Maker".into()),
+			model: Some("Model 1".into()),
+			lens_model: Some("50mm F1.8".into()),
+			date_time: Some("2019:06:30 12:34:56".into()),
+			utc_offset: Some("-07:00".into()),
+			exposure_time: Some(Rational(1, 250)),
+			f_number: Some(Rational(28, 10)),
+			iso: Some(400),
+			exposure_bias: Some(SRational(-2, 3)),
+			orientation: Some(6),
+			..Default::default()
+		}
+	}
+
+	fn write(format: Format) -> Vec<u8> {
+		let mut out = vec![];
+		OutImage::new(6, 4, vec![100; 6 * 4 * 3])
+			.unwrap()
+			.with_metadata(metadata())
+			.write(&mut out, format)
+			.unwrap();
+		out
+	}
+
+	/// Find the EXIF the way other readers would and check it's ours
+	fn check_exif(file: &[u8]) {
+		let exif = Reader::new()
+			.read_from_container(&mut Cursor::new(file))
+			.unwrap();
+		let field = |tag| &exif.get_field(tag, In::PRIMARY).unwrap().value;
+		let ascii = |tag| match field(tag) {
+			Value::Ascii(strings) => String::from_utf8(strings[0].clone()).unwrap(),
+			other => panic!("{tag} is {other:?}"),
+		};
+
+		assert_eq!(ascii(Tag::Make), "Maker");
+		assert_eq!(ascii(Tag::Model), "Model 1");
+		assert_eq!(ascii(Tag::LensModel), "50mm F1.8");
+		assert_eq!(ascii(Tag::DateTimeOriginal), "2019:06:30 12:34:56");
+		assert_eq!(ascii(Tag::OffsetTimeOriginal), "-07:00");
+		assert_eq!(field(Tag::Orientation).get_uint(0), Some(6));
+		assert_eq!(field(Tag::PhotographicSensitivity).get_uint(0), Some(400));
+		assert_eq!(field(Tag::PixelXDimension).get_uint(0), Some(6));
+		assert_eq!(field(Tag::PixelYDimension).get_uint(0), Some(4));
+		match field(Tag::ExposureTime) {
+			Value::Rational(v) => assert_eq!((v[0].num, v[0].denom), (1, 250)),
+			other => panic!("the exposure time is {other:?}"),
+		}
+		match field(Tag::ExposureBiasValue) {
+			Value::SRational(v) => assert_eq!((v[0].num, v[0].denom), (-2, 3)),
+			other => panic!("the exposure bias is {other:?}"),
+		}
+	}
+
+	fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+		haystack
+			.windows(needle.len())
+			.position(|window| window == needle)
+	}
+
+	#[test]
+	fn png_exif_and_xmp() {
+		let png = write(Format::Png);
+		check_exif(&png);
+
+		let reader = png::Decoder::new(&png[..]).read_info().unwrap();
+		let xmp = reader
+			.info()
+			.utf8_text
+			.iter()
+			.find(|chunk| chunk.keyword == "XML:com.adobe.xmp")
+			.unwrap()
+			.get_text()
+			.unwrap();
+		assert!(xmp.contains("tiff:Make=\"Maker\""));
+		assert!(xmp.contains("exif:DateTimeOriginal=\"2019-06-30T12:34:56-07:00\""));
+	}
+
+	#[test]
+	fn webp_exif_and_xmp() {
+		let webp = write(Format::Webp);
+		check_exif(&webp);
+
+		let xmp = find(&webp, b"XMP ").unwrap();
+		let length = u32::from_le_bytes(webp[xmp + 4..xmp + 8].try_into().unwrap()) as usize;
+		let xmp = std::str::from_utf8(&webp[xmp + 8..xmp + 8 + length]).unwrap();
+		assert!(xmp.contains("tiff:Model=\"Model 1\""));
+	}
+
+	#[test]
+	fn jpeg_exif_follows_jfif() {
+		let jpeg = write(Format::Jpeg);
+		check_exif(&jpeg);
+
+		let (segments, _) = embed::jpeg_segments(&jpeg).unwrap();
+		let markers: Vec<u8> = segments.iter().map(|(marker, _)| *marker).collect();
+		// JFIF's APP0 has to be first, then the EXIF and XMP APP1s
+		assert_eq!(markers[..4], [0xE0, 0xE1, 0xE1, 0xE2]);
+		assert!(segments[0].1.starts_with(b"JFIF\0"));
+		assert!(segments[1].1.starts_with(b"Exif\0\0"));
+		assert!(segments[2].1.starts_with(b"http://ns.adobe.com/xap/1.0/\0"));
+	}
+}
@@ -0,0 +1,191 @@
+use std::{ffi::OsStr, path::Path};
+
+/// The formats an [OutImage](crate::OutImage) can be saved as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+	Png,
+	Jpeg,
+	Webp,
+	Tiff,
+}
+
+impl Format {
+	/// The format a file extension is for, whatever its case. `None` if we
+	/// can't write it.
+	pub fn from_extension<S: AsRef<OsStr>>(ext: S) -> Option<Self> {
+		let ext = ext.as_ref().to_str()?.to_ascii_lowercase();
+
+		match ext.as_str() {
+			"png" => Some(Format::Png),
+			"jpg" | "jpeg" | "jpe" => Some(Format::Jpeg),
+			"webp" => Some(Format::Webp),
+			"tif" | "tiff" => Some(Format::Tiff),
+			_ => None,
+		}
+	}
+
+	/// The format going by the path's extension.
+	pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+		path.as_ref().extension().and_then(Self::from_extension)
+	}
+
+	/// The extension files of this format usually have.
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Format::Png => "png",
+			Format::Jpeg => "jpg",
+			Format::Webp => "webp",
+			Format::Tiff => "tiff",
+		}
+	}
+}
+
+/// What to save an image as and how. Each format's options convert into
+/// this, and a [Format] does too with its default options.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SaveOptions {
+	Png(PngOptions),
+	Jpeg(JpegOptions),
+	Webp(WebpOptions),
+	Tiff,
+}
+
+impl SaveOptions {
+	pub fn format(&self) -> Format {
+		match self {
+			SaveOptions::Png(_) => Format::Png,
+			SaveOptions::Jpeg(_) => Format::Jpeg,
+			SaveOptions::Webp(_) => Format::Webp,
+			SaveOptions::Tiff => Format::Tiff,
+		}
+	}
+}
+
+impl From<Format> for SaveOptions {
+	fn from(format: Format) -> Self {
+		match format {
+			Format::Png => SaveOptions::Png(PngOptions::default()),
+			Format::Jpeg => SaveOptions::Jpeg(JpegOptions::default()),
+			Format::Webp => SaveOptions::Webp(WebpOptions::default()),
+			Format::Tiff => SaveOptions::Tiff,
+		}
+	}
+}
+
+impl From<PngOptions> for SaveOptions {
+	fn from(options: PngOptions) -> Self {
+		SaveOptions::Png(options)
+	}
+}
+
+impl From<JpegOptions> for SaveOptions {
+	fn from(options: JpegOptions) -> Self {
+		SaveOptions::Jpeg(options)
+	}
+}
+
+impl From<WebpOptions> for SaveOptions {
+	fn from(options: WebpOptions) -> Self {
+		SaveOptions::Webp(options)
+	}
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PngOptions {
+	pub compression: PngCompression,
+	pub filter: PngFilter,
+}
+
+/// How hard the deflate encoder tries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngCompression {
+	Fast,
+	#[default]
+	Default,
+	Best,
+}
+
+/// The filter run over every row before it's compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngFilter {
+	None,
+	#[default]
+	Sub,
+	Up,
+	Average,
+	Paeth,
+	/// Pick whichever filter seems best for each row. Slower, but usually
+	/// smaller.
+	Adaptive,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JpegOptions {
+	/// 0 to 100
+	pub quality: f32,
+	pub chroma_subsampling: ChromaSubsampling,
+	/// Write the image in several passes that each add detail, rather than
+	/// top to bottom. A bit smaller, too.
+	pub progressive: bool,
+	/// Build Huffman tables for this image rather than use the standard ones.
+	pub optimize_huffman: bool,
+}
+
+impl Default for JpegOptions {
+	// mozjpeg's own defaults
+	fn default() -> Self {
+		Self {
+			quality: 85.0,
+			chroma_subsampling: ChromaSubsampling::Yuv420,
+			progressive: true,
+			optimize_huffman: true,
+		}
+	}
+}
+
+/// How much colour resolution JPEG throws away.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+	/// Full colour resolution
+	Yuv444,
+	/// Half the horizontal colour resolution
+	Yuv422,
+	/// Half the colour resolution both ways
+	#[default]
+	Yuv420,
+}
+
+impl ChromaSubsampling {
+	/// The size of a chroma sample in luma samples, horizontally and vertically
+	pub(crate) fn pixel_size(&self) -> (u8, u8) {
+		match self {
+			ChromaSubsampling::Yuv444 => (1, 1),
+			ChromaSubsampling::Yuv422 => (2, 1),
+			ChromaSubsampling::Yuv420 => (2, 2),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebpOptions {
+	/// 0 to 100. When lossless, how much effort goes into making it small.
+	pub quality: f32,
+	pub lossless: bool,
+	/// 0 to 6, trading speed for a smaller or better looking file.
+	pub method: u8,
+	/// 0 to 100, the quality of the alpha channel if there is one. 100 keeps
+	/// it lossless.
+	pub alpha_quality: u8,
+}
+
+impl Default for WebpOptions {
+	// libwebp's own defaults
+	fn default() -> Self {
+		Self {
+			quality: 75.0,
+			lossless: false,
+			method: 4,
+			alpha_quality: 100,
+		}
+	}
+}
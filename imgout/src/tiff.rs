@@ -0,0 +1,294 @@
+//! A baseline TIFF writer. Uncompressed RGB in one strip, which everything
+//! can read, at 8 or 16 bits or as 32-bit floats. EXIF is a TIFF too, so the
+//! IFDs are built here for that as well.
+
+use std::io::Write;
+
+use crate::Error;
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const UNDEFINED: u16 = 7;
+const SRATIONAL: u16 = 10;
+
+/// Little endian TIFF. The offset of the first IFD follows.
+pub(crate) const HEADER: &[u8] = b"II*\0";
+
+pub(crate) enum SampleFormat {
+	Unsigned,
+	Float,
+}
+
+pub(crate) struct Entry {
+	tag: u16,
+	kind: u16,
+	values: Vec<u8>,
+	count: u32,
+}
+
+impl Entry {
+	pub fn ascii(tag: u16, text: &str) -> Self {
+		let mut values = text.as_bytes().to_vec();
+		values.push(0);
+
+		Self {
+			tag,
+			kind: ASCII,
+			count: values.len() as u32,
+			values,
+		}
+	}
+
+	pub fn shorts(tag: u16, values: &[u16]) -> Self {
+		Self {
+			tag,
+			kind: SHORT,
+			values: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+			count: values.len() as u32,
+		}
+	}
+
+	pub fn long(tag: u16, value: u32) -> Self {
+		Self {
+			tag,
+			kind: LONG,
+			values: value.to_le_bytes().to_vec(),
+			count: 1,
+		}
+	}
+
+	pub fn rational(tag: u16, numerator: u32, denominator: u32) -> Self {
+		let mut values = numerator.to_le_bytes().to_vec();
+		values.extend_from_slice(&denominator.to_le_bytes());
+
+		Self {
+			tag,
+			kind: RATIONAL,
+			values,
+			count: 1,
+		}
+	}
+
+	pub fn srational(tag: u16, numerator: i32, denominator: i32) -> Self {
+		let mut values = numerator.to_le_bytes().to_vec();
+		values.extend_from_slice(&denominator.to_le_bytes());
+
+		Self {
+			tag,
+			kind: SRATIONAL,
+			values,
+			count: 1,
+		}
+	}
+
+	pub fn undefined(tag: u16, values: &[u8]) -> Self {
+		Self {
+			tag,
+			kind: UNDEFINED,
+			values: values.to_vec(),
+			count: values.len() as u32,
+		}
+	}
+}
+
+/// An IFD that will start `offset` bytes into the file, followed by the
+/// values that didn't fit in their entries. Entries have to be sorted by tag.
+/// There's never a next IFD.
+pub(crate) fn ifd(entries: &[Entry], offset: usize) -> Vec<u8> {
+	let ifd_length = 2 + entries.len() * 12 + 4;
+
+	let mut ifd = (entries.len() as u16).to_le_bytes().to_vec();
+	let mut overflow = vec![];
+	for entry in entries {
+		ifd.extend_from_slice(&entry.tag.to_le_bytes());
+		ifd.extend_from_slice(&entry.kind.to_le_bytes());
+		ifd.extend_from_slice(&entry.count.to_le_bytes());
+
+		if entry.values.len() <= 4 {
+			let mut inline = entry.values.clone();
+			inline.resize(4, 0);
+			ifd.extend(inline);
+		} else {
+			let value_offset = offset + ifd_length + overflow.len();
+			ifd.extend_from_slice(&(value_offset as u32).to_le_bytes());
+			overflow.extend_from_slice(&entry.values);
+			if overflow.len() % 2 == 1 {
+				overflow.push(0);
+			}
+		}
+	}
+	// No next IFD
+	ifd.extend_from_slice(&0u32.to_le_bytes());
+
+	ifd.extend(overflow);
+	ifd
+}
+
+/// Write a little endian TIFF. `samples` are the pixels, already in their
+/// bytes, little endian, `bits` per sample. Every offset in a TIFF is 32 bits,
+/// so it's an error if the file would be larger than that.
+pub(crate) fn write<W: Write>(
+	mut writer: W,
+	width: usize,
+	height: usize,
+	bits: u16,
+	format: SampleFormat,
+	samples: &[u8],
+	icc_profile: Option<&[u8]>,
+) -> Result<(), Error> {
+	let too_large = |_| Error::TooLargeForTiff;
+	let (width, height) = (
+		u32::try_from(width).map_err(too_large)?,
+		u32::try_from(height).map_err(too_large)?,
+	);
+	let strip_length = u32::try_from(samples.len()).map_err(too_large)?;
+
+	let sample_format = match format {
+		SampleFormat::Unsigned => 1,
+		SampleFormat::Float => 3,
+	};
+
+	// The header and the pixels come first, the IFD and anything it points to
+	// after. The strip offset is right past the header.
+	let header_length = 8u32;
+	let strip_offset = header_length;
+
+	let mut entries = vec![
+		Entry::long(256, width),
+		Entry::long(257, height),
+		Entry::shorts(258, &[bits; 3]),
+		// No compression
+		Entry::shorts(259, &[1]),
+		// RGB
+		Entry::shorts(262, &[2]),
+		Entry::long(273, strip_offset),
+		Entry::shorts(277, &[3]),
+		Entry::long(278, height),
+		Entry::long(279, strip_length),
+		// Chunky, RGBRGB
+		Entry::shorts(284, &[1]),
+		Entry::shorts(339, &[sample_format; 3]),
+	];
+
+	if let Some(profile) = icc_profile {
+		entries.push(Entry::undefined(34675, profile));
+	}
+
+	// IFDs have to start on a word boundary
+	let padding = samples.len() % 2;
+	let ifd_offset = strip_offset as usize + samples.len() + padding;
+
+	let ifd = ifd(&entries, ifd_offset);
+	// The profile is last, so if the end fits everything's offset does
+	u32::try_from(ifd_offset + ifd.len()).map_err(too_large)?;
+
+	writer.write_all(HEADER)?;
+	writer.write_all(&(ifd_offset as u32).to_le_bytes())?;
+	writer.write_all(samples)?;
+	writer.write_all(&[0; 1][..padding])?;
+	writer.write_all(&ifd)?;
+	writer.flush()?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use exif::{Context, In, Reader, Tag, Value};
+
+	use crate::OutImage;
+
+	const SAMPLE_FORMAT: Tag = Tag(Context::Tiff, 339);
+
+	/// The strip, BitsPerSample, SampleFormat, and ICC profile from IFD0
+	fn read_back(tiff: Vec<u8>) -> (Vec<u8>, Vec<u32>, Vec<u32>, Vec<u8>) {
+		let exif = Reader::new().read_raw(tiff.clone()).unwrap();
+		let uints = |tag| {
+			let field = exif.get_field(tag, In::PRIMARY).unwrap();
+			match &field.value {
+				Value::Short(v) => v.iter().map(|v| *v as u32).collect::<Vec<u32>>(),
+				Value::Long(v) => v.clone(),
+				other => panic!("{tag} is {other:?}"),
+			}
+		};
+
+		let offset = uints(Tag::StripOffsets)[0] as usize;
+		// Right after the header
+		assert_eq!(offset, 8);
+		let length = uints(Tag::StripByteCounts)[0] as usize;
+		let strip = tiff[offset..offset + length].to_vec();
+
+		let profile = match &exif.get_field(Tag(Context::Tiff, 34675), In::PRIMARY) {
+			Some(field) => match &field.value {
+				Value::Undefined(v, _) => v.clone(),
+				other => panic!("the profile is {other:?}"),
+			},
+			None => vec![],
+		};
+
+		(
+			strip,
+			uints(Tag::BitsPerSample),
+			uints(SAMPLE_FORMAT),
+			profile,
+		)
+	}
+
+	#[test]
+	fn sixteen_bit() {
+		let data: Vec<u16> = (0..5 * 3 * 3).map(|v| v * 1000 + 1).collect();
+		let mut tiff = vec![];
+		OutImage::new_u16(5, 3, data.clone())
+			.unwrap()
+			.write_tiff(&mut tiff)
+			.unwrap();
+
+		let (strip, bits, format, profile) = read_back(tiff);
+		assert_eq!(bits, [16; 3]);
+		assert_eq!(format, [1; 3]);
+		assert_eq!(
+			strip,
+			data.iter()
+				.flat_map(|v| v.to_le_bytes())
+				.collect::<Vec<u8>>()
+		);
+		assert_eq!(profile, crate::Colorspace::Srgb.icc_profile());
+	}
+
+	#[test]
+	fn float() {
+		let data: Vec<f32> = (0..3 * 2 * 3).map(|v| v as f32 / 10.0 - 0.5).collect();
+		let mut tiff = vec![];
+		OutImage::new_f32(3, 2, data.clone())
+			.unwrap()
+			.write_tiff(&mut tiff)
+			.unwrap();
+
+		let (strip, bits, format, _) = read_back(tiff);
+		assert_eq!(bits, [32; 3]);
+		assert_eq!(format, [3; 3]);
+		assert_eq!(
+			strip,
+			data.iter()
+				.flat_map(|v| v.to_le_bytes())
+				.collect::<Vec<u8>>()
+		);
+	}
+
+	#[test]
+	fn odd_strips_are_padded() {
+		// 3 bytes of pixels, so the IFD needs a byte in front of it
+		let mut tiff = vec![];
+		OutImage::new(1, 1, vec![10, 20, 30])
+			.unwrap()
+			.write_tiff(&mut tiff)
+			.unwrap();
+
+		let (strip, bits, format, _) = read_back(tiff);
+		assert_eq!(strip, [10, 20, 30]);
+		assert_eq!(bits, [8; 3]);
+		assert_eq!(format, [1; 3]);
+	}
+}
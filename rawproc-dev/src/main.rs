@@ -4,7 +4,7 @@ use imgout::OutImage;
 use rawproc::{
 	colorspace::{Hsv, Srgb},
 	decode,
-	image::Image,
+	image::{Image, Interpolation, WhiteBalance},
 };
 
 fn main() {
@@ -16,16 +16,20 @@ fn main() {
 
 	p.start(Profile::AllOfIt);
 	p.start(Profile::Decode);
-	let mut raw = decode(&mut file).unwrap();
+	let mut raw = decode(&mut file).unwrap().into_integer();
 	p.end(Profile::Decode);
 
 	p.start(Profile::Crop);
-	raw.crop();
+	raw.crop().unwrap();
 	p.end(Profile::Crop);
 
+	p.start(Profile::Blacklevel);
+	raw.blacklevels(None);
+	p.end(Profile::Blacklevel);
+
 	p.start(Profile::Whitebalance);
 	// Pre bayer whitebalance
-	raw.whitebalance();
+	raw.whitebalance(WhiteBalance::AsShot);
 	p.end(Profile::Whitebalance);
 
 	println!("WB {:?}", raw.metadata.whitebalance);
@@ -38,7 +42,7 @@ fn main() {
 	p.end(Profile::PreColour);
 
 	p.start(Profile::Debayer);
-	let rgb = raw.debayer();
+	let rgb = raw.debayer(Interpolation::MalvarHeCutler);
 	p.end(Profile::Debayer);
 
 	p.start(Profile::XyzToSrgb);
@@ -81,6 +85,7 @@ fn main() {
 	println!("");
 	println!("Decode  {}ms", p.elapsed_ms(Profile::Decode).unwrap());
 	println!("Crop    {}ms", p.elapsed_ms(Profile::Crop).unwrap());
+	println!("Black   {}ms", p.elapsed_ms(Profile::Blacklevel).unwrap());
 	println!("W.B.    {}ms", p.elapsed_ms(Profile::Whitebalance).unwrap());
 	println!("Colour  {}ms", p.elapsed_ms(Profile::PreColour).unwrap());
 	println!("Debayer {}ms\n", p.elapsed_ms(Profile::Debayer).unwrap());
@@ -92,27 +97,14 @@ fn main() {
 		p.elapsed(Profile::AllOfIt).unwrap().as_secs_f64()
 	);
 
-	let img = srgb.bytes();
+	let mut img = srgb.bytes();
+	img.orient();
 
-	let out = OutImage::new(img.width, img.height, img.data);
+	let out = OutImage::new(img.width, img.height, img.data)
+		.unwrap()
+		.with_metadata((&img.metadata).into());
 	let name = std::env::args().nth(2).unwrap();
-	out.jpeg(name, 85.0);
-
-	/*let mut enc = png::Encoder::new(file, width, height);
-	enc.set_color(png::ColorType::Rgb);
-	enc.set_depth(png::BitDepth::Eight);
-	enc.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2));
-	let source_chromaticities = png::SourceChromaticities::new(
-		(0.31270, 0.32900),
-		(0.64000, 0.33000),
-		(0.30000, 0.60000),
-		(0.15000, 0.06000),
-	);
-	enc.set_source_chromaticities(source_chromaticities);
-	enc.set_srgb(png::SrgbRenderingIntent::Perceptual);
-
-	let mut writer = enc.write_header().unwrap();
-	writer.write_image_data(&data).unwrap();*/
+	out.jpeg(name, 85.0).unwrap();
 }
 
 struct Profiler {
@@ -159,6 +151,7 @@ enum Profile {
 	AllOfIt,
 	Decode,
 	Crop,
+	Blacklevel,
 	Whitebalance,
 	PreColour,
 	Debayer,
@@ -0,0 +1,245 @@
+//! Chromatic adaptation, which is fancy speak for moving colours from one
+//! reference white to another. If a sheet of paper was white under tungsten,
+//! this is how we figure out what colour it should be under daylight.
+//!
+//! <http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html>
+
+use nalgebra::{Matrix3, Matrix3x1};
+
+use crate::image::temperature_to_xyz;
+
+/// The cone response domain the adaptation happens in. The whites are scaled
+/// in there, so what it looks like changes how colours move.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transform {
+	/// Scale XYZ directly. Simple and the least accurate.
+	XyzScaling,
+	/// What most everything uses, and what ICC profiles expect.
+	Bradford,
+	/// Von Kries with the Hunt-Pointer-Estevez cone responses.
+	VonKries,
+	/// From CIECAM02.
+	Cat02,
+	/// From CAM16. A fixed up CAT02.
+	Cat16,
+}
+
+impl Transform {
+	/// The matrix that takes XYZ into this transform's cone response domain.
+	pub fn cone_response(&self) -> Matrix3<f32> {
+		match self {
+			Transform::XyzScaling => Matrix3::identity(),
+			Transform::Bradford => BRADFORD,
+			Transform::VonKries => VON_KRIES,
+			Transform::Cat02 => CAT02,
+			Transform::Cat16 => CAT16,
+		}
+	}
+}
+
+#[rustfmt::skip]
+const BRADFORD: Matrix3<f32> = Matrix3::new(
+	 0.8951,  0.2664, -0.1614,
+	-0.7502,  1.7135,  0.0367,
+	 0.0389, -0.0685,  1.0296,
+);
+
+#[rustfmt::skip]
+const VON_KRIES: Matrix3<f32> = Matrix3::new(
+	 0.40024, 0.7076,  -0.08081,
+	-0.2263,  1.16532,  0.0457,
+	 0.0,     0.0,      0.91822,
+);
+
+#[rustfmt::skip]
+const CAT02: Matrix3<f32> = Matrix3::new(
+	 0.7328, 0.4296, -0.1624,
+	-0.7036, 1.6975,  0.0061,
+	 0.0030, 0.0136,  0.9834,
+);
+
+#[rustfmt::skip]
+const CAT16: Matrix3<f32> = Matrix3::new(
+	 0.401288, 0.650173, -0.051461,
+	-0.250268, 1.204414,  0.045854,
+	-0.002079, 0.048952,  0.953127,
+);
+
+/// A reference white as XYZ tristimulus values. Y is usually 1.0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WhitePoint {
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
+}
+
+impl WhitePoint {
+	/// Noon daylight. What ICC profiles are connected through.
+	pub const D50: WhitePoint = WhitePoint::new(0.96422, 1.0, 0.82521);
+	/// Average daylight. The white of sRGB, Display P3, Rec.2020, and friends.
+	pub const D65: WhitePoint = WhitePoint::new(0.95047, 1.0, 1.08883);
+	/// Incandescent light.
+	pub const A: WhitePoint = WhitePoint::new(1.09850, 1.0, 0.35585);
+	/// Equal energy.
+	pub const E: WhitePoint = WhitePoint::new(1.0, 1.0, 1.0);
+
+	pub const fn new(x: f32, y: f32, z: f32) -> Self {
+		Self { x, y, z }
+	}
+
+	/// The white with the `x`, `y` chromaticity coordinates and a Y of 1.0.
+	pub fn from_xy(x: f32, y: f32) -> Self {
+		Self::new(x / y, 1.0, (1.0 - x - y) / y)
+	}
+
+	/// The white of a blackbody at `kelvin`, moved off the curve by `tint`.
+	/// See [WhiteBalance::Temperature](crate::image::WhiteBalance::Temperature).
+	pub fn from_temperature(kelvin: f32, tint: f32) -> Self {
+		temperature_to_xyz(kelvin, tint).into()
+	}
+
+	/// The `x`, `y` chromaticity coordinates.
+	pub fn xy(&self) -> (f32, f32) {
+		let sum = self.x + self.y + self.z;
+		(self.x / sum, self.y / sum)
+	}
+
+	pub fn xyz(&self) -> Matrix3x1<f32> {
+		Matrix3x1::new(self.x, self.y, self.z)
+	}
+}
+
+impl From<Matrix3x1<f32>> for WhitePoint {
+	fn from(xyz: Matrix3x1<f32>) -> Self {
+		Self::new(xyz[0], xyz[1], xyz[2])
+	}
+}
+
+/// The matrix that takes XYZ colours seen under `from` to how they'd look
+/// under `to`.
+///
+/// The whites aren't normalized first, so if their Y differs the brightness
+/// is scaled too. `from`'s white always comes out as `to`'s white exactly.
+pub fn matrix(transform: Transform, from: WhitePoint, to: WhitePoint) -> Matrix3<f32> {
+	let cone = transform.cone_response();
+	// All of the transforms are invertible
+	let cone_inv = cone.try_inverse().unwrap();
+
+	let from_cone = cone * from.xyz();
+	let to_cone = cone * to.xyz();
+
+	let scale = Matrix3::from_diagonal(&Matrix3x1::new(
+		to_cone[0] / from_cone[0],
+		to_cone[1] / from_cone[1],
+		to_cone[2] / from_cone[2],
+	));
+
+	cone_inv * scale * cone
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn assert_close(got: Matrix3<f32>, expected: Matrix3<f32>) {
+		for (g, e) in got.iter().zip(expected.iter()) {
+			assert!((g - e).abs() < 1e-4, "expected\n{expected}\ngot\n{got}");
+		}
+	}
+
+	#[test]
+	fn xyz_scaling() {
+		// Bruce Lindbloom's D65 to D50 matrix from
+		// http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html
+		#[rustfmt::skip]
+		let expected = Matrix3::new(
+			1.0144665, 0.0000000, 0.0000000,
+			0.0000000, 1.0000000, 0.0000000,
+			0.0000000, 0.0000000, 0.7578869,
+		);
+
+		let got = matrix(Transform::XyzScaling, WhitePoint::D65, WhitePoint::D50);
+		assert_close(got, expected);
+	}
+
+	#[test]
+	fn bradford() {
+		// Lindbloom's again, from the same page
+		#[rustfmt::skip]
+		let expected = Matrix3::new(
+			 1.0478112, 0.0228866, -0.0501270,
+			 0.0295424, 0.9904844, -0.0170491,
+			-0.0092345, 0.0150436,  0.7521316,
+		);
+
+		let got = matrix(Transform::Bradford, WhitePoint::D65, WhitePoint::D50);
+		assert_close(got, expected);
+	}
+
+	#[test]
+	fn von_kries() {
+		// Lindbloom's again, from the same page
+		#[rustfmt::skip]
+		let expected = Matrix3::new(
+			1.0160803, 0.0552297, -0.0521824,
+			0.0060666, 0.9955661, -0.0012235,
+			0.0000000, 0.0000000,  0.7578869,
+		);
+
+		let got = matrix(Transform::VonKries, WhitePoint::D65, WhitePoint::D50);
+		assert_close(got, expected);
+	}
+
+	// The whites colour-science adapts between in its examples. These and its
+	// matrix are quoted as published, past what an f32 holds
+	#[allow(clippy::excessive_precision)]
+	const COLOUR_D65: WhitePoint = WhitePoint::new(0.95045593, 1.0, 1.08905775);
+	#[allow(clippy::excessive_precision)]
+	const COLOUR_D50: WhitePoint = WhitePoint::new(0.96429568, 1.0, 0.8251046);
+
+	#[test]
+	#[allow(clippy::excessive_precision)]
+	fn cat02() {
+		// colour-science's matrix_chromatic_adaptation_VonKries with CAT02
+		#[rustfmt::skip]
+		let expected = Matrix3::new(
+			 1.04257389,  0.03089108, -0.05281257,
+			 0.02219345,  1.00185663, -0.02107375,
+			-0.00116488, -0.00342053,  0.76178907,
+		);
+
+		let got = matrix(Transform::Cat02, COLOUR_D65, COLOUR_D50);
+		assert_close(got, expected);
+	}
+
+	#[test]
+	fn cat16() {
+		// Worked out in double precision from M16 as Li et al. publish it in
+		// "Comprehensive color solutions: CAM16, CAT16, and CAM16-UCS"
+		#[rustfmt::skip]
+		let expected = Matrix3::new(
+			1.0108543,  0.0407086, -0.0341446,
+			0.0054281,  0.9935819,  0.0011559,
+			0.0002507, -0.0114919,  0.767965,
+		);
+
+		let got = matrix(Transform::Cat16, COLOUR_D65, COLOUR_D50);
+		assert_close(got, expected);
+	}
+
+	#[test]
+	fn whites_map_to_whites() {
+		for transform in [Transform::Cat02, Transform::Cat16] {
+			let got = matrix(transform, WhitePoint::A, WhitePoint::D65) * WhitePoint::A.xyz();
+			let expected = WhitePoint::D65.xyz();
+			assert!((got - expected).abs().max() < 1e-5, "{transform:?}: {got}");
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let there = matrix(Transform::Bradford, WhitePoint::D50, WhitePoint::A);
+		let back = matrix(Transform::Bradford, WhitePoint::A, WhitePoint::D50);
+		assert_close(back * there, Matrix3::identity());
+	}
+}
@@ -9,6 +9,36 @@ pub fn srgb_gamma(mut float: f32) -> f32 {
 	float.max(0.0).min(1.0)
 }
 
+/// The BT.709 transfer function, which BT.2020 uses too
+#[inline]
+pub fn rec709_oetf(float: f32) -> f32 {
+	let float = if float < 0.018053968 {
+		float * 4.5
+	} else {
+		1.0992968 * float.powf(0.45) - 0.0992968
+	};
+
+	float.clamp(0.0, 1.0)
+}
+
+/// Adobe RGB's pure gamma of 563/256, which is about 2.2
+#[inline]
+pub fn adobe_rgb_gamma(float: f32) -> f32 {
+	float.max(0.0).powf(256.0 / 563.0).min(1.0)
+}
+
+/// ProPhoto's gamma of 1.8 with a linear bit at the bottom
+#[inline]
+pub fn prophoto_gamma(float: f32) -> f32 {
+	let float = if float < 1.0 / 512.0 {
+		float * 16.0
+	} else {
+		float.powf(1.0 / 1.8)
+	};
+
+	float.clamp(0.0, 1.0)
+}
+
 #[inline]
 pub fn contrast(float: f32, adjustment: f32) -> f32 {
 	(adjustment * (float - 0.5) + 0.5).clamp(0.0, 1.0)
@@ -70,3 +100,75 @@ pub fn pixel_hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> (f32, f32, f32
 		);
 	}
 }
+
+const LAB_EPSILON: f32 = 216.0 / 24389.0;
+const LAB_KAPPA: f32 = 24389.0 / 27.0;
+
+/// XYZ to CIELAB, relative to the `white` XYZ.
+/// <http://www.brucelindbloom.com/index.html?Eqn_XYZ_to_Lab.html>
+#[inline]
+pub fn pixel_xyz_to_lab(x: f32, y: f32, z: f32, white: [f32; 3]) -> (f32, f32, f32) {
+	let f = |t: f32| {
+		if t > LAB_EPSILON {
+			t.cbrt()
+		} else {
+			(LAB_KAPPA * t + 16.0) / 116.0
+		}
+	};
+
+	let fx = f(x / white[0]);
+	let fy = f(y / white[1]);
+	let fz = f(z / white[2]);
+
+	(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// CIELAB to XYZ, relative to the `white` XYZ.
+/// <http://www.brucelindbloom.com/index.html?Eqn_Lab_to_XYZ.html>
+#[inline]
+pub fn pixel_lab_to_xyz(l: f32, a: f32, b: f32, white: [f32; 3]) -> (f32, f32, f32) {
+	let fy = (l + 16.0) / 116.0;
+	let fx = fy + a / 500.0;
+	let fz = fy - b / 200.0;
+
+	let finv = |f: f32| {
+		let cubed = f * f * f;
+		if cubed > LAB_EPSILON {
+			cubed
+		} else {
+			(116.0 * f - 16.0) / LAB_KAPPA
+		}
+	};
+
+	let y = if l > LAB_KAPPA * LAB_EPSILON {
+		fy * fy * fy
+	} else {
+		l / LAB_KAPPA
+	};
+
+	(finv(fx) * white[0], y * white[1], finv(fz) * white[2])
+}
+
+/// The rectangular a and b of Lab or Oklab to chroma and a hue in degrees.
+#[inline]
+pub fn pixel_ab_to_ch(a: f32, b: f32) -> (f32, f32) {
+	let chroma = (a * a + b * b).sqrt();
+	let hue = b.atan2(a).to_degrees().rem_euclid(360.0);
+
+	(chroma, hue)
+}
+
+/// Chroma and a hue in degrees to the rectangular a and b of Lab or Oklab.
+#[inline]
+pub fn pixel_ch_to_ab(chroma: f32, hue: f32) -> (f32, f32) {
+	let (sin, cos) = hue.to_radians().sin_cos();
+	(chroma * cos, chroma * sin)
+}
+
+/// Scale chroma up by `amount`, but less the more colourful it already is.
+/// Anything at or past `full` isn't touched at all.
+#[inline]
+pub fn vibrance(chroma: f32, amount: f32, full: f32) -> f32 {
+	let dull = 1.0 - (chroma / full).clamp(0.0, 1.0);
+	(chroma * (1.0 + amount * dull)).max(0.0)
+}
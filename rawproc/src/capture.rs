@@ -0,0 +1,200 @@
+//! How a photo was taken: the camera, the lens, the exposure, and when. Read
+//! from the EXIF tags of TIFF based raw files so that it can be written out
+//! again with the processed image.
+
+use crate::tiff::Tiff;
+
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const DATE_TIME: u16 = 0x0132;
+const ARTIST: u16 = 0x013B;
+const COPYRIGHT: u16 = 0x8298;
+const EXPOSURE_TIME: u16 = 0x829A;
+const F_NUMBER: u16 = 0x829D;
+const ISO: u16 = 0x8827;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const EXPOSURE_BIAS: u16 = 0x9204;
+const FLASH: u16 = 0x9209;
+const FOCAL_LENGTH: u16 = 0x920A;
+const FOCAL_LENGTH_35MM: u16 = 0xA405;
+const BODY_SERIAL_NUMBER: u16 = 0xA431;
+const LENS_MAKE: u16 = 0xA433;
+const LENS_MODEL: u16 = 0xA434;
+// Not EXIF, but DNG has its own tag for the serial number and some cameras
+// only have this one.
+const CAMERA_SERIAL_NUMBER: u16 = 0xC62F;
+
+/// An unsigned fraction, the way EXIF keeps its numbers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rational(pub u32, pub u32);
+
+impl Rational {
+	pub fn to_f64(self) -> f64 {
+		self.0 as f64 / self.1 as f64
+	}
+}
+
+/// A signed fraction, the way EXIF keeps its numbers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SRational(pub i32, pub i32);
+
+impl SRational {
+	pub fn to_f64(self) -> f64 {
+		self.0 as f64 / self.1 as f64
+	}
+}
+
+/// What the camera wrote down about the shot. Anything it didn't, or that we
+/// couldn't find, is `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capture {
+	pub make: Option<String>,
+	pub model: Option<String>,
+	pub serial_number: Option<String>,
+	pub lens_make: Option<String>,
+	pub lens_model: Option<String>,
+	pub artist: Option<String>,
+	pub copyright: Option<String>,
+	/// When the photo was taken, in the camera's local time, as EXIF writes
+	/// it: `YYYY:MM:DD HH:MM:SS`
+	pub date_time: Option<String>,
+	/// The offset of that local time from UTC, `+HH:MM`, if the camera knew.
+	pub utc_offset: Option<String>,
+	/// In seconds
+	pub exposure_time: Option<Rational>,
+	pub f_number: Option<Rational>,
+	pub iso: Option<u32>,
+	/// In millimetres
+	pub focal_length: Option<Rational>,
+	/// The focal length a lens would need on a 35mm camera to see the same.
+	pub focal_length_35mm: Option<u16>,
+	/// In stops
+	pub exposure_bias: Option<SRational>,
+	/// The EXIF Flash value. The lowest bit is set if it fired.
+	pub flash: Option<u16>,
+}
+
+impl Capture {
+	/// Read what we can from the IFDs of a TIFF. The first IFD with a tag
+	/// wins.
+	pub(crate) fn from_tiff(tiff: &Tiff) -> Self {
+		let entry = |tag| tiff.ifds.iter().find_map(|ifd| ifd.get(tag));
+
+		let string = |tag| entry(tag).and_then(|entry| tiff.string(entry));
+		let unsigned = |tag| {
+			entry(tag)
+				.and_then(|entry| tiff.unsigned(entry).first().copied())
+				.filter(|v| *v != 0)
+		};
+		let rational = |tag| {
+			entry(tag)
+				.and_then(|entry| tiff.rationals(entry).first().copied())
+				.filter(|(_, d)| *d != 0)
+		};
+
+		Self {
+			make: string(MAKE),
+			model: string(MODEL),
+			serial_number: string(BODY_SERIAL_NUMBER).or_else(|| string(CAMERA_SERIAL_NUMBER)),
+			lens_make: string(LENS_MAKE),
+			lens_model: string(LENS_MODEL),
+			artist: string(ARTIST),
+			copyright: string(COPYRIGHT),
+			date_time: string(DATE_TIME_ORIGINAL).or_else(|| string(DATE_TIME)),
+			utc_offset: string(OFFSET_TIME_ORIGINAL),
+			exposure_time: rational(EXPOSURE_TIME).map(|(n, d)| Rational(n as u32, d as u32)),
+			f_number: rational(F_NUMBER).map(|(n, d)| Rational(n as u32, d as u32)),
+			iso: unsigned(ISO),
+			focal_length: rational(FOCAL_LENGTH).map(|(n, d)| Rational(n as u32, d as u32)),
+			focal_length_35mm: unsigned(FOCAL_LENGTH_35MM).map(|v| v as u16),
+			exposure_bias: rational(EXPOSURE_BIAS).map(|(n, d)| SRational(n as i32, d as i32)),
+			// No flash is a zero, so it can't go through unsigned
+			flash: entry(FLASH).and_then(|entry| tiff.unsigned(entry).first().map(|v| *v as u16)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::tiff::test::{build, Value};
+
+	#[test]
+	fn reads_ifd0_and_exif() {
+		let data = build(&[
+			vec![
+				(MAKE, Value::Ascii("Maker")),
+				(MODEL, Value::Ascii("Model 1")),
+				(DATE_TIME, Value::Ascii("2020:01:01 00:00:00")),
+				(ARTIST, Value::Ascii("Someone")),
+				(CAMERA_SERIAL_NUMBER, Value::Ascii("DNG 1234")),
+			],
+			// Where the EXIF IFD would be
+			vec![
+				(EXPOSURE_TIME, Value::Rational(vec![(1, 250)])),
+				(F_NUMBER, Value::Rational(vec![(28, 10)])),
+				(ISO, Value::Short(vec![400])),
+				(DATE_TIME_ORIGINAL, Value::Ascii("2019:06:30 12:34:56")),
+				(OFFSET_TIME_ORIGINAL, Value::Ascii("-07:00")),
+				(EXPOSURE_BIAS, Value::SRational(vec![(-2, 3)])),
+				(FLASH, Value::Short(vec![0])),
+				(FOCAL_LENGTH, Value::Rational(vec![(50, 1)])),
+				(FOCAL_LENGTH_35MM, Value::Short(vec![75])),
+				(BODY_SERIAL_NUMBER, Value::Ascii("0042")),
+				(LENS_MODEL, Value::Ascii("50mm F1.8")),
+			],
+		]);
+		let tiff = Tiff::parse(&data).unwrap();
+
+		assert_eq!(
+			Capture::from_tiff(&tiff),
+			Capture {
+				make: Some("Maker".into()),
+				model: Some("Model 1".into()),
+				// The EXIF tag over DNG's
+				serial_number: Some("0042".into()),
+				lens_make: None,
+				lens_model: Some("50mm F1.8".into()),
+				artist: Some("Someone".into()),
+				copyright: None,
+				// When it was taken over when the file was last changed
+				date_time: Some("2019:06:30 12:34:56".into()),
+				utc_offset: Some("-07:00".into()),
+				exposure_time: Some(Rational(1, 250)),
+				f_number: Some(Rational(28, 10)),
+				iso: Some(400),
+				focal_length: Some(Rational(50, 1)),
+				focal_length_35mm: Some(75),
+				exposure_bias: Some(SRational(-2, 3)),
+				flash: Some(0),
+			}
+		);
+	}
+
+	#[test]
+	fn first_ifd_wins_and_unknowns_are_none() {
+		let data = build(&[
+			vec![
+				(MAKE, Value::Ascii("First")),
+				(CAMERA_SERIAL_NUMBER, Value::Ascii("DNG 1234")),
+				(DATE_TIME, Value::Ascii("2020:01:01 00:00:00")),
+			],
+			vec![
+				(MAKE, Value::Ascii("Second")),
+				// Zero means the camera didn't know
+				(ISO, Value::Long(vec![0])),
+				(F_NUMBER, Value::Rational(vec![(0, 0)])),
+			],
+		]);
+		let tiff = Tiff::parse(&data).unwrap();
+		let capture = Capture::from_tiff(&tiff);
+
+		assert_eq!(capture.make.as_deref(), Some("First"));
+		assert_eq!(capture.serial_number.as_deref(), Some("DNG 1234"));
+		assert_eq!(capture.date_time.as_deref(), Some("2020:01:01 00:00:00"));
+		assert_eq!(capture.iso, None);
+		assert_eq!(capture.f_number, None);
+		assert_eq!(capture.flash, None);
+	}
+}
@@ -1,3 +1,7 @@
+use nalgebra::{Matrix3, Matrix3x1};
+
+use crate::{adaptation::WhitePoint, algorithms, image::RawMetadata};
+
 /*
 We need to be able to represent:
 - Sensor data
@@ -8,6 +12,40 @@ We need to be able to represent:
 pub trait Colorspace: Clone {
 	/// Number of elements per pixel
 	const COMPONENTS: usize;
+
+	/// The whitelevel for the value at `idx` in an image's data. Integer data
+	/// is divided by this to get floats that are 1.0 at white.
+	///
+	/// Only the camera's own colours have a whitelevel per channel. Once
+	/// they're mixed into anything else, every component shares the first.
+	fn whitelevel(metadata: &RawMetadata, width: usize, idx: usize) -> u16 {
+		let _ = (width, idx);
+		metadata.whitelevels[0]
+	}
+}
+
+/// An RGB colourspace defined by its primaries, white, and transfer function.
+/// Images in one have had the transfer function applied.
+pub trait RgbSpace: Colorspace {
+	/// The xy chromaticity of the red, green, and blue primaries
+	const PRIMARIES: [(f32, f32); 3];
+	/// The colour of (1.0, 1.0, 1.0)
+	const WHITE: WhitePoint;
+
+	/// Take a linear value to its encoded one
+	fn encode(linear: f32) -> f32;
+
+	/// The matrix that takes linear RGB to XYZ. Worked out from the primaries
+	/// and white like at <http://www.brucelindbloom.com/index.html?Eqn_RGB_XYZ_Matrix.html>
+	fn rgb_to_xyz() -> Matrix3<f32> {
+		let primary = |(x, y): (f32, f32)| Matrix3x1::new(x / y, 1.0, (1.0 - x - y) / y);
+		let [r, g, b] = Self::PRIMARIES.map(primary);
+		let primaries = Matrix3::from_columns(&[r, g, b]);
+
+		// The primaries are all real and different, so this inverts
+		let scale = primaries.try_inverse().unwrap() * Self::WHITE.xyz();
+		primaries * Matrix3::from_diagonal(&scale)
+	}
 }
 
 /// Straight-from-the-camera colours. Almost certainly linear.
@@ -16,6 +54,12 @@ pub struct BayerRgb {}
 
 impl Colorspace for BayerRgb {
 	const COMPONENTS: usize = 1;
+
+	/// Every site has one value, so the whitelevel is whichever belongs to the
+	/// colour of the CFA there.
+	fn whitelevel(metadata: &RawMetadata, width: usize, idx: usize) -> u16 {
+		metadata.whitelevels[metadata.cfa.color_at(idx / width, idx % width)]
+	}
 }
 
 /// Linear RGB.
@@ -24,6 +68,12 @@ pub struct LinRgb {}
 
 impl Colorspace for LinRgb {
 	const COMPONENTS: usize = 3;
+
+	/// Still the camera's colours, so red, green, and blue each have their own
+	fn whitelevel(metadata: &RawMetadata, width: usize, idx: usize) -> u16 {
+		let _ = width;
+		metadata.whitelevels[idx % Self::COMPONENTS]
+	}
 }
 
 #[derive(Clone, Debug)]
@@ -47,6 +97,120 @@ impl Colorspace for Srgb {
 	const COMPONENTS: usize = 3;
 }
 
+impl RgbSpace for Srgb {
+	const PRIMARIES: [(f32, f32); 3] = [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)];
+	const WHITE: WhitePoint = WhitePoint::D65;
+
+	fn encode(linear: f32) -> f32 {
+		algorithms::srgb_gamma(linear)
+	}
+}
+
+/// Apple's wide gamut. DCI-P3 primaries with sRGB's white and transfer
+/// function.
+#[derive(Clone, Debug)]
+pub struct DisplayP3 {}
+
+impl Colorspace for DisplayP3 {
+	const COMPONENTS: usize = 3;
+}
+
+impl RgbSpace for DisplayP3 {
+	const PRIMARIES: [(f32, f32); 3] = [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)];
+	const WHITE: WhitePoint = WhitePoint::D65;
+
+	fn encode(linear: f32) -> f32 {
+		algorithms::srgb_gamma(linear)
+	}
+}
+
+/// ITU-R BT.2020, the UHDTV colourspace. Uses the BT.709 transfer function
+/// it shares with HD video.
+#[derive(Clone, Debug)]
+pub struct Rec2020 {}
+
+impl Colorspace for Rec2020 {
+	const COMPONENTS: usize = 3;
+}
+
+impl RgbSpace for Rec2020 {
+	const PRIMARIES: [(f32, f32); 3] = [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)];
+	const WHITE: WhitePoint = WhitePoint::D65;
+
+	fn encode(linear: f32) -> f32 {
+		algorithms::rec709_oetf(linear)
+	}
+}
+
+/// Adobe RGB (1998). Wider greens than sRGB, popular for print.
+#[derive(Clone, Debug)]
+pub struct AdobeRgb {}
+
+impl Colorspace for AdobeRgb {
+	const COMPONENTS: usize = 3;
+}
+
+impl RgbSpace for AdobeRgb {
+	const PRIMARIES: [(f32, f32); 3] = [(0.64, 0.33), (0.21, 0.71), (0.15, 0.06)];
+	const WHITE: WhitePoint = WhitePoint::D65;
+
+	fn encode(linear: f32) -> f32 {
+		algorithms::adobe_rgb_gamma(linear)
+	}
+}
+
+/// ProPhoto RGB, also called ROMM RGB. Big enough to hold nearly every
+/// surface colour, but some of its primaries aren't real colours.
+#[derive(Clone, Debug)]
+pub struct ProPhoto {}
+
+impl Colorspace for ProPhoto {
+	const COMPONENTS: usize = 3;
+}
+
+impl RgbSpace for ProPhoto {
+	const PRIMARIES: [(f32, f32); 3] = [(0.7347, 0.2653), (0.1596, 0.8404), (0.0366, 0.0001)];
+	const WHITE: WhitePoint = WhitePoint::D50;
+
+	fn encode(linear: f32) -> f32 {
+		algorithms::prophoto_gamma(linear)
+	}
+}
+
+/// CIELAB relative to D65. L is 0 to 100, a and b are roughly -128 to 128.
+#[derive(Clone, Debug)]
+pub struct Lab {}
+
+impl Colorspace for Lab {
+	const COMPONENTS: usize = 3;
+}
+
+/// [Lab] in polar form: lightness, chroma, and hue in degrees.
+#[derive(Clone, Debug)]
+pub struct LCh {}
+
+impl Colorspace for LCh {
+	const COMPONENTS: usize = 3;
+}
+
+/// Björn Ottosson's Oklab, relative to D65. L is 0 to 1, a and b are roughly
+/// -0.4 to 0.4. Hue stays put much better than it does in [Lab].
+/// <https://bottosson.github.io/posts/oklab/>
+#[derive(Clone, Debug)]
+pub struct Oklab {}
+
+impl Colorspace for Oklab {
+	const COMPONENTS: usize = 3;
+}
+
+/// [Oklab] in polar form: lightness, chroma, and hue in degrees.
+#[derive(Clone, Debug)]
+pub struct OkLCh {}
+
+impl Colorspace for OkLCh {
+	const COMPONENTS: usize = 3;
+}
+
 //TODO: gen- Not really a colorspace but more like, representation?
 #[derive(Clone, Debug)]
 pub struct Hsv {}
@@ -54,3 +218,40 @@ pub struct Hsv {}
 impl Colorspace for Hsv {
 	const COMPONENTS: usize = 3;
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::image::Image;
+
+	fn metadata(cfa: &str) -> RawMetadata {
+		let mut metadata = RawMetadata::test(cfa);
+		metadata.whitelevels = [1000, 2000, 3000, 4000];
+		metadata
+	}
+
+	#[test]
+	fn bayer_whitelevel_follows_the_cfa() {
+		// Red, green, emerald, blue
+		let sixteen: Image<u16, BayerRgb> =
+			Image::from_raw_parts(2, 2, metadata("RGEB"), vec![500, 1000, 2000, 1500]);
+
+		let floats = sixteen.floats();
+		assert_eq!(floats.data, vec![0.5; 4]);
+
+		let sixteen = floats.sxiteen();
+		assert_eq!(sixteen.data, vec![u16::MAX / 2; 4]);
+		assert_eq!(sixteen.metadata.whitelevels, [u16::MAX; 4]);
+	}
+
+	#[test]
+	fn mixed_colours_share_a_whitelevel() {
+		let rgb: Image<u16, LinRgb> =
+			Image::from_raw_parts(1, 1, metadata("RGGB"), vec![500, 1000, 1500]);
+		assert_eq!(rgb.clone().floats().data, vec![0.5; 3]);
+
+		let xyz = rgb.to_xyz();
+		assert_eq!(xyz.metadata.whitelevels, [3000; 4]);
+		assert_eq!(xyz.floats().data, vec![0.5; 3]);
+	}
+}
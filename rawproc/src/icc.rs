@@ -0,0 +1,368 @@
+//! Reading matrix/TRC ICC profiles. These are three tone curves that make the
+//! device values linear and a matrix from there to XYZ D50. LUT based profiles
+//! aren't understood.
+//!
+//! <https://www.color.org/specification/ICC.1-2022-05.pdf>
+
+use nalgebra::Matrix3;
+
+use crate::{adaptation::WhitePoint, Error};
+
+const HEADER_LENGTH: usize = 128;
+
+/// An ICC profile for an RGB device with a matrix and tone curves.
+#[derive(Clone, Debug)]
+pub struct IccProfile {
+	pub description: Option<String>,
+	/// Linear device RGB to XYZ D50, the profile connection space. The device
+	/// white comes out as D50.
+	pub rgb_to_xyz: Matrix3<f32>,
+	/// The white of the device before it was adapted to D50.
+	pub media_white: WhitePoint,
+	/// The red, green, and blue tone curves. They take device values to linear.
+	pub curves: [Curve; 3],
+}
+
+/// A tone curve from an ICC `curv` or `para` tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Curve {
+	Identity,
+	Gamma(f32),
+	/// Evenly spaced samples from 0.0 to 1.0
+	Table(Vec<f32>),
+	/// The ICC parametric function. Parameters past the ones the function type
+	/// uses are zero.
+	Parametric {
+		function: u16,
+		params: [f32; 7],
+	},
+}
+
+impl Curve {
+	/// Device value to linear.
+	pub fn eval(&self, x: f32) -> f32 {
+		let x = x.clamp(0.0, 1.0);
+
+		match self {
+			Curve::Identity => x,
+			Curve::Gamma(gamma) => x.powf(*gamma),
+			Curve::Table(table) => {
+				if table.len() < 2 {
+					return table.first().copied().unwrap_or(x);
+				}
+
+				let position = x * (table.len() - 1) as f32;
+				let low = (position.floor() as usize).min(table.len() - 2);
+				let fraction = position - low as f32;
+				table[low] * (1.0 - fraction) + table[low + 1] * fraction
+			}
+			Curve::Parametric { function, params } => {
+				let [g, a, b, c, d, e, f] = *params;
+				match function {
+					0 => x.powf(g),
+					1 if x >= -b / a => (a * x + b).powf(g),
+					1 => 0.0,
+					2 if x >= -b / a => (a * x + b).powf(g) + c,
+					2 => c,
+					3 if x >= d => (a * x + b).powf(g),
+					3 => c * x,
+					4 if x >= d => (a * x + b).powf(g) + e,
+					4 => c * x + f,
+					_ => x,
+				}
+			}
+		}
+	}
+}
+
+impl IccProfile {
+	pub fn parse(data: &[u8]) -> Result<Self, Error> {
+		if data.len() < HEADER_LENGTH + 4 || data.get(36..40) != Some(b"acsp") {
+			return Err(Error::InvalidProfile {
+				reason: "not an ICC profile",
+			});
+		}
+
+		if &data[16..20] != b"RGB " {
+			return Err(Error::InvalidProfile {
+				reason: "only RGB ICC profiles are supported",
+			});
+		}
+
+		if &data[20..24] != b"XYZ " {
+			return Err(Error::InvalidProfile {
+				reason: "only ICC profiles with an XYZ connection space are supported",
+			});
+		}
+
+		let tags = Tags::read(data).ok_or(Error::InvalidProfile {
+			reason: "the ICC tag table is truncated",
+		})?;
+
+		let matrix_trc = || -> Option<IccProfile> {
+			let red = tags.xyz(b"rXYZ")?;
+			let green = tags.xyz(b"gXYZ")?;
+			let blue = tags.xyz(b"bXYZ")?;
+
+			#[rustfmt::skip]
+			let rgb_to_xyz = Matrix3::new(
+				red[0], green[0], blue[0],
+				red[1], green[1], blue[1],
+				red[2], green[2], blue[2],
+			);
+
+			let media_white = tags
+				.xyz(b"wtpt")
+				.map(|[x, y, z]| WhitePoint::new(x, y, z))
+				.unwrap_or(WhitePoint::D50);
+
+			Some(IccProfile {
+				description: tags.description(),
+				rgb_to_xyz,
+				media_white,
+				curves: [
+					tags.curve(b"rTRC")?,
+					tags.curve(b"gTRC")?,
+					tags.curve(b"bTRC")?,
+				],
+			})
+		};
+
+		matrix_trc().ok_or(Error::InvalidProfile {
+			reason: "only matrix/TRC ICC profiles are supported",
+		})
+	}
+}
+
+struct Tags<'a> {
+	/// Signature and the tag's bytes
+	table: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> Tags<'a> {
+	fn read(data: &'a [u8]) -> Option<Self> {
+		let count = be_u32(data, HEADER_LENGTH)? as usize;
+
+		let mut table = vec![];
+		for idx in 0..count {
+			let entry = HEADER_LENGTH + 4 + idx * 12;
+			let signature = data.get(entry..entry + 4)?.try_into().ok()?;
+			let offset = be_u32(data, entry + 4)? as usize;
+			let size = be_u32(data, entry + 8)? as usize;
+
+			// Skip tags that point outside the profile rather than give up
+			if let Some(bytes) = data.get(offset..offset.checked_add(size)?) {
+				table.push((signature, bytes));
+			}
+		}
+
+		Some(Self { table })
+	}
+
+	fn get(&self, signature: &[u8; 4]) -> Option<&'a [u8]> {
+		self.table
+			.iter()
+			.find(|(sig, _)| sig == signature)
+			.map(|(_, bytes)| *bytes)
+	}
+
+	fn xyz(&self, signature: &[u8; 4]) -> Option<[f32; 3]> {
+		let tag = self.get(signature)?;
+		if tag.get(0..4)? != b"XYZ " {
+			return None;
+		}
+
+		Some([
+			s15fixed16(tag, 8)?,
+			s15fixed16(tag, 12)?,
+			s15fixed16(tag, 16)?,
+		])
+	}
+
+	fn curve(&self, signature: &[u8; 4]) -> Option<Curve> {
+		let tag = self.get(signature)?;
+
+		match tag.get(0..4)? {
+			b"curv" => {
+				let count = be_u32(tag, 8)? as usize;
+				match count {
+					0 => Some(Curve::Identity),
+					1 => Some(Curve::Gamma(be_u16(tag, 12)? as f32 / 256.0)),
+					_ => (0..count)
+						.map(|idx| be_u16(tag, 12 + idx * 2).map(|v| v as f32 / u16::MAX as f32))
+						.collect::<Option<Vec<f32>>>()
+						.map(Curve::Table),
+				}
+			}
+			b"para" => {
+				let function = be_u16(tag, 8)?;
+				let used = match function {
+					0 => 1,
+					1 => 3,
+					2 => 4,
+					3 => 5,
+					4 => 7,
+					_ => return None,
+				};
+
+				let mut params = [0.0; 7];
+				for (idx, param) in params.iter_mut().enumerate().take(used) {
+					*param = s15fixed16(tag, 12 + idx * 4)?;
+				}
+
+				Some(Curve::Parametric { function, params })
+			}
+			_ => None,
+		}
+	}
+
+	/// The profile's description. Version 2 profiles use `desc` with ASCII,
+	/// version 4 use `mluc` with UTF-16.
+	fn description(&self) -> Option<String> {
+		let tag = self.get(b"desc")?;
+
+		match tag.get(0..4)? {
+			b"desc" => {
+				let length = be_u32(tag, 8)? as usize;
+				let ascii = tag.get(12..12 + length)?;
+				let text = String::from_utf8_lossy(ascii);
+				Some(text.trim_end_matches('\0').to_owned())
+			}
+			b"mluc" => {
+				// Take the first record, whatever language it is
+				let length = be_u32(tag, 20)? as usize;
+				let offset = be_u32(tag, 24)? as usize;
+				let utf16: Vec<u16> = (0..length / 2)
+					.map(|idx| be_u16(tag, offset + idx * 2))
+					.collect::<Option<_>>()?;
+				Some(String::from_utf16_lossy(&utf16))
+			}
+			_ => None,
+		}
+	}
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+	Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+	Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn s15fixed16(data: &[u8], at: usize) -> Option<f32> {
+	be_u32(data, at).map(|v| v as i32 as f32 / 65536.0)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn s15fixed16_bytes(value: f32) -> [u8; 4] {
+		((value * 65536.0).round() as i32).to_be_bytes()
+	}
+
+	fn xyz_tag([x, y, z]: [f32; 3]) -> Vec<u8> {
+		let mut tag = b"XYZ \0\0\0\0".to_vec();
+		for v in [x, y, z] {
+			tag.extend_from_slice(&s15fixed16_bytes(v));
+		}
+		tag
+	}
+
+	fn para_tag(function: u16, params: &[f32]) -> Vec<u8> {
+		let mut tag = b"para\0\0\0\0".to_vec();
+		tag.extend_from_slice(&function.to_be_bytes());
+		tag.extend_from_slice(&[0, 0]);
+		for param in params {
+			tag.extend_from_slice(&s15fixed16_bytes(*param));
+		}
+		tag
+	}
+
+	/// An RGB, XYZ connection space profile with these tags
+	fn profile(tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+		let mut data = vec![0; HEADER_LENGTH];
+		data[16..20].copy_from_slice(b"RGB ");
+		data[20..24].copy_from_slice(b"XYZ ");
+		data[36..40].copy_from_slice(b"acsp");
+		data.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+
+		let mut offset = data.len() + tags.len() * 12;
+		for (signature, tag) in tags {
+			data.extend_from_slice(*signature);
+			data.extend_from_slice(&(offset as u32).to_be_bytes());
+			data.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+			offset += tag.len();
+		}
+		for (_, tag) in tags {
+			data.extend_from_slice(tag);
+		}
+
+		let size = data.len() as u32;
+		data[0..4].copy_from_slice(&size.to_be_bytes());
+		data
+	}
+
+	// sRGB's colorants adapted to D50, as the ICC publishes them
+	const RED: [f32; 3] = [0.4361, 0.2225, 0.0139];
+	const GREEN: [f32; 3] = [0.3851, 0.7169, 0.0971];
+	const BLUE: [f32; 3] = [0.1431, 0.0606, 0.7141];
+
+	fn srgb_like(curve: Vec<u8>) -> Vec<u8> {
+		profile(&[
+			(b"rXYZ", xyz_tag(RED)),
+			(b"gXYZ", xyz_tag(GREEN)),
+			(b"bXYZ", xyz_tag(BLUE)),
+			(b"rTRC", curve.clone()),
+			(b"gTRC", curve.clone()),
+			(b"bTRC", curve),
+		])
+	}
+
+	#[test]
+	fn parametric_srgb_curve() {
+		let params = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+		let icc = IccProfile::parse(&srgb_like(para_tag(3, &params))).unwrap();
+
+		for (got, expected) in icc.rgb_to_xyz.column_iter().zip([RED, GREEN, BLUE]) {
+			for (g, e) in got.iter().zip(expected) {
+				assert!(
+					(g - e).abs() < 1.0 / 65536.0,
+					"expected {expected:?}, got {got}"
+				);
+			}
+		}
+
+		for curve in &icc.curves {
+			assert!(matches!(curve, Curve::Parametric { function: 3, .. }));
+			// Straight line below d and the power function above
+			assert!((curve.eval(0.02) - 0.02 / 12.92).abs() < 1e-5);
+			assert!((curve.eval(0.5) - 0.21404).abs() < 1e-4);
+			assert!((curve.eval(1.0) - 1.0).abs() < 1e-4);
+		}
+	}
+
+	#[test]
+	fn gamma_and_table_curves() {
+		let mut gamma = b"curv\0\0\0\0".to_vec();
+		gamma.extend_from_slice(&1u32.to_be_bytes());
+		gamma.extend_from_slice(&(563u16).to_be_bytes());
+		let icc = IccProfile::parse(&srgb_like(gamma)).unwrap();
+		assert_eq!(icc.curves[0], Curve::Gamma(563.0 / 256.0));
+
+		let mut table = b"curv\0\0\0\0".to_vec();
+		table.extend_from_slice(&3u32.to_be_bytes());
+		for v in [0u16, 0x4000, 0xFFFF] {
+			table.extend_from_slice(&v.to_be_bytes());
+		}
+		let icc = IccProfile::parse(&srgb_like(table)).unwrap();
+		assert!((icc.curves[1].eval(0.25) - 0.125).abs() < 1e-3);
+	}
+
+	#[test]
+	fn unknown_parametric_function() {
+		let data = srgb_like(para_tag(5, &[1.0]));
+		assert!(IccProfile::parse(&data).is_err());
+	}
+}
@@ -1,13 +1,21 @@
 use std::ops::Range;
 
+use nalgebra::{Matrix3, Matrix3x1, Matrix3x4};
+use num_traits::AsPrimitive;
 use rawloader::CFA;
 
 use crate::{
 	colorspace::{BayerRgb, Colorspace, LinRgb},
-	RollingRandom,
+	Error, RollingRandom,
 };
 
-use super::Image;
+use super::{
+	debayer::{self, Interpolation, Mosaic},
+	highlights::{self, Highlights},
+	whitebalance::{self, Estimator, WhiteBalance},
+	xyz::BRUCE_SRGB_XYZ,
+	Image,
+};
 
 impl<T: Copy + Clone> Image<T, BayerRgb> {
 	/// Crops the raw image down, removing parts we're supposed to.
@@ -15,44 +23,40 @@ impl<T: Copy + Clone> Image<T, BayerRgb> {
 	/// A camera may cover part of a sensor to gather black level information
 	/// or noise information, and this function removes those parts so we can
 	/// get just the image itself
-	pub fn crop(&mut self) {
+	pub fn crop(&mut self) -> Result<(), Error> {
 		let crop = if let Some(crop) = self.metadata.crop.as_ref() {
 			*crop
 		} else {
-			return;
+			return Ok(());
 		};
 
-		let new_width = self.width - (crop.left + crop.right);
-		let new_height = self.height - (crop.top + crop.bottom);
-		let new_size = new_width * new_height;
+		self.crop_with(crop)?;
+		self.metadata.crop = None;
+		Ok(())
+	}
 
-		//TODO: gen- we do not need to allocate again here. We, in theory, can
-		// do this all in the already existing vec
-		let mut image = Vec::with_capacity(new_size);
-		for row in 0..new_height {
-			let row_x = row + crop.top;
+	fn color_at_i(&self, i: usize) -> CfaColor {
+		CfaColor::from(self.metadata.cfa.color_at(i / self.width, i % self.width))
+	}
 
-			let start = row_x * self.width + crop.left;
-			let end = start + new_width;
-			image.extend_from_slice(&self.data[start..end]);
-		}
+	/// The black and white level of every CFA colour, with `overrides` taking
+	/// the place of the black levels from the metadata if they're given.
+	fn levels(&self, overrides: Option<[u16; 4]>) -> [(f32, f32); 4] {
+		let black = overrides.unwrap_or(self.metadata.blacklevels);
+		let white = self.metadata.whitelevels;
 
-		self.width = new_width;
-		self.height = new_height;
-		self.data = image;
-		self.metadata.crop = None;
-		self.metadata.cfa = self.metadata.cfa.shift(crop.left, crop.top);
+		[0, 1, 2, 3].map(|idx| (black[idx] as f32, white[idx] as f32))
 	}
 
-	fn color_at_i(&self, i: usize) -> CfaColor {
-		CfaColor::from(self.metadata.cfa.color_at(i % self.width, i / self.width))
+	/// The whitelevel of every CFA colour, for data that isn't normalized.
+	fn whitelevels(&self) -> [f32; 4] {
+		self.metadata.whitelevels.map(|white| white.max(1) as f32)
 	}
 
-	pub fn debayer(self) -> Image<T, LinRgb> {
+	fn debayer_random(self, rr: &mut RollingRandom) -> Image<T, LinRgb> {
 		let mut rgb = vec![self.data[0]; self.width * self.height * 3];
 
 		let cfa = self.metadata.cfa.clone();
-		let mut rr = RollingRandom::new();
 
 		#[rustfmt::skip]
 		let top_options = [
@@ -99,7 +103,7 @@ impl<T: Copy + Clone> Image<T, BayerRgb> {
 					self.width,
 					&mut rgb,
 					&cfa,
-					&mut rr,
+					rr,
 					self.data.as_slice(),
 					$range,
 					$opt,
@@ -113,7 +117,7 @@ impl<T: Copy + Clone> Image<T, BayerRgb> {
 					self.width,
 					&mut rgb,
 					&cfa,
-					&mut rr,
+					rr,
 					self.data.as_slice(),
 					$idx,
 					$opt,
@@ -198,10 +202,10 @@ impl<T: Copy + Clone> Image<T, BayerRgb> {
 		let options = options.clone().into_iter().map(|(x_off, y_off)| {
 			let x = (x as isize + x_off) as usize;
 			let y = (y as isize + y_off) as usize;
-			(CfaColor::from(cfa.color_at(x, y)), x, y)
+			(CfaColor::from(cfa.color_at(y, x)), x, y)
 		});
 
-		match CfaColor::from(cfa.color_at(x, y)) {
+		match CfaColor::from(cfa.color_at(y, x)) {
 			#[rustfmt::skip]
 				CfaColor::Red => {
 					set(x, y, CfaColor::Red, get((x, y)));
@@ -225,46 +229,327 @@ impl<T: Copy + Clone> Image<T, BayerRgb> {
 	}
 }
 
-impl Image<f32, BayerRgb> {
-	pub fn whitebalance(&mut self) {
-		let wb = self.metadata.whitebalance;
-		for (i, light) in self.data.iter_mut().enumerate() {
-			match CfaColor::from(self.metadata.cfa.color_at(i % self.width, i / self.width)) {
-				CfaColor::Red => *light = *light as f32 * wb[0],
-				CfaColor::Green => *light = *light as f32 * wb[1],
-				CfaColor::Blue => *light = *light as f32 * wb[2],
-				CfaColor::Emerald => unreachable!(),
+impl<T> Image<T, BayerRgb>
+where
+	T: Copy + Clone + AsPrimitive<f32>,
+	f32: AsPrimitive<T>,
+{
+	/// Fill in the two colours each sensor site is missing, producing an RGB
+	/// image in the camera's colourspace.
+	///
+	/// Four colour sensors, like RGBE and CYGM, are always interpolated
+	/// bilinearly and then brought down to three colours with
+	/// [RawMetadata::xyz_to_cam4](super::RawMetadata::xyz_to_cam4). The result
+	/// has sRGB primaries and `cam_to_xyz` is set to match.
+	///
+	/// X-Trans sensors are noticed by their 6x6 CFA and always go through an
+	/// X-Trans specific interpolation. The methods here only understand 2x2.
+	pub fn debayer(self, interpolation: Interpolation) -> Image<T, LinRgb> {
+		if debayer::is_four_color(&self.metadata.cfa) {
+			return self.debayer_four_color();
+		}
+
+		let algorithm = if debayer::is_xtrans(&self.metadata.cfa) {
+			debayer::xtrans
+		} else {
+			match interpolation {
+				Interpolation::Bilinear => debayer::bilinear,
+				Interpolation::MalvarHeCutler => debayer::malvar_he_cutler,
+				Interpolation::Ppg => debayer::ppg,
+				Interpolation::Random { seed } => {
+					return self.debayer_random(&mut RollingRandom::new(seed))
+				}
+			}
+		};
+
+		let mosaic = Mosaic {
+			width: self.width,
+			height: self.height,
+			data: self.data.iter().map(|v| v.as_()).collect(),
+			cfa: &self.metadata.cfa,
+		};
+
+		// The gradient corrections can undershoot zero around hard edges
+		let rgb = algorithm(&mosaic)
+			.into_iter()
+			.map(|v| v.max(0.0).as_())
+			.collect();
+
+		Image {
+			width: self.width,
+			height: self.height,
+			metadata: self.metadata,
+			data: rgb,
+			phantom: Default::default(),
+		}
+	}
+
+	/// [Self::estimate_whitebalance] for data where each CFA colour is at
+	/// white when it's at `white`.
+	fn estimate_whitebalance_within(&self, estimator: Estimator, white: [f32; 4]) -> [f32; 3] {
+		let mut channels: [Vec<f32>; 3] = Default::default();
+		for (i, value) in self.data.iter().enumerate() {
+			let color = self.color_at_i(i).index();
+			if color < 3 {
+				channels[color].push(value.as_() / white[color]);
+			}
+		}
+
+		estimator.estimate(channels)
+	}
+
+	/// The four multipliers `wb` means for this image, which is at white at
+	/// `white`.
+	fn resolve_whitebalance(&self, wb: WhiteBalance, white: [f32; 4]) -> [f32; 4] {
+		match wb {
+			WhiteBalance::Auto(estimator) => {
+				let [red, green, blue] = self.estimate_whitebalance_within(estimator, white);
+				[red, green, blue, self.metadata.whitebalance[3]]
+			}
+			// Auto is the only one that's None
+			wb => wb
+				.multipliers(&self.metadata)
+				.unwrap_or(self.metadata.whitebalance),
+		}
+	}
+
+	/// [Self::spot_whitebalance] for data where each CFA colour is at white
+	/// when it's at `white`.
+	fn spot_whitebalance_within(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+		white: [f32; 4],
+	) -> Result<[f32; 4], Error> {
+		self.rect_to_crop(x, y, width, height)?;
+
+		let mut sums = [0.0; 4];
+		let mut counts = [0; 4];
+		for row in y..y + height {
+			for col in x..x + width {
+				let color = self.color_at_i(row * self.width + col).index();
+				sums[color] += self.data[row * self.width + col].as_() / white[color];
+				counts[color] += 1;
 			}
 		}
+
+		self.metadata.whitebalance =
+			whitebalance::patch_multipliers(sums, counts, self.metadata.whitebalance);
+		Ok(self.metadata.whitebalance)
+	}
+
+	fn debayer_four_color(mut self) -> Image<T, LinRgb> {
+		let cam_to_rgb = self
+			.metadata
+			.xyz_to_cam4
+			.as_ref()
+			.and_then(debayer::cam4_to_rgb)
+			.unwrap_or_else(|| {
+				// We don't know anything about the fourth colour, so call it green
+				#[rustfmt::skip]
+				let fallback = Matrix3x4::new(
+					1.0, 0.0, 0.0, 0.0,
+					0.0, 0.5, 0.0, 0.5,
+					0.0, 0.0, 1.0, 0.0,
+				);
+				fallback
+			});
+
+		let mosaic = Mosaic {
+			width: self.width,
+			height: self.height,
+			data: self.data.iter().map(|v| v.as_()).collect(),
+			cfa: &self.metadata.cfa,
+		};
+
+		let rgb = debayer::four_color(&mosaic, &cam_to_rgb)
+			.into_iter()
+			.map(|v| v.max(0.0).as_())
+			.collect();
+
+		// The colours are balanced sRGB now. Set it up so that
+		// balanced_cam_to_xyz, which takes the balance back out, gives sRGB
+		let [red, green, blue, _] = self.metadata.whitebalance;
+		self.metadata.cam_to_xyz =
+			BRUCE_SRGB_XYZ * Matrix3::from_diagonal(&Matrix3x1::new(red, green, blue));
+
+		Image {
+			width: self.width,
+			height: self.height,
+			metadata: self.metadata,
+			data: rgb,
+			phantom: Default::default(),
+		}
+	}
+}
+
+impl Image<f32, BayerRgb> {
+	/// Subtract the black levels and stretch what's left so the whitelevel is
+	/// still 1.0. The data is expected to be normalized to the whitelevels.
+	///
+	/// Pass `overrides` to use your own red, green, and blue black levels
+	/// instead of the ones the camera gave us.
+	pub fn blacklevels(&mut self, overrides: Option<[u16; 4]>) {
+		let levels = self.levels(overrides);
+
+		for i in 0..self.data.len() {
+			let (black, white) = levels[self.color_at_i(i).index()];
+			let black = black / white;
+
+			self.data[i] = ((self.data[i] - black) / (1.0 - black).max(f32::EPSILON)).max(0.0);
+		}
+
+		self.metadata.blacklevels = [0; 4];
+	}
+
+	/// Guess the red, green, and blue whitebalance multipliers from the image.
+	/// A fourth colour, if there is one, isn't guessed.
+	pub fn estimate_whitebalance(&self, estimator: Estimator) -> [f32; 3] {
+		self.estimate_whitebalance_within(estimator, [1.0; 4])
+	}
+
+	/// Find the whitebalance that makes the rectangle neutral, like when it's
+	/// over a grey card, and store it in the metadata. Do this before
+	/// [Self::whitebalance] and then balance with [WhiteBalance::AsShot].
+	///
+	/// Returns the multipliers that were stored.
+	pub fn spot_whitebalance(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<[f32; 4], Error> {
+		self.spot_whitebalance_within(x, y, width, height, [1.0; 4])
+	}
+
+	/// Balance the image for the light `wb` describes. The multipliers that
+	/// were used are stored in the metadata's whitebalance.
+	pub fn whitebalance(&mut self, wb: WhiteBalance) {
+		let wb = self.resolve_whitebalance(wb, [1.0; 4]);
+		self.metadata.whitebalance = wb;
+		self.metadata.balanced = true;
+
+		for i in 0..self.data.len() {
+			self.data[i] *= wb[self.color_at_i(i).index()];
+		}
+	}
+
+	/// Deal with the sites that clipped. Run this after [Self::whitebalance],
+	/// and before the colour matrix.
+	///
+	/// The data is normalized to the whitelevels, so a colour clips at 1.0 times
+	/// its whitebalance multiplier.
+	pub fn highlights(&mut self, mode: Highlights) {
+		let channels = if debayer::is_four_color(&self.metadata.cfa) {
+			4
+		} else {
+			3
+		};
+		let clip = &self.metadata.whitebalance[..channels];
+
+		let mosaic = Mosaic {
+			width: self.width,
+			height: self.height,
+			data: self.data.clone(),
+			cfa: &self.metadata.cfa,
+		};
+
+		let mut estimate = highlights::estimate(&mosaic, channels, clip);
+		highlights::recover(mode, &mut estimate, self.width, self.height, channels, clip);
+
+		for i in 0..self.data.len() {
+			self.data[i] = estimate[i * channels + self.color_at_i(i).index()];
+		}
 	}
 }
 
 impl Image<u16, BayerRgb> {
-	pub fn whitebalance(&mut self) {
-		let wb = self.metadata.whitebalance;
-		for (i, light) in self.data.iter_mut().enumerate() {
-			/*match CfaColor::from(self.metadata.cfa.color_at(i % self.width, i / self.width)) {
-				CfaColor::Red => *light = (*light as f32 * wb[0]) as u16,
-				CfaColor::Green => *light = (*light as f32 * wb[1]) as u16,
-				CfaColor::Blue => *light = (*light as f32 * wb[2]) as u16,
-				CfaColor::Emerald => unreachable!(),
-			}*/
-			*light = (*light as f32
-				* wb[self.metadata.cfa.color_at(i % self.width, i / self.width)]) as u16;
+	/// Subtract the black levels and stretch what's left back out to the
+	/// whitelevels, so the whitelevels still mean "fully exposed".
+	///
+	/// Pass `overrides` to use your own red, green, and blue black levels
+	/// instead of the ones the camera gave us.
+	pub fn blacklevels(&mut self, overrides: Option<[u16; 4]>) {
+		let levels = self.levels(overrides);
+
+		for i in 0..self.data.len() {
+			let (black, white) = levels[self.color_at_i(i).index()];
+			let light = (self.data[i] as f32 - black).max(0.0);
+
+			self.data[i] = (light * (white / (white - black).max(1.0))) as u16;
+		}
+
+		self.metadata.blacklevels = [0; 4];
+	}
+
+	/// Guess the red, green, and blue whitebalance multipliers from the image.
+	/// A fourth colour, if there is one, isn't guessed.
+	pub fn estimate_whitebalance(&self, estimator: Estimator) -> [f32; 3] {
+		self.estimate_whitebalance_within(estimator, self.whitelevels())
+	}
+
+	/// Find the whitebalance that makes the rectangle neutral, like when it's
+	/// over a grey card, and store it in the metadata. Do this before
+	/// [Self::whitebalance] and then balance with [WhiteBalance::AsShot].
+	///
+	/// Returns the multipliers that were stored.
+	pub fn spot_whitebalance(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<[f32; 4], Error> {
+		self.spot_whitebalance_within(x, y, width, height, self.whitelevels())
+	}
+
+	/// Balance the image for the light `wb` describes. The multipliers that
+	/// were used are stored in the metadata's whitebalance.
+	pub fn whitebalance(&mut self, wb: WhiteBalance) {
+		let wb = self.resolve_whitebalance(wb, self.whitelevels());
+		self.metadata.whitebalance = wb;
+		self.metadata.balanced = true;
+
+		for i in 0..self.data.len() {
+			self.data[i] = (self.data[i] as f32 * wb[self.color_at_i(i).index()]) as u16;
 		}
 	}
 }
 
 impl Image<u8, BayerRgb> {
-	pub fn whitebalance(&mut self) {
-		let wb = self.metadata.whitebalance;
-		for (i, light) in self.data.iter_mut().enumerate() {
-			match CfaColor::from(self.metadata.cfa.color_at(i % self.width, i / self.width)) {
-				CfaColor::Red => *light = (*light as f32 * wb[0]) as u8,
-				CfaColor::Green => *light = (*light as f32 * wb[1]) as u8,
-				CfaColor::Blue => *light = (*light as f32 * wb[2]) as u8,
-				CfaColor::Emerald => unreachable!(),
-			}
+	/// Guess the red, green, and blue whitebalance multipliers from the image.
+	/// A fourth colour, if there is one, isn't guessed.
+	pub fn estimate_whitebalance(&self, estimator: Estimator) -> [f32; 3] {
+		self.estimate_whitebalance_within(estimator, self.whitelevels())
+	}
+
+	/// Find the whitebalance that makes the rectangle neutral, like when it's
+	/// over a grey card, and store it in the metadata. Do this before
+	/// [Self::whitebalance] and then balance with [WhiteBalance::AsShot].
+	///
+	/// Returns the multipliers that were stored.
+	pub fn spot_whitebalance(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<[f32; 4], Error> {
+		self.spot_whitebalance_within(x, y, width, height, self.whitelevels())
+	}
+
+	/// Balance the image for the light `wb` describes. The multipliers that
+	/// were used are stored in the metadata's whitebalance.
+	pub fn whitebalance(&mut self, wb: WhiteBalance) {
+		let wb = self.resolve_whitebalance(wb, self.whitelevels());
+		self.metadata.whitebalance = wb;
+		self.metadata.balanced = true;
+
+		for i in 0..self.data.len() {
+			self.data[i] = (self.data[i] as f32 * wb[self.color_at_i(i).index()]) as u8;
 		}
 	}
 }
@@ -291,6 +576,16 @@ enum CfaColor {
 }
 
 impl CfaColor {
+	/// Index into the four-long per colour arrays of [RawMetadata](super::RawMetadata)
+	pub fn index(&self) -> usize {
+		match self {
+			CfaColor::Red => 0,
+			CfaColor::Green => 1,
+			CfaColor::Blue => 2,
+			CfaColor::Emerald => 3,
+		}
+	}
+
 	pub fn rgb_index(&self) -> usize {
 		match self {
 			CfaColor::Red => 0,
@@ -312,3 +607,128 @@ impl From<usize> for CfaColor {
 		}
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::image::RawMetadata;
+
+	// Green, red / blue, green
+	fn mosaic<T: Copy>(
+		data: [T; 4],
+		whitelevels: [u16; 4],
+		blacklevels: [u16; 4],
+	) -> Image<T, BayerRgb> {
+		let mut metadata = RawMetadata::test("GRBG");
+		metadata.whitelevels = whitelevels;
+		metadata.blacklevels = blacklevels;
+		Image::from_raw_parts(2, 2, metadata, data.to_vec())
+	}
+
+	const WHITE: [u16; 4] = [4096, 16384, 8192, 16384];
+	const BLACK: [u16; 4] = [2048, 1024, 512, 0];
+
+	fn assert_near(got: &[u16], expected: [u16; 4]) {
+		for (got, expected) in got.iter().zip(expected) {
+			assert!(
+				got.abs_diff(expected) <= 1,
+				"got {got:?}, expected {expected:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn integer_blacklevels_stretch_to_white() {
+		// Green half way, red and blue at white, green at white
+		let mut img = mosaic([8704, 4096, 8192, 16384], WHITE, BLACK);
+		img.blacklevels(None);
+		assert_near(&img.data, [8192, 4096, 8192, 16384]);
+		assert_eq!(img.metadata.blacklevels, [0; 4]);
+	}
+
+	#[test]
+	fn integer_blacklevels_clamp_at_zero() {
+		// At and under each site's own black
+		let mut img = mosaic([1024, 2000, 512, 0], WHITE, BLACK);
+		img.blacklevels(None);
+		assert_eq!(img.data, [0; 4]);
+	}
+
+	#[test]
+	fn integer_blacklevel_overrides() {
+		let mut img = mosaic([1024, 2560, 1024, 16384], WHITE, BLACK);
+		img.blacklevels(Some([1024, 0, 0, 0]));
+		// Red is 1536 of 3072 above its new black, green and blue have none
+		assert_near(&img.data, [1024, 2048, 1024, 16384]);
+	}
+
+	#[test]
+	fn float_blacklevels_stretch_to_one() {
+		// Red's black is half its white, and green's and blue's a sixteenth
+		let mut img = mosaic([1.0, 0.75, 0.0625, 17.0 / 32.0], WHITE, BLACK);
+		img.blacklevels(None);
+		let expected = [1.0, 0.5, 0.0, 0.5];
+		for (got, expected) in img.data.iter().zip(expected) {
+			assert!(
+				(got - expected).abs() < 1e-6,
+				"got {got}, expected {expected}"
+			);
+		}
+
+		let mut img = mosaic([0.0, 0.25, 0.5, 0.0], WHITE, BLACK);
+		img.blacklevels(Some([0, 0, 4096, 0]));
+		assert_eq!(img.data, [0.0, 0.25, 0.0, 0.0]);
+		assert_eq!(img.metadata.blacklevels, [0; 4]);
+	}
+
+	/// A 4x4 RGGB mosaic where `inside` fills the 2x2 at 1,1 and `outside`
+	/// everything else. Each is red, green, and blue.
+	fn patch<T: Copy>(inside: [T; 3], outside: [T; 3]) -> Image<T, BayerRgb> {
+		let cfa = CFA::new("RGGB");
+		let data = (0..4)
+			.flat_map(|y| (0..4).map(move |x| (x, y)))
+			.map(|(x, y)| {
+				let colour = if (1..3).contains(&x) && (1..3).contains(&y) {
+					inside
+				} else {
+					outside
+				};
+				colour[cfa.color_at(y, x)]
+			})
+			.collect();
+
+		let mut metadata = RawMetadata::test("RGGB");
+		metadata.whitelevels = WHITE;
+		Image::from_raw_parts(4, 4, metadata, data)
+	}
+
+	#[test]
+	fn integer_spot_whitebalance_is_against_the_whitelevels() {
+		// A quarter of the way to each colour's own white is neutral
+		let mut img: Image<u16, BayerRgb> = patch([1024, 4096, 2048], [4000, 100, 8000]);
+		let multipliers = img.spot_whitebalance(1, 1, 2, 2).unwrap();
+		assert_eq!(multipliers, [1.0; 4]);
+		assert_eq!(img.metadata.whitebalance, [1.0; 4]);
+
+		// And it agrees with the estimators over the same neutral
+		let img: Image<u16, BayerRgb> = patch([1024, 4096, 2048], [1024, 4096, 2048]);
+		assert_eq!(img.estimate_whitebalance(Estimator::GreyWorld), [1.0; 3]);
+	}
+
+	#[test]
+	fn float_spot_whitebalance() {
+		let mut img = patch([0.2, 0.4, 0.1], [0.9, 0.1, 0.5]);
+		let multipliers = img.spot_whitebalance(1, 1, 2, 2).unwrap();
+		// There's no emerald, so its multiplier is left alone
+		assert_eq!(multipliers, [2.0, 1.0, 4.0, 1.0]);
+	}
+
+	#[test]
+	fn spot_whitebalance_outside_the_image() {
+		let mut img = patch([0.2, 0.4, 0.1], [0.9, 0.1, 0.5]);
+		img.metadata.whitebalance = [2.0, 1.0, 1.5, 1.0];
+		assert!(img.spot_whitebalance(3, 3, 2, 2).is_err());
+		assert!(img.spot_whitebalance(0, 0, 0, 2).is_err());
+		assert_eq!(img.metadata.whitebalance, [2.0, 1.0, 1.5, 1.0]);
+	}
+}
@@ -0,0 +1,704 @@
+use nalgebra::{Matrix3x4, Matrix4x1, Matrix4x3};
+use rawloader::CFA;
+
+use super::xyz::BRUCE_SRGB_XYZ;
+
+/// How the missing colours of every sensor site are filled in when going from
+/// [BayerRgb](crate::colorspace::BayerRgb) to [LinRgb](crate::colorspace::LinRgb).
+///
+/// These are for 2x2 Bayer patterns. X-Trans and four colour sensors have an
+/// interpolation of their own that's always used instead, whichever of these
+/// you ask for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interpolation {
+	/// Average the same-colour sites in the 3x3 neighbourhood. Fast and soft.
+	Bilinear,
+	/// "High-Quality Linear Interpolation for Demosaicing of Bayer-Patterned
+	/// Color Images" by Malvar, He, and Cutler. Bilinear with a gradient
+	/// correction taken from the colour we actually have at the site.
+	MalvarHeCutler,
+	/// Patterned Pixel Grouping. Interpolates along whichever direction has the
+	/// smallest gradient so we don't smear across edges.
+	/// <https://web.archive.org/web/20160923211135/https://sites.google.com/site/chklin/demosaic/>
+	Ppg,
+	/// Copy a randomly picked neighbour of the right colour. Not accurate at
+	/// all, but it looks neat. This is what rawproc used to always do.
+	///
+	/// The same seed always picks the same neighbours, so keep it if you want
+	/// to make the same image again.
+	Random { seed: u64 },
+}
+
+/// The raw sensor values as floats along with the CFA, so the algorithms
+/// don't have to care what the image was stored as.
+pub(crate) struct Mosaic<'a> {
+	pub width: usize,
+	pub height: usize,
+	pub data: Vec<f32>,
+	pub cfa: &'a CFA,
+}
+
+impl<'a> Mosaic<'a> {
+	/// Mirror a coordinate back into the image. We mirror around the edge
+	/// pixel, not between pixels, so the parity (and with it the colour of a
+	/// 2x2 CFA) stays the same.
+	#[inline]
+	fn reflect(v: isize, len: usize) -> usize {
+		let last = len as isize - 1;
+		let v = if v < 0 {
+			-v
+		} else if v > last {
+			2 * last - v
+		} else {
+			v
+		};
+
+		v.clamp(0, last) as usize
+	}
+
+	#[inline]
+	pub fn position(&self, x: isize, y: isize) -> (usize, usize) {
+		(Self::reflect(x, self.width), Self::reflect(y, self.height))
+	}
+
+	#[inline]
+	pub fn get(&self, x: isize, y: isize) -> f32 {
+		let (x, y) = self.position(x, y);
+		self.data[y * self.width + x]
+	}
+
+	/// The CFA colour at the position. Out of bounds coordinates are mirrored
+	/// the same way [Mosaic::get] does.
+	#[inline]
+	pub fn color(&self, x: isize, y: isize) -> usize {
+		let (x, y) = self.position(x, y);
+		self.cfa.color_at(y, x)
+	}
+}
+
+pub(crate) fn bilinear(mosaic: &Mosaic) -> Vec<f32> {
+	let mut rgb = vec![0.0; mosaic.width * mosaic.height * 3];
+
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let mut sums = [0.0; 3];
+			let mut counts = [0; 3];
+
+			for y_off in -1..=1 {
+				for x_off in -1..=1 {
+					let color = mosaic.color(x + x_off, y + y_off);
+					if color < 3 {
+						sums[color] += mosaic.get(x + x_off, y + y_off);
+						counts[color] += 1;
+					}
+				}
+			}
+
+			let idx = (y as usize * mosaic.width + x as usize) * 3;
+			let here = mosaic.color(x, y);
+			for color in 0..3 {
+				rgb[idx + color] = if color == here {
+					mosaic.get(x, y)
+				} else if counts[color] > 0 {
+					sums[color] / counts[color] as f32
+				} else {
+					0.0
+				};
+			}
+		}
+	}
+
+	rgb
+}
+
+pub(crate) fn malvar_he_cutler(mosaic: &Mosaic) -> Vec<f32> {
+	let mut rgb = vec![0.0; mosaic.width * mosaic.height * 3];
+
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let p = |x_off: isize, y_off: isize| mosaic.get(x + x_off, y + y_off);
+
+			let center = p(0, 0);
+			let cross = p(0, -1) + p(0, 1) + p(-1, 0) + p(1, 0);
+			let horizontal = p(-1, 0) + p(1, 0);
+			let vertical = p(0, -1) + p(0, 1);
+			let diagonal = p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1);
+			let far_horizontal = p(-2, 0) + p(2, 0);
+			let far_vertical = p(0, -2) + p(0, 2);
+
+			// The filters from figure 2 of the paper, all with a gain of 1/8
+			let green_at_rb = 4.0 * center + 2.0 * cross - (far_horizontal + far_vertical);
+			let along_row =
+				5.0 * center + 4.0 * horizontal - far_horizontal - diagonal + 0.5 * far_vertical;
+			let along_column =
+				5.0 * center + 4.0 * vertical - far_vertical - diagonal + 0.5 * far_horizontal;
+			let opposite = 6.0 * center + 2.0 * diagonal - 1.5 * (far_horizontal + far_vertical);
+
+			let idx = (y as usize * mosaic.width + x as usize) * 3;
+			let px = &mut rgb[idx..idx + 3];
+			match mosaic.color(x, y) {
+				1 => {
+					// Green sites have red on one axis and blue on the other
+					let (red, blue) = if mosaic.color(x + 1, y) == 0 {
+						(along_row, along_column)
+					} else {
+						(along_column, along_row)
+					};
+
+					px[0] = red / 8.0;
+					px[1] = center;
+					px[2] = blue / 8.0;
+				}
+				color @ (0 | 2) => {
+					px[color] = center;
+					px[1] = green_at_rb / 8.0;
+					px[2 - color] = opposite / 8.0;
+				}
+				_ => unreachable!(),
+			}
+		}
+	}
+
+	rgb
+}
+
+pub(crate) fn ppg(mosaic: &Mosaic) -> Vec<f32> {
+	let width = mosaic.width;
+	let idx = |x: isize, y: isize| {
+		let (x, y) = mosaic.position(x, y);
+		y * width + x
+	};
+
+	// Pass one: green everywhere. At red and blue sites we pick the direction
+	// with the smallest gradient and correct it with the site's own colour.
+	let mut green = vec![0.0; mosaic.width * mosaic.height];
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let p = |x_off: isize, y_off: isize| mosaic.get(x + x_off, y + y_off);
+			let center = p(0, 0);
+
+			if mosaic.color(x, y) == 1 {
+				green[idx(x, y)] = center;
+				continue;
+			}
+
+			let vertical = (p(0, -1) - p(0, 1)).abs();
+			let horizontal = (p(-1, 0) - p(1, 0)).abs();
+			let north = (center - p(0, -2)).abs() * 2.0 + vertical;
+			let east = (center - p(2, 0)).abs() * 2.0 + horizontal;
+			let west = (center - p(-2, 0)).abs() * 2.0 + horizontal;
+			let south = (center - p(0, 2)).abs() * 2.0 + vertical;
+
+			let smallest = north.min(east).min(west).min(south);
+			let estimate = if smallest == north {
+				(p(0, -1) * 3.0 + p(0, 1) + center - p(0, -2)) / 4.0
+			} else if smallest == east {
+				(p(1, 0) * 3.0 + p(-1, 0) + center - p(2, 0)) / 4.0
+			} else if smallest == west {
+				(p(-1, 0) * 3.0 + p(1, 0) + center - p(-2, 0)) / 4.0
+			} else {
+				(p(0, 1) * 3.0 + p(0, -1) + center - p(0, 2)) / 4.0
+			};
+
+			// Keep the estimate within the greens we used to make it
+			let low = p(0, -1).min(p(0, 1)).min(p(-1, 0)).min(p(1, 0));
+			let high = p(0, -1).max(p(0, 1)).max(p(-1, 0)).max(p(1, 0));
+			green[idx(x, y)] = estimate.clamp(low, high);
+		}
+	}
+
+	let g = |x: isize, y: isize| green[idx(x, y)];
+
+	// Pass two: red and blue at green sites, from the horizontal and vertical
+	// neighbours. Pass three: blue at red and red at blue along the diagonal
+	// with the smallest gradient.
+	let mut rgb = vec![0.0; mosaic.width * mosaic.height * 3];
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let p = |x_off: isize, y_off: isize| mosaic.get(x + x_off, y + y_off);
+			let gr = |x_off: isize, y_off: isize| g(x + x_off, y + y_off);
+			let center = p(0, 0);
+
+			let out = idx(x, y) * 3;
+			match mosaic.color(x, y) {
+				1 => {
+					let horizontal = hue_transit(gr(-1, 0), center, gr(1, 0), p(-1, 0), p(1, 0));
+					let vertical = hue_transit(gr(0, -1), center, gr(0, 1), p(0, -1), p(0, 1));
+
+					let row_color = mosaic.color(x + 1, y);
+					rgb[out + row_color] = horizontal;
+					rgb[out + 1] = center;
+					rgb[out + (2 - row_color)] = vertical;
+				}
+				color @ (0 | 2) => {
+					let here = gr(0, 0);
+					let north_east = (p(1, -1) - p(-1, 1)).abs()
+						+ (center - p(2, -2)).abs()
+						+ (center - p(-2, 2)).abs()
+						+ (gr(1, -1) - here).abs()
+						+ (gr(-1, 1) - here).abs();
+					let north_west = (p(-1, -1) - p(1, 1)).abs()
+						+ (center - p(-2, -2)).abs()
+						+ (center - p(2, 2)).abs()
+						+ (gr(-1, -1) - here).abs()
+						+ (gr(1, 1) - here).abs();
+
+					let other = if north_east < north_west {
+						hue_transit(gr(1, -1), here, gr(-1, 1), p(1, -1), p(-1, 1))
+					} else {
+						hue_transit(gr(-1, -1), here, gr(1, 1), p(-1, -1), p(1, 1))
+					};
+
+					rgb[out + color] = center;
+					rgb[out + 1] = here;
+					rgb[out + (2 - color)] = other;
+				}
+				_ => unreachable!(),
+			}
+		}
+	}
+
+	rgb
+}
+
+/// Interpolate `v1` and `v3` at the point where `l2` sits between `l1` and
+/// `l3`. If `l2` isn't between them, average and correct with the laplacian.
+#[inline]
+fn hue_transit(l1: f32, l2: f32, l3: f32, v1: f32, v3: f32) -> f32 {
+	if (l1 < l2 && l2 < l3) || (l1 > l2 && l2 > l3) {
+		v1 + (v3 - v1) * (l2 - l1) / (l3 - l1)
+	} else {
+		(v1 + v3) / 2.0 + (l2 * 2.0 - l1 - l3) / 4.0
+	}
+}
+
+/// Fujifilm's X-Trans sensors have a 6x6 pattern rather than the 2x2 of Bayer.
+pub(crate) fn is_xtrans(cfa: &CFA) -> bool {
+	cfa.width == 6 && cfa.height == 6
+}
+
+/// Demosaic an X-Trans mosaic. Green is over half of the sensor, so we fill
+/// it in first along the smoothest axis. Red and blue are then done as a
+/// difference from green, weighted by distance, because they're too sparse
+/// to interpolate well on their own.
+pub(crate) fn xtrans(mosaic: &Mosaic) -> Vec<f32> {
+	let width = mosaic.width;
+	let idx = |x: isize, y: isize| {
+		let (x, y) = mosaic.position(x, y);
+		y * width + x
+	};
+
+	let mut green = vec![0.0; mosaic.width * mosaic.height];
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let p = |x_off: isize, y_off: isize| mosaic.get(x + x_off, y + y_off);
+			let is_green = |x_off: isize, y_off: isize| mosaic.color(x + x_off, y + y_off) == 1;
+
+			if is_green(0, 0) {
+				green[idx(x, y)] = p(0, 0);
+				continue;
+			}
+
+			let horizontal = is_green(-1, 0) && is_green(1, 0);
+			let vertical = is_green(0, -1) && is_green(0, 1);
+
+			green[idx(x, y)] = match (horizontal, vertical) {
+				(true, true) => {
+					if (p(-1, 0) - p(1, 0)).abs() < (p(0, -1) - p(0, 1)).abs() {
+						(p(-1, 0) + p(1, 0)) / 2.0
+					} else {
+						(p(0, -1) + p(0, 1)) / 2.0
+					}
+				}
+				(true, false) => (p(-1, 0) + p(1, 0)) / 2.0,
+				(false, true) => (p(0, -1) + p(0, 1)) / 2.0,
+				(false, false) => {
+					// Every site on an X-Trans sensor has a green somewhere in
+					// the 3x3 around it
+					let mut sum = 0.0;
+					let mut count = 0;
+					for y_off in -1..=1 {
+						for x_off in -1..=1 {
+							if is_green(x_off, y_off) {
+								sum += p(x_off, y_off);
+								count += 1;
+							}
+						}
+					}
+
+					if count > 0 {
+						sum / count as f32
+					} else {
+						0.0
+					}
+				}
+			};
+		}
+	}
+
+	let mut rgb = vec![0.0; mosaic.width * mosaic.height * 3];
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let here = mosaic.color(x, y);
+			let g = green[idx(x, y)];
+			let out = idx(x, y) * 3;
+
+			rgb[out + 1] = g;
+			for color in [0, 2] {
+				if color == here {
+					rgb[out + color] = mosaic.get(x, y);
+					continue;
+				}
+
+				let mut difference = 0.0;
+				let mut weights = 0.0;
+				for y_off in -2..=2isize {
+					for x_off in -2..=2isize {
+						if mosaic.color(x + x_off, y + y_off) != color {
+							continue;
+						}
+
+						let weight = 1.0 / (x_off * x_off + y_off * y_off) as f32;
+						let sample = mosaic.get(x + x_off, y + y_off);
+						difference += weight * (sample - green[idx(x + x_off, y + y_off)]);
+						weights += weight;
+					}
+				}
+
+				rgb[out + color] = if weights > 0.0 {
+					g + difference / weights
+				} else {
+					g
+				};
+			}
+		}
+	}
+
+	rgb
+}
+
+/// Whether there's a fourth colour in the CFA, like the emerald of an RGBE
+/// sensor. CYGM sensors also come to us with four colours.
+pub(crate) fn is_four_color(cfa: &CFA) -> bool {
+	(0..cfa.height).any(|row| (0..cfa.width).any(|col| cfa.color_at(row, col) == 3))
+}
+
+/// Build the matrix that takes the four camera colours to linear sRGB. This is
+/// how dcraw does it: go from sRGB to the camera's colours, make it so white
+/// stays white, and then take the pseudoinverse to go back the other way.
+pub(crate) fn cam4_to_rgb(xyz_to_cam: &Matrix4x3<f32>) -> Option<Matrix3x4<f32>> {
+	let mut rgb_to_cam = xyz_to_cam * BRUCE_SRGB_XYZ;
+
+	for mut row in rgb_to_cam.row_iter_mut() {
+		let sum = row.sum();
+		if sum == 0.0 {
+			return None;
+		}
+		row /= sum;
+	}
+
+	let transposed = rgb_to_cam.transpose();
+	(transposed * rgb_to_cam)
+		.try_inverse()
+		.map(|inverse| inverse * transposed)
+}
+
+/// Bilinear interpolation for all four colours and then a trip through
+/// `cam_to_rgb` to get down to three.
+pub(crate) fn four_color(mosaic: &Mosaic, cam_to_rgb: &Matrix3x4<f32>) -> Vec<f32> {
+	let mut rgb = vec![0.0; mosaic.width * mosaic.height * 3];
+
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let mut sums = [0.0; 4];
+			let mut counts = [0; 4];
+
+			for y_off in -1..=1 {
+				for x_off in -1..=1 {
+					let color = mosaic.color(x + x_off, y + y_off);
+					sums[color] += mosaic.get(x + x_off, y + y_off);
+					counts[color] += 1;
+				}
+			}
+
+			let here = mosaic.color(x, y);
+			let mut cam = Matrix4x1::zeros();
+			for color in 0..4 {
+				cam[color] = if color == here {
+					mosaic.get(x, y)
+				} else if counts[color] > 0 {
+					sums[color] / counts[color] as f32
+				} else {
+					0.0
+				};
+			}
+
+			let idx = (y as usize * mosaic.width + x as usize) * 3;
+			rgb[idx..idx + 3].copy_from_slice((cam_to_rgb * cam).as_slice());
+		}
+	}
+
+	rgb
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{
+		colorspace::{BayerRgb, LinRgb},
+		image::{Image, RawImage, RawMetadata},
+	};
+
+	const EVERY: [Interpolation; 4] = [
+		Interpolation::Bilinear,
+		Interpolation::MalvarHeCutler,
+		Interpolation::Ppg,
+		Interpolation::Random { seed: 7 },
+	];
+
+	const BAYER: [&str; 4] = ["RGGB", "GRBG", "GBRG", "BGGR"];
+
+	// Fujifilm's, as rawloader writes it
+	const XTRANS: &str = "GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG";
+
+	fn debayer_cfa(
+		cfa: &str,
+		width: usize,
+		height: usize,
+		interpolation: Interpolation,
+		value: impl Fn(usize, usize) -> f32,
+	) -> Image<f32, LinRgb> {
+		let data = (0..height)
+			.flat_map(|y| (0..width).map(move |x| (x, y)))
+			.map(|(x, y)| value(x, y))
+			.collect();
+
+		Image::<f32, BayerRgb>::from_raw_parts(width, height, RawMetadata::test(cfa), data)
+			.debayer(interpolation)
+	}
+
+	#[test]
+	fn flat_stays_flat() {
+		for cfa in BAYER {
+			for interpolation in EVERY {
+				let img = debayer_cfa(cfa, 16, 12, interpolation, |_, _| 0.5);
+				for v in &img.data {
+					assert!((v - 0.5).abs() < 1e-6, "{cfa} {interpolation:?} gave {v}");
+				}
+			}
+		}
+	}
+
+	// Only one colour has any light, so that's all that should come out. A
+	// CFA read the wrong way around fills the other channels in from it.
+	#[test]
+	fn single_colour() {
+		let (width, height) = (16, 12);
+		for cfa_name in BAYER {
+			let cfa = CFA::new(cfa_name);
+			for lit in [0, 2] {
+				for interpolation in EVERY {
+					let img = debayer_cfa(cfa_name, width, height, interpolation, |x, y| {
+						if cfa.color_at(y, x) == lit {
+							0.8
+						} else {
+							0.0
+						}
+					});
+
+					for y in 3..height - 3 {
+						for x in 3..width - 3 {
+							let idx = (y * width + x) * 3;
+							for (c, v) in img.data[idx..idx + 3].iter().enumerate() {
+								let expected = if c == lit { 0.8 } else { 0.0 };
+								assert!(
+									(v - expected).abs() < 1e-4,
+									"{cfa_name} {interpolation:?} at {x},{y} channel {c}: got {v}"
+								);
+							}
+						}
+					}
+				}
+			}
+		}
+	}
+
+	// A grey ramp is the same in every channel, so whatever we fill in
+	// should land back on it. Random copies a neighbour, so it's allowed to
+	// be a pixel off.
+	fn ramp(horizontal: bool) {
+		let (width, height) = (24, 20);
+		let step = 0.01;
+		let at = |x: usize, y: usize| 0.1 + step * if horizontal { x } else { y } as f32;
+
+		for cfa in BAYER {
+			for interpolation in EVERY {
+				let tolerance = match interpolation {
+					Interpolation::Random { .. } => step * 1.01,
+					_ => 1e-4,
+				};
+
+				let img = debayer_cfa(cfa, width, height, interpolation, at);
+				for y in 3..height - 3 {
+					for x in 3..width - 3 {
+						let idx = (y * width + x) * 3;
+						for (c, v) in img.data[idx..idx + 3].iter().enumerate() {
+							let expected = at(x, y);
+							assert!(
+								(v - expected).abs() <= tolerance,
+								"{cfa} {interpolation:?} at {x},{y} channel {c}: expected {expected} got {v}"
+							);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn horizontal_ramp() {
+		ramp(true);
+	}
+
+	#[test]
+	fn vertical_ramp() {
+		ramp(false);
+	}
+
+	#[test]
+	fn raw_image_debayers_either_sample_type() {
+		let metadata = RawMetadata::test("RGGB");
+		let ints = Image::from_raw_parts(4, 4, metadata.clone(), vec![u16::MAX / 2; 16]);
+		let floats = Image::from_raw_parts(4, 4, metadata, vec![0.5f32; 16]);
+
+		let ints = RawImage::Integer(ints).debayer(Interpolation::Bilinear);
+		let floats = RawImage::Float(floats).debayer(Interpolation::Bilinear);
+		assert_eq!(ints.data, floats.data);
+	}
+
+	#[test]
+	fn xtrans_flat_stays_flat() {
+		let img = debayer_cfa(XTRANS, 18, 12, Interpolation::Bilinear, |_, _| 0.5);
+		for v in &img.data {
+			assert!((v - 0.5).abs() < 1e-6, "got {v}");
+		}
+	}
+
+	#[test]
+	fn xtrans_single_colour() {
+		let cfa = CFA::new(XTRANS);
+		let (width, height) = (18, 12);
+		let img = debayer_cfa(XTRANS, width, height, Interpolation::Bilinear, |x, y| {
+			if cfa.color_at(y, x) == 0 {
+				0.8
+			} else {
+				0.0
+			}
+		});
+
+		for (idx, px) in img.data.chunks_exact(3).enumerate() {
+			let (x, y) = (idx % width, idx / width);
+			assert!(
+				(px[0] - 0.8).abs() < 1e-6 && px[1].abs() < 1e-6 && px[2].abs() < 1e-6,
+				"at {x},{y} got {px:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn xtrans_ignores_interpolation() {
+		let value = |x: usize, y: usize| ((x * 7 + y * 13) % 10) as f32 / 10.0;
+		let expected = debayer_cfa(XTRANS, 12, 12, Interpolation::Bilinear, value);
+
+		for interpolation in EVERY {
+			let img = debayer_cfa(XTRANS, 12, 12, interpolation, value);
+			assert_eq!(img.data, expected.data, "{interpolation:?}");
+		}
+	}
+
+	// dcraw's Sony DSC-F828, the RGBE camera
+	#[rustfmt::skip]
+	fn f828() -> Matrix4x3<f32> {
+		Matrix4x3::new(
+			0.7924, -0.1910, -0.0777,
+			-0.8226, 1.5459, 0.2998,
+			-0.1517, 0.2199, 0.6818,
+			-0.7242, 1.1401, 0.3481,
+		)
+	}
+
+	fn debayer_rgbe(
+		xyz_to_cam4: Option<Matrix4x3<f32>>,
+		value: impl Fn(usize) -> f32,
+	) -> Image<f32, LinRgb> {
+		let (width, height) = (8, 6);
+		let cfa = CFA::new("RGEB");
+		let mut metadata = RawMetadata::test("RGEB");
+		metadata.xyz_to_cam4 = xyz_to_cam4;
+
+		let data = (0..height)
+			.flat_map(|y| (0..width).map(move |x| (x, y)))
+			.map(|(x, y)| value(cfa.color_at(y, x)))
+			.collect();
+
+		Image::<f32, BayerRgb>::from_raw_parts(width, height, metadata, data)
+			.debayer(Interpolation::Bilinear)
+	}
+
+	#[test]
+	fn cam4_to_rgb_keeps_white() {
+		let cam_to_rgb = cam4_to_rgb(&f828()).unwrap();
+		let white = cam_to_rgb * Matrix4x1::new(1.0, 1.0, 1.0, 1.0);
+		for v in white.iter() {
+			assert!((v - 1.0).abs() < 1e-4, "white came out {white:?}");
+		}
+
+		// A colour the camera can't see can't be made white
+		let mut blind = f828();
+		blind.row_mut(3).fill(0.0);
+		assert!(cam4_to_rgb(&blind).is_none());
+	}
+
+	#[test]
+	fn four_color_flat_stays_flat() {
+		for xyz_to_cam4 in [Some(f828()), None] {
+			let img = debayer_rgbe(xyz_to_cam4, |_| 0.5);
+			for v in &img.data {
+				assert!((v - 0.5).abs() < 1e-4, "{xyz_to_cam4:?} gave {v}");
+			}
+			// Balanced sRGB, and no balance to take back out
+			assert_eq!(img.metadata.cam_to_xyz, BRUCE_SRGB_XYZ);
+		}
+	}
+
+	#[test]
+	fn four_color_single_colour() {
+		let cam_to_rgb = cam4_to_rgb(&f828()).unwrap();
+		for lit in 0..4 {
+			let light = |color| if color == lit { 0.8 } else { 0.0 };
+
+			// Every site sees the one colour at 0.8 and the others at nothing.
+			// The debayer clips what goes negative
+			let expected = cam_to_rgb.column(lit) * 0.8;
+			let img = debayer_rgbe(Some(f828()), light);
+			for px in img.data.chunks_exact(3) {
+				for (got, expected) in px.iter().zip(expected.iter()) {
+					assert!(
+						(got.max(0.0) - expected.max(0.0)).abs() < 1e-4,
+						"{lit}: {px:?}"
+					);
+				}
+			}
+		}
+
+		// Without a matrix the emerald is taken as half of green
+		let img = debayer_rgbe(None, |color| if color == 3 { 0.8 } else { 0.0 });
+		for px in img.data.chunks_exact(3) {
+			assert_eq!(px, [0.0, 0.4, 0.0]);
+		}
+		let img = debayer_rgbe(None, |color| if color == 0 { 0.8 } else { 0.0 });
+		for px in img.data.chunks_exact(3) {
+			assert_eq!(px, [0.8, 0.0, 0.0]);
+		}
+	}
+}
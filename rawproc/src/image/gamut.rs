@@ -0,0 +1,172 @@
+/// Where the distance from neutral starts getting compressed by
+/// [GamutMapping::Compress], as a fraction of the way to the gamut boundary.
+const COMPRESS_THRESHOLD: f32 = 0.8;
+/// The distance from neutral that [GamutMapping::Compress] brings right up to
+/// the boundary. Anything further out than this is still clipped.
+const COMPRESS_LIMIT: f32 = 1.2;
+/// How hard the compression curve bends.
+const COMPRESS_POWER: f32 = 1.2;
+
+/// What to do with colours that fall outside of the colourspace we're
+/// converting to. They show up as channels below zero or above one.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GamutMapping {
+	/// Clamp every channel on its own. Out of gamut colours shift hue and
+	/// smooth gradients can band.
+	Clip,
+	/// Pull the colour straight toward the grey of the same luminance until
+	/// it fits. Keeps hue and brightness, gives up saturation.
+	Desaturate,
+	/// Smoothly squash the colours near and past the edge of the gamut in,
+	/// leaving the ones well inside alone. This is the approach of the ACES
+	/// reference gamut compression.
+	Compress,
+}
+
+impl GamutMapping {
+	/// Bring linear `rgb` into the gamut. `luminance` is the row of the
+	/// colourspace's RGB to XYZ matrix that gives Y.
+	pub(crate) fn map(&self, rgb: &mut [f32; 3], luminance: [f32; 3]) {
+		match self {
+			GamutMapping::Clip => (),
+			GamutMapping::Desaturate => desaturate(rgb, luminance),
+			GamutMapping::Compress => compress(rgb),
+		}
+
+		for c in rgb.iter_mut() {
+			*c = c.clamp(0.0, 1.0);
+		}
+	}
+}
+
+fn desaturate(rgb: &mut [f32; 3], luminance: [f32; 3]) {
+	let y = (rgb[0] * luminance[0] + rgb[1] * luminance[1] + rgb[2] * luminance[2]).clamp(0.0, 1.0);
+
+	// How far toward the colour from grey we can go before a channel leaves
+	let mut amount: f32 = 1.0;
+	for c in rgb.iter() {
+		let offset = c - y;
+		if *c < 0.0 {
+			amount = amount.min(-y / offset);
+		} else if *c > 1.0 {
+			amount = amount.min((1.0 - y) / offset);
+		}
+	}
+
+	for c in rgb.iter_mut() {
+		*c = y + (*c - y) * amount.max(0.0);
+	}
+}
+
+fn compress(rgb: &mut [f32; 3]) {
+	let achromatic = rgb[0].max(rgb[1]).max(rgb[2]);
+	if achromatic <= 0.0 {
+		return;
+	}
+
+	let scale = (COMPRESS_LIMIT - COMPRESS_THRESHOLD)
+		/ (((1.0 - COMPRESS_THRESHOLD) / (COMPRESS_LIMIT - COMPRESS_THRESHOLD))
+			.powf(-COMPRESS_POWER)
+			- 1.0)
+			.powf(1.0 / COMPRESS_POWER);
+
+	for c in rgb.iter_mut() {
+		// Zero for grey, one for right on the boundary, more past it
+		let distance = (achromatic - *c) / achromatic;
+		if distance < COMPRESS_THRESHOLD {
+			continue;
+		}
+
+		let over = (distance - COMPRESS_THRESHOLD) / scale;
+		let compressed = COMPRESS_THRESHOLD
+			+ scale * over / (1.0 + over.powf(COMPRESS_POWER)).powf(1.0 / COMPRESS_POWER);
+
+		*c = achromatic - compressed * achromatic;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// The Y row of sRGB's RGB to XYZ matrix
+	const SRGB_LUMINANCE: [f32; 3] = [0.2126729, 0.7151522, 0.072175];
+
+	fn luminance(rgb: [f32; 3]) -> f32 {
+		rgb[0] * SRGB_LUMINANCE[0] + rgb[1] * SRGB_LUMINANCE[1] + rgb[2] * SRGB_LUMINANCE[2]
+	}
+
+	fn in_gamut(rgb: [f32; 3]) -> bool {
+		rgb.iter().all(|c| (0.0..=1.0).contains(c))
+	}
+
+	#[test]
+	fn clip_clamps_each_channel() {
+		let mut rgb = [1.4, 0.5, -0.2];
+		GamutMapping::Clip.map(&mut rgb, SRGB_LUMINANCE);
+		assert_eq!(rgb, [1.0, 0.5, 0.0]);
+	}
+
+	#[test]
+	fn desaturate_keeps_the_luminance() {
+		for colour in [[1.4, 0.5, -0.2], [-0.3, 0.4, 0.2], [0.2, 1.1, 1.3]] {
+			let mut rgb = colour;
+			GamutMapping::Desaturate.map(&mut rgb, SRGB_LUMINANCE);
+
+			assert!(in_gamut(rgb), "{colour:?} went to {rgb:?}");
+			assert!(
+				(luminance(rgb) - luminance(colour)).abs() < 1e-5,
+				"{colour:?} went to {rgb:?}"
+			);
+
+			// Still the same way from grey, just less of it
+			let y = luminance(colour);
+			let ratio = (rgb[0] - y) / (colour[0] - y);
+			for c in 1..3 {
+				assert!((rgb[c] - y - (colour[c] - y) * ratio).abs() < 1e-5);
+			}
+		}
+	}
+
+	#[test]
+	fn desaturate_leaves_the_gamut_alone() {
+		let colour = [0.9, 0.2, 0.05];
+		let mut rgb = colour;
+		GamutMapping::Desaturate.map(&mut rgb, SRGB_LUMINANCE);
+		for c in 0..3 {
+			assert!((rgb[c] - colour[c]).abs() < 1e-6, "{rgb:?}");
+		}
+	}
+
+	#[test]
+	fn compress_leaves_the_middle_alone() {
+		// Blue is 0.4 of the way from neutral to the boundary, well short of
+		// where compression starts
+		for colour in [[0.5, 0.4, 0.3], [0.2, 0.2, 0.2], [1.0, 0.9, 0.25]] {
+			let mut rgb = colour;
+			GamutMapping::Compress.map(&mut rgb, SRGB_LUMINANCE);
+			assert_eq!(rgb, colour);
+		}
+	}
+
+	#[test]
+	fn compress_brings_colours_in() {
+		// Up to the limit, compression alone is enough without the clip
+		for colour in [[1.0, 0.5, -0.1], [0.5, -0.05, 0.2], [0.1, 0.8, -0.16]] {
+			let mut rgb = colour;
+			compress(&mut rgb);
+			assert!(in_gamut(rgb), "{colour:?} went to {rgb:?}");
+
+			// The brightest channel, and so the hue's order, stays put
+			assert_eq!(
+				rgb[0].max(rgb[1]).max(rgb[2]),
+				colour[0].max(colour[1]).max(colour[2])
+			);
+		}
+
+		// Just inside the boundary gets pushed further in so there's room
+		let mut rgb = [1.0, 0.5, 0.05];
+		compress(&mut rgb);
+		assert!(rgb[2] > 0.05 && rgb[2] < 0.2, "{rgb:?}");
+	}
+}
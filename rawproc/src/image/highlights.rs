@@ -0,0 +1,284 @@
+use super::debayer::Mosaic;
+
+/// A value this close to its clipping point is treated as clipped. Sensors
+/// tend to flatten out a little before they reach the whitelevel.
+const CLIPPED_AT: f32 = 0.99;
+
+/// How far, in pixels, [Highlights::Reconstruct] looks for unclipped colour.
+const SEARCH_RADIUS: isize = 8;
+
+/// What to do with sensor sites that hit the whitelevel.
+///
+/// Once the image is white balanced every channel clips at a different value,
+/// so an area where only green has clipped is left with too much red and blue
+/// and turns pink or magenta.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Highlights {
+	/// Clip every channel to the lowest clipping point. Blown areas come out
+	/// neutral white, but any detail left in the other channels is lost.
+	Clip,
+	/// Keep the brightness of the clipped pixel but take its colour from the
+	/// clipped-to-white version, blending the cast out.
+	Blend,
+	/// Rebuild the clipped channels from the ones that aren't, using the
+	/// colour ratios of nearby unclipped pixels. Falls back to [Highlights::Blend]
+	/// where every channel is clipped or nothing around it is unclipped.
+	Reconstruct,
+}
+
+/// Run `mode` over interleaved data with `channels` components per pixel.
+/// Component `c` is clipped once it gets to `clip[c]`.
+pub(crate) fn recover(
+	mode: Highlights,
+	data: &mut [f32],
+	width: usize,
+	height: usize,
+	channels: usize,
+	clip: &[f32],
+) {
+	match mode {
+		Highlights::Clip => {
+			let lowest = lowest(clip);
+			for v in data.iter_mut() {
+				*v = v.min(lowest);
+			}
+		}
+		Highlights::Blend => {
+			for px in data.chunks_exact_mut(channels) {
+				blend(px, clip);
+			}
+		}
+		Highlights::Reconstruct => reconstruct(data, width, height, channels, clip),
+	}
+}
+
+#[inline]
+fn lowest(clip: &[f32]) -> f32 {
+	clip.iter().copied().fold(f32::INFINITY, f32::min)
+}
+
+#[inline]
+fn is_clipped(v: f32, clip: f32) -> bool {
+	v >= clip * CLIPPED_AT
+}
+
+#[inline]
+fn any_clipped(px: &[f32], clip: &[f32]) -> bool {
+	px.iter().zip(clip).any(|(v, c)| is_clipped(*v, *c))
+}
+
+#[inline]
+fn mean(values: impl Iterator<Item = f32>) -> f32 {
+	let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+	if count == 0 {
+		0.0
+	} else {
+		sum / count as f32
+	}
+}
+
+/// Lightness from the pixel with each channel held at its own clipping point,
+/// chroma from the pixel with every channel held at the lowest one.
+fn blend(px: &mut [f32], clip: &[f32]) {
+	if !any_clipped(px, clip) {
+		return;
+	}
+
+	let lowest = lowest(clip);
+	let lightness = mean(px.iter().zip(clip).map(|(v, c)| v.min(*c)));
+	let neutral = mean(px.iter().map(|v| v.min(lowest)));
+
+	for v in px.iter_mut() {
+		*v = lightness + (v.min(lowest) - neutral);
+	}
+}
+
+fn reconstruct(data: &mut [f32], width: usize, height: usize, channels: usize, clip: &[f32]) {
+	let original = data.to_vec();
+	let pixel = |x: usize, y: usize| {
+		let idx = (y * width + x) * channels;
+		&original[idx..idx + channels]
+	};
+
+	for y in 0..height {
+		for x in 0..width {
+			let here = pixel(x, y);
+			if !any_clipped(here, clip) {
+				continue;
+			}
+
+			let unclipped: Vec<usize> = (0..channels)
+				.filter(|c| !is_clipped(here[*c], clip[*c]))
+				.collect();
+
+			let idx = (y * width + x) * channels;
+			let px = &mut data[idx..idx + channels];
+
+			let reference = mean(unclipped.iter().map(|c| here[*c]));
+			if unclipped.is_empty() || reference <= f32::EPSILON {
+				blend(px, clip);
+				continue;
+			}
+
+			// How each clipped channel relates to the unclipped ones in the
+			// pixels around us, weighted toward the brighter pixels since
+			// they're more like the highlight we're filling in
+			let mut ratios = vec![0.0; channels];
+			let mut weight = 0.0;
+
+			let y_range = y.saturating_sub(SEARCH_RADIUS as usize)
+				..(y + SEARCH_RADIUS as usize + 1).min(height);
+			for sy in y_range {
+				let x_range = x.saturating_sub(SEARCH_RADIUS as usize)
+					..(x + SEARCH_RADIUS as usize + 1).min(width);
+				for sx in x_range {
+					let near = pixel(sx, sy);
+					if any_clipped(near, clip) {
+						continue;
+					}
+
+					let near_reference = mean(unclipped.iter().map(|c| near[*c]));
+					if near_reference <= f32::EPSILON {
+						continue;
+					}
+
+					for (ratio, v) in ratios.iter_mut().zip(near) {
+						*ratio += v;
+					}
+					weight += near_reference;
+				}
+			}
+
+			if weight <= f32::EPSILON {
+				blend(px, clip);
+				continue;
+			}
+
+			for (c, v) in px.iter_mut().enumerate() {
+				if is_clipped(here[c], clip[c]) {
+					*v = v.max(ratios[c] / weight * reference);
+				}
+			}
+		}
+	}
+}
+
+/// Estimate every colour at every site of the mosaic so it can go through
+/// [recover]. A site keeps its own value, the other colours are the average of
+/// the 3x3 neighbourhood. If any of those neighbours is clipped, the colour is
+/// reported as clipped too.
+pub(crate) fn estimate(mosaic: &Mosaic, channels: usize, clip: &[f32]) -> Vec<f32> {
+	let mut estimate = vec![0.0; mosaic.width * mosaic.height * channels];
+
+	for y in 0..mosaic.height as isize {
+		for x in 0..mosaic.width as isize {
+			let mut sums = [0.0; 4];
+			let mut counts = [0; 4];
+			let mut clipped = [false; 4];
+
+			for y_off in -1..=1 {
+				for x_off in -1..=1 {
+					let color = mosaic.color(x + x_off, y + y_off);
+					let value = mosaic.get(x + x_off, y + y_off);
+					sums[color] += value;
+					counts[color] += 1;
+					clipped[color] |= is_clipped(value, clip[color]);
+				}
+			}
+
+			let idx = (y as usize * mosaic.width + x as usize) * channels;
+			let here = mosaic.color(x, y);
+			for color in 0..channels {
+				estimate[idx + color] = if color == here {
+					mosaic.get(x, y)
+				} else if clipped[color] {
+					clip[color]
+				} else if counts[color] > 0 {
+					sums[color] / counts[color] as f32
+				} else {
+					0.0
+				};
+			}
+		}
+	}
+
+	estimate
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Balanced by 2, 1, and 1.5, so that's where each channel clips
+	const CLIP: [f32; 3] = [2.0, 1.0, 1.5];
+
+	fn assert_close(got: &[f32], expected: &[f32]) {
+		for (g, e) in got.iter().zip(expected) {
+			assert!((g - e).abs() < 1e-5, "got {got:?}, expected {expected:?}");
+		}
+	}
+
+	#[test]
+	fn blown_comes_out_neutral() {
+		for mode in [Highlights::Clip, Highlights::Blend, Highlights::Reconstruct] {
+			let mut data = CLIP.repeat(9);
+			recover(mode, &mut data, 3, 3, 3, &CLIP);
+
+			for px in data.chunks_exact(3) {
+				assert!(
+					(px[0] - px[1]).abs() < 1e-5 && (px[1] - px[2]).abs() < 1e-5,
+					"{mode:?} gave {px:?}"
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn blend_keeps_the_lightness() {
+		// Green is blown, red and blue aren't, so it's magenta
+		let mut px = [1.6, 1.0, 1.2];
+		blend(&mut px, &CLIP);
+		// The mean of what's there, 1.6 + 1.0 + 1.2, and no colour since all
+		// of them are past where green clips
+		assert_close(&px, &[3.8 / 3.0; 3]);
+
+		// Nothing clipped, nothing changes
+		let mut px = [0.5, 0.2, 0.1];
+		blend(&mut px, &CLIP);
+		assert_eq!(px, [0.5, 0.2, 0.1]);
+	}
+
+	#[test]
+	fn reconstruct_from_the_neighbours() {
+		// A patch of one colour with a brighter middle, where green would
+		// have been 1.2 but clipped at 1.0
+		let colour = [0.4, 0.8, 0.6];
+		let mut data = colour.repeat(25);
+		data[12 * 3..13 * 3].copy_from_slice(&[0.6, 1.0, 0.9]);
+
+		let mut reconstructed = data.clone();
+		recover(Highlights::Reconstruct, &mut reconstructed, 5, 5, 3, &CLIP);
+		assert_close(&reconstructed[12 * 3..13 * 3], &[0.6, 1.2, 0.9]);
+		// Only the clipped pixel changed
+		assert_eq!(reconstructed[..12 * 3], data[..12 * 3]);
+		assert_eq!(reconstructed[13 * 3..], data[13 * 3..]);
+
+		// Clip can't know what green was and leaves it
+		let mut clipped = data.clone();
+		recover(Highlights::Clip, &mut clipped, 5, 5, 3, &CLIP);
+		assert_close(&clipped[12 * 3..13 * 3], &[0.6, 1.0, 0.9]);
+	}
+
+	#[test]
+	fn reconstruct_without_neighbours_blends() {
+		// Every pixel has clipped green, so there's nothing to learn from
+		let mut data = [0.6, 1.0, 0.9].repeat(4);
+		recover(Highlights::Reconstruct, &mut data, 2, 2, 3, &CLIP);
+
+		let mut blended = [0.6, 1.0, 0.9];
+		blend(&mut blended, &CLIP);
+		for px in data.chunks_exact(3) {
+			assert_close(px, &blended);
+		}
+	}
+}
@@ -0,0 +1,157 @@
+use nalgebra::{Matrix3, Matrix3x1};
+
+use crate::{
+	adaptation::WhitePoint,
+	algorithms,
+	colorspace::{LCh, Lab, LinSrgb, XYZ},
+};
+
+use super::{
+	xyz::{BRUCE_SRGB_XYZ, BRUCE_XYZ_SRGB},
+	Image,
+};
+
+const WHITE: WhitePoint = WhitePoint::D65;
+
+fn white() -> [f32; 3] {
+	[WHITE.x, WHITE.y, WHITE.z]
+}
+
+fn xyz_to_lab(data: &mut [f32]) {
+	data.chunks_mut(3).for_each(|px| {
+		let (l, a, b) = algorithms::pixel_xyz_to_lab(px[0], px[1], px[2], white());
+		px[0] = l;
+		px[1] = a;
+		px[2] = b;
+	});
+}
+
+fn lab_to_xyz(data: &mut [f32]) {
+	data.chunks_mut(3).for_each(|px| {
+		let (x, y, z) = algorithms::pixel_lab_to_xyz(px[0], px[1], px[2], white());
+		px[0] = x;
+		px[1] = y;
+		px[2] = z;
+	});
+}
+
+fn transform(data: &mut [f32], matrix: Matrix3<f32>) {
+	data.chunks_mut(3).for_each(|px| {
+		let res = matrix * Matrix3x1::new(px[0], px[1], px[2]);
+		px[0] = res[0];
+		px[1] = res[1];
+		px[2] = res[2];
+	});
+}
+
+/// The XYZ should already be relative to D65. Use [Image::adapt] if it isn't.
+impl From<Image<f32, XYZ>> for Image<f32, Lab> {
+	fn from(mut value: Image<f32, XYZ>) -> Self {
+		xyz_to_lab(&mut value.data);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, Lab>> for Image<f32, XYZ> {
+	fn from(mut value: Image<f32, Lab>) -> Self {
+		lab_to_xyz(&mut value.data);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, LinSrgb>> for Image<f32, Lab> {
+	fn from(mut value: Image<f32, LinSrgb>) -> Self {
+		transform(&mut value.data, BRUCE_SRGB_XYZ);
+		xyz_to_lab(&mut value.data);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, Lab>> for Image<f32, LinSrgb> {
+	fn from(mut value: Image<f32, Lab>) -> Self {
+		lab_to_xyz(&mut value.data);
+		transform(&mut value.data, BRUCE_XYZ_SRGB);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, Lab>> for Image<f32, LCh> {
+	fn from(mut value: Image<f32, Lab>) -> Self {
+		value.data.chunks_mut(3).for_each(|px| {
+			let (c, h) = algorithms::pixel_ab_to_ch(px[1], px[2]);
+			px[1] = c;
+			px[2] = h;
+		});
+
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, LCh>> for Image<f32, Lab> {
+	fn from(mut value: Image<f32, LCh>) -> Self {
+		value.data.chunks_mut(3).for_each(|px| {
+			let (a, b) = algorithms::pixel_ch_to_ab(px[1], px[2]);
+			px[1] = a;
+			px[2] = b;
+		});
+
+		value.change_colorspace(None)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::image::RawMetadata;
+
+	fn image<C: crate::colorspace::Colorspace>(data: &[f32]) -> Image<f32, C> {
+		Image::from_raw_parts(data.len() / 3, 1, RawMetadata::test("RGGB"), data.to_vec())
+	}
+
+	fn assert_near(got: &[f32], expected: &[f32], tolerance: f32) {
+		for (g, e) in got.iter().zip(expected) {
+			assert!(
+				(g - e).abs() < tolerance,
+				"got {got:?}, expected {expected:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn white_is_a_hundred() {
+		let lab: Image<f32, Lab> = image::<XYZ>(&white()).into();
+		assert_near(&lab.data, &[100.0, 0.0, 0.0], 1e-4);
+
+		let lab: Image<f32, Lab> = image::<LinSrgb>(&[1.0, 1.0, 1.0]).into();
+		assert_near(&lab.data, &[100.0, 0.0, 0.0], 1e-3);
+	}
+
+	#[test]
+	fn srgb_red() {
+		// What Lindbloom's calculator gives for sRGB's red under D65
+		let lab: Image<f32, Lab> = image::<LinSrgb>(&[1.0, 0.0, 0.0]).into();
+		assert_near(&lab.data, &[53.2408, 80.0925, 67.2032], 1e-2);
+	}
+
+	#[test]
+	fn round_trips() {
+		// The last is dark enough to be on the linear part of the curve
+		let colours = [
+			0.2, 0.4, 0.6, 0.9, 0.1, 0.05, 0.5, 0.5, 0.5, 0.001, 0.002, 0.003,
+		];
+
+		let lab: Image<f32, Lab> = image::<XYZ>(&colours).into();
+		let back: Image<f32, XYZ> = lab.into();
+		assert_near(&back.data, &colours, 1e-5);
+
+		let lab: Image<f32, Lab> = image::<LinSrgb>(&colours).into();
+		let back: Image<f32, LinSrgb> = lab.into();
+		assert_near(&back.data, &colours, 1e-4);
+
+		let lab = image::<Lab>(&[60.0, -20.0, 35.0]);
+		let lch: Image<f32, LCh> = lab.into();
+		assert_near(&lch.data[..2], &[60.0, 1625.0f32.sqrt()], 1e-4);
+		let back: Image<f32, Lab> = lch.into();
+		assert_near(&back.data, &[60.0, -20.0, 35.0], 1e-4);
+	}
+}
@@ -1,23 +1,303 @@
-use nalgebra::Matrix3x1;
+use nalgebra::{Matrix3, Matrix3x1};
 
-use crate::colorspace::{LinRgb, XYZ};
+use crate::{
+	colorspace::{LinRgb, XYZ},
+	profile::InputProfile,
+	Error,
+};
 
-use super::Image;
+use super::{
+	highlights::{self, Highlights},
+	whitebalance::{self, Estimator, WhiteBalance},
+	Image,
+};
 
 impl Image<u16, LinRgb> {
+	/// Take the camera's colour to XYZ. Each channel had its own whitelevel,
+	/// but X, Y, and Z are a mix of all three, so they come out sharing the
+	/// largest of them.
 	pub fn to_xyz(mut self) -> Image<u16, XYZ> {
+		let cam_to_xyz = self.metadata.balanced_cam_to_xyz();
+		let levels = self.metadata.whitelevels;
+		let white = levels[0].max(levels[1]).max(levels[2]) as f32;
+
 		for px in self.data.chunks_mut(3) {
 			let m = Matrix3x1::new(
-				px[0] as f32 / self.metadata.whitelevels[0] as f32,
-				px[1] as f32 / self.metadata.whitelevels[1] as f32,
-				px[2] as f32 / self.metadata.whitelevels[2] as f32,
+				px[0] as f32 / levels[0] as f32,
+				px[1] as f32 / levels[1] as f32,
+				px[2] as f32 / levels[2] as f32,
 			);
-			let res = self.metadata.cam_to_xyz * m;
-			px[0] = (res[0] * self.metadata.whitelevels[0] as f32) as u16;
-			px[1] = (res[1] * self.metadata.whitelevels[1] as f32) as u16;
-			px[2] = (res[2] * self.metadata.whitelevels[2] as f32) as u16;
+			let res = cam_to_xyz * m;
+			px[0] = (res[0] * white) as u16;
+			px[1] = (res[1] * white) as u16;
+			px[2] = (res[2] * white) as u16;
 		}
+		self.metadata.whitelevels = [white as u16; 4];
 
 		self.change_colorspace(None)
 	}
+
+	/// Like [Self::to_xyz], but the colour comes from `profile` rather than the
+	/// matrix the camera was decoded with.
+	///
+	/// Camera profiles replace [RawMetadata::cam_to_xyz](super::RawMetadata::cam_to_xyz)
+	/// for the light the whitebalance points to, and their hue/sat maps, look
+	/// table, and tone curve are applied after. ICC profiles are for white
+	/// balanced colour, so the data is balanced if it wasn't, then their tone
+	/// curves go on and their matrix takes it the rest of the way.
+	pub fn to_xyz_with(mut self, profile: &InputProfile) -> Image<u16, XYZ> {
+		match profile {
+			InputProfile::Camera(camera) => {
+				let neutral = self.metadata.neutral();
+				if let Some(cam_to_xyz) = camera.camera_to_xyz(neutral) {
+					self.metadata.cam_to_xyz = cam_to_xyz;
+				}
+
+				let mut xyz = self.to_xyz();
+				if camera.has_looks() {
+					xyz.apply_looks(camera);
+				}
+				xyz
+			}
+			InputProfile::Icc(icc) => {
+				// The curves are for balanced colour too, so balance it first
+				// if it wasn't
+				let [red, green, blue, _] = self.metadata.whitebalance;
+				let balance = if self.metadata.balanced {
+					[1.0; 3]
+				} else {
+					[red, green, blue]
+				};
+
+				for px in self.data.chunks_mut(3) {
+					for (c, curve) in icc.curves.iter().enumerate() {
+						let whitelevel = self.metadata.whitelevels[c] as f32;
+						let value = px[c] as f32 * balance[c] / whitelevel;
+						px[c] = (curve.eval(value) * whitelevel) as u16;
+					}
+				}
+				self.metadata.balanced = true;
+
+				// to_xyz takes the whitebalance back out first, so put it in
+				self.metadata.cam_to_xyz =
+					icc.rgb_to_xyz * Matrix3::from_diagonal(&Matrix3x1::new(red, green, blue));
+
+				self.to_xyz()
+			}
+		}
+	}
+}
+
+impl Image<f32, LinRgb> {
+	/// Balance the image for the light `wb` describes. This is for images that
+	/// were debayered without being balanced first. The multipliers that were
+	/// used are stored in the metadata's whitebalance.
+	pub fn whitebalance(&mut self, wb: WhiteBalance) {
+		let wb = match wb {
+			WhiteBalance::Auto(estimator) => {
+				let [red, green, blue] = self.estimate_whitebalance(estimator);
+				[red, green, blue, self.metadata.whitebalance[3]]
+			}
+			// Auto is the only one that's None
+			wb => wb
+				.multipliers(&self.metadata)
+				.unwrap_or(self.metadata.whitebalance),
+		};
+		self.metadata.whitebalance = wb;
+		self.metadata.balanced = true;
+
+		for px in self.data.chunks_exact_mut(3) {
+			px[0] *= wb[0];
+			px[1] *= wb[1];
+			px[2] *= wb[2];
+		}
+	}
+
+	/// Guess the red, green, and blue whitebalance multipliers from the image.
+	/// The data is normalized, so values near 1.0 are taken as clipped.
+	pub fn estimate_whitebalance(&self, estimator: Estimator) -> [f32; 3] {
+		let mut channels: [Vec<f32>; 3] = Default::default();
+		for px in self.data.chunks_exact(3) {
+			channels[0].push(px[0]);
+			channels[1].push(px[1]);
+			channels[2].push(px[2]);
+		}
+
+		estimator.estimate(channels)
+	}
+
+	/// Find the whitebalance that makes the rectangle neutral, like when it's
+	/// over a grey card, and store it in the metadata. Do this before
+	/// [Self::whitebalance] and then balance with [WhiteBalance::AsShot].
+	///
+	/// Returns the multipliers that were stored.
+	pub fn spot_whitebalance(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<[f32; 4], Error> {
+		self.rect_to_crop(x, y, width, height)?;
+
+		let mut sums = [0.0; 4];
+		for row in y..y + height {
+			let start = (row * self.width + x) * 3;
+			for px in self.data[start..start + width * 3].chunks_exact(3) {
+				sums[0] += px[0];
+				sums[1] += px[1];
+				sums[2] += px[2];
+			}
+		}
+
+		let count = width * height;
+		self.metadata.whitebalance = whitebalance::patch_multipliers(
+			sums,
+			[count, count, count, 0],
+			self.metadata.whitebalance,
+		);
+		Ok(self.metadata.whitebalance)
+	}
+
+	/// Deal with the pixels that clipped. Run this after white balancing the
+	/// sensor data and before going to XYZ, while the channels are still the
+	/// camera's.
+	///
+	/// The data is normalized to the whitelevels, so a channel clips at 1.0 times
+	/// its whitebalance multiplier.
+	pub fn highlights(&mut self, mode: Highlights) {
+		let clip = &self.metadata.whitebalance[..3];
+		highlights::recover(mode, &mut self.data, self.width, self.height, 3, clip);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{
+		adaptation::WhitePoint,
+		icc::{Curve, IccProfile},
+		image::RawMetadata,
+		profile::{CameraProfile, D65_ILLUMINANT},
+	};
+
+	// Something the camera saw as this colour was grey
+	const NEUTRAL: [f32; 3] = [0.5, 1.0, 0.8];
+
+	fn grey_patch(balanced: bool) -> Image<u16, LinRgb> {
+		let mut metadata = RawMetadata::test("RGGB");
+		metadata.whitebalance = [2.0, 1.0, 1.25, 1.0];
+		metadata.balanced = balanced;
+		#[rustfmt::skip]
+		let cam_to_xyz = Matrix3::new(
+			0.6, 0.3, 0.1,
+			0.2, 0.7, 0.1,
+			0.0, 0.1, 0.9,
+		);
+		metadata.cam_to_xyz = cam_to_xyz;
+
+		let data = NEUTRAL
+			.iter()
+			.zip(metadata.whitebalance)
+			.map(|(neutral, wb)| {
+				let value = if balanced { neutral * wb } else { *neutral };
+				(value * 0.4 * u16::MAX as f32) as u16
+			})
+			.collect();
+
+		Image::from_raw_parts(1, 1, metadata, data)
+	}
+
+	fn assert_grey(img: Image<u16, LinRgb>) {
+		let srgb = img.to_xyz().to_linsrgb().floats();
+		let [r, g, b] = [srgb.data[0], srgb.data[1], srgb.data[2]];
+		assert!(
+			(r - g).abs() < 0.002 && (b - g).abs() < 0.002,
+			"expected grey, got {r} {g} {b}"
+		);
+	}
+
+	#[test]
+	fn unbalanced_is_not_unbalanced_again() {
+		assert_grey(grey_patch(false));
+	}
+
+	#[test]
+	fn balanced_comes_out_grey() {
+		assert_grey(grey_patch(true));
+	}
+
+	fn assert_xyz(xyz: Image<u16, XYZ>, expected: Matrix3x1<f32>) {
+		let got = Matrix3x1::from_iterator(xyz.data.iter().map(|v| *v as f32 / u16::MAX as f32));
+		assert!(
+			(got - expected).abs().max() < 1e-3,
+			"expected {expected:?}, got {got:?}"
+		);
+	}
+
+	#[test]
+	fn to_xyz_with_a_camera_profile() {
+		// A camera that sees D65 as the neutral, so the grey patch is 0.4 of
+		// D65 whatever the metadata's own matrix says
+		let d65 = WhitePoint::D65;
+		let xyz_to_cam = Matrix3::from_diagonal(&Matrix3x1::new(
+			NEUTRAL[0] / d65.x,
+			NEUTRAL[1] / d65.y,
+			NEUTRAL[2] / d65.z,
+		));
+		let profile = InputProfile::Camera(Box::new(CameraProfile::from_color_matrix(
+			xyz_to_cam,
+			D65_ILLUMINANT,
+		)));
+
+		for balanced in [false, true] {
+			let xyz = grey_patch(balanced).to_xyz_with(&profile);
+			assert_xyz(xyz, d65.xyz() * 0.4);
+		}
+	}
+
+	#[test]
+	fn to_xyz_with_an_icc_profile() {
+		#[rustfmt::skip]
+		let rgb_to_xyz = Matrix3::new(
+			0.4360, 0.3851, 0.1431,
+			0.2225, 0.7169, 0.0606,
+			0.0139, 0.0971, 0.7141,
+		);
+		let profile = InputProfile::Icc(IccProfile {
+			description: None,
+			rgb_to_xyz,
+			media_white: WhitePoint::D65,
+			curves: [Curve::Gamma(2.0), Curve::Gamma(2.0), Curve::Gamma(2.0)],
+		});
+
+		// Balanced, the patch is 0.4 on every channel, which the curves take
+		// to 0.16 before the matrix. Unbalanced has to get balanced before the
+		// curves to end up in the same place
+		let expected = rgb_to_xyz * Matrix3x1::new(0.16, 0.16, 0.16);
+		for balanced in [false, true] {
+			let xyz = grey_patch(balanced).to_xyz_with(&profile);
+			assert_xyz(xyz, expected);
+		}
+	}
+
+	#[test]
+	fn spot_whitebalance() {
+		// A grey card that came out this colour, in the middle of something
+		// that's not grey at all
+		let mut data = [0.9, 0.1, 0.5].repeat(16);
+		for (x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)] {
+			let idx = (y * 4 + x) * 3;
+			data[idx..idx + 3].copy_from_slice(&[0.2, 0.4, 0.1]);
+		}
+		let mut img: Image<f32, LinRgb> =
+			Image::from_raw_parts(4, 4, RawMetadata::test("RGGB"), data);
+
+		let multipliers = img.spot_whitebalance(1, 1, 2, 2).unwrap();
+		assert_eq!(multipliers, [2.0, 1.0, 4.0, 1.0]);
+		assert_eq!(img.metadata.whitebalance, multipliers);
+
+		assert!(img.spot_whitebalance(2, 2, 3, 1).is_err());
+		assert_eq!(img.metadata.whitebalance, multipliers);
+	}
 }
@@ -1,6 +1,6 @@
 use crate::{
 	algorithms,
-	colorspace::{LinSrgb, Srgb},
+	colorspace::{Colorspace, LinSrgb, Srgb},
 };
 
 use super::Image;
@@ -16,11 +16,11 @@ use super::Image;
 
 impl Image<u16, LinSrgb> {
 	pub fn gamma(mut self) -> Image<u16, Srgb> {
-		for px in self.data.iter_mut() {
-			//TOOD: use correct whitelevel
-			let mut float = *px as f32 / self.metadata.whitelevels[0] as f32;
+		for (idx, px) in self.data.iter_mut().enumerate() {
+			let level = LinSrgb::whitelevel(&self.metadata, self.width, idx) as f32;
+			let mut float = *px as f32 / level;
 			float = algorithms::srgb_gamma(float);
-			*px = (float.max(0.0).min(1.0) * self.metadata.whitelevels[0] as f32) as u16;
+			*px = (float.max(0.0).min(1.0) * level) as u16;
 		}
 
 		self.change_colorspace(None)
@@ -1,28 +1,211 @@
 mod bayerrgb;
+mod debayer;
+mod gamut;
+mod highlights;
 mod hsv;
+mod lab;
 mod linrgb;
 mod linsrgb;
+mod oklab;
+mod orientation;
+mod perceptual;
 mod srgb;
+mod whitebalance;
 mod xyz;
 
+pub use debayer::Interpolation;
+pub use gamut::GamutMapping;
+pub use highlights::Highlights;
+pub use orientation::Orientation;
+pub use whitebalance::{temperature_to_xyz, Estimator, WhiteBalance};
 pub use xyz::XYZ_TO_SRGB;
 
+pub(crate) use debayer::is_four_color;
+pub(crate) use xyz::BRUCE_SRGB_XYZ;
+
 use std::marker::PhantomData;
 
-use nalgebra::Matrix3;
+use nalgebra::{Matrix3, Matrix3x1, Matrix4x3};
 use rawloader::CFA;
 
-use crate::colorspace::{Colorspace, Hsv, LinSrgb, Srgb};
+use crate::{
+	capture::Capture,
+	colorspace::{
+		AdobeRgb, BayerRgb, Colorspace, DisplayP3, Hsv, LinRgb, LinSrgb, ProPhoto, Rec2020, Srgb,
+	},
+	Error,
+};
+
+/// A decoded raw file. Most cameras give us integers, but some files, like
+/// merged HDR DNGs, store their data as floats.
+///
+/// Float data is normalized so that the whitelevel sits at 1.0.
+#[derive(Clone, Debug)]
+pub enum RawImage {
+	Integer(Image<u16, BayerRgb>),
+	Float(Image<f32, BayerRgb>),
+}
+
+impl RawImage {
+	pub fn width(&self) -> usize {
+		match self {
+			RawImage::Integer(img) => img.width,
+			RawImage::Float(img) => img.width,
+		}
+	}
+
+	pub fn height(&self) -> usize {
+		match self {
+			RawImage::Integer(img) => img.height,
+			RawImage::Float(img) => img.height,
+		}
+	}
+
+	pub fn metadata(&self) -> &RawMetadata {
+		match self {
+			RawImage::Integer(img) => &img.metadata,
+			RawImage::Float(img) => &img.metadata,
+		}
+	}
+
+	pub fn crop(&mut self) -> Result<(), Error> {
+		match self {
+			RawImage::Integer(img) => img.crop(),
+			RawImage::Float(img) => img.crop(),
+		}
+	}
+
+	pub fn blacklevels(&mut self, overrides: Option<[u16; 4]>) {
+		match self {
+			RawImage::Integer(img) => img.blacklevels(overrides),
+			RawImage::Float(img) => img.blacklevels(overrides),
+		}
+	}
+
+	pub fn whitebalance(&mut self, wb: WhiteBalance) {
+		match self {
+			RawImage::Integer(img) => img.whitebalance(wb),
+			RawImage::Float(img) => img.whitebalance(wb),
+		}
+	}
+
+	pub fn spot_whitebalance(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<[f32; 4], Error> {
+		match self {
+			RawImage::Integer(img) => img.spot_whitebalance(x, y, width, height),
+			RawImage::Float(img) => img.spot_whitebalance(x, y, width, height),
+		}
+	}
+
+	/// Fill in the missing colours. See [Image::debayer].
+	///
+	/// Float data is debayered as floats and then brought to 16-bit, which is
+	/// what the colour conversions after the debayer work in.
+	pub fn debayer(self, interpolation: Interpolation) -> Image<u16, LinRgb> {
+		match self {
+			RawImage::Integer(img) => img.debayer(interpolation),
+			RawImage::Float(img) => img.debayer(interpolation).into(),
+		}
+	}
+
+	/// Get the image as 16-bit integers. Float data is scaled so that 1.0 is
+	/// [u16::MAX] and the levels in the metadata are changed to match.
+	pub fn into_integer(self) -> Image<u16, BayerRgb> {
+		match self {
+			RawImage::Integer(img) => img,
+			RawImage::Float(img) => img.into(),
+		}
+	}
+
+	/// Get the image as floats, normalized to the whitelevels.
+	pub fn into_floats(self) -> Image<f32, BayerRgb> {
+		match self {
+			RawImage::Integer(img) => img.into(),
+			RawImage::Float(img) => img,
+		}
+	}
+}
 
 #[derive(Clone, Debug)]
 pub struct RawMetadata {
-	/// Whitebalance coefficients. Red, green, blue
-	pub whitebalance: [f32; 3],
+	/// Whitebalance coefficients. Red, green, blue, and the fourth colour of
+	/// four colour sensors. Like emerald! It's 1.0 for everyone else.
+	pub whitebalance: [f32; 4],
+	/// Whether the data has been multiplied by [Self::whitebalance]. Right
+	/// after decoding it's the camera's as shot balance and it hasn't.
+	pub balanced: bool,
 	/// Whitelevel values; the highest per channel value
-	pub whitelevels: [u16; 3],
+	pub whitelevels: [u16; 4],
+	/// Blacklevel values; the value a channel reads when no light hit it.
+	/// Same order as the whitelevels.
+	pub blacklevels: [u16; 4],
 	pub crop: Option<Crop>,
 	pub cfa: CFA,
+	/// Camera colour, before any whitebalance, to XYZ under the light of the
+	/// scene. The as shot neutral comes out as the white of that light with a
+	/// Y of 1.0. See [CameraProfile::camera_to_xyz](crate::profile::CameraProfile::camera_to_xyz).
 	pub cam_to_xyz: Matrix3<f32>,
+	/// XYZ to camera for sensors with four colours in their CFA. `cam_to_xyz`
+	/// can't describe them, so the debayer uses this to get down to RGB.
+	pub xyz_to_cam4: Option<Matrix4x3<f32>>,
+	/// The camera, lens, and exposure the photo was taken with.
+	pub capture: Capture,
+	/// Which way up the camera was held. [Image::orient] turns the pixels to
+	/// match.
+	pub orientation: Orientation,
+}
+
+impl RawMetadata {
+	/// [Self::cam_to_xyz] for the data as it is. If it's been balanced with
+	/// [Self::whitebalance], that's taken back out first so neutral comes out
+	/// as the white of the light the whitebalance was for.
+	pub fn balanced_cam_to_xyz(&self) -> Matrix3<f32> {
+		if self.balanced {
+			self.cam_to_xyz * Matrix3::from_diagonal(&self.neutral())
+		} else {
+			self.cam_to_xyz
+		}
+	}
+
+	/// The XYZ of the light [Self::whitebalance] is for. This is what's
+	/// adapted to the white of the output colourspace.
+	pub fn white(&self) -> Matrix3x1<f32> {
+		self.cam_to_xyz * self.neutral()
+	}
+
+	/// The camera colour [Self::whitebalance] makes neutral.
+	pub fn neutral(&self) -> Matrix3x1<f32> {
+		Matrix3x1::new(
+			1.0 / self.whitebalance[0],
+			1.0 / self.whitebalance[1],
+			1.0 / self.whitebalance[2],
+		)
+	}
+}
+
+#[cfg(test)]
+impl RawMetadata {
+	/// Metadata that leaves the data alone: a 16-bit whitelevel, no black,
+	/// no crop, and an identity colour matrix.
+	pub(crate) fn test(cfa: &str) -> Self {
+		RawMetadata {
+			whitebalance: [1.0; 4],
+			balanced: false,
+			whitelevels: [u16::MAX; 4],
+			blacklevels: [0; 4],
+			crop: None,
+			cfa: CFA::new(cfa),
+			cam_to_xyz: Matrix3::identity(),
+			xyz_to_cam4: None,
+			capture: Capture::default(),
+			orientation: Orientation::Normal,
+		}
+	}
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -60,22 +243,209 @@ pub struct Image<T: Copy + Clone, C: Colorspace> {
 }
 
 impl<T: Copy + Clone, C: Colorspace> Image<T, C> {
+	/// Put an image together from its parts.
+	///
+	/// # Panics
+	/// If `data` is not `width * height * C::COMPONENTS` long. Use
+	/// [Image::try_from_raw_parts] if you'd rather get an error.
 	pub fn from_raw_parts(
 		width: usize,
 		height: usize,
 		metadata: RawMetadata,
 		data: Vec<T>,
 	) -> Image<T, C> {
-		//TODO: gen- check data is correct length
-		Image {
+		match Self::try_from_raw_parts(width, height, metadata, data) {
+			Ok(img) => img,
+			Err(e) => panic!("{e}"),
+		}
+	}
+
+	/// Put an image together from its parts, checking that `data` is the
+	/// right length for the dimensions and colourspace.
+	pub fn try_from_raw_parts(
+		width: usize,
+		height: usize,
+		metadata: RawMetadata,
+		data: Vec<T>,
+	) -> Result<Image<T, C>, Error> {
+		let expected = width
+			.checked_mul(height)
+			.and_then(|px| px.checked_mul(C::COMPONENTS));
+
+		if expected != Some(data.len()) {
+			return Err(Error::DataLength {
+				width,
+				height,
+				components: C::COMPONENTS,
+				actual: data.len(),
+			});
+		}
+
+		Ok(Image {
 			width,
 			height,
 			metadata,
 			data,
 			phantom: Default::default(),
+		})
+	}
+
+	#[inline]
+	fn index_of(&self, x: usize, y: usize) -> Option<usize> {
+		if x < self.width && y < self.height {
+			Some((y * self.width + x) * C::COMPONENTS)
+		} else {
+			None
 		}
 	}
 
+	/// The components of the pixel at `x`, `y`, or None if that's outside the
+	/// image.
+	pub fn pixel(&self, x: usize, y: usize) -> Option<&[T]> {
+		self.index_of(x, y)
+			.map(|idx| &self.data[idx..idx + C::COMPONENTS])
+	}
+
+	pub fn pixel_mut(&mut self, x: usize, y: usize) -> Option<&mut [T]> {
+		self.index_of(x, y)
+			.map(|idx| &mut self.data[idx..idx + C::COMPONENTS])
+	}
+
+	/// Set the pixel at `x`, `y`. `value` has to have a value for every
+	/// component of the colourspace.
+	pub fn set_pixel(&mut self, x: usize, y: usize, value: &[T]) -> Result<(), Error> {
+		if value.len() != C::COMPONENTS {
+			return Err(Error::ComponentCount {
+				expected: C::COMPONENTS,
+				actual: value.len(),
+			});
+		}
+
+		let (width, height) = (self.width, self.height);
+		match self.pixel_mut(x, y) {
+			None => Err(Error::OutOfBounds {
+				x,
+				y,
+				width,
+				height,
+			}),
+			Some(px) => {
+				px.copy_from_slice(value);
+				Ok(())
+			}
+		}
+	}
+
+	/// Every component of every pixel in row `y`
+	pub fn row(&self, y: usize) -> Option<&[T]> {
+		let len = self.width * C::COMPONENTS;
+		(y < self.height).then(|| &self.data[y * len..(y + 1) * len])
+	}
+
+	pub fn row_mut(&mut self, y: usize) -> Option<&mut [T]> {
+		let len = self.width * C::COMPONENTS;
+		(y < self.height).then(|| &mut self.data[y * len..(y + 1) * len])
+	}
+
+	/// Iterate over the pixels, left to right and top to bottom. Each item is
+	/// a slice of the pixel's components.
+	pub fn pixels(&self) -> std::slice::ChunksExact<'_, T> {
+		self.data.chunks_exact(C::COMPONENTS)
+	}
+
+	pub fn pixels_mut(&mut self) -> std::slice::ChunksExactMut<'_, T> {
+		self.data.chunks_exact_mut(C::COMPONENTS)
+	}
+
+	/// Remove `crop` from the sides of the image. This is done in place, no
+	/// new buffer is allocated.
+	///
+	/// Errors if the crop would leave nothing, or wants more than is there.
+	pub fn crop_with(&mut self, crop: Crop) -> Result<(), Error> {
+		let too_large = || Error::CropTooLarge {
+			crop,
+			width: self.width,
+			height: self.height,
+		};
+
+		let new_width = self
+			.width
+			.checked_sub(crop.left.checked_add(crop.right).ok_or_else(too_large)?)
+			.filter(|w| *w > 0)
+			.ok_or_else(too_large)?;
+		let new_height = self
+			.height
+			.checked_sub(crop.top.checked_add(crop.bottom).ok_or_else(too_large)?)
+			.filter(|h| *h > 0)
+			.ok_or_else(too_large)?;
+
+		// Each row moves up and to the left, so the place we're copying to is
+		// always before where we're copying from and we don't clobber anything
+		let row_len = new_width * C::COMPONENTS;
+		for row in 0..new_height {
+			let start = ((row + crop.top) * self.width + crop.left) * C::COMPONENTS;
+			self.data.copy_within(start..start + row_len, row * row_len);
+		}
+		self.data.truncate(new_height * row_len);
+
+		self.width = new_width;
+		self.height = new_height;
+		self.metadata.cfa = self.metadata.cfa.shift(crop.left, crop.top);
+		Ok(())
+	}
+
+	/// Crop the image to the rectangle starting at `x`, `y` that's `width` by
+	/// `height` large.
+	pub fn crop_rect(
+		&mut self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<(), Error> {
+		let crop = self.rect_to_crop(x, y, width, height)?;
+		self.crop_with(crop)
+	}
+
+	/// The [Crop] that leaves only the rectangle, if the rectangle is inside
+	/// the image.
+	pub(crate) fn rect_to_crop(
+		&self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	) -> Result<Crop, Error> {
+		let too_large = || Error::RectOutOfBounds {
+			x,
+			y,
+			rect_width: width,
+			rect_height: height,
+			width: self.width,
+			height: self.height,
+		};
+
+		let right = x
+			.checked_add(width)
+			.and_then(|rx| self.width.checked_sub(rx))
+			.ok_or_else(too_large)?;
+		let bottom = y
+			.checked_add(height)
+			.and_then(|by| self.height.checked_sub(by))
+			.ok_or_else(too_large)?;
+
+		if width == 0 || height == 0 {
+			return Err(too_large());
+		}
+
+		Ok(Crop {
+			top: y,
+			right,
+			bottom,
+			left: x,
+		})
+	}
+
 	pub(crate) fn change_colorspace<N: Colorspace>(self, data: Option<Vec<T>>) -> Image<T, N> {
 		Image {
 			width: self.width,
@@ -114,42 +484,64 @@ where
 	}
 }
 
-macro_rules! impl_u16_to_f32 {
-	($colorspace:path) => {
-		impl From<Image<u16, $colorspace>> for Image<f32, $colorspace> {
-			fn from(img: Image<u16, $colorspace>) -> Self {
-				let Image {
-					width,
-					height,
-					metadata,
-					data: data_u16,
-					phantom: _phantom,
-				} = img;
-				let levels = metadata.whitelevels;
-
-				let data = data_u16
-					.into_iter()
-					.enumerate()
-					.map(|(idx, sixteen)| {
-						let color_index = idx % 3;
-						sixteen as f32 / levels[color_index] as f32
-					})
-					.collect();
+impl<C: Colorspace> From<Image<u16, C>> for Image<f32, C> {
+	/// Normalize the data so that each value's whitelevel, as the colourspace
+	/// sees it, is 1.0
+	fn from(img: Image<u16, C>) -> Self {
+		let data = img
+			.data
+			.iter()
+			.enumerate()
+			.map(|(idx, sixteen)| {
+				let level = C::whitelevel(&img.metadata, img.width, idx).max(1);
+				*sixteen as f32 / level as f32
+			})
+			.collect();
 
-				Image {
-					width,
-					height,
-					metadata,
-					data,
-					phantom: Default::default(),
-				}
-			}
+		Image {
+			width: img.width,
+			height: img.height,
+			metadata: img.metadata,
+			data,
+			phantom: Default::default(),
 		}
-	};
+	}
 }
 
-impl_u16_to_f32!(Srgb);
-impl_u16_to_f32!(LinSrgb);
+impl<C: Colorspace> From<Image<f32, C>> for Image<u16, C> {
+	/// Stretch the floats out so 1.0 is [u16::MAX]. The whitelevels become
+	/// [u16::MAX], too, and the black levels are moved to stay where they were
+	/// relative to white.
+	fn from(img: Image<f32, C>) -> Self {
+		let Image {
+			width,
+			height,
+			mut metadata,
+			data: data_f32,
+			phantom: _phantom,
+		} = img;
+
+		let data = data_f32
+			.into_iter()
+			.map(|float| (float * u16::MAX as f32) as u16)
+			.collect();
+
+		for idx in 0..metadata.whitelevels.len() {
+			let white = metadata.whitelevels[idx].max(1) as f32;
+			let black = metadata.blacklevels[idx] as f32 / white;
+			metadata.blacklevels[idx] = (black * u16::MAX as f32) as u16;
+			metadata.whitelevels[idx] = u16::MAX;
+		}
+
+		Image {
+			width,
+			height,
+			metadata,
+			data,
+			phantom: Default::default(),
+		}
+	}
+}
 
 macro_rules! impl_f32_to_u8 {
 	($colorspace:path) => {
@@ -183,35 +575,210 @@ macro_rules! impl_f32_to_u8 {
 impl_f32_to_u8!(Srgb);
 impl_f32_to_u8!(LinSrgb);
 impl_f32_to_u8!(Hsv);
+impl_f32_to_u8!(DisplayP3);
+impl_f32_to_u8!(Rec2020);
+impl_f32_to_u8!(AdobeRgb);
+impl_f32_to_u8!(ProPhoto);
 
-macro_rules! impl_f32_to_u16 {
-	($colorspace:path) => {
-		impl From<Image<f32, $colorspace>> for Image<u16, $colorspace> {
-			fn from(img: Image<f32, $colorspace>) -> Self {
-				let Image {
-					width,
-					height,
-					metadata,
-					data: data_u8,
-					phantom: _phantom,
-				} = img;
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::Error;
 
-				let data = data_u8
-					.into_iter()
-					.map(|float| (float * u16::MAX as f32) as u16)
-					.collect();
+	/// Every component is its own index, so we can see where they moved to
+	fn numbered(width: usize, height: usize) -> Image<u16, Srgb> {
+		let data = (0..width * height * 3).map(|v| v as u16).collect();
+		Image::from_raw_parts(width, height, RawMetadata::test("RGGB"), data)
+	}
 
-				Image {
-					width,
-					height,
-					metadata,
-					data,
-					phantom: Default::default(),
+	fn crop(top: usize, right: usize, bottom: usize, left: usize) -> Crop {
+		Crop {
+			top,
+			right,
+			bottom,
+			left,
+		}
+	}
+
+	#[test]
+	fn raw_parts_must_be_the_right_length() {
+		let metadata = RawMetadata::test("RGGB");
+		let img = Image::<u8, Srgb>::try_from_raw_parts(2, 3, metadata.clone(), vec![0; 18]);
+		assert!(img.is_ok());
+
+		for (width, height, len) in [(2, 3, 17), (2, 3, 6), (0, 3, 1)] {
+			match Image::<u8, Srgb>::try_from_raw_parts(
+				width,
+				height,
+				metadata.clone(),
+				vec![0; len],
+			) {
+				Err(Error::DataLength {
+					components, actual, ..
+				}) => assert_eq!((components, actual), (3, len)),
+				other => panic!("{width}x{height} with {len} gave {other:?}"),
+			}
+		}
+
+		// width * height * 3 would wrap around to 0
+		let huge = 1 << (usize::BITS - 2);
+		match Image::<u8, Srgb>::try_from_raw_parts(huge, 4, metadata, vec![]) {
+			Err(Error::DataLength { width, .. }) => assert_eq!(width, huge),
+			other => panic!("overflow gave {other:?}"),
+		}
+	}
+
+	#[test]
+	fn pixels_and_rows() {
+		let mut img = numbered(3, 2);
+		assert_eq!(img.pixel(0, 0), Some(&[0, 1, 2][..]));
+		assert_eq!(img.pixel(2, 1), Some(&[15, 16, 17][..]));
+		assert_eq!(img.pixel(3, 0), None);
+		assert_eq!(img.pixel(0, 2), None);
+
+		img.pixel_mut(1, 0).unwrap()[2] = 100;
+		assert_eq!(img.pixel(1, 0), Some(&[3, 4, 100][..]));
+		assert!(img.pixel_mut(0, 2).is_none());
+
+		assert_eq!(img.row(1), Some(&[9, 10, 11, 12, 13, 14, 15, 16, 17][..]));
+		assert_eq!(img.row(2), None);
+
+		let pixels: Vec<&[u16]> = img.pixels().collect();
+		assert_eq!(pixels.len(), 6);
+		assert_eq!(pixels[1], [3, 4, 100]);
+		assert_eq!(pixels[5], [15, 16, 17]);
+	}
+
+	#[test]
+	fn set_pixel() {
+		let mut img = numbered(3, 2);
+		img.set_pixel(2, 0, &[7, 8, 9]).unwrap();
+		assert_eq!(img.pixel(2, 0), Some(&[7, 8, 9][..]));
+
+		match img.set_pixel(0, 0, &[1, 2]) {
+			Err(Error::ComponentCount { expected, actual }) => {
+				assert_eq!((expected, actual), (3, 2))
+			}
+			other => panic!("two components gave {other:?}"),
+		}
+		match img.set_pixel(3, 1, &[1, 2, 3]) {
+			Err(Error::OutOfBounds {
+				x,
+				y,
+				width,
+				height,
+			}) => assert_eq!((x, y, width, height), (3, 1, 3, 2)),
+			other => panic!("outside gave {other:?}"),
+		}
+		// Neither touched anything
+		assert_eq!(img.pixel(0, 0), Some(&[0, 1, 2][..]));
+	}
+
+	#[test]
+	fn crop_moves_rows_in_place() {
+		let mut img = numbered(5, 4);
+		img.crop_with(crop(1, 1, 1, 2)).unwrap();
+
+		assert_eq!((img.width, img.height), (2, 2));
+		// The pixels at 2,1 and 3,1, then 2,2 and 3,2
+		#[rustfmt::skip]
+		let expected = [
+			21, 22, 23, 24, 25, 26,
+			36, 37, 38, 39, 40, 41,
+		];
+		assert_eq!(img.data, expected);
+	}
+
+	#[test]
+	fn crop_shifts_the_cfa() {
+		let cfa = CFA::new("GRBG");
+		for (left, top) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 3)] {
+			let (width, height) = (7, 6);
+			let data = (0..height)
+				.flat_map(|y| (0..width).map(move |x| (x, y)))
+				.map(|(x, y)| cfa.color_at(y, x) as u8)
+				.collect();
+			let mut img: Image<u8, BayerRgb> =
+				Image::from_raw_parts(width, height, RawMetadata::test("GRBG"), data);
+
+			img.crop_with(crop(top, 1, 1, left)).unwrap();
+			for y in 0..img.height {
+				for x in 0..img.width {
+					assert_eq!(
+						img.data[y * img.width + x] as usize,
+						img.metadata.cfa.color_at(y, x),
+						"cropped {left},{top}, at {x},{y}"
+					);
 				}
 			}
 		}
-	};
-}
+	}
+
+	#[test]
+	fn crop_too_large() {
+		let crops = [
+			// Nothing left
+			crop(0, 2, 0, 3),
+			crop(2, 0, 2, 0),
+			// More than there is
+			crop(0, 0, 0, 6),
+			crop(5, 0, 0, 0),
+			// Adding the sides together overflows
+			crop(0, usize::MAX, 0, 1),
+			crop(usize::MAX, 0, usize::MAX, 0),
+		];
 
-impl_f32_to_u16!(Srgb);
-impl_f32_to_u16!(LinSrgb);
+		for crop in crops {
+			let mut img = numbered(5, 4);
+			match img.crop_with(crop) {
+				Err(Error::CropTooLarge { width, height, .. }) => {
+					assert_eq!((width, height), (5, 4))
+				}
+				other => panic!("{crop:?} gave {other:?}"),
+			}
+			// And nothing moved
+			assert_eq!(img.data, numbered(5, 4).data);
+		}
+	}
+
+	#[test]
+	fn crop_rect() {
+		let mut img = numbered(5, 4);
+		img.crop_rect(2, 1, 2, 2).unwrap();
+		assert_eq!(img.data, {
+			let mut cropped = numbered(5, 4);
+			cropped.crop_with(crop(1, 1, 1, 2)).unwrap();
+			cropped.data
+		});
+
+		// The whole image is fine
+		let mut img = numbered(5, 4);
+		img.crop_rect(0, 0, 5, 4).unwrap();
+		assert_eq!(img.data, numbered(5, 4).data);
+	}
+
+	#[test]
+	fn rect_out_of_bounds() {
+		let rects = [
+			// Empty
+			(0, 0, 0, 4),
+			(0, 0, 5, 0),
+			// Hanging off the side
+			(1, 0, 5, 4),
+			(0, 4, 1, 1),
+			// Overflowing
+			(usize::MAX, 0, 1, 1),
+			(0, 0, usize::MAX, 1),
+			(1, 1, 1, usize::MAX),
+		];
+
+		for (x, y, width, height) in rects {
+			let mut img = numbered(5, 4);
+			match img.crop_rect(x, y, width, height) {
+				Err(Error::RectOutOfBounds { .. }) => (),
+				other => panic!("{width}x{height} at {x},{y} gave {other:?}"),
+			}
+			assert_eq!((img.width, img.height), (5, 4));
+		}
+	}
+}
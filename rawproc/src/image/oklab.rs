@@ -0,0 +1,193 @@
+use nalgebra::{Matrix3, Matrix3x1};
+
+use crate::{
+	algorithms,
+	colorspace::{LinSrgb, OkLCh, Oklab, XYZ},
+};
+
+use super::Image;
+
+// All of these are from https://bottosson.github.io/posts/oklab/, digit for
+// digit. That's more than an f32 holds, but they stay checkable against it
+
+/// XYZ, relative to D65, to the cone responses
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const XYZ_LMS: Matrix3<f32> = Matrix3::new(
+	0.8189330101, 0.3618667424, -0.1288597137,
+	0.0329845436, 0.9293118715,  0.0361456387,
+	0.0482003018, 0.2643662691,  0.6338517070,
+);
+
+/// Linear sRGB to the cone responses
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const LINSRGB_LMS: Matrix3<f32> = Matrix3::new(
+	0.4122214708, 0.5363325363, 0.0514459929,
+	0.2119034982, 0.6806995451, 0.1073969566,
+	0.0883024619, 0.2817188376, 0.6299787005,
+);
+
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const LMS_LINSRGB: Matrix3<f32> = Matrix3::new(
+	 4.0767416621, -3.3077115913,  0.2309699292,
+	-1.2684380046,  2.6097574011, -0.3413193965,
+	-0.0041960863, -0.7034186147,  1.7076147010,
+);
+
+/// Non-linear cone responses to Oklab
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const LMS_OKLAB: Matrix3<f32> = Matrix3::new(
+	0.2104542553,  0.7936177850, -0.0040720468,
+	1.9779984951, -2.4285922050,  0.4505937099,
+	0.0259040371,  0.7827717662, -0.8086757660,
+);
+
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const OKLAB_LMS: Matrix3<f32> = Matrix3::new(
+	1.0,  0.3963377774,  0.2158037573,
+	1.0, -0.1055613458, -0.0638541728,
+	1.0, -0.0894841775, -1.2914855480,
+);
+
+fn to_oklab(data: &mut [f32], to_lms: Matrix3<f32>) {
+	data.chunks_mut(3).for_each(|px| {
+		let lms = to_lms * Matrix3x1::new(px[0], px[1], px[2]);
+		let lab = LMS_OKLAB * lms.map(f32::cbrt);
+		px[0] = lab[0];
+		px[1] = lab[1];
+		px[2] = lab[2];
+	});
+}
+
+fn from_oklab(data: &mut [f32], from_lms: Matrix3<f32>) {
+	data.chunks_mut(3).for_each(|px| {
+		let lms = OKLAB_LMS * Matrix3x1::new(px[0], px[1], px[2]);
+		let res = from_lms * lms.map(|v| v * v * v);
+		px[0] = res[0];
+		px[1] = res[1];
+		px[2] = res[2];
+	});
+}
+
+/// The XYZ should already be relative to D65. Use [Image::adapt] if it isn't.
+impl From<Image<f32, XYZ>> for Image<f32, Oklab> {
+	fn from(mut value: Image<f32, XYZ>) -> Self {
+		to_oklab(&mut value.data, XYZ_LMS);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, Oklab>> for Image<f32, XYZ> {
+	fn from(mut value: Image<f32, Oklab>) -> Self {
+		// It's a well conditioned matrix
+		from_oklab(&mut value.data, XYZ_LMS.try_inverse().unwrap());
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, LinSrgb>> for Image<f32, Oklab> {
+	fn from(mut value: Image<f32, LinSrgb>) -> Self {
+		to_oklab(&mut value.data, LINSRGB_LMS);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, Oklab>> for Image<f32, LinSrgb> {
+	fn from(mut value: Image<f32, Oklab>) -> Self {
+		from_oklab(&mut value.data, LMS_LINSRGB);
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, Oklab>> for Image<f32, OkLCh> {
+	fn from(mut value: Image<f32, Oklab>) -> Self {
+		value.data.chunks_mut(3).for_each(|px| {
+			let (c, h) = algorithms::pixel_ab_to_ch(px[1], px[2]);
+			px[1] = c;
+			px[2] = h;
+		});
+
+		value.change_colorspace(None)
+	}
+}
+
+impl From<Image<f32, OkLCh>> for Image<f32, Oklab> {
+	fn from(mut value: Image<f32, OkLCh>) -> Self {
+		value.data.chunks_mut(3).for_each(|px| {
+			let (a, b) = algorithms::pixel_ch_to_ab(px[1], px[2]);
+			px[1] = a;
+			px[2] = b;
+		});
+
+		value.change_colorspace(None)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::image::RawMetadata;
+
+	fn image<C: crate::colorspace::Colorspace>(data: &[f32]) -> Image<f32, C> {
+		Image::from_raw_parts(data.len() / 3, 1, RawMetadata::test("RGGB"), data.to_vec())
+	}
+
+	fn assert_near(got: &[f32], expected: &[f32], tolerance: f32) {
+		for (g, e) in got.iter().zip(expected) {
+			assert!(
+				(g - e).abs() < tolerance,
+				"got {got:?}, expected {expected:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn published_values() {
+		// The table of XYZ and the Oklab it should give from the post
+		#[rustfmt::skip]
+		let xyz = [
+			0.950, 1.000, 1.089,
+			1.000, 0.000, 0.000,
+			0.000, 1.000, 0.000,
+			0.000, 0.000, 1.000,
+		];
+		#[rustfmt::skip]
+		let expected = [
+			1.000, 0.000, 0.000,
+			0.450, 1.236, -0.019,
+			0.922, -0.671, 0.263,
+			0.153, -1.415, -0.449,
+		];
+
+		let oklab: Image<f32, Oklab> = image::<XYZ>(&xyz).into();
+		assert_near(&oklab.data, &expected, 1e-3);
+	}
+
+	#[test]
+	fn white_is_one() {
+		let oklab: Image<f32, Oklab> = image::<LinSrgb>(&[1.0, 1.0, 1.0]).into();
+		assert_near(&oklab.data, &[1.0, 0.0, 0.0], 1e-4);
+	}
+
+	#[test]
+	fn round_trips() {
+		let colours = [0.2, 0.4, 0.6, 0.9, 0.1, 0.05, 0.0, 0.0, 0.0, 0.5, 0.5, 0.5];
+
+		let oklab: Image<f32, Oklab> = image::<LinSrgb>(&colours).into();
+		let back: Image<f32, LinSrgb> = oklab.into();
+		assert_near(&back.data, &colours, 1e-4);
+
+		let oklab: Image<f32, Oklab> = image::<XYZ>(&colours).into();
+		let back: Image<f32, XYZ> = oklab.into();
+		assert_near(&back.data, &colours, 1e-4);
+
+		let oklab = image::<Oklab>(&[0.6, 0.1, -0.05]);
+		let oklch: Image<f32, OkLCh> = oklab.into();
+		let back: Image<f32, Oklab> = oklch.into();
+		assert_near(&back.data, &[0.6, 0.1, -0.05], 1e-6);
+	}
+}
@@ -0,0 +1,472 @@
+use rawloader::CFA;
+
+use super::{Crop, Image};
+use crate::{colorspace::Colorspace, tiff::Tiff};
+
+const ORIENTATION: u16 = 0x0112;
+
+/// Which way up the camera was held, as the EXIF orientation tag says. Each
+/// variant names what has to be done to the stored pixels to show them the
+/// right way up.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Orientation {
+	#[default]
+	Normal,
+	FlipHorizontal,
+	Rotate180,
+	FlipVertical,
+	/// Mirror across the top-left to bottom-right diagonal
+	Transpose,
+	/// A quarter turn clockwise
+	Rotate90,
+	/// Mirror across the top-right to bottom-left diagonal
+	Transverse,
+	/// A quarter turn counter-clockwise
+	Rotate270,
+}
+
+impl Orientation {
+	/// The orientation for an EXIF orientation value, 1 through 8. `None` for
+	/// anything else.
+	pub fn from_exif(value: u16) -> Option<Self> {
+		match value {
+			1 => Some(Orientation::Normal),
+			2 => Some(Orientation::FlipHorizontal),
+			3 => Some(Orientation::Rotate180),
+			4 => Some(Orientation::FlipVertical),
+			5 => Some(Orientation::Transpose),
+			6 => Some(Orientation::Rotate90),
+			7 => Some(Orientation::Transverse),
+			8 => Some(Orientation::Rotate270),
+			_ => None,
+		}
+	}
+
+	pub fn exif(&self) -> u16 {
+		match self {
+			Orientation::Normal => 1,
+			Orientation::FlipHorizontal => 2,
+			Orientation::Rotate180 => 3,
+			Orientation::FlipVertical => 4,
+			Orientation::Transpose => 5,
+			Orientation::Rotate90 => 6,
+			Orientation::Transverse => 7,
+			Orientation::Rotate270 => 8,
+		}
+	}
+
+	pub(crate) fn from_rawloader(orientation: rawloader::Orientation) -> Option<Self> {
+		use rawloader::Orientation as Rl;
+
+		match orientation {
+			Rl::Normal => Some(Orientation::Normal),
+			Rl::HorizontalFlip => Some(Orientation::FlipHorizontal),
+			Rl::Rotate180 => Some(Orientation::Rotate180),
+			Rl::VerticalFlip => Some(Orientation::FlipVertical),
+			Rl::Transpose => Some(Orientation::Transpose),
+			Rl::Rotate90 => Some(Orientation::Rotate90),
+			Rl::Transverse => Some(Orientation::Transverse),
+			Rl::Rotate270 => Some(Orientation::Rotate270),
+			Rl::Unknown => None,
+		}
+	}
+
+	/// The orientation tag of the first IFD, which is the one describing the
+	/// full image.
+	pub(crate) fn from_tiff(tiff: &Tiff) -> Option<Self> {
+		let ifd = tiff.ifds.first()?;
+		let value = ifd.get(ORIENTATION).map(|entry| tiff.unsigned(entry))?;
+		Self::from_exif(*value.first()? as u16)
+	}
+
+	/// If the width and height trade places
+	pub fn swaps_dimensions(&self) -> bool {
+		matches!(
+			self,
+			Orientation::Transpose
+				| Orientation::Rotate90
+				| Orientation::Transverse
+				| Orientation::Rotate270
+		)
+	}
+
+	/// Where the pixel at `x`, `y` of the oriented image comes from in a
+	/// `width` by `height` image that hasn't been.
+	#[inline]
+	fn source(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+		match self {
+			Orientation::Normal => (x, y),
+			Orientation::FlipHorizontal => (width - 1 - x, y),
+			Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+			Orientation::FlipVertical => (x, height - 1 - y),
+			Orientation::Transpose => (y, x),
+			Orientation::Rotate90 => (y, height - 1 - x),
+			Orientation::Transverse => (width - 1 - y, height - 1 - x),
+			Orientation::Rotate270 => (width - 1 - y, x),
+		}
+	}
+
+	/// Where the `width` by `height` rectangle at `x`, `y` of the oriented
+	/// image is in the `image_width` by `image_height` image that hasn't been.
+	/// Returned as `(x, y, width, height)`, or `None` if the rectangle isn't
+	/// inside the oriented image.
+	pub fn source_rect(
+		&self,
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+		image_width: usize,
+		image_height: usize,
+	) -> Option<(usize, usize, usize, usize)> {
+		let (oriented_width, oriented_height) = if self.swaps_dimensions() {
+			(image_height, image_width)
+		} else {
+			(image_width, image_height)
+		};
+
+		if width == 0
+			|| height == 0
+			|| x.checked_add(width)? > oriented_width
+			|| y.checked_add(height)? > oriented_height
+		{
+			return None;
+		}
+
+		let (ax, ay) = self.source(x, y, image_width, image_height);
+		let (bx, by) = self.source(x + width - 1, y + height - 1, image_width, image_height);
+
+		Some((
+			ax.min(bx),
+			ay.min(by),
+			ax.abs_diff(bx) + 1,
+			ay.abs_diff(by) + 1,
+		))
+	}
+
+	/// Orient `data`, a `width` by `height` image with `components` values to
+	/// a pixel. Returns the new width and height with the pixels.
+	///
+	/// This is what [Image::orient] does, for when you have a buffer and not
+	/// an [Image].
+	pub fn apply<T: Copy>(
+		&self,
+		width: usize,
+		height: usize,
+		components: usize,
+		data: &[T],
+	) -> (usize, usize, Vec<T>) {
+		let (new_width, new_height) = if self.swaps_dimensions() {
+			(height, width)
+		} else {
+			(width, height)
+		};
+
+		let mut oriented = Vec::with_capacity(data.len());
+		for y in 0..new_height {
+			for x in 0..new_width {
+				let (sx, sy) = self.source(x, y, width, height);
+				let idx = (sy * width + sx) * components;
+				oriented.extend_from_slice(&data[idx..idx + components]);
+			}
+		}
+
+		(new_width, new_height, oriented)
+	}
+
+	/// The crop for the same area once the image is oriented
+	fn crop(&self, crop: Crop) -> Crop {
+		let Crop {
+			top,
+			right,
+			bottom,
+			left,
+		} = crop;
+
+		let [top, right, bottom, left] = match self {
+			Orientation::Normal => [top, right, bottom, left],
+			Orientation::FlipHorizontal => [top, left, bottom, right],
+			Orientation::Rotate180 => [bottom, left, top, right],
+			Orientation::FlipVertical => [bottom, right, top, left],
+			Orientation::Transpose => [left, bottom, right, top],
+			Orientation::Rotate90 => [left, top, right, bottom],
+			Orientation::Transverse => [right, top, left, bottom],
+			Orientation::Rotate270 => [right, bottom, left, top],
+		};
+
+		Crop {
+			top,
+			right,
+			bottom,
+			left,
+		}
+	}
+
+	/// The CFA of a `width` by `height` mosaic once it's oriented.
+	///
+	/// rawloader can only describe a pattern that's 2x8 one way, so those stay
+	/// as they are if the image is turned on its side. Orient after the debayer
+	/// if your sensor has one.
+	fn cfa(&self, cfa: &CFA, width: usize, height: usize) -> CFA {
+		if cfa.name.is_empty() || (self.swaps_dimensions() && cfa.width != cfa.height) {
+			return cfa.clone();
+		}
+
+		let (pattern_width, pattern_height) = if self.swaps_dimensions() {
+			(cfa.height, cfa.width)
+		} else {
+			(cfa.width, cfa.height)
+		};
+
+		// Every pattern repeats within 48 pixels, so going 48 further doesn't
+		// change the colour and keeps us from going below zero on an image
+		// smaller than its pattern
+		let (width, height) = (width + 48, height + 48);
+
+		let mut name = String::with_capacity(pattern_width * pattern_height);
+		for y in 0..pattern_height {
+			for x in 0..pattern_width {
+				let (sx, sy) = self.source(x, y, width, height);
+				// The colour indices are all the rest of rawproc looks at, so
+				// the other letters don't need to survive
+				name.push(match cfa.color_at(sy, sx) {
+					0 => 'R',
+					1 => 'G',
+					2 => 'B',
+					_ => 'E',
+				});
+			}
+		}
+
+		CFA::new(&name)
+	}
+}
+
+impl<T: Copy + Clone, C: Colorspace> Image<T, C> {
+	/// Turn the image the right way up, as the camera said it was held, and
+	/// set [RawMetadata::orientation](super::RawMetadata::orientation) to
+	/// [Orientation::Normal] so it's not done twice. Nothing is lost; the
+	/// pixels only move.
+	pub fn orient(&mut self) {
+		self.reorient(self.metadata.orientation);
+		self.metadata.orientation = Orientation::Normal;
+	}
+
+	/// A quarter turn clockwise
+	pub fn rotate_90(&mut self) {
+		self.reorient(Orientation::Rotate90);
+	}
+
+	pub fn rotate_180(&mut self) {
+		self.reorient(Orientation::Rotate180);
+	}
+
+	/// A quarter turn counter-clockwise
+	pub fn rotate_270(&mut self) {
+		self.reorient(Orientation::Rotate270);
+	}
+
+	/// Mirror the image left to right
+	pub fn flip_horizontal(&mut self) {
+		self.reorient(Orientation::FlipHorizontal);
+	}
+
+	/// Mirror the image top to bottom
+	pub fn flip_vertical(&mut self) {
+		self.reorient(Orientation::FlipVertical);
+	}
+
+	/// Move the pixels as `orientation` says. The crop and CFA in the metadata
+	/// move with them, so this works on a mosaic too, but
+	/// [RawMetadata::orientation](super::RawMetadata::orientation) is left
+	/// alone.
+	pub fn reorient(&mut self, orientation: Orientation) {
+		if orientation == Orientation::Normal {
+			return;
+		}
+
+		let (width, height, data) =
+			orientation.apply(self.width, self.height, C::COMPONENTS, &self.data);
+
+		self.metadata.cfa = orientation.cfa(&self.metadata.cfa, self.width, self.height);
+		self.metadata.crop = self.metadata.crop.map(|crop| orientation.crop(crop));
+		self.width = width;
+		self.height = height;
+		self.data = data;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{colorspace::BayerRgb, image::RawMetadata};
+
+	const ALL: [Orientation; 8] = [
+		Orientation::Normal,
+		Orientation::FlipHorizontal,
+		Orientation::Rotate180,
+		Orientation::FlipVertical,
+		Orientation::Transpose,
+		Orientation::Rotate90,
+		Orientation::Transverse,
+		Orientation::Rotate270,
+	];
+
+	fn image(
+		width: usize,
+		height: usize,
+		orientation: Orientation,
+		pixel: impl Fn(usize, usize) -> u16,
+	) -> Image<u16, BayerRgb> {
+		let mut metadata = RawMetadata::test("RGGB");
+		metadata.orientation = orientation;
+		let data = (0..height)
+			.flat_map(|y| (0..width).map(move |x| (x, y)))
+			.map(|(x, y)| pixel(x, y))
+			.collect();
+
+		Image::from_raw_parts(width, height, metadata, data)
+	}
+
+	#[test]
+	fn exif_values() {
+		for (idx, orientation) in ALL.iter().enumerate() {
+			assert_eq!(orientation.exif(), idx as u16 + 1);
+			assert_eq!(Orientation::from_exif(idx as u16 + 1), Some(*orientation));
+		}
+		assert_eq!(Orientation::from_exif(0), None);
+		assert_eq!(Orientation::from_exif(9), None);
+	}
+
+	#[test]
+	fn pixels() {
+		// 0 1 2
+		// 3 4 5
+		let expected: [(usize, &[u16]); 8] = [
+			(3, &[0, 1, 2, 3, 4, 5]),
+			(3, &[2, 1, 0, 5, 4, 3]),
+			(3, &[5, 4, 3, 2, 1, 0]),
+			(3, &[3, 4, 5, 0, 1, 2]),
+			(2, &[0, 3, 1, 4, 2, 5]),
+			(2, &[3, 0, 4, 1, 5, 2]),
+			(2, &[5, 2, 4, 1, 3, 0]),
+			(2, &[2, 5, 1, 4, 0, 3]),
+		];
+
+		for (orientation, (width, data)) in ALL.into_iter().zip(expected) {
+			let mut img = image(3, 2, orientation, |x, y| (y * 3 + x) as u16);
+			img.orient();
+
+			assert_eq!(img.width, width, "{orientation:?}");
+			assert_eq!(img.height, 6 / width, "{orientation:?}");
+			assert_eq!(img.data, data, "{orientation:?}");
+			assert_eq!(img.metadata.orientation, Orientation::Normal);
+		}
+	}
+
+	#[test]
+	fn crop_moves_with_the_pixels() {
+		let crop = Crop {
+			top: 1,
+			right: 2,
+			bottom: 0,
+			left: 3,
+		};
+		let inside = |crop: &Crop, width: usize, height: usize, x: usize, y: usize| {
+			x >= crop.left && x < width - crop.right && y >= crop.top && y < height - crop.bottom
+		};
+
+		for orientation in ALL {
+			// Ones where the crop keeps, zeros where it doesn't
+			let mut img = image(7, 4, orientation, |x, y| inside(&crop, 7, 4, x, y) as u16);
+			img.metadata.crop = Some(crop);
+			img.orient();
+
+			let oriented = img.metadata.crop.unwrap();
+			for y in 0..img.height {
+				for x in 0..img.width {
+					assert_eq!(
+						img.data[y * img.width + x] == 1,
+						inside(&oriented, img.width, img.height, x, y),
+						"{orientation:?} at {x}, {y}"
+					);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn cfa_moves_with_the_pixels() {
+		// Odd sizes, so flipping moves where the pattern starts
+		for pattern in ["RGGB", "GRBG", "BGGR", "GBRG"] {
+			for orientation in ALL {
+				let cfa = CFA::new(pattern);
+				let mut img = image(5, 3, orientation, |x, y| cfa.color_at(y, x) as u16);
+				img.metadata.cfa = cfa;
+				img.orient();
+
+				for y in 0..img.height {
+					for x in 0..img.width {
+						assert_eq!(
+							img.data[y * img.width + x] as usize,
+							img.metadata.cfa.color_at(y, x),
+							"{pattern} {orientation:?} at {x}, {y}"
+						);
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn source_rect_covers_the_same_pixels() {
+		let (x, y, width, height) = (1, 0, 2, 2);
+		for orientation in ALL {
+			// Ones inside the rectangle once it's oriented, zeros outside
+			let (sx, sy, sw, sh) = orientation.source_rect(x, y, width, height, 5, 3).unwrap();
+			let mut img = image(5, 3, orientation, |px, py| {
+				(px >= sx && px < sx + sw && py >= sy && py < sy + sh) as u16
+			});
+			img.orient();
+
+			for py in 0..img.height {
+				for px in 0..img.width {
+					let inside = px >= x && px < x + width && py >= y && py < y + height;
+					assert_eq!(
+						img.data[py * img.width + px] == 1,
+						inside,
+						"{orientation:?} at {px}, {py}"
+					);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn source_rect_outside() {
+		// 5x3 turned on its side is 3x5
+		assert_eq!(
+			Orientation::Rotate90.source_rect(0, 0, 3, 5, 5, 3),
+			Some((0, 0, 5, 3))
+		);
+		assert_eq!(Orientation::Rotate90.source_rect(0, 0, 5, 3, 5, 3), None);
+		assert_eq!(Orientation::Normal.source_rect(4, 2, 2, 1, 5, 3), None);
+		assert_eq!(Orientation::Normal.source_rect(0, 0, 0, 1, 5, 3), None);
+		assert_eq!(
+			Orientation::Normal.source_rect(usize::MAX, 0, 1, 1, 5, 3),
+			None
+		);
+	}
+
+	#[test]
+	fn orienting_twice_does_nothing_more() {
+		for orientation in ALL {
+			let mut img = image(3, 2, orientation, |x, y| (y * 3 + x) as u16);
+			img.orient();
+			let once = (img.width, img.height, img.data.clone());
+
+			img.orient();
+			assert_eq!((img.width, img.height, img.data), once, "{orientation:?}");
+		}
+	}
+}
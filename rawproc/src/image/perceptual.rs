@@ -0,0 +1,174 @@
+//! Saturation, vibrance, and hue edits in the perceptual colourspaces. They
+//! change colourfulness and hue without moving lightness, which editing in
+//! [Hsv](crate::colorspace::Hsv) can't promise.
+
+use crate::{
+	algorithms,
+	colorspace::{LCh, Lab, OkLCh, Oklab},
+};
+
+use super::Image;
+
+macro_rules! impl_perceptual_polar {
+	($colorspace:path, $full:expr) => {
+		impl Image<f32, $colorspace> {
+			/// Multiply the chroma of every pixel by `scalar`.
+			pub fn saturation(&mut self, scalar: f32) {
+				for px in self.data.chunks_mut(3) {
+					px[1] = (px[1] * scalar).max(0.0);
+				}
+			}
+
+			/// Boost the chroma of the duller colours by up to `amount`, leaving
+			/// the already colourful ones mostly alone. Negative values mute.
+			pub fn vibrance(&mut self, amount: f32) {
+				for px in self.data.chunks_mut(3) {
+					px[1] = algorithms::vibrance(px[1], amount, $full);
+				}
+			}
+
+			/// Turn every hue by `degrees`.
+			pub fn hue_rotate(&mut self, degrees: f32) {
+				for px in self.data.chunks_mut(3) {
+					px[2] = (px[2] + degrees).rem_euclid(360.0);
+				}
+			}
+		}
+	};
+}
+
+macro_rules! impl_perceptual_rectangular {
+	($colorspace:path, $full:expr) => {
+		impl Image<f32, $colorspace> {
+			/// Multiply the chroma of every pixel by `scalar`.
+			pub fn saturation(&mut self, scalar: f32) {
+				for px in self.data.chunks_mut(3) {
+					px[1] *= scalar;
+					px[2] *= scalar;
+				}
+			}
+
+			/// Boost the chroma of the duller colours by up to `amount`, leaving
+			/// the already colourful ones mostly alone. Negative values mute.
+			pub fn vibrance(&mut self, amount: f32) {
+				for px in self.data.chunks_mut(3) {
+					let (c, h) = algorithms::pixel_ab_to_ch(px[1], px[2]);
+					let c = algorithms::vibrance(c, amount, $full);
+					let (a, b) = algorithms::pixel_ch_to_ab(c, h);
+					px[1] = a;
+					px[2] = b;
+				}
+			}
+
+			/// Turn every hue by `degrees`.
+			pub fn hue_rotate(&mut self, degrees: f32) {
+				let (sin, cos) = degrees.to_radians().sin_cos();
+				for px in self.data.chunks_mut(3) {
+					let (a, b) = (px[1], px[2]);
+					px[1] = a * cos - b * sin;
+					px[2] = a * sin + b * cos;
+				}
+			}
+		}
+	};
+}
+
+// The chroma of about the most colourful thing sRGB can show
+impl_perceptual_rectangular!(Lab, 130.0);
+impl_perceptual_polar!(LCh, 130.0);
+impl_perceptual_rectangular!(Oklab, 0.32);
+impl_perceptual_polar!(OkLCh, 0.32);
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{colorspace::Colorspace, image::RawMetadata};
+
+	fn image<C: Colorspace>(data: &[f32]) -> Image<f32, C> {
+		Image::from_raw_parts(data.len() / 3, 1, RawMetadata::test("RGGB"), data.to_vec())
+	}
+
+	fn lightness<C: Colorspace>(img: &Image<f32, C>) -> Vec<f32> {
+		img.data.chunks(3).map(|px| px[0]).collect()
+	}
+
+	macro_rules! assert_keeps_lightness {
+		($colorspace:ty, $data:expr) => {
+			let mut img = image::<$colorspace>(&$data);
+			let before = lightness(&img);
+
+			img.saturation(1.5);
+			assert_eq!(lightness(&img), before, "saturation");
+			img.vibrance(0.5);
+			assert_eq!(lightness(&img), before, "vibrance");
+			img.vibrance(-0.5);
+			assert_eq!(lightness(&img), before, "vibrance");
+			img.hue_rotate(135.0);
+			assert_eq!(lightness(&img), before, "hue_rotate");
+		};
+	}
+
+	#[test]
+	fn lightness_is_kept() {
+		assert_keeps_lightness!(Lab, [50.0, 30.0, -40.0, 80.0, 0.0, 0.0, 20.0, -5.0, 90.0]);
+		assert_keeps_lightness!(LCh, [50.0, 50.0, 307.0, 80.0, 0.0, 0.0, 20.0, 90.0, 93.0]);
+		assert_keeps_lightness!(Oklab, [0.5, 0.1, -0.2, 0.8, 0.0, 0.0, 0.2, -0.02, 0.3]);
+		assert_keeps_lightness!(OkLCh, [0.5, 0.2, 297.0, 0.8, 0.0, 0.0, 0.2, 0.3, 94.0]);
+	}
+
+	#[test]
+	fn rectangular_and_polar_agree() {
+		let mut lab = image::<Lab>(&[50.0, 30.0, -40.0, 20.0, -5.0, 90.0]);
+		let mut lch: Image<f32, LCh> = image::<Lab>(&lab.data).into();
+
+		lab.saturation(1.5);
+		lch.saturation(1.5);
+		lab.vibrance(0.3);
+		lch.vibrance(0.3);
+		lab.hue_rotate(100.0);
+		lch.hue_rotate(100.0);
+
+		let lch: Image<f32, Lab> = lch.into();
+		for (got, expected) in lch.data.iter().zip(lab.data.iter()) {
+			assert!(
+				(got - expected).abs() < 1e-3,
+				"{:?} {:?}",
+				lch.data,
+				lab.data
+			);
+		}
+	}
+
+	#[test]
+	fn vibrance_favours_the_dull() {
+		let mut lch = image::<LCh>(&[50.0, 10.0, 0.0, 50.0, 100.0, 0.0, 50.0, 140.0, 0.0]);
+		lch.vibrance(0.5);
+
+		let dull = lch.data[1] / 10.0;
+		let vivid = lch.data[4] / 100.0;
+		assert!(dull > vivid && vivid > 1.0, "{:?}", lch.data);
+		// Past what sRGB shows is left be
+		assert_eq!(lch.data[7], 140.0);
+	}
+
+	#[test]
+	fn hue_wraps() {
+		let mut lch = image::<LCh>(&[50.0, 20.0, 350.0]);
+		lch.hue_rotate(20.0);
+		assert!((lch.data[2] - 10.0).abs() < 1e-4);
+
+		lch.hue_rotate(-30.0);
+		assert!((lch.data[2] - 340.0).abs() < 1e-4);
+	}
+
+	#[test]
+	fn no_saturation_is_grey() {
+		let mut oklab = image::<Oklab>(&[0.5, 0.1, -0.2]);
+		oklab.saturation(0.0);
+		assert_eq!(oklab.data, [0.5, 0.0, 0.0]);
+
+		let mut oklch = image::<OkLCh>(&[0.5, 0.2, 297.0]);
+		oklch.saturation(-1.0);
+		assert_eq!(oklch.data[1], 0.0);
+	}
+}
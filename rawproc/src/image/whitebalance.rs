@@ -0,0 +1,472 @@
+use nalgebra::{Matrix3x1, Matrix4x1};
+
+use super::RawMetadata;
+
+/// A value this close to the whitelevel is treated as clipped and left out of
+/// an estimate. Clipped values don't tell us about colour.
+const CLIPPED_AT: f32 = 0.99;
+
+/// Which light the image should be balanced for. Anything that's the colour of
+/// this light comes out neutral.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WhiteBalance {
+	/// Whatever is in [RawMetadata::whitebalance]. Right after decoding that's
+	/// what the camera picked.
+	AsShot,
+	/// Midday sun, 5500K.
+	Daylight,
+	/// Incandescent bulbs, 2850K.
+	Tungsten,
+	/// Under an open, blue sky. 7500K.
+	Shade,
+	/// Electronic flash, 6000K.
+	Flash,
+	/// A correlated colour temperature in kelvin and a tint. The tint moves the
+	/// white off the blackbody curve and is in thousandths of Δuv. Positive is
+	/// toward magenta, negative is toward green.
+	Temperature { kelvin: f32, tint: f32 },
+	/// Guess from the image itself. Good for files the camera didn't leave
+	/// a whitebalance in, and for composites.
+	Auto(Estimator),
+}
+
+/// Ways of guessing the whitebalance from what's in the image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Estimator {
+	/// Assume everything averages out to grey.
+	GreyWorld,
+	/// Assume the brightest value of every channel is white. Also called
+	/// white patch.
+	MaxRgb,
+	/// Like [Estimator::MaxRgb], but take this percentile, from 0 to 100, of
+	/// every channel instead of the brightest value. A few hot pixels or
+	/// specular highlights won't throw it off.
+	Percentile(f32),
+}
+
+impl Estimator {
+	/// Red, green, and blue multipliers, green at 1.0, from every value of
+	/// each of the three channels. The values should be normalized so that
+	/// the whitelevel is 1.0.
+	pub(crate) fn estimate(&self, mut channels: [Vec<f32>; 3]) -> [f32; 3] {
+		for values in channels.iter_mut() {
+			let unclipped: Vec<f32> = values.iter().copied().filter(|v| *v < CLIPPED_AT).collect();
+
+			// If everything was at the top, it's all we've got
+			if !unclipped.is_empty() {
+				*values = unclipped;
+			}
+		}
+
+		let stats = channels.map(|mut values| {
+			if values.is_empty() {
+				return 0.0;
+			}
+
+			match self {
+				Estimator::GreyWorld => values.iter().sum::<f32>() / values.len() as f32,
+				Estimator::MaxRgb => values.iter().copied().fold(0.0, f32::max),
+				Estimator::Percentile(percentile) => {
+					let rank = (percentile.clamp(0.0, 100.0) / 100.0) * (values.len() - 1) as f32;
+					let (_, value, _) =
+						values.select_nth_unstable_by(rank.round() as usize, |a, b| a.total_cmp(b));
+					*value
+				}
+			}
+		});
+
+		let green = stats[1];
+		stats.map(|stat| {
+			if stat > 0.0 && green > 0.0 {
+				green / stat
+			} else {
+				1.0
+			}
+		})
+	}
+}
+
+impl WhiteBalance {
+	/// The colour temperature and tint of the presets. [WhiteBalance::AsShot]
+	/// and [WhiteBalance::Auto] don't have one.
+	pub fn temperature(&self) -> Option<(f32, f32)> {
+		match self {
+			WhiteBalance::AsShot | WhiteBalance::Auto(_) => None,
+			WhiteBalance::Daylight => Some((5500.0, 0.0)),
+			WhiteBalance::Tungsten => Some((2850.0, 0.0)),
+			WhiteBalance::Shade => Some((7500.0, 0.0)),
+			WhiteBalance::Flash => Some((6000.0, 0.0)),
+			WhiteBalance::Temperature { kelvin, tint } => Some((*kelvin, *tint)),
+		}
+	}
+
+	/// The per-CFA-colour multipliers that make this light neutral, with green
+	/// at 1.0 like the camera gives us.
+	///
+	/// The white is taken through XYZ and into the camera's space with the
+	/// inverse of [RawMetadata::cam_to_xyz], or with
+	/// [RawMetadata::xyz_to_cam4] on four colour sensors. If the matrix can't
+	/// be inverted we stay with the metadata's whitebalance.
+	///
+	/// `None` for [WhiteBalance::Auto], which needs the image to guess from.
+	/// Use the whitebalance functions on the image for that.
+	pub fn multipliers(&self, metadata: &RawMetadata) -> Option<[f32; 4]> {
+		let (kelvin, tint) = match self {
+			WhiteBalance::AsShot => return Some(metadata.whitebalance),
+			WhiteBalance::Auto(_) => return None,
+			wb => wb.temperature()?,
+		};
+		let white = temperature_to_xyz(kelvin, tint);
+
+		let camera = if let Some(xyz_to_cam4) = metadata.xyz_to_cam4.as_ref() {
+			let cam: Matrix4x1<f32> = xyz_to_cam4 * white;
+			[cam[0], cam[1], cam[2], cam[3]]
+		} else {
+			let xyz_to_cam = match metadata.cam_to_xyz.try_inverse() {
+				Some(inv) => inv,
+				None => return Some(metadata.whitebalance),
+			};
+			let cam = xyz_to_cam * white;
+			[cam[0], cam[1], cam[2], cam[1]]
+		};
+
+		// We want to divide each colour by how much of the light it sees
+		let mut multipliers = camera.map(|c| if c > 0.0 { 1.0 / c } else { 1.0 });
+		let green = multipliers[1];
+		for mul in multipliers.iter_mut() {
+			*mul /= green;
+		}
+
+		Some(multipliers)
+	}
+}
+
+/// Multipliers that make a patch with these per-colour totals neutral, with
+/// green at 1.0. Colours that weren't seen at all, or were black, keep
+/// whatever they had in `current`.
+pub(crate) fn patch_multipliers(sums: [f32; 4], counts: [usize; 4], current: [f32; 4]) -> [f32; 4] {
+	let means: Vec<Option<f32>> = sums
+		.iter()
+		.zip(counts)
+		.map(|(sum, count)| Some(sum / count as f32).filter(|mean| count > 0 && *mean > 0.0))
+		.collect();
+
+	let green = match means[1] {
+		Some(green) => green,
+		None => return current,
+	};
+
+	let mut multipliers = current;
+	for (mul, mean) in multipliers.iter_mut().zip(means) {
+		if let Some(mean) = mean {
+			*mul = green / mean;
+		}
+	}
+
+	multipliers
+}
+
+/// The XYZ, with Y at 1.0, of a blackbody at `kelvin` that's been moved off
+/// the curve by `tint` thousandths of Δuv.
+///
+/// Uses Krystek's approximation of the Planckian locus in CIE 1960 UCS, which
+/// is good from 1000K to 15000K. Temperatures outside that are clamped.
+/// <https://en.wikipedia.org/wiki/Planckian_locus#Approximation>
+pub fn temperature_to_xyz(kelvin: f32, tint: f32) -> Matrix3x1<f32> {
+	let kelvin = kelvin.clamp(1000.0, 15000.0);
+
+	let (u, v) = planckian_uv(kelvin);
+
+	// The direction the curve is going in so we can step perpendicular to it.
+	// The curve heads toward lower u as it gets hotter, so turning left from
+	// it takes us below the curve, toward magenta.
+	let (next_u, next_v) = planckian_uv(kelvin + 1.0);
+	let (du, dv) = (next_u - u, next_v - v);
+	let len = (du * du + dv * dv).sqrt();
+	let (normal_u, normal_v) = (-dv / len, du / len);
+
+	let duv = tint / 1000.0;
+	let (u, v) = (u + normal_u * duv, v + normal_v * duv);
+
+	let denominator = 2.0 * u - 8.0 * v + 4.0;
+	let x = 3.0 * u / denominator;
+	let y = 2.0 * v / denominator;
+
+	Matrix3x1::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+fn planckian_uv(kelvin: f32) -> (f32, f32) {
+	// Squaring kelvin loses too much in an f32
+	let t = kelvin as f64;
+	let t2 = t * t;
+
+	let u = (0.860117757 + 1.54118254e-4 * t + 1.28641212e-7 * t2)
+		/ (1.0 + 8.42420235e-4 * t + 7.08145163e-7 * t2);
+	let v = (0.317398726 + 4.22806245e-5 * t + 4.20481691e-8 * t2)
+		/ (1.0 - 2.89741816e-5 * t + 1.61456053e-7 * t2);
+
+	(u as f32, v as f32)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn clipping_is_at_the_whitelevel() {
+		// Green's brightest is clipped and should be left out, but red and
+		// blue are nowhere near white so all of them count
+		let channels = [vec![0.2, 0.4], vec![0.8, 1.0], vec![0.1, 0.4]];
+		let multipliers = Estimator::MaxRgb.estimate(channels);
+		assert_eq!(multipliers, [2.0, 1.0, 2.0]);
+	}
+
+	#[test]
+	fn everything_clipped_is_still_used() {
+		let channels = [vec![1.0], vec![1.0, 1.0], vec![1.0]];
+		assert_eq!(Estimator::GreyWorld.estimate(channels), [1.0; 3]);
+	}
+
+	/// The channels a camera that sees red at half and blue at a quarter of
+	/// green gets from `scene`
+	fn cast(scene: &[[f32; 3]]) -> [Vec<f32>; 3] {
+		let cast = [0.5, 1.0, 0.25];
+		[0, 1, 2].map(|c| scene.iter().map(|rgb| rgb[c] * cast[c]).collect())
+	}
+
+	fn greys() -> Vec<[f32; 3]> {
+		(1..10).map(|step| [step as f32 / 10.0; 3]).collect()
+	}
+
+	fn assert_multipliers(got: [f32; 3], expected: [f32; 3], estimator: Estimator) {
+		for c in 0..3 {
+			assert!(
+				(got[c] - expected[c]).abs() < 1e-5,
+				"{estimator:?}: got {got:?}, expected {expected:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn estimators_undo_the_cast() {
+		for estimator in [
+			Estimator::GreyWorld,
+			Estimator::MaxRgb,
+			Estimator::Percentile(0.0),
+			Estimator::Percentile(50.0),
+			Estimator::Percentile(90.0),
+			Estimator::Percentile(100.0),
+		] {
+			let multipliers = estimator.estimate(cast(&greys()));
+			assert_multipliers(multipliers, [2.0, 1.0, 4.0], estimator);
+		}
+	}
+
+	#[test]
+	fn estimators_on_a_coloured_scene() {
+		// As much of a red object as there is grey
+		let mut scene = greys();
+		scene.extend([[0.8, 0.1, 0.1]; 9]);
+		let channels = cast(&scene);
+
+		// The red drags the average with it, which is grey world's weakness.
+		// Red is (2.25 + 3.6) / 18, green (4.5 + 0.9) / 18, and blue
+		// (1.125 + 0.225) / 18
+		let grey_world = Estimator::GreyWorld.estimate(channels.clone());
+		assert_multipliers(grey_world, [5.4 / 5.85, 1.0, 4.0], Estimator::GreyWorld);
+
+		// The brightest grey is still brighter than the red, so these don't
+		// notice it
+		let max = Estimator::MaxRgb.estimate(channels.clone());
+		assert_multipliers(max, [2.0, 1.0, 4.0], Estimator::MaxRgb);
+		let top = Estimator::Percentile(100.0).estimate(channels.clone());
+		assert_multipliers(top, [2.0, 1.0, 4.0], Estimator::Percentile(100.0));
+
+		// The 90th percentile of 18 values is the 16th smallest. In green
+		// that's the 0.7 grey, but the red object fills the top of red so it's
+		// 0.4 there instead of 0.35
+		let ninetieth = Estimator::Percentile(90.0).estimate(channels);
+		assert_multipliers(
+			ninetieth,
+			[0.7 / 0.4, 1.0, 0.7 / 0.175],
+			Estimator::Percentile(90.0),
+		);
+	}
+
+	#[test]
+	fn auto_has_no_multipliers_without_the_image() {
+		let mut metadata = RawMetadata::test("RGGB");
+		metadata.whitebalance = [2.0, 1.0, 1.5, 1.0];
+
+		let auto = WhiteBalance::Auto(Estimator::GreyWorld);
+		assert_eq!(auto.multipliers(&metadata), None);
+		assert_eq!(
+			WhiteBalance::AsShot.multipliers(&metadata),
+			Some(metadata.whitebalance)
+		);
+	}
+
+	fn xy(xyz: Matrix3x1<f32>) -> (f32, f32) {
+		let sum = xyz.sum();
+		(xyz[0] / sum, xyz[1] / sum)
+	}
+
+	fn uv(xyz: Matrix3x1<f32>) -> (f32, f32) {
+		let denominator = xyz[0] + 15.0 * xyz[1] + 3.0 * xyz[2];
+		(4.0 * xyz[0] / denominator, 6.0 * xyz[1] / denominator)
+	}
+
+	#[test]
+	fn planckian_chromaticities() {
+		// Illuminant A is a blackbody at 2856K. The others are points on the
+		// locus worked out from Planck's law
+		let known = [
+			(2856.0, (0.44757, 0.40745)),
+			(4000.0, (0.38045, 0.37674)),
+			(5000.0, (0.34510, 0.35162)),
+			(6500.0, (0.31352, 0.32363)),
+		];
+
+		for (kelvin, (x, y)) in known {
+			let xyz = temperature_to_xyz(kelvin, 0.0);
+			assert_eq!(xyz[1], 1.0);
+
+			let (got_x, got_y) = xy(xyz);
+			assert!(
+				(got_x - x).abs() < 5e-4 && (got_y - y).abs() < 5e-4,
+				"{kelvin}K: got {got_x}, {got_y}, expected {x}, {y}"
+			);
+		}
+	}
+
+	#[test]
+	fn temperatures_are_clamped() {
+		assert_eq!(
+			temperature_to_xyz(500.0, 0.0),
+			temperature_to_xyz(1000.0, 0.0)
+		);
+		assert_eq!(
+			temperature_to_xyz(40000.0, 0.0),
+			temperature_to_xyz(15000.0, 0.0)
+		);
+	}
+
+	#[test]
+	fn positive_tint_is_magenta() {
+		for kelvin in [2856.0, 5000.0, 6500.0] {
+			let (u, v) = uv(temperature_to_xyz(kelvin, 0.0));
+			let (magenta_u, magenta_v) = uv(temperature_to_xyz(kelvin, 10.0));
+			let (green_u, green_v) = uv(temperature_to_xyz(kelvin, -10.0));
+
+			// Magenta is below the locus, less green, and green above it
+			assert!(magenta_v < v && green_v > v, "{kelvin}K");
+			assert!(xy(temperature_to_xyz(kelvin, 10.0)).1 < xy(temperature_to_xyz(kelvin, 0.0)).1);
+
+			// Ten thousandths of Δuv away either way
+			for (tu, tv) in [(magenta_u, magenta_v), (green_u, green_v)] {
+				let distance = ((tu - u).powi(2) + (tv - v).powi(2)).sqrt();
+				assert!((distance - 0.01).abs() < 1e-4, "{kelvin}K moved {distance}");
+			}
+		}
+	}
+
+	#[test]
+	fn presets() {
+		assert_eq!(WhiteBalance::Daylight.temperature(), Some((5500.0, 0.0)));
+		assert_eq!(WhiteBalance::Tungsten.temperature(), Some((2850.0, 0.0)));
+		assert_eq!(WhiteBalance::Shade.temperature(), Some((7500.0, 0.0)));
+		assert_eq!(WhiteBalance::Flash.temperature(), Some((6000.0, 0.0)));
+		assert_eq!(
+			WhiteBalance::Temperature {
+				kelvin: 3200.0,
+				tint: 5.0
+			}
+			.temperature(),
+			Some((3200.0, 5.0))
+		);
+		assert_eq!(WhiteBalance::AsShot.temperature(), None);
+	}
+
+	#[test]
+	fn multipliers_neutralize_the_light() {
+		// A camera that sees XYZ
+		let metadata = RawMetadata::test("RGGB");
+
+		for wb in [
+			WhiteBalance::Tungsten,
+			WhiteBalance::Daylight,
+			WhiteBalance::Shade,
+		] {
+			let (kelvin, tint) = wb.temperature().unwrap();
+			let white = temperature_to_xyz(kelvin, tint);
+			let multipliers = wb.multipliers(&metadata).unwrap();
+
+			assert_eq!(multipliers[1], 1.0);
+			assert_eq!(multipliers[3], 1.0);
+			for c in 0..3 {
+				assert!((white[c] * multipliers[c] - 1.0).abs() < 1e-5, "{wb:?}");
+			}
+		}
+
+		// Warmer light needs less red and more blue
+		let tungsten = WhiteBalance::Tungsten.multipliers(&metadata).unwrap();
+		let shade = WhiteBalance::Shade.multipliers(&metadata).unwrap();
+		assert!(tungsten[0] < shade[0] && tungsten[2] > shade[2]);
+	}
+
+	#[test]
+	fn multipliers_without_an_inverse() {
+		let mut metadata = RawMetadata::test("RGGB");
+		metadata.whitebalance = [2.0, 1.0, 1.5, 1.0];
+		metadata.cam_to_xyz = nalgebra::Matrix3::zeros();
+
+		assert_eq!(
+			WhiteBalance::Daylight.multipliers(&metadata),
+			Some(metadata.whitebalance)
+		);
+	}
+
+	#[test]
+	fn four_colour_multipliers() {
+		let mut metadata = RawMetadata::test("RGEB");
+		// The emerald sees half of what green does
+		#[rustfmt::skip]
+		let xyz_to_cam4 = nalgebra::Matrix4x3::new(
+			1.0, 0.0, 0.0,
+			0.0, 1.0, 0.0,
+			0.0, 0.0, 1.0,
+			0.0, 0.5, 0.0,
+		);
+		metadata.xyz_to_cam4 = Some(xyz_to_cam4);
+
+		let multipliers = WhiteBalance::Daylight.multipliers(&metadata).unwrap();
+		assert!((multipliers[3] - 2.0).abs() < 1e-5, "{multipliers:?}");
+	}
+
+	#[test]
+	fn patch_multipliers_against_green() {
+		let current = [2.0, 1.0, 1.5, 3.0];
+		// Two of each, the emerald wasn't seen
+		let sums = [0.4, 0.8, 1.6, 0.0];
+		assert_eq!(
+			patch_multipliers(sums, [2, 2, 2, 0], current),
+			[2.0, 1.0, 0.5, 3.0]
+		);
+
+		// A black red keeps what it had
+		assert_eq!(
+			patch_multipliers([0.0, 0.8, 1.6, 0.0], [2, 2, 2, 0], current),
+			[2.0, 1.0, 0.5, 3.0]
+		);
+
+		// Without green there's nothing to be relative to
+		assert_eq!(
+			patch_multipliers([0.4, 0.0, 1.6, 0.0], [2, 2, 2, 0], current),
+			current
+		);
+		assert_eq!(
+			patch_multipliers([0.4, 0.8, 1.6, 0.0], [2, 0, 2, 0], current),
+			current
+		);
+	}
+}
@@ -1,8 +1,12 @@
 use nalgebra::{Matrix3, Matrix3x1};
 
-use crate::colorspace::{LinSrgb, XYZ};
+use crate::{
+	adaptation::{self, Transform, WhitePoint},
+	colorspace::{LinSrgb, ProPhoto, RgbSpace, XYZ},
+	profile::CameraProfile,
+};
 
-use super::Image;
+use super::{gamut::GamutMapping, Image};
 
 impl Image<u16, XYZ> {
 	//TODO: gen-
@@ -10,20 +14,14 @@ impl Image<u16, XYZ> {
 	//get from the camera I guess? I don't know how to make it D65. I'm already
 	//trying to chromatically-shove it into D65.
 	pub fn to_linsrgb(mut self) -> Image<u16, LinSrgb> {
-		let cam_reference = self.metadata.cam_to_xyz * Matrix3x1::new(1.0, 1.0, 1.0);
+		let cam_reference = self.metadata.white();
 		let srgb_reference = BRUCE_XYZ_SRGB.try_inverse().unwrap() * Matrix3x1::new(1.0, 1.0, 1.0);
 
-		let cam_cones = BRADFORD * cam_reference;
-		let srgb_cones = BRADFORD * srgb_reference;
-
-		#[rustfmt::skip]
-		let difference_matrix = Matrix3::new(
-			srgb_cones[0] / cam_cones[0], 0.0, 0.0,
-			0.0, srgb_cones[1] / cam_cones[1], 0.0,
-			0.0, 0.0, srgb_cones[2] / cam_cones[2]
+		let chromatic_adaptation_matrix = adaptation::matrix(
+			Transform::Bradford,
+			cam_reference.into(),
+			srgb_reference.into(),
 		);
-
-		let chromatic_adaptation_matrix = BRADFORD_INV * difference_matrix * BRADFORD;
 		let premul_trans = BRUCE_XYZ_SRGB * chromatic_adaptation_matrix;
 
 		for px in self.data.chunks_mut(3) {
@@ -40,6 +38,88 @@ impl Image<u16, XYZ> {
 
 		self.change_colorspace(None)
 	}
+
+	/// Convert to an RGB colourspace, like [DisplayP3](crate::colorspace::DisplayP3)
+	/// or [ProPhoto](crate::colorspace::ProPhoto), and apply its transfer
+	/// function. The white of the camera is adapted to the white of the
+	/// colourspace, the same as [Self::to_linsrgb] does for sRGB.
+	///
+	/// Colours the colourspace can't hold are brought in with `mapping`.
+	pub fn to_rgb<S: RgbSpace>(mut self, mapping: GamutMapping) -> Image<u16, S> {
+		let cam_reference = self.metadata.white();
+
+		let rgb_to_xyz = S::rgb_to_xyz();
+		let chromatic_adaptation_matrix =
+			adaptation::matrix(Transform::Bradford, cam_reference.into(), S::WHITE);
+		let premul_trans = rgb_to_xyz.try_inverse().unwrap() * chromatic_adaptation_matrix;
+		let luminance = [rgb_to_xyz[(1, 0)], rgb_to_xyz[(1, 1)], rgb_to_xyz[(1, 2)]];
+
+		for px in self.data.chunks_mut(3) {
+			let m = Matrix3x1::new(
+				px[0] as f32 / self.metadata.whitelevels[0] as f32,
+				px[1] as f32 / self.metadata.whitelevels[1] as f32,
+				px[2] as f32 / self.metadata.whitelevels[2] as f32,
+			);
+			let res = premul_trans * m;
+
+			let mut rgb = [res[0], res[1], res[2]];
+			mapping.map(&mut rgb, luminance);
+
+			px[0] = (S::encode(rgb[0]) * self.metadata.whitelevels[0] as f32) as u16;
+			px[1] = (S::encode(rgb[1]) * self.metadata.whitelevels[1] as f32) as u16;
+			px[2] = (S::encode(rgb[2]) * self.metadata.whitelevels[2] as f32) as u16;
+		}
+
+		self.change_colorspace(None)
+	}
+
+	/// Put a camera profile's hue/sat map, look table, and tone curve on the
+	/// image. They work on linear ProPhoto with a D50 white, so we adapt the
+	/// camera's white there and back again around them.
+	pub fn apply_looks(&mut self, profile: &CameraProfile) {
+		let cam_reference = self.metadata.white();
+		let map = profile.hue_sat_map(WhitePoint::from(cam_reference).xy());
+
+		let to_d50 = adaptation::matrix(Transform::Bradford, cam_reference.into(), WhitePoint::D50);
+		let prophoto_to_xyz = ProPhoto::rgb_to_xyz();
+		// Both of these are invertible
+		let to_prophoto = prophoto_to_xyz.try_inverse().unwrap() * to_d50;
+		let from_prophoto = to_d50.try_inverse().unwrap() * prophoto_to_xyz;
+
+		for px in self.data.chunks_mut(3) {
+			let m = Matrix3x1::new(
+				px[0] as f32 / self.metadata.whitelevels[0] as f32,
+				px[1] as f32 / self.metadata.whitelevels[1] as f32,
+				px[2] as f32 / self.metadata.whitelevels[2] as f32,
+			);
+			let rgb = to_prophoto * m;
+
+			let mut rgb = [rgb[0], rgb[1], rgb[2]];
+			profile.apply_looks(map.as_ref(), &mut rgb);
+
+			let res = from_prophoto * Matrix3x1::new(rgb[0], rgb[1], rgb[2]);
+			px[0] = (res[0] * self.metadata.whitelevels[0] as f32) as u16;
+			px[1] = (res[1] * self.metadata.whitelevels[1] as f32) as u16;
+			px[2] = (res[2] * self.metadata.whitelevels[2] as f32) as u16;
+		}
+	}
+
+	/// Move the colours from the `from` reference white to the `to` one.
+	pub fn adapt(&mut self, transform: Transform, from: WhitePoint, to: WhitePoint) {
+		let adapt = adaptation::matrix(transform, from, to);
+
+		for px in self.data.chunks_mut(3) {
+			let m = Matrix3x1::new(
+				px[0] as f32 / self.metadata.whitelevels[0] as f32,
+				px[1] as f32 / self.metadata.whitelevels[1] as f32,
+				px[2] as f32 / self.metadata.whitelevels[2] as f32,
+			);
+			let res = adapt * m;
+			px[0] = (res[0] * self.metadata.whitelevels[0] as f32) as u16;
+			px[1] = (res[1] * self.metadata.whitelevels[1] as f32) as u16;
+			px[2] = (res[2] * self.metadata.whitelevels[2] as f32) as u16;
+		}
+	}
 }
 
 // Assumes D65 white
@@ -57,23 +137,79 @@ pub const BRUCE_XYZ_SRGB: Matrix3<f32> = Matrix3::new(
  0.0556434, -0.2040259,  1.0572252
 );
 
-// This is a method of "chromatic adaption", which is fancy speak for switching
-// between reference whites? I didn't know that. This is how you change the
-// whitebalance in an image!
-// http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html
 #[rustfmt::skip]
-const XYZ_SCALING: Matrix3<f32> = Matrix3::new(
-	1.0, 0.0, 0.0,
-	0.0, 1.0, 0.0,
-	0.0, 0.0, 1.0
+pub const BRUCE_SRGB_XYZ: Matrix3<f32> = Matrix3::new(
+	0.4124564, 0.3575761, 0.1804375,
+	0.2126729, 0.7151522, 0.072175,
+	0.0193339, 0.119192,  0.9503041
 );
 
-const BRADFORD: Matrix3<f32> = Matrix3::new(
-	0.8951000, 0.2664000, -0.1614000, -0.7502000, 1.7135000, 0.0367000, 0.0389000, -0.0685000,
-	1.0296000,
-);
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{
+		colorspace::{AdobeRgb, DisplayP3, Rec2020},
+		image::RawMetadata,
+	};
 
-const BRADFORD_INV: Matrix3<f32> = Matrix3::new(
-	0.9869929, -0.1470543, 0.1599627, 0.4323053, 0.5183603, 0.0492912, -0.0085287, 0.0400428,
-	0.9684867,
-);
+	/// A pixel of `xyz` as a fraction of white, seen by a camera that sees
+	/// XYZ and has an equal energy white
+	fn pixel(xyz: [f32; 3]) -> Image<u16, XYZ> {
+		let data = xyz.map(|v| (v * u16::MAX as f32) as u16).to_vec();
+		Image::from_raw_parts(1, 1, RawMetadata::test("RGGB"), data)
+	}
+
+	fn assert_rgb<S: RgbSpace>(xyz: [f32; 3], expected: [f32; 3]) {
+		for mapping in [
+			GamutMapping::Clip,
+			GamutMapping::Desaturate,
+			GamutMapping::Compress,
+		] {
+			let rgb = pixel(xyz).to_rgb::<S>(mapping);
+			for c in 0..3 {
+				let got = rgb.data[c] as f32 / u16::MAX as f32;
+				assert!(
+					(got - expected[c]).abs() < 1e-3,
+					"{mapping:?} {xyz:?}: got {:?}, expected {expected:?}",
+					rgb.data
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn white_is_white() {
+		assert_rgb::<DisplayP3>([1.0; 3], [1.0; 3]);
+		assert_rgb::<Rec2020>([1.0; 3], [1.0; 3]);
+		assert_rgb::<AdobeRgb>([1.0; 3], [1.0; 3]);
+		assert_rgb::<ProPhoto>([1.0; 3], [1.0; 3]);
+	}
+
+	#[test]
+	fn grey_is_encoded() {
+		let grey = [0.2; 3];
+		assert_rgb::<DisplayP3>(grey, [DisplayP3::encode(0.2); 3]);
+		assert_rgb::<Rec2020>(grey, [Rec2020::encode(0.2); 3]);
+		assert_rgb::<AdobeRgb>(grey, [AdobeRgb::encode(0.2); 3]);
+		assert_rgb::<ProPhoto>(grey, [ProPhoto::encode(0.2); 3]);
+	}
+
+	#[test]
+	fn primaries_come_out_alone() {
+		// ProPhoto's green, under its own D50 white so nothing gets adapted
+		let rgb_to_xyz = ProPhoto::rgb_to_xyz();
+		let green = rgb_to_xyz * Matrix3x1::new(0.0, 1.0, 0.0);
+		let white = rgb_to_xyz * Matrix3x1::new(1.0, 1.0, 1.0);
+
+		let mut metadata = RawMetadata::test("RGGB");
+		metadata.whitebalance = [1.0 / white[0], 1.0 / white[1], 1.0 / white[2], 1.0];
+		let data = [green[0], green[1], green[2]]
+			.map(|v| (v * u16::MAX as f32) as u16)
+			.to_vec();
+		let rgb = Image::<u16, XYZ>::from_raw_parts(1, 1, metadata, data)
+			.to_rgb::<ProPhoto>(GamutMapping::Clip);
+
+		assert!(rgb.data[0] < 8 && rgb.data[2] < 8, "{:?}", rgb.data);
+		assert!(rgb.data[1] > u16::MAX - 8, "{:?}", rgb.data);
+	}
+}
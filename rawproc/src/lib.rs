@@ -1,19 +1,32 @@
+pub mod adaptation;
 pub mod algorithms;
+pub mod capture;
 pub mod colorspace;
+pub mod icc;
 pub mod image;
+pub mod preview;
+pub mod profile;
+mod tiff;
 
-use std::io::Read;
+use std::io::{Cursor, Read};
 
-use colorspace::BayerRgb;
-use image::{Image, RawMetadata};
-use nalgebra::Matrix3;
+use capture::Capture;
+use image::{is_four_color, Image, Orientation, RawImage, RawMetadata, BRUCE_SRGB_XYZ};
+use nalgebra::{Matrix3, Matrix3x1, Matrix4x3};
+use preview::Preview;
+use profile::{AsShot, CameraProfile};
 use rand::{thread_rng, Rng};
 use rawloader::{RawImageData, RawLoaderError};
 
-use crate::image::Crop;
+use crate::{adaptation::WhitePoint, image::Crop, tiff::Tiff};
 
-pub fn decode<R: Read>(reader: &mut R) -> Result<Image<u16, BayerRgb>, Error> {
-	let image = rawloader::decode(reader)?;
+pub fn decode<R: Read>(reader: &mut R) -> Result<RawImage, Error> {
+	// We hold on to the bytes so we can go looking for the DNG colour tags
+	// and EXIF that rawloader doesn't give us
+	let mut bytes = vec![];
+	reader.read_to_end(&mut bytes)?;
+	let image = rawloader::decode(&mut Cursor::new(&bytes))?;
+	let tiff = Tiff::parse(&bytes);
 
 	// the whitebalance and a few other values are apparently RGBE, which is RGB
 	// with a shared exponent. It's weird and I don't entirely understand how to
@@ -26,70 +39,259 @@ pub fn decode<R: Read>(reader: &mut R) -> Result<Image<u16, BayerRgb>, Error> {
 	// https://en.wikipedia.org/wiki/CYGM_filter
 	// http://camera-wiki.org/wiki/Canon_PowerShot_Pro70
 	// https://www.snappiness.space/testing-the-only-rgbe-sensor-ever-made/
-	let wb_coeffs = image.wb_coeffs;
-	let whitebalance = [wb_coeffs[0], wb_coeffs[1], wb_coeffs[2]];
-	let wl = image.whitelevels;
-	let whitelevels = [wl[0], wl[1], wl[2]];
+	let mut whitebalance = image.wb_coeffs;
+	if whitebalance[3].is_nan() {
+		whitebalance[3] = 1.0;
+	}
 	let crop = Crop::from_css_quad(image.crops);
 
 	let rlm = image.xyz_to_cam;
-	#[rustfmt::skip]
-	let xyz_to_cam = Matrix3::new(
-		rlm[0][0], rlm[0][1], rlm[0][2],
-		rlm[1][0], rlm[1][1], rlm[1][2],
-		rlm[2][0], rlm[2][1], rlm[2][2],
-	);
+	let (cam_to_xyz, xyz_to_cam4) = if is_four_color(&image.cfa) {
+		#[rustfmt::skip]
+		let xyz_to_cam4 = Matrix4x3::new(
+			rlm[0][0], rlm[0][1], rlm[0][2],
+			rlm[1][0], rlm[1][1], rlm[1][2],
+			rlm[2][0], rlm[2][1], rlm[2][2],
+			rlm[3][0], rlm[3][1], rlm[3][2],
+		);
+
+		// The debayer takes these down to sRGB primaries for us
+		(BRUCE_SRGB_XYZ, Some(xyz_to_cam4))
+	} else {
+		#[rustfmt::skip]
+		let xyz_to_cam = Matrix3::new(
+			rlm[0][0], rlm[0][1], rlm[0][2],
+			rlm[1][0], rlm[1][1], rlm[1][2],
+			rlm[2][0], rlm[2][1], rlm[2][2],
+		);
+
+		(camera_to_xyz(tiff.as_ref(), xyz_to_cam, whitebalance), None)
+	};
+
+	let mut capture = tiff.as_ref().map(Capture::from_tiff).unwrap_or_default();
+	// rawloader knows the camera even when it's not a TIFF
+	if capture.make.is_none() && !image.make.is_empty() {
+		capture.make = Some(image.make.clone());
+	}
+	if capture.model.is_none() && !image.model.is_empty() {
+		capture.model = Some(image.model.clone());
+	}
 
-	#[rustfmt::skip]
-	let cam_to_xyz = xyz_to_cam.try_inverse().unwrap().normalize();
+	// rawloader only knows the orientation for some formats, but everything
+	// TIFF based has the tag
+	let orientation = Orientation::from_rawloader(image.orientation)
+		.or_else(|| tiff.as_ref().and_then(Orientation::from_tiff))
+		.unwrap_or_default();
 
 	let metadata = RawMetadata {
 		whitebalance,
+		balanced: false,
 		crop,
-		whitelevels,
+		whitelevels: image.whitelevels,
+		blacklevels: image.blacklevels,
 		cfa: image.cfa,
 		cam_to_xyz,
+		xyz_to_cam4,
+		capture,
+		orientation,
 	};
 
-	let data = match image.data {
-		RawImageData::Float(_) => return Err(Error::FloatImageData),
-		RawImageData::Integer(intu16) => intu16,
+	raw_image(image.width, image.height, metadata, image.data)
+}
+
+/// Put rawloader's data together with our metadata. Float data is in the same
+/// units as the levels, which are usually just 1.0 for those files, and comes
+/// back normalized so that 1.0 is at the whitelevel like any other float
+/// image.
+fn raw_image(
+	width: usize,
+	height: usize,
+	metadata: RawMetadata,
+	data: RawImageData,
+) -> Result<RawImage, Error> {
+	match data {
+		RawImageData::Integer(data) => Ok(RawImage::Integer(Image::try_from_raw_parts(
+			width, height, metadata, data,
+		)?)),
+		RawImageData::Float(mut data) => {
+			for (i, light) in data.iter_mut().enumerate() {
+				let (x, y) = (i % width.max(1), i / width.max(1));
+				let color = metadata.cfa.color_at(y, x);
+				*light /= metadata.whitelevels[color].max(1) as f32;
+			}
+
+			Ok(RawImage::Float(Image::try_from_raw_parts(
+				width, height, metadata, data,
+			)?))
+		}
+	}
+}
+
+/// Which way up the camera was held, without decoding the image. This only
+/// looks at the orientation tag, so it's always [Orientation::Normal] for
+/// raws that aren't TIFF based. [decode] knows about more.
+pub fn read_orientation<R: Read>(reader: &mut R) -> Result<Orientation, Error> {
+	let mut bytes = vec![];
+	reader.read_to_end(&mut bytes)?;
+
+	Ok(Tiff::parse(&bytes)
+		.as_ref()
+		.and_then(Orientation::from_tiff)
+		.unwrap_or_default())
+}
+
+/// The JPEG previews embedded in the raw, largest first, without decoding the
+/// image. NEF, CR2, ARW, DNG, and RW2 have at least one. Empty if there
+/// aren't any, or the raw isn't TIFF based.
+pub fn read_previews<R: Read>(reader: &mut R) -> Result<Vec<Preview>, Error> {
+	let mut bytes = vec![];
+	reader.read_to_end(&mut bytes)?;
+
+	Ok(Tiff::parse(&bytes)
+		.map(|tiff| Preview::from_tiff(&tiff))
+		.unwrap_or_default())
+}
+
+/// Camera to XYZ for a three colour sensor. DNGs bring their own profile, and
+/// maybe two sets of matrices for different lights. Everyone else gets the
+/// single D65 matrix rawloader has for the camera.
+fn camera_to_xyz(
+	tiff: Option<&Tiff>,
+	xyz_to_cam: Matrix3<f32>,
+	whitebalance: [f32; 4],
+) -> Matrix3<f32> {
+	let dng = tiff.and_then(CameraProfile::from_tiff);
+	let (profile, as_shot) = match dng {
+		Some((profile, as_shot)) => (profile, as_shot),
+		None => (
+			CameraProfile::from_color_matrix(xyz_to_cam, profile::D65_ILLUMINANT),
+			None,
+		),
 	};
 
-	Ok(Image {
-		width: image.width,
-		height: image.height,
-		metadata,
-		phantom: Default::default(),
+	let neutral = match as_shot {
+		Some(AsShot::Neutral(neutral)) => neutral,
+		Some(AsShot::WhiteXy(x, y)) => profile.xy_to_neutral((x, y)),
+		None if whitebalance[..3].iter().all(|c| c.is_finite() && *c > 0.0) => Matrix3x1::new(
+			1.0 / whitebalance[0],
+			1.0 / whitebalance[1],
+			1.0 / whitebalance[2],
+		),
+		// Without anything to go on, pretend it was daylight
+		None => profile.xy_to_neutral(WhitePoint::D65.xy()),
+	};
 
-		data,
-	})
+	profile
+		.camera_to_xyz(neutral)
+		.or_else(|| xyz_to_cam.try_inverse())
+		.unwrap_or_else(Matrix3::identity)
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+	#[error("{source}")]
+	Io {
+		#[from]
+		source: std::io::Error,
+	},
+	#[error("Could not read the profile: {reason}")]
+	InvalidProfile { reason: &'static str },
 	#[error("{source}")]
 	RawLoaderError {
 		#[from]
 		source: RawLoaderError,
 	},
-	#[error("Raw image data was floats. Please talk to gennyble if you want this supported")]
-	FloatImageData,
+	#[error("Expected {width}x{height} with {components} component(s) per pixel, but there were {actual} values")]
+	DataLength {
+		width: usize,
+		height: usize,
+		components: usize,
+		actual: usize,
+	},
+	#[error("Pixels have {expected} component(s), but {actual} were given")]
+	ComponentCount { expected: usize, actual: usize },
+	#[error("{x},{y} is outside the {width}x{height} image")]
+	OutOfBounds {
+		x: usize,
+		y: usize,
+		width: usize,
+		height: usize,
+	},
+	#[error("Cannot crop {crop:?} from a {width}x{height} image, nothing would be left")]
+	CropTooLarge {
+		crop: Crop,
+		width: usize,
+		height: usize,
+	},
+	#[error("The {rect_width}x{rect_height} rectangle at {x},{y} is not inside the {width}x{height} image")]
+	RectOutOfBounds {
+		x: usize,
+		y: usize,
+		rect_width: usize,
+		rect_height: usize,
+		width: usize,
+		height: usize,
+	},
 }
 
-struct RollingRandom {
+/// A fast source of random bytes. Generating a random value every time we need
+/// one is slow, so this fills a big ol' array once and walks through it,
+/// wrapping around at the end.
+///
+/// It's seeded explicitly so the same seed will always give you the same
+/// values, which means a render that used randomness can be reproduced. Keep
+/// the [seed](RollingRandom::seed) around if you want that.
+#[derive(Clone, Debug)]
+pub struct RollingRandom {
+	seed: u64,
 	values: [u8; Self::BUCKET_SIZE],
 	index: u16,
 }
 
 impl RollingRandom {
-	const BUCKET_SIZE: usize = 1024;
+	// 8.3K
+	// .3 because 4K is a common image length, and I'd rather if there is a pattern
+	// that it fall on a diagonal I guess?
+	//
+	// rawproc's copy of this used to be 1024 and dslr-trichrome's 8533. 1024
+	// goes evenly into a lot of image widths, so the same values would line up
+	// row after row, and the larger one won when they were merged.
+	const BUCKET_SIZE: usize = 8533;
+
+	pub fn new(seed: u64) -> Self {
+		// SplitMix64. We do this ourselves rather than use one of rand's rngs
+		// because those don't promise the same output across versions
+		let mut state = seed;
+		let mut next = || {
+			state = state.wrapping_add(0x9E3779B97F4A7C15);
+			let mut z = state;
+			z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+			z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+			z ^ (z >> 31)
+		};
 
-	pub fn new() -> Self {
 		let mut values = [0u8; Self::BUCKET_SIZE];
-		thread_rng().fill(&mut values[..]);
+		for chunk in values.chunks_mut(8) {
+			let bytes = next().to_le_bytes();
+			chunk.copy_from_slice(&bytes[..chunk.len()]);
+		}
 
-		Self { values, index: 0 }
+		Self {
+			seed,
+			values,
+			index: 0,
+		}
+	}
+
+	/// Seed from the thread's rng. You probably still want to look at
+	/// [RollingRandom::seed] and write it down somewhere.
+	pub fn from_entropy() -> Self {
+		Self::new(thread_rng().gen())
+	}
+
+	/// The seed this was created with
+	pub fn seed(&self) -> u64 {
+		self.seed
 	}
 
 	pub fn random_bool(&mut self) -> bool {
@@ -108,25 +310,65 @@ impl RollingRandom {
 	}
 }
 
-// Looking into rawloader's source, it does this weird normalization I can't
-// find references to anywhere else. This doesn't seem to be how matricies
-// are normally normalized, but it makes good results? Well, when I put white
-// as (1, 1, 1) in, I get (1,1,1) out which seems ideal for a colorspace that,
-// as far as I can tell, has no reference white applied at all.
-// I will be checking this against darktable.
-fn weird_rawloader_normalize(m: Matrix3<f32>) -> Matrix3<f32> {
-	let yr = m.row(1);
-	let unity = m / yr.sum(); //yr[0].max(yr[1]).max(yr[2]);
-	unity
-	/*
-	let mut terrible_things = m.clone();
-	for row_num in 0..3 {
-		let mut row = terrible_things.row_mut(row_num);
-		let yr = m.row(1);
-		let unity = m / yr[0].max(yr[1]).max(yr[2])
-		*row.get_mut(0).unwrap() = row[0] / sum;
-		*row.get_mut(1).unwrap() = row[1] / sum;
-		*row.get_mut(2).unwrap() = row[2] / sum;
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn bytes(rr: &mut RollingRandom) -> Vec<u8> {
+		// Past the end of the bucket, so wrapping around is the same too
+		(0..RollingRandom::BUCKET_SIZE + 100)
+			.map(|_| rr.random_u8())
+			.collect()
+	}
+
+	#[test]
+	fn float_data_is_normalized_per_site() {
+		// Green, red / blue, green, each at half its whitelevel
+		let mut metadata = RawMetadata::test("GRBG");
+		metadata.whitelevels = [4, 2, 8, 2];
+		let data = RawImageData::Float(vec![1.0, 2.0, 4.0, 1.0]);
+
+		match raw_image(2, 2, metadata.clone(), data).unwrap() {
+			RawImage::Float(img) => assert_eq!(img.data, [0.5; 4]),
+			RawImage::Integer(_) => panic!("floats came back as integers"),
+		}
+
+		match raw_image(2, 2, metadata, RawImageData::Integer(vec![1, 2, 3, 4])).unwrap() {
+			RawImage::Integer(img) => assert_eq!(img.data, [1, 2, 3, 4]),
+			RawImage::Float(_) => panic!("integers came back as floats"),
+		}
+	}
+
+	#[test]
+	fn float_data_must_fit() {
+		let data = RawImageData::Float(vec![1.0; 3]);
+		assert!(raw_image(2, 2, RawMetadata::test("RGGB"), data).is_err());
+	}
+
+	#[test]
+	fn same_seed_same_bytes() {
+		let mut first = RollingRandom::new(42);
+		let mut second = RollingRandom::new(42);
+		assert_eq!(first.seed(), 42);
+		assert_eq!(bytes(&mut first), bytes(&mut second));
+	}
+
+	#[test]
+	fn different_seeds_different_bytes() {
+		let zero = bytes(&mut RollingRandom::new(0));
+		let one = bytes(&mut RollingRandom::new(1));
+		assert_ne!(zero, one);
+		// Nowhere near all of them should line up by chance
+		let same = zero.iter().zip(&one).filter(|(a, b)| a == b).count();
+		assert!(same < zero.len() / 32, "{same} the same");
+	}
+
+	#[test]
+	fn known_bytes() {
+		// SplitMix64's first output for a seed of zero, little endian. If
+		// these change, every seed anyone wrote down makes a different image
+		let mut rr = RollingRandom::new(0);
+		let first: Vec<u8> = (0..8).map(|_| rr.random_u8()).collect();
+		assert_eq!(first, 0xE220A8397B1DCDAFu64.to_le_bytes());
 	}
-	terrible_things*/
 }
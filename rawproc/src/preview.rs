@@ -0,0 +1,239 @@
+//! The JPEGs cameras tuck inside their raws so they can show you the photo
+//! without developing it. Pulling one out is a lot faster than decoding the
+//! sensor data, which is what you want when going through a card of shots.
+
+use crate::{
+	capture::Capture,
+	image::Orientation,
+	tiff::{Ifd, Tiff},
+};
+
+const COMPRESSION: u16 = 0x0103;
+const STRIP_OFFSETS: u16 = 0x0111;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const JPEG_OFFSET: u16 = 0x0201;
+const JPEG_LENGTH: u16 = 0x0202;
+// Panasonic keeps the whole JPEG in a tag of its own
+const RW2_JPEG: u16 = 0x002E;
+
+/// An embedded JPEG, exactly as it was in the raw.
+#[derive(Clone, Debug)]
+pub struct Preview {
+	pub width: usize,
+	pub height: usize,
+	/// Which way up the camera was held. Previews are stored the same way the
+	/// sensor data is, so they need turning the same way.
+	pub orientation: Orientation,
+	/// The camera, lens, and exposure, so the preview can be tagged with
+	/// them.
+	pub capture: Capture,
+	/// The whole JPEG file
+	pub jpeg: Vec<u8>,
+}
+
+impl Preview {
+	/// Every preview we can find in the IFDs, largest first.
+	///
+	/// They're pointed to by the JPEGInterchangeFormat tags (NEF, ARW, and
+	/// the thumbnails of most), or stored as the single strip of a JPEG
+	/// compressed IFD (CR2, DNG). The sensor data of CR2 and a lot of DNGs is
+	/// lossless JPEG, so only the baseline and progressive JPEGs a viewer can
+	/// show are kept.
+	pub(crate) fn from_tiff(tiff: &Tiff) -> Vec<Self> {
+		let orientation = Orientation::from_tiff(tiff).unwrap_or_default();
+		let capture = Capture::from_tiff(tiff);
+		let first = |ifd: &Ifd, tag| {
+			ifd.get(tag)
+				.and_then(|entry| tiff.unsigned(entry).first().copied())
+				.map(|v| v as usize)
+		};
+
+		let mut found: Vec<(usize, usize)> = vec![];
+		for ifd in &tiff.ifds {
+			if let (Some(offset), Some(length)) = (first(ifd, JPEG_OFFSET), first(ifd, JPEG_LENGTH))
+			{
+				found.push((offset, length));
+			}
+
+			// 6 is the old way of putting a JPEG in a TIFF and 7 the new
+			let jpeg_compressed = matches!(first(ifd, COMPRESSION), Some(6 | 7));
+			let single_strip = ifd.get(STRIP_OFFSETS).map(|entry| entry.count) == Some(1);
+			if jpeg_compressed && single_strip {
+				if let (Some(offset), Some(length)) =
+					(first(ifd, STRIP_OFFSETS), first(ifd, STRIP_BYTE_COUNTS))
+				{
+					found.push((offset, length));
+				}
+			}
+
+			if let Some(entry) = ifd.get(RW2_JPEG) {
+				found.push((entry.offset, entry.count));
+			}
+		}
+		found.sort_unstable();
+		found.dedup();
+
+		let mut previews: Vec<Self> = found
+			.into_iter()
+			.filter_map(|(offset, length)| {
+				let jpeg = tiff.slice(offset, length)?;
+				let (width, height) = viewable_dimensions(jpeg)?;
+
+				Some(Preview {
+					width,
+					height,
+					orientation,
+					capture: capture.clone(),
+					jpeg: jpeg.to_vec(),
+				})
+			})
+			.collect();
+
+		previews.sort_by_key(|preview| std::cmp::Reverse(preview.width * preview.height));
+		previews
+	}
+}
+
+/// The width and height from the frame header, if `jpeg` is a baseline or
+/// progressive JPEG. `None` for anything else, lossless JPEG included.
+fn viewable_dimensions(jpeg: &[u8]) -> Option<(usize, usize)> {
+	// Every SOF but DHT, JPG, and DAC, which share the range
+	let (marker, data) = jpeg_segments(jpeg)?.into_iter().find(|(marker, _)| {
+		matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+	})?;
+
+	// Baseline, extended sequential, and progressive. The rest are lossless,
+	// hierarchical, or arithmetic coded
+	if !matches!(marker, 0xC0..=0xC2) || data.len() < 5 {
+		return None;
+	}
+
+	let height = u16::from_be_bytes([data[1], data[2]]) as usize;
+	let width = u16::from_be_bytes([data[3], data[4]]) as usize;
+	(width > 0 && height > 0).then_some((width, height))
+}
+
+/// The segments of a JPEG before its first scan, each marker with its data
+/// but not the length. `None` if it doesn't look like a JPEG or there's no
+/// scan. imgout walks JPEGs the same way.
+fn jpeg_segments(jpeg: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+	if jpeg.get(0..2)? != [0xFF, 0xD8] {
+		return None;
+	}
+
+	let mut segments = vec![];
+	let mut at = 2;
+	loop {
+		if *jpeg.get(at)? != 0xFF {
+			return None;
+		}
+		// Markers can have any number of 0xFF before them as padding
+		while *jpeg.get(at + 1)? == 0xFF {
+			at += 1;
+		}
+
+		match jpeg[at + 1] {
+			0xDA => return Some(segments),
+			// The end of the image
+			0xD9 => return None,
+			// TEM, the restarts, and SOI stand alone without a length
+			0x01 | 0xD0..=0xD8 => at += 2,
+			marker => {
+				let length = u16::from_be_bytes([*jpeg.get(at + 2)?, *jpeg.get(at + 3)?]) as usize;
+				segments.push((marker, jpeg.get(at + 4..at + 2 + length)?));
+				at += 2 + length;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::tiff::test::{build, Value};
+
+	/// A JPEG with a frame header and nothing worth decoding in its scan
+	fn jpeg(sof: u8, width: u16, height: u16) -> Vec<u8> {
+		let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 7];
+		jpeg.extend_from_slice(b"JFIF\0");
+		jpeg.extend_from_slice(&[0xFF, sof, 0, 11, 8]);
+		jpeg.extend_from_slice(&height.to_be_bytes());
+		jpeg.extend_from_slice(&width.to_be_bytes());
+		jpeg.extend_from_slice(&[1, 1, 0x11, 0]);
+		jpeg.extend_from_slice(&[0xFF, 0xDA, 0, 8, 1, 1, 0, 0, 63, 0, 0xFF, 0xD9]);
+		jpeg
+	}
+
+	#[test]
+	fn standalone_markers_are_skipped() {
+		let mut padded = jpeg(0xC0, 4, 3);
+		// A restart, TEM, and padding after the JFIF segment
+		padded.splice(11..11, [0xFF, 0xD3, 0xFF, 0x01, 0xFF, 0xFF]);
+		assert_eq!(viewable_dimensions(&padded), Some((4, 3)));
+
+		let segments = jpeg_segments(&padded).unwrap();
+		let markers: Vec<u8> = segments.iter().map(|(marker, _)| *marker).collect();
+		assert_eq!(markers, [0xE0, 0xC0]);
+		assert_eq!(segments[0].1, b"JFIF\0");
+	}
+
+	#[test]
+	fn only_viewable_frames() {
+		assert_eq!(viewable_dimensions(&jpeg(0xC2, 640, 480)), Some((640, 480)));
+		// Lossless and arithmetic coded
+		assert_eq!(viewable_dimensions(&jpeg(0xC3, 640, 480)), None);
+		assert_eq!(viewable_dimensions(&jpeg(0xC9, 640, 480)), None);
+		assert_eq!(viewable_dimensions(&jpeg(0xC0, 0, 480)), None);
+		// It ends before there's a frame
+		assert_eq!(viewable_dimensions(&[0xFF, 0xD8, 0xFF, 0xD9]), None);
+		assert_eq!(viewable_dimensions(&jpeg(0xC0, 640, 480)[..16]), None);
+	}
+
+	#[test]
+	fn from_tiff() {
+		let thumbnail = jpeg(0xC0, 160, 120);
+		let large = jpeg(0xC2, 1600, 1200);
+		let rw2 = jpeg(0xC0, 320, 240);
+		let lossless = jpeg(0xC3, 6000, 4000);
+		let data = build(&[
+			vec![
+				(0x010F, Value::Ascii("Maker")),
+				(0x0112, Value::Short(vec![6])),
+				(JPEG_OFFSET, Value::Offset(thumbnail.clone())),
+				(JPEG_LENGTH, Value::Long(vec![thumbnail.len() as u32])),
+			],
+			// The sensor data, lossless JPEG like a CR2 or DNG
+			vec![
+				(COMPRESSION, Value::Short(vec![7])),
+				(STRIP_OFFSETS, Value::Offset(lossless.clone())),
+				(STRIP_BYTE_COUNTS, Value::Long(vec![lossless.len() as u32])),
+			],
+			vec![
+				(COMPRESSION, Value::Short(vec![6])),
+				(STRIP_OFFSETS, Value::Offset(large.clone())),
+				(STRIP_BYTE_COUNTS, Value::Long(vec![large.len() as u32])),
+			],
+			vec![(RW2_JPEG, Value::Undefined(rw2.clone()))],
+		]);
+		let tiff = Tiff::parse(&data).unwrap();
+		let previews = Preview::from_tiff(&tiff);
+
+		let found: Vec<(usize, usize, &[u8])> = previews
+			.iter()
+			.map(|preview| (preview.width, preview.height, preview.jpeg.as_slice()))
+			.collect();
+		assert_eq!(
+			found,
+			[
+				(1600, 1200, large.as_slice()),
+				(320, 240, rw2.as_slice()),
+				(160, 120, thumbnail.as_slice()),
+			]
+		);
+
+		for preview in &previews {
+			assert_eq!(preview.orientation, Orientation::Rotate90);
+			assert_eq!(preview.capture.make.as_deref(), Some("Maker"));
+		}
+	}
+}
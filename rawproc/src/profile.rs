@@ -0,0 +1,850 @@
+//! Camera colour the way the DNG spec does it. A camera is described by one
+//! or two XYZ to camera matrices, each measured under a different light, and
+//! maybe forward matrices that go from white balanced camera colour straight
+//! to XYZ D50. We guess the light of the scene from the camera neutral and
+//! blend between the two.
+//!
+//! DCP files are the same tags on their own, with hue/sat maps and tone curves
+//! that give the colours a look. [InputProfile] loads those, or a matrix/TRC
+//! ICC profile, to use in place of the matrix the camera was decoded with.
+//!
+//! Chapter 6 of the DNG specification, "Mapping Camera Color Space to CIE XYZ
+//! Space", is where all of this comes from.
+
+use std::io::Read;
+
+use nalgebra::{Matrix3, Matrix3x1};
+
+use crate::{
+	adaptation::{self, Transform, WhitePoint},
+	algorithms,
+	icc::IccProfile,
+	tiff::Tiff,
+	Error,
+};
+
+const COLOR_MATRIX_1: u16 = 0xC621;
+const COLOR_MATRIX_2: u16 = 0xC622;
+const CAMERA_CALIBRATION_1: u16 = 0xC623;
+const CAMERA_CALIBRATION_2: u16 = 0xC624;
+const ANALOG_BALANCE: u16 = 0xC627;
+const AS_SHOT_NEUTRAL: u16 = 0xC628;
+const AS_SHOT_WHITE_XY: u16 = 0xC629;
+const CALIBRATION_ILLUMINANT_1: u16 = 0xC65A;
+const CALIBRATION_ILLUMINANT_2: u16 = 0xC65B;
+const FORWARD_MATRIX_1: u16 = 0xC714;
+const FORWARD_MATRIX_2: u16 = 0xC715;
+const PROFILE_NAME: u16 = 0xC6F8;
+const HUE_SAT_MAP_DIMS: u16 = 0xC6F9;
+const HUE_SAT_MAP_DATA_1: u16 = 0xC6FA;
+const HUE_SAT_MAP_DATA_2: u16 = 0xC6FB;
+const TONE_CURVE: u16 = 0xC6FC;
+const LOOK_TABLE_DIMS: u16 = 0xC725;
+const LOOK_TABLE_DATA: u16 = 0xC726;
+const HUE_SAT_MAP_ENCODING: u16 = 0xC7A3;
+const LOOK_TABLE_ENCODING: u16 = 0xC7A4;
+
+/// The EXIF LightSource value for D65
+pub const D65_ILLUMINANT: u16 = 21;
+
+/// How many times we'll go around looking for the white of the scene. The DNG
+/// SDK uses the same.
+const NEUTRAL_PASSES: usize = 30;
+
+/// The colour matrices of a camera and the lights they were made under.
+/// Everything comes in pairs; the second of each is optional.
+#[derive(Clone, Debug)]
+pub struct CameraProfile {
+	/// EXIF LightSource values for the light each set of matrices was
+	/// calibrated under. 21 is D65, 17 is standard light A.
+	pub illuminants: [u16; 2],
+	/// XYZ to camera.
+	pub color_matrices: [Option<Matrix3<f32>>; 2],
+	/// White balanced camera to XYZ D50.
+	pub forward_matrices: [Option<Matrix3<f32>>; 2],
+	/// Per unit corrections that go on top of the colour matrices.
+	pub camera_calibrations: [Option<Matrix3<f32>>; 2],
+	/// The gain applied to each channel before the data was digitized.
+	pub analog_balance: Option<Matrix3x1<f32>>,
+	pub name: Option<String>,
+	/// Hue, saturation, and value adjustments for each calibration light.
+	pub hue_sat_maps: [Option<HueSatMap>; 2],
+	/// A look that goes on after the hue/sat maps.
+	pub look_table: Option<HueSatMap>,
+	/// Input to output pairs, both from 0.0 to 1.0. Applied last.
+	pub tone_curve: Option<Vec<(f32, f32)>>,
+}
+
+/// A three dimensional table of hue shifts, saturation scales, and value
+/// scales, indexed by hue, saturation, and value. It works on linear ProPhoto
+/// RGB with a D50 white.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HueSatMap {
+	pub hue_divisions: usize,
+	pub sat_divisions: usize,
+	/// One if the map doesn't care about value.
+	pub val_divisions: usize,
+	/// Hue shift in degrees, saturation scale, and value scale. Value is the
+	/// outermost index, then hue, then saturation.
+	pub entries: Vec<[f32; 3]>,
+	/// The value axis is indexed with sRGB encoded values rather than linear.
+	pub srgb_value: bool,
+}
+
+/// Something to take the camera's colour to XYZ with. See
+/// [Image::to_xyz_with](crate::image::Image::to_xyz_with).
+#[derive(Clone, Debug)]
+pub enum InputProfile {
+	/// From a DCP or a DNG.
+	Camera(Box<CameraProfile>),
+	/// A matrix/TRC ICC profile made for white balanced camera colour.
+	Icc(IccProfile),
+}
+
+impl InputProfile {
+	/// Read a DCP, DNG, or ICC profile. Which one it is comes from the data.
+	pub fn load<R: Read>(reader: &mut R) -> Result<Self, Error> {
+		let mut bytes = vec![];
+		reader.read_to_end(&mut bytes)?;
+		Self::parse(&bytes)
+	}
+
+	pub fn parse(data: &[u8]) -> Result<Self, Error> {
+		if data.get(36..40) == Some(b"acsp") {
+			IccProfile::parse(data).map(InputProfile::Icc)
+		} else {
+			CameraProfile::from_dcp(data).map(|camera| InputProfile::Camera(Box::new(camera)))
+		}
+	}
+}
+
+/// What the camera thought the light was when the picture was taken.
+#[derive(Copy, Clone, Debug)]
+pub enum AsShot {
+	/// The camera colour of something neutral.
+	Neutral(Matrix3x1<f32>),
+	/// The chromaticity of the light.
+	WhiteXy(f32, f32),
+}
+
+impl CameraProfile {
+	/// A profile with only the one XYZ to camera matrix. This is what most
+	/// raw formats give us, which are calibrated under D65.
+	pub fn from_color_matrix(xyz_to_cam: Matrix3<f32>, illuminant: u16) -> Self {
+		Self {
+			illuminants: [illuminant, 0],
+			color_matrices: [Some(xyz_to_cam), None],
+			forward_matrices: [None, None],
+			camera_calibrations: [None, None],
+			analog_balance: None,
+			name: None,
+			hue_sat_maps: [None, None],
+			look_table: None,
+			tone_curve: None,
+		}
+	}
+
+	/// Read a DCP profile. The colour tags of a DNG work just as well.
+	pub fn from_dcp(data: &[u8]) -> Result<Self, Error> {
+		Tiff::parse(data)
+			.and_then(|tiff| CameraProfile::from_tiff(&tiff))
+			.map(|(profile, _)| profile)
+			.ok_or(Error::InvalidProfile {
+				reason: "not a DCP or DNG with a three colour ColorMatrix1",
+			})
+	}
+
+	/// Read the colour tags out of a DNG's first IFD. Returns None if there
+	/// isn't a three colour ColorMatrix1.
+	pub(crate) fn from_tiff(tiff: &Tiff) -> Option<(Self, Option<AsShot>)> {
+		let ifd = tiff.ifds.first()?;
+
+		let matrix = |tag| {
+			let floats = tiff.floats(ifd.get(tag)?);
+			(floats.len() == 9)
+				.then(|| Matrix3::from_iterator(floats.iter().map(|f| *f as f32)).transpose())
+		};
+		let vector = |tag| {
+			let floats = tiff.floats(ifd.get(tag)?);
+			(floats.len() == 3)
+				.then(|| Matrix3x1::new(floats[0] as f32, floats[1] as f32, floats[2] as f32))
+		};
+		let illuminant = |tag| {
+			ifd.get(tag)
+				.and_then(|entry| tiff.unsigned(entry).first().copied())
+				.unwrap_or(0) as u16
+		};
+		let hue_sat_map = |dims, data, encoding| {
+			let dims = tiff.unsigned(ifd.get(dims)?);
+			let data = tiff.floats(ifd.get(data)?);
+			let encoding = ifd.get(encoding).map(|entry| tiff.unsigned(entry));
+			HueSatMap::new(
+				&dims,
+				&data,
+				encoding.and_then(|e| e.first().copied()) == Some(1),
+			)
+		};
+
+		let profile = Self {
+			illuminants: [
+				illuminant(CALIBRATION_ILLUMINANT_1),
+				illuminant(CALIBRATION_ILLUMINANT_2),
+			],
+			color_matrices: [Some(matrix(COLOR_MATRIX_1)?), matrix(COLOR_MATRIX_2)],
+			forward_matrices: [matrix(FORWARD_MATRIX_1), matrix(FORWARD_MATRIX_2)],
+			camera_calibrations: [matrix(CAMERA_CALIBRATION_1), matrix(CAMERA_CALIBRATION_2)],
+			analog_balance: vector(ANALOG_BALANCE),
+			name: ifd.get(PROFILE_NAME).and_then(|entry| tiff.string(entry)),
+			hue_sat_maps: [
+				hue_sat_map(HUE_SAT_MAP_DIMS, HUE_SAT_MAP_DATA_1, HUE_SAT_MAP_ENCODING),
+				hue_sat_map(HUE_SAT_MAP_DIMS, HUE_SAT_MAP_DATA_2, HUE_SAT_MAP_ENCODING),
+			],
+			look_table: hue_sat_map(LOOK_TABLE_DIMS, LOOK_TABLE_DATA, LOOK_TABLE_ENCODING),
+			tone_curve: ifd.get(TONE_CURVE).map(|entry| {
+				tiff.floats(entry)
+					.chunks_exact(2)
+					.map(|pair| (pair[0] as f32, pair[1] as f32))
+					.collect()
+			}),
+		};
+
+		let as_shot = vector(AS_SHOT_NEUTRAL).map(AsShot::Neutral).or_else(|| {
+			let floats = tiff.floats(ifd.get(AS_SHOT_WHITE_XY)?);
+			(floats.len() == 2).then(|| AsShot::WhiteXy(floats[0] as f32, floats[1] as f32))
+		});
+
+		Some((profile, as_shot))
+	}
+
+	/// The XYZ to camera matrix for a scene lit by a light with the
+	/// chromaticity `xy`. Calibration and analog balance are included, so this
+	/// is the whole way to what the sensor recorded.
+	pub fn xyz_to_camera(&self, xy: (f32, f32)) -> Matrix3<f32> {
+		let weight = self.weight(xy);
+
+		let color_matrix = blend(self.color_matrices, weight).unwrap_or_else(Matrix3::identity);
+		let calibration = blend(self.camera_calibrations, weight).unwrap_or_else(Matrix3::identity);
+		let analog = self.analog_balance();
+
+		analog * calibration * color_matrix
+	}
+
+	/// The camera colour of something neutral under the light `xy`.
+	pub fn xy_to_neutral(&self, xy: (f32, f32)) -> Matrix3x1<f32> {
+		let white = WhitePoint::from_xy(xy.0, xy.1);
+		self.xyz_to_camera(xy) * white.xyz()
+	}
+
+	/// Find the light that makes `neutral` neutral. The matrices we should use
+	/// depend on the light, which we're trying to find, so we go around until
+	/// it stops moving.
+	pub fn neutral_to_xy(&self, neutral: Matrix3x1<f32>) -> (f32, f32) {
+		let mut last = WhitePoint::D50.xy();
+
+		for pass in 0..NEUTRAL_PASSES {
+			let xyz = match self.xyz_to_camera(last).try_inverse() {
+				Some(camera_to_xyz) => camera_to_xyz * neutral,
+				None => return last,
+			};
+
+			let next = WhitePoint::from(xyz).xy();
+			if !next.0.is_finite() || !next.1.is_finite() {
+				return last;
+			}
+
+			if (next.0 - last.0).abs() + (next.1 - last.1).abs() < 1e-7 {
+				return next;
+			}
+
+			// If we never settle, meet in the middle of the last two
+			last = if pass == NEUTRAL_PASSES - 1 {
+				((last.0 + next.0) / 2.0, (last.1 + next.1) / 2.0)
+			} else {
+				next
+			};
+		}
+
+		last
+	}
+
+	/// Camera colour, before any whitebalance, to XYZ under the light of the
+	/// scene. `neutral` comes out as the white of that light with a Y of 1.0,
+	/// so adapting from there to another white is all that's left to do.
+	///
+	/// With forward matrices this is the DNG CameraToXYZ_D50 adapted from D50
+	/// back to the scene white with Bradford. Bradford adaptations compose, so
+	/// adapting onward from here lands in the same place as going from D50.
+	pub fn camera_to_xyz(&self, neutral: Matrix3x1<f32>) -> Option<Matrix3<f32>> {
+		let xy = self.neutral_to_xy(neutral);
+		let weight = self.weight(xy);
+
+		let camera_to_xyz = match blend(self.forward_matrices, weight) {
+			Some(forward) => {
+				let calibration =
+					blend(self.camera_calibrations, weight).unwrap_or_else(Matrix3::identity);
+				let reference_to_camera = self.analog_balance() * calibration;
+				let camera_to_reference = reference_to_camera.try_inverse()?;
+
+				// Balance the camera neutral in the reference space
+				let reference_neutral = camera_to_reference * neutral;
+				let balance = Matrix3::from_diagonal(&reference_neutral.map(|c| 1.0 / c));
+
+				let to_scene = adaptation::matrix(
+					Transform::Bradford,
+					WhitePoint::D50,
+					WhitePoint::from_xy(xy.0, xy.1),
+				);
+
+				to_scene * forward * balance * camera_to_reference
+			}
+			None => self.xyz_to_camera(xy).try_inverse()?,
+		};
+
+		let white = camera_to_xyz * neutral;
+		if white[1] <= 0.0 || !white[1].is_finite() {
+			return None;
+		}
+
+		Some(camera_to_xyz / white[1])
+	}
+
+	/// Whether there's anything for [Self::apply_looks] to do.
+	pub fn has_looks(&self) -> bool {
+		self.hue_sat_maps.iter().any(Option::is_some)
+			|| self.look_table.is_some()
+			|| self.tone_curve.is_some()
+	}
+
+	/// The hue/sat map for the light `xy`, blended between the two the same
+	/// way the colour matrices are.
+	pub fn hue_sat_map(&self, xy: (f32, f32)) -> Option<HueSatMap> {
+		let weight = self.weight(xy);
+
+		match &self.hue_sat_maps {
+			[Some(first), Some(second)] => first.blend(second, weight),
+			[Some(only), None] | [None, Some(only)] => Some(only.clone()),
+			[None, None] => None,
+		}
+	}
+
+	/// Put the hue/sat map, look table, and tone curve on a linear ProPhoto
+	/// RGB colour. `map` is from [Self::hue_sat_map].
+	pub fn apply_looks(&self, map: Option<&HueSatMap>, rgb: &mut [f32; 3]) {
+		if let Some(map) = map {
+			map.apply(rgb);
+		}
+
+		if let Some(look) = &self.look_table {
+			look.apply(rgb);
+		}
+
+		if let Some(curve) = &self.tone_curve {
+			apply_tone_curve(curve, rgb);
+		}
+	}
+
+	fn analog_balance(&self) -> Matrix3<f32> {
+		self.analog_balance
+			.map(|ab| Matrix3::from_diagonal(&ab))
+			.unwrap_or_else(Matrix3::identity)
+	}
+
+	/// How much of the first set of matrices to use for the light `xy`. It's
+	/// linear in inverse temperature between the two calibration lights.
+	fn weight(&self, xy: (f32, f32)) -> f32 {
+		let temperatures = (
+			illuminant_temperature(self.illuminants[0]),
+			illuminant_temperature(self.illuminants[1]),
+		);
+
+		let (t1, t2) = match temperatures {
+			(Some(t1), Some(t2)) if t1 != t2 && self.color_matrices[1].is_some() => (t1, t2),
+			_ => return 1.0,
+		};
+
+		let temperature = xy_to_temperature(xy);
+		let weight = (1.0 / temperature - 1.0 / t2) / (1.0 / t1 - 1.0 / t2);
+		weight.clamp(0.0, 1.0)
+	}
+}
+
+impl HueSatMap {
+	/// Build a map from the dimensions and data of the DNG tags. None if they
+	/// don't agree with each other.
+	fn new(dims: &[u32], data: &[f64], srgb_value: bool) -> Option<Self> {
+		let (hue_divisions, sat_divisions, val_divisions) = match dims {
+			[hue, sat] => (*hue as usize, *sat as usize, 1),
+			[hue, sat, val] => (*hue as usize, *sat as usize, (*val as usize).max(1)),
+			_ => return None,
+		};
+
+		// The dimensions come straight from the file, so they can be anything
+		let count = hue_divisions
+			.checked_mul(sat_divisions)?
+			.checked_mul(val_divisions)?;
+		if count == 0 || sat_divisions < 2 || count.checked_mul(3) != Some(data.len()) {
+			return None;
+		}
+
+		let entries = data
+			.chunks_exact(3)
+			.map(|entry| [entry[0] as f32, entry[1] as f32, entry[2] as f32])
+			.collect();
+
+		Some(Self {
+			hue_divisions,
+			sat_divisions,
+			val_divisions,
+			entries,
+			srgb_value,
+		})
+	}
+
+	/// `weight` of this map and the rest of `other`. None if their sizes
+	/// differ.
+	fn blend(&self, other: &HueSatMap, weight: f32) -> Option<HueSatMap> {
+		let same_size = self.hue_divisions == other.hue_divisions
+			&& self.sat_divisions == other.sat_divisions
+			&& self.val_divisions == other.val_divisions;
+		if !same_size {
+			return None;
+		}
+
+		let entries = self
+			.entries
+			.iter()
+			.zip(other.entries.iter())
+			.map(|(a, b)| {
+				[
+					a[0] * weight + b[0] * (1.0 - weight),
+					a[1] * weight + b[1] * (1.0 - weight),
+					a[2] * weight + b[2] * (1.0 - weight),
+				]
+			})
+			.collect();
+
+		Some(HueSatMap {
+			entries,
+			..self.clone()
+		})
+	}
+
+	fn entry(&self, hue: usize, sat: usize, val: usize) -> [f32; 3] {
+		let idx = (val * self.hue_divisions + hue) * self.sat_divisions + sat;
+		self.entries[idx]
+	}
+
+	/// Look up the colour's adjustment, interpolating between the entries
+	/// around it, and apply it.
+	pub fn apply(&self, rgb: &mut [f32; 3]) {
+		let (hue, sat, val) =
+			algorithms::pixel_rgb_to_hsv(rgb[0].max(0.0), rgb[1].max(0.0), rgb[2].max(0.0));
+
+		// Hue wraps around, the others stop at the ends
+		let hue_scaled = hue / 360.0 * self.hue_divisions as f32;
+		let hue_low = (hue_scaled.floor() as usize) % self.hue_divisions;
+		let hue_high = (hue_low + 1) % self.hue_divisions;
+		let hue_fraction = hue_scaled - hue_scaled.floor();
+
+		let sat_scaled = sat.clamp(0.0, 1.0) * (self.sat_divisions - 1) as f32;
+		let sat_low = (sat_scaled.floor() as usize).min(self.sat_divisions - 2);
+		let sat_fraction = sat_scaled - sat_low as f32;
+
+		let (val_low, val_fraction) = if self.val_divisions > 1 {
+			let indexed = if self.srgb_value {
+				algorithms::srgb_gamma(val)
+			} else {
+				val.clamp(0.0, 1.0)
+			};
+			let val_scaled = indexed * (self.val_divisions - 1) as f32;
+			let val_low = (val_scaled.floor() as usize).min(self.val_divisions - 2);
+			(val_low, val_scaled - val_low as f32)
+		} else {
+			(0, 0.0)
+		};
+		let val_high = (val_low + 1).min(self.val_divisions - 1);
+
+		let mut adjust = [0.0; 3];
+		for (val_idx, val_weight) in [(val_low, 1.0 - val_fraction), (val_high, val_fraction)] {
+			for (hue_idx, hue_weight) in [(hue_low, 1.0 - hue_fraction), (hue_high, hue_fraction)] {
+				for (sat_idx, sat_weight) in
+					[(sat_low, 1.0 - sat_fraction), (sat_low + 1, sat_fraction)]
+				{
+					let weight = val_weight * hue_weight * sat_weight;
+					let entry = self.entry(hue_idx, sat_idx, val_idx);
+					for (a, e) in adjust.iter_mut().zip(entry) {
+						*a += e * weight;
+					}
+				}
+			}
+		}
+
+		let [hue_shift, sat_scale, val_scale] = adjust;
+		let hue = (hue + hue_shift).rem_euclid(360.0);
+		let sat = (sat * sat_scale).clamp(0.0, 1.0);
+		let val = (val * val_scale).max(0.0);
+
+		let (r, g, b) = algorithms::pixel_hsv_to_rgb(hue, sat, val);
+		*rgb = [r, g, b];
+	}
+}
+
+/// The tone curve is applied to the largest and smallest channel and the
+/// middle one is put where it keeps the hue, like Adobe does it. Between the
+/// points of the curve we go in a straight line.
+fn apply_tone_curve(curve: &[(f32, f32)], rgb: &mut [f32; 3]) {
+	let tone = |x: f32| {
+		let x = x.clamp(0.0, 1.0);
+		match curve.iter().position(|(input, _)| *input >= x) {
+			None => curve.last().map(|(_, output)| *output).unwrap_or(x),
+			Some(0) => curve[0].1,
+			Some(high) => {
+				let (x0, y0) = curve[high - 1];
+				let (x1, y1) = curve[high];
+				y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+			}
+		}
+	};
+
+	let mut order = [0, 1, 2];
+	order.sort_by(|a, b| rgb[*a].total_cmp(&rgb[*b]));
+	let [min, mid, max] = order;
+
+	let (low, high) = (rgb[min], rgb[max]);
+	let (toned_low, toned_high) = (tone(low), tone(high));
+
+	rgb[mid] = if high > low {
+		toned_low + (toned_high - toned_low) * (rgb[mid] - low) / (high - low)
+	} else {
+		toned_low
+	};
+	rgb[min] = toned_low;
+	rgb[max] = toned_high;
+}
+
+/// Mix the pair of matrices, `weight` of the first and the rest of the second.
+/// If only one of them is there, that's the one.
+fn blend(matrices: [Option<Matrix3<f32>>; 2], weight: f32) -> Option<Matrix3<f32>> {
+	match matrices {
+		[Some(first), Some(second)] => Some(first * weight + second * (1.0 - weight)),
+		[Some(only), None] | [None, Some(only)] => Some(only),
+		[None, None] => None,
+	}
+}
+
+/// The correlated colour temperature of `xy` with McCamy's approximation.
+/// It's good to a few kelvin near the daylight and blackbody curves, which is
+/// plenty for picking a blend of two matrices.
+fn xy_to_temperature((x, y): (f32, f32)) -> f32 {
+	let n = (x - 0.3320) / (0.1858 - y);
+	(449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33).clamp(1000.0, 50000.0)
+}
+
+/// The colour temperature of an EXIF LightSource, if it has one we know.
+fn illuminant_temperature(illuminant: u16) -> Option<f32> {
+	let kelvin = match illuminant {
+		// Daylight, flash, and fine weather
+		1 | 4 | 9 => 5500.0,
+		// Fluorescent
+		2 => 4200.0,
+		// Tungsten
+		3 => 2850.0,
+		// Cloudy
+		10 => 6500.0,
+		// Shade
+		11 => 7500.0,
+		// Daylight, day white, cool white, white, and warm white fluorescent
+		12 => 6430.0,
+		13 => 5000.0,
+		14 => 4150.0,
+		15 => 3450.0,
+		16 => 2940.0,
+		// Standard light A, B, and C
+		17 => 2856.0,
+		18 => 4874.0,
+		19 => 6774.0,
+		// D55, D65, D75, D50
+		20 => 5503.0,
+		21 => 6504.0,
+		22 => 7504.0,
+		23 => 5003.0,
+		// ISO studio tungsten
+		24 => 3200.0,
+		_ => return None,
+	};
+
+	Some(kelvin)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::tiff::test::{build, Value};
+
+	#[rustfmt::skip]
+	const CM_A: [f32; 9] = [
+		 1.00, -0.30, -0.10,
+		-0.40,  1.30,  0.10,
+		-0.05,  0.20,  0.60,
+	];
+	#[rustfmt::skip]
+	const CM_D65: [f32; 9] = [
+		 0.80, -0.20, -0.10,
+		-0.50,  1.40,  0.10,
+		-0.10,  0.30,  0.70,
+	];
+	// Both take camera white, (1, 1, 1), to D50
+	#[rustfmt::skip]
+	const FM_A: [f32; 9] = [
+		0.60, 0.30, 0.06422,
+		0.25, 0.70, 0.05,
+		0.02, 0.10, 0.70521,
+	];
+	#[rustfmt::skip]
+	const FM_D65: [f32; 9] = [
+		0.70, 0.20, 0.06422,
+		0.30, 0.60, 0.10,
+		0.00, 0.05, 0.77521,
+	];
+
+	fn srationals(values: &[f32]) -> Value {
+		Value::SRational(
+			values
+				.iter()
+				.map(|v| ((v * 100000.0).round() as i32, 100000))
+				.collect(),
+		)
+	}
+
+	fn matrix(values: [f32; 9]) -> Matrix3<f32> {
+		Matrix3::from_row_slice(&values)
+	}
+
+	fn assert_close(got: Matrix3<f32>, expected: Matrix3<f32>, tolerance: f32) {
+		assert!(
+			(got - expected).abs().max() < tolerance,
+			"expected\n{expected}\ngot\n{got}"
+		);
+	}
+
+	fn dng(color_matrices: [[f32; 9]; 2], forward_matrices: Option<[[f32; 9]; 2]>) -> Vec<u8> {
+		let mut ifd = vec![
+			(COLOR_MATRIX_1, srationals(&color_matrices[0])),
+			(COLOR_MATRIX_2, srationals(&color_matrices[1])),
+			(
+				AS_SHOT_NEUTRAL,
+				Value::Rational(vec![(1, 2), (1, 1), (3, 4)]),
+			),
+			(CALIBRATION_ILLUMINANT_1, Value::Short(vec![17])),
+			(CALIBRATION_ILLUMINANT_2, Value::Short(vec![D65_ILLUMINANT])),
+			(PROFILE_NAME, Value::Ascii("Test Profile")),
+		];
+		if let Some([first, second]) = forward_matrices {
+			ifd.push((FORWARD_MATRIX_1, srationals(&first)));
+			ifd.push((FORWARD_MATRIX_2, srationals(&second)));
+		}
+
+		build(&[ifd])
+	}
+
+	/// Entries for a map that does the same thing everywhere
+	fn uniform_map(dims: [u32; 3], entry: [f64; 3]) -> Vec<f64> {
+		let count = dims.iter().product::<u32>() as usize;
+		entry.repeat(count)
+	}
+
+	fn assert_rgb(got: [f32; 3], expected: [f32; 3]) {
+		let off = got.iter().zip(expected).any(|(g, e)| (g - e).abs() > 1e-5);
+		assert!(!off, "expected {expected:?}, got {got:?}");
+	}
+
+	#[test]
+	fn reads_dng_tags() {
+		let data = dng([CM_A, CM_D65], None);
+		let tiff = Tiff::parse(&data).unwrap();
+		let (profile, as_shot) = CameraProfile::from_tiff(&tiff).unwrap();
+
+		assert_eq!(profile.illuminants, [17, 21]);
+		assert_close(profile.color_matrices[0].unwrap(), matrix(CM_A), 1e-6);
+		assert_close(profile.color_matrices[1].unwrap(), matrix(CM_D65), 1e-6);
+		assert!(profile.forward_matrices.iter().all(Option::is_none));
+		assert_eq!(profile.name.as_deref(), Some("Test Profile"));
+
+		match as_shot {
+			Some(AsShot::Neutral(neutral)) => {
+				assert_eq!(neutral, Matrix3x1::new(0.5, 1.0, 0.75))
+			}
+			other => panic!("expected the as shot neutral, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn color_matrices_follow_the_light() {
+		let data = dng([CM_A, CM_D65], None);
+		let (profile, _) = CameraProfile::from_tiff(&Tiff::parse(&data).unwrap()).unwrap();
+
+		// McCamy's approximation is a few kelvin off, so not exactly
+		assert_close(
+			profile.xyz_to_camera(WhitePoint::A.xy()),
+			matrix(CM_A),
+			2e-3,
+		);
+		assert_close(
+			profile.xyz_to_camera(WhitePoint::D65.xy()),
+			matrix(CM_D65),
+			2e-3,
+		);
+
+		// D50 is between them, nearer to D65 in inverse temperature
+		let weight = profile.weight(WhitePoint::D50.xy());
+		assert!((0.2..0.3).contains(&weight), "weight {weight}");
+		let expected = matrix(CM_A) * weight + matrix(CM_D65) * (1.0 - weight);
+		assert_close(profile.xyz_to_camera(WhitePoint::D50.xy()), expected, 1e-6);
+	}
+
+	#[test]
+	fn forward_matrices_are_blended() {
+		// Camera white is D50 under both, so the scene light is D50 and no
+		// adapting needs to be done
+		let d50 = WhitePoint::D50.xyz();
+		#[rustfmt::skip]
+		let cm = [
+			1.0 / d50[0], 0.0, 0.0,
+			0.0,          1.0, 0.0,
+			0.0,          0.0, 1.0 / d50[2],
+		];
+		let data = dng([cm, cm.map(|v| v * 2.0)], Some([FM_A, FM_D65]));
+		let (profile, _) = CameraProfile::from_tiff(&Tiff::parse(&data).unwrap()).unwrap();
+
+		let neutral = Matrix3x1::new(1.0, 1.0, 1.0);
+		let (x, y) = profile.neutral_to_xy(neutral);
+		let (d50_x, d50_y) = WhitePoint::D50.xy();
+		assert!((x - d50_x).abs() < 1e-5 && (y - d50_y).abs() < 1e-5);
+
+		let weight = profile.weight((x, y));
+		let expected = matrix(FM_A) * weight + matrix(FM_D65) * (1.0 - weight);
+		assert_close(profile.camera_to_xyz(neutral).unwrap(), expected, 1e-4);
+	}
+
+	#[test]
+	fn published_color_matrix() {
+		// Adobe's D65 ColorMatrix for the Canon EOS 5D Mark II, as dcraw and
+		// rawloader carry it
+		#[rustfmt::skip]
+		let cm = [
+			 0.4716, 0.0603, -0.0830,
+			-0.7798, 1.5474,  0.2480,
+			-0.1496, 0.1937,  0.6651,
+		];
+		// What it sees D65 as, relative to green
+		let neutral = Matrix3x1::new(0.3885412, 1.0, 0.7207323);
+		// Chapter 6 of the DNG spec worked through in double precision: the
+		// inverse of the colour matrix, scaled so the neutral has a Y of one
+		#[rustfmt::skip]
+		let expected = matrix([
+			2.176931, -0.124657,  0.318148,
+			1.068441,  0.668395, -0.115895,
+			0.178487, -0.222699,  1.723496,
+		]);
+
+		let tiff = build(&[vec![
+			(COLOR_MATRIX_1, srationals(&cm)),
+			(CALIBRATION_ILLUMINANT_1, Value::Short(vec![D65_ILLUMINANT])),
+		]]);
+		let (profile, _) = CameraProfile::from_tiff(&Tiff::parse(&tiff).unwrap()).unwrap();
+
+		let (x, y) = profile.neutral_to_xy(neutral);
+		let (d65_x, d65_y) = WhitePoint::D65.xy();
+		assert!((x - d65_x).abs() < 1e-5 && (y - d65_y).abs() < 1e-5);
+		assert_close(profile.camera_to_xyz(neutral).unwrap(), expected, 1e-4);
+
+		// The forward matrix that agrees with it, which the spec has take
+		// balanced camera colour to D50. Going through it and adapting back to
+		// the scene has to end up in the same place
+		#[rustfmt::skip]
+		let fm = [
+			0.8922922, -0.1041565,  0.1760842,
+			0.4349887,  0.6621494, -0.0971381,
+			0.0505944, -0.1562925,  0.9309081,
+		];
+		let tiff = build(&[vec![
+			(COLOR_MATRIX_1, srationals(&cm)),
+			(FORWARD_MATRIX_1, srationals(&fm)),
+			(CALIBRATION_ILLUMINANT_1, Value::Short(vec![D65_ILLUMINANT])),
+		]]);
+		let (profile, _) = CameraProfile::from_tiff(&Tiff::parse(&tiff).unwrap()).unwrap();
+		assert_close(profile.camera_to_xyz(neutral).unwrap(), expected, 1e-4);
+	}
+
+	#[test]
+	fn hue_sat_map_dimensions_must_agree() {
+		let data = uniform_map([4, 2, 1], [0.0, 1.0, 1.0]);
+		assert!(HueSatMap::new(&[4, 2], &data, false).is_some());
+		assert!(HueSatMap::new(&[4, 2, 1], &data, false).is_some());
+		assert!(HueSatMap::new(&[4, 3], &data, false).is_none());
+		// One saturation division has nothing to interpolate between
+		assert!(HueSatMap::new(&[8, 1], &data, false).is_none());
+		assert!(HueSatMap::new(&[u32::MAX, u32::MAX, u32::MAX], &data, false).is_none());
+	}
+
+	#[test]
+	fn hue_sat_map_applies() {
+		let identity = HueSatMap::new(&[6, 3, 2], &uniform_map([6, 3, 2], [0.0, 1.0, 1.0]), false);
+		let mut rgb = [0.5, 0.25, 0.25];
+		identity.unwrap().apply(&mut rgb);
+		assert_rgb(rgb, [0.5, 0.25, 0.25]);
+
+		// Red turned to orange, half as saturated, and a little brighter
+		let entry = [30.0, 0.5, 1.2];
+		let map = HueSatMap::new(&[6, 3, 2], &uniform_map([6, 3, 2], entry), false).unwrap();
+		let mut rgb = [0.5, 0.25, 0.25];
+		map.apply(&mut rgb);
+		// Value 0.6, saturation 0.25, hue 30
+		assert_rgb(rgb, [0.6, 0.525, 0.45]);
+	}
+
+	#[test]
+	fn hue_sat_maps_blend() {
+		let dims = [4, 2, 1];
+		let first = HueSatMap::new(&dims, &uniform_map(dims, [20.0, 1.0, 1.0]), false).unwrap();
+		let second = HueSatMap::new(&dims, &uniform_map(dims, [0.0, 2.0, 1.0]), false).unwrap();
+		let blended = first.blend(&second, 0.25).unwrap();
+		assert!(blended.entries.iter().all(|e| *e == [5.0, 1.75, 1.0]));
+
+		let other = HueSatMap::new(&[2, 4], &uniform_map(dims, [0.0, 1.0, 1.0]), false).unwrap();
+		assert!(first.blend(&other, 0.5).is_none());
+	}
+
+	#[test]
+	fn reads_hue_sat_maps() {
+		let dims = [4, 2, 1];
+		let data = |entry| {
+			let values: Vec<f32> = uniform_map(dims, entry).iter().map(|v| *v as f32).collect();
+			srationals(&values)
+		};
+		let tiff = build(&[vec![
+			(COLOR_MATRIX_1, srationals(&CM_A)),
+			(HUE_SAT_MAP_DIMS, Value::Long(dims.to_vec())),
+			(HUE_SAT_MAP_DATA_1, data([10.0, 1.0, 1.0])),
+			(HUE_SAT_MAP_DATA_2, data([0.0, 1.5, 1.0])),
+		]]);
+		let (profile, _) = CameraProfile::from_tiff(&Tiff::parse(&tiff).unwrap()).unwrap();
+
+		let [first, second] = &profile.hue_sat_maps;
+		let (first, second) = (first.as_ref().unwrap(), second.as_ref().unwrap());
+		assert_eq!(
+			(
+				first.hue_divisions,
+				first.sat_divisions,
+				first.val_divisions
+			),
+			(4, 2, 1)
+		);
+		assert!(first.entries.iter().all(|e| *e == [10.0, 1.0, 1.0]));
+		assert!(second.entries.iter().all(|e| *e == [0.0, 1.5, 1.0]));
+
+		// There's only the one colour matrix, so it's all the first map
+		let map = profile.hue_sat_map(WhitePoint::D65.xy()).unwrap();
+		assert_eq!(&map, first);
+	}
+}
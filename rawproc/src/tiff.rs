@@ -0,0 +1,377 @@
+//! Just enough TIFF to get at the tags rawloader doesn't hand us. Most raw
+//! formats, DNG, NEF, CR2, ARW, and friends, are TIFF underneath. ORF and RW2
+//! are too, with their own magic numbers.
+
+use std::collections::HashSet;
+
+pub(crate) const SUB_IFDS: u16 = 0x014A;
+pub(crate) const EXIF_IFD: u16 = 0x8769;
+
+/// Stop following IFDs after this many. A broken file could send us around
+/// in circles otherwise.
+const MAX_IFDS: usize = 64;
+
+pub(crate) struct Tiff<'a> {
+	data: &'a [u8],
+	big_endian: bool,
+	/// Every IFD we found. The main chain first, in order, with SubIFDs and
+	/// the EXIF IFD right after the IFD that points to them.
+	pub ifds: Vec<Ifd>,
+}
+
+pub(crate) struct Ifd {
+	pub entries: Vec<Entry>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Entry {
+	pub tag: u16,
+	pub kind: u16,
+	pub count: usize,
+	/// Where the value starts in the file
+	pub offset: usize,
+}
+
+impl Ifd {
+	pub fn get(&self, tag: u16) -> Option<&Entry> {
+		self.entries.iter().find(|entry| entry.tag == tag)
+	}
+}
+
+impl<'a> Tiff<'a> {
+	pub fn parse(data: &'a [u8]) -> Option<Self> {
+		// DCP profiles, Olympus, and Panasonic are TIFF with a different magic
+		// number
+		let big_endian = match data.get(0..4)? {
+			[b'I', b'I', 42, 0]
+			| [b'I', b'I', b'R', b'C']
+			| [b'I', b'I', b'R', b'O']
+			| [b'I', b'I', b'R', b'S']
+			| [b'I', b'I', b'U', 0] => false,
+			[b'M', b'M', 0, 42] | [b'M', b'M', b'C', b'R'] | [b'M', b'M', b'O', b'R'] => true,
+			_ => return None,
+		};
+
+		let mut tiff = Tiff {
+			data,
+			big_endian,
+			ifds: vec![],
+		};
+
+		let first = tiff.u32_at(4)? as usize;
+		let mut seen = HashSet::new();
+		tiff.read_chain(first, &mut seen);
+
+		Some(tiff)
+	}
+
+	fn read_chain(&mut self, mut offset: usize, seen: &mut HashSet<usize>) {
+		while offset != 0 && self.ifds.len() < MAX_IFDS && seen.insert(offset) {
+			let (ifd, next) = match self.read_ifd(offset) {
+				Some(read) => read,
+				None => return,
+			};
+
+			let children: Vec<usize> = [SUB_IFDS, EXIF_IFD]
+				.into_iter()
+				.filter_map(|tag| ifd.get(tag).copied())
+				.flat_map(|entry| self.unsigned(&entry))
+				.map(|child| child as usize)
+				.collect();
+
+			self.ifds.push(ifd);
+
+			for child in children {
+				self.read_chain(child, seen);
+			}
+
+			offset = next;
+		}
+	}
+
+	fn read_ifd(&self, offset: usize) -> Option<(Ifd, usize)> {
+		let count = self.u16_at(offset)? as usize;
+
+		let mut entries = Vec::with_capacity(count);
+		for idx in 0..count {
+			let start = offset + 2 + idx * 12;
+
+			let tag = self.u16_at(start)?;
+			let kind = self.u16_at(start + 2)?;
+			let count = self.u32_at(start + 4)? as usize;
+
+			// Values that fit in four bytes are right in the entry
+			let size = type_size(kind).saturating_mul(count);
+			let offset = if size <= 4 {
+				start + 8
+			} else {
+				self.u32_at(start + 8)? as usize
+			};
+
+			entries.push(Entry {
+				tag,
+				kind,
+				count,
+				offset,
+			});
+		}
+
+		let next = self.u32_at(offset + 2 + count * 12).unwrap_or(0) as usize;
+		Some((Ifd { entries }, next))
+	}
+
+	/// `length` bytes of the file starting at `offset`, if they're all there
+	pub fn slice(&self, offset: usize, length: usize) -> Option<&'a [u8]> {
+		self.data.get(offset..offset.checked_add(length)?)
+	}
+
+	/// The raw bytes of the value
+	pub fn bytes(&self, entry: &Entry) -> Option<&'a [u8]> {
+		let len = type_size(entry.kind).checked_mul(entry.count)?;
+		self.data.get(entry.offset..entry.offset.checked_add(len)?)
+	}
+
+	/// An ASCII value without its NUL and any padding. `None` if it's empty.
+	pub fn string(&self, entry: &Entry) -> Option<String> {
+		let bytes = self.bytes(entry)?;
+		let text = bytes.split(|b| *b == 0).next().unwrap_or(bytes);
+		let text = String::from_utf8_lossy(text).trim().to_owned();
+
+		(!text.is_empty()).then_some(text)
+	}
+
+	/// Every value of a RATIONAL or SRATIONAL entry as a numerator and
+	/// denominator.
+	pub fn rationals(&self, entry: &Entry) -> Vec<(i64, i64)> {
+		(0..entry.count)
+			.map_while(|idx| {
+				let at = entry.offset + idx * 8;
+				let (numerator, denominator) = (self.u32_at(at)?, self.u32_at(at + 4)?);
+				match entry.kind {
+					5 => Some((numerator as i64, denominator as i64)),
+					10 => Some((numerator as i32 as i64, denominator as i32 as i64)),
+					_ => None,
+				}
+			})
+			.collect()
+	}
+
+	/// Every value of an integer entry
+	pub fn unsigned(&self, entry: &Entry) -> Vec<u32> {
+		(0..entry.count)
+			.map_while(|idx| match entry.kind {
+				1 | 7 => self.data.get(entry.offset + idx).map(|b| *b as u32),
+				3 => self.u16_at(entry.offset + idx * 2).map(|v| v as u32),
+				4 | 13 => self.u32_at(entry.offset + idx * 4),
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Every value of a numeric entry, whatever its type
+	pub fn floats(&self, entry: &Entry) -> Vec<f64> {
+		(0..entry.count)
+			.map_while(|idx| {
+				let at = entry.offset + idx * type_size(entry.kind);
+				match entry.kind {
+					1 | 7 => self.data.get(at).map(|b| *b as f64),
+					3 => self.u16_at(at).map(|v| v as f64),
+					4 | 13 => self.u32_at(at).map(|v| v as f64),
+					5 => Some(self.u32_at(at)? as f64 / self.u32_at(at + 4)? as f64),
+					6 => self.data.get(at).map(|b| *b as i8 as f64),
+					8 => self.u16_at(at).map(|v| v as i16 as f64),
+					9 => self.u32_at(at).map(|v| v as i32 as f64),
+					10 => Some(self.u32_at(at)? as i32 as f64 / self.u32_at(at + 4)? as i32 as f64),
+					11 => self.u32_at(at).map(|v| f32::from_bits(v) as f64),
+					12 => self.u64_at(at).map(f64::from_bits),
+					_ => None,
+				}
+			})
+			.collect()
+	}
+
+	fn u16_at(&self, at: usize) -> Option<u16> {
+		let bytes: [u8; 2] = self.data.get(at..at + 2)?.try_into().ok()?;
+		Some(if self.big_endian {
+			u16::from_be_bytes(bytes)
+		} else {
+			u16::from_le_bytes(bytes)
+		})
+	}
+
+	fn u64_at(&self, at: usize) -> Option<u64> {
+		let bytes: [u8; 8] = self.data.get(at..at + 8)?.try_into().ok()?;
+		Some(if self.big_endian {
+			u64::from_be_bytes(bytes)
+		} else {
+			u64::from_le_bytes(bytes)
+		})
+	}
+
+	fn u32_at(&self, at: usize) -> Option<u32> {
+		let bytes: [u8; 4] = self.data.get(at..at + 4)?.try_into().ok()?;
+		Some(if self.big_endian {
+			u32::from_be_bytes(bytes)
+		} else {
+			u32::from_le_bytes(bytes)
+		})
+	}
+}
+
+fn type_size(kind: u16) -> usize {
+	match kind {
+		1 | 2 | 6 | 7 => 1,
+		3 | 8 => 2,
+		4 | 9 | 11 | 13 => 4,
+		5 | 10 | 12 => 8,
+		_ => 0,
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+
+	/// The value of an entry for [build]
+	pub(crate) enum Value {
+		Ascii(&'static str),
+		Short(Vec<u16>),
+		Long(Vec<u32>),
+		Rational(Vec<(u32, u32)>),
+		SRational(Vec<(i32, i32)>),
+		Undefined(Vec<u8>),
+		/// A LONG with where these bytes ended up in the file
+		Offset(Vec<u8>),
+	}
+
+	impl Value {
+		fn kind(&self) -> u16 {
+			match self {
+				Value::Ascii(_) => 2,
+				Value::Short(_) => 3,
+				Value::Long(_) | Value::Offset(_) => 4,
+				Value::Rational(_) => 5,
+				Value::Undefined(_) => 7,
+				Value::SRational(_) => 10,
+			}
+		}
+
+		fn count(&self) -> usize {
+			match self {
+				Value::Ascii(text) => text.len() + 1,
+				Value::Short(values) => values.len(),
+				Value::Long(values) => values.len(),
+				Value::Rational(values) => values.len(),
+				Value::SRational(values) => values.len(),
+				Value::Undefined(bytes) => bytes.len(),
+				Value::Offset(_) => 1,
+			}
+		}
+
+		fn bytes(&self) -> Vec<u8> {
+			match self {
+				Value::Ascii(text) => text.bytes().chain([0]).collect(),
+				Value::Short(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+				Value::Long(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+				Value::Rational(values) => values
+					.iter()
+					.flat_map(|(n, d)| n.to_le_bytes().into_iter().chain(d.to_le_bytes()))
+					.collect(),
+				Value::SRational(values) => values
+					.iter()
+					.flat_map(|(n, d)| n.to_le_bytes().into_iter().chain(d.to_le_bytes()))
+					.collect(),
+				Value::Undefined(bytes) | Value::Offset(bytes) => bytes.clone(),
+			}
+		}
+	}
+
+	/// A little-endian TIFF with `ifds` one after the other in the main
+	/// chain. Values that don't fit in their entry go after the last IFD.
+	pub(crate) fn build(ifds: &[Vec<(u16, Value)>]) -> Vec<u8> {
+		let mut ifd_offsets = vec![];
+		let mut at = 8;
+		for ifd in ifds {
+			ifd_offsets.push(at);
+			at += 2 + ifd.len() * 12 + 4;
+		}
+		let extra_start = at;
+		let mut extra = vec![];
+
+		let mut out = b"II*\0".to_vec();
+		out.extend_from_slice(&8u32.to_le_bytes());
+		for (idx, ifd) in ifds.iter().enumerate() {
+			out.extend_from_slice(&(ifd.len() as u16).to_le_bytes());
+			for (tag, value) in ifd {
+				out.extend_from_slice(&tag.to_le_bytes());
+				out.extend_from_slice(&value.kind().to_le_bytes());
+				out.extend_from_slice(&(value.count() as u32).to_le_bytes());
+
+				let mut bytes = value.bytes();
+				if matches!(value, Value::Offset(_)) || bytes.len() > 4 {
+					let offset = (extra_start + extra.len()) as u32;
+					extra.append(&mut bytes);
+					// Keep the next value on a word boundary like TIFF wants
+					if extra.len() % 2 == 1 {
+						extra.push(0);
+					}
+					bytes = offset.to_le_bytes().to_vec();
+				}
+				bytes.resize(4, 0);
+				out.extend_from_slice(&bytes);
+			}
+
+			let next = ifd_offsets.get(idx + 1).copied().unwrap_or(0) as u32;
+			out.extend_from_slice(&next.to_le_bytes());
+		}
+
+		out.append(&mut extra);
+		out
+	}
+
+	#[test]
+	fn reads_back_what_was_built() {
+		let data = build(&[
+			vec![
+				(0x010F, Value::Ascii("Camera Maker")),
+				(0x0100, Value::Short(vec![6000])),
+				(0x0111, Value::Offset(vec![1, 2, 3, 4, 5, 6])),
+			],
+			vec![
+				(0x829A, Value::Rational(vec![(1, 250)])),
+				(0x9204, Value::SRational(vec![(-2, 3), (5, -10)])),
+				(0x0201, Value::Long(vec![70000, 1])),
+			],
+		]);
+		let tiff = Tiff::parse(&data).unwrap();
+		assert_eq!(tiff.ifds.len(), 2);
+
+		let [first, second] = [&tiff.ifds[0], &tiff.ifds[1]];
+		assert_eq!(
+			tiff.string(first.get(0x010F).unwrap()).unwrap(),
+			"Camera Maker"
+		);
+		assert_eq!(tiff.unsigned(first.get(0x0100).unwrap()), vec![6000]);
+
+		let offset = tiff.unsigned(first.get(0x0111).unwrap())[0] as usize;
+		assert_eq!(tiff.slice(offset, 6), Some(&[1, 2, 3, 4, 5, 6][..]));
+		assert_eq!(tiff.slice(offset, data.len()), None);
+
+		assert_eq!(tiff.rationals(second.get(0x829A).unwrap()), vec![(1, 250)]);
+		let exposure_bias = second.get(0x9204).unwrap();
+		assert_eq!(tiff.rationals(exposure_bias), vec![(-2, 3), (5, -10)]);
+		assert_eq!(tiff.floats(exposure_bias), vec![-2.0 / 3.0, -0.5]);
+		assert_eq!(tiff.unsigned(second.get(0x0201).unwrap()), vec![70000, 1]);
+	}
+
+	#[test]
+	fn ifds_that_loop_are_read_once() {
+		let mut data = build(&[vec![(0x0100, Value::Short(vec![1]))]]);
+		// Point the only IFD's next at itself
+		let next = data.len() - 4;
+		data[next..].copy_from_slice(&8u32.to_le_bytes());
+
+		let tiff = Tiff::parse(&data).unwrap();
+		assert_eq!(tiff.ifds.len(), 1);
+	}
+}